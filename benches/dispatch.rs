@@ -0,0 +1,123 @@
+// Compares the threading modes of the event loop: clients send small GET
+// requests for a few seconds and the throughput of each mode is reported,
+// with `cargo bench --bench dispatch`.
+
+#![allow(bare_trait_objects)]
+
+extern crate webserver;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::str;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use webserver::handler_lib::*;
+use webserver::http::*;
+use webserver::*;
+
+const WORKERS: usize = 4;
+const CLIENTS: usize = 32;
+const RUN_TIME: Duration = Duration::from_secs(5);
+
+struct HelloHandler;
+impl Handler for HelloHandler {
+    fn process(&mut self, _: Request, resp: &mut Response) {
+        resp.set_header("Content-Type", "text/plain").set_body_str("Hello, world!").send();
+    }
+    fn duplicate(&self) -> Box<Handler> {
+        Box::new(HelloHandler)
+    }
+}
+
+// Reads one response, returns false if the connection was closed.
+fn read_response(stream: &mut TcpStream, buf: &mut Vec<u8>) -> bool {
+    let mut chunk = [0; 4096];
+    loop {
+        if let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            let headers = str::from_utf8(&buf[..end]).unwrap().to_string();
+            let length = headers.lines()
+                                .filter_map(|l| l.strip_prefix("Content-Length: "))
+                                .map(|v| v.parse::<usize>().unwrap())
+                                .next()
+                                .unwrap_or(0);
+            if buf.len() >= end + 4 + length {
+                buf.drain(..end + 4 + length);
+                return true;
+            }
+        }
+        match stream.read(&mut chunk) {
+            Ok(0) | Err(_) => return false,
+            Ok(n) => buf.extend_from_slice(&chunk[..n]),
+        }
+    }
+}
+
+fn client(addr: &str, keep_alive: bool, stop: &AtomicBool, count: &AtomicUsize) {
+    let mut conn: Option<TcpStream> = None;
+    let mut buf = Vec::new();
+    while !stop.load(Ordering::Relaxed) {
+        if conn.is_none() {
+            conn = Some(TcpStream::connect(addr).unwrap());
+            buf.clear();
+        }
+        let ok = {
+            let stream = conn.as_mut().unwrap();
+            stream.write_all(b"GET /hello HTTP/1.1\r\nHost: bench\r\n\r\n").is_ok() &&
+            read_response(stream, &mut buf)
+        };
+        if ok {
+            count.fetch_add(1, Ordering::Relaxed);
+        }
+        if !ok || !keep_alive {
+            conn = None;
+        }
+    }
+}
+
+fn bench(threading: Threading, addr: &str, keep_alive: bool) -> f64 {
+    let mut server = WebServer::new(addr, WORKERS);
+    server.add_handler("/hello", HelloHandler);
+    server.set_threading(threading);
+    server.set_shutdown_timeout(Duration::from_secs(1));
+    let handle = server.shutdown_handle();
+    let server_thread = thread::spawn(move || server.run());
+    thread::sleep(Duration::from_millis(200));
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let count = Arc::new(AtomicUsize::new(0));
+    let start = Instant::now();
+    let clients: Vec<_> = (0..CLIENTS)
+                              .map(|_| {
+                                  let addr = addr.to_string();
+                                  let stop = stop.clone();
+                                  let count = count.clone();
+                                  thread::spawn(move || client(&addr, keep_alive, &stop, &count))
+                              })
+                              .collect();
+    thread::sleep(RUN_TIME);
+    stop.store(true, Ordering::Relaxed);
+    for c in clients {
+        c.join().unwrap();
+    }
+    let elapsed = start.elapsed();
+    handle.shutdown();
+    server_thread.join().unwrap().unwrap();
+    count.load(Ordering::Relaxed) as f64 / elapsed.as_secs_f64()
+}
+
+fn main() {
+    let mut port = 18080;
+    for &keep_alive in &[true, false] {
+        for &threading in &[Threading::Dispatcher, Threading::ReusePort] {
+            let addr = format!("127.0.0.1:{}", port);
+            port += 1;
+            let rate = bench(threading, &addr, keep_alive);
+            eprintln!("{:?}, {}: {:.0} requests/s",
+                      threading,
+                      if keep_alive { "keep-alive" } else { "connection per request" },
+                      rate);
+        }
+    }
+}
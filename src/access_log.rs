@@ -0,0 +1,353 @@
+use std::fmt::Write;
+use std::io::{self, Write as IoWrite};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Instant, SystemTime};
+use date::{clf_date, rfc3339};
+use http::Request;
+use logging::LogFile;
+use metrics::HttpMetrics;
+
+const COMMON: &str = "%h - - %t \"%r\" %>s %b";
+const COMBINED: &str = "%h - - %t \"%r\" %>s %b \"%{Referer}i\" \"%{User-Agent}i\"";
+
+/// How the requests are written in an access log.
+#[derive(Debug, Clone)]
+pub enum LogFormat {
+    /// The Common Log Format: `%h - - %t "%r" %>s %b`.
+    Common,
+    /// The Common Log Format with the referer and user agent.
+    Combined,
+    /// One JSON object per line, with the time, client address, method,
+    /// URI, query, protocol, host, status, bytes, duration in milliseconds,
+    /// referer and user agent.
+    Json,
+    /// A template with the directives of Apache: `%h` the client address,
+    /// `%t` the time, `%r` the request line, `%m` the method, `%U` the path,
+    /// `%q` the query string, `%H` the protocol, `%s` or `%>s` the status,
+    /// `%b` or `%B` the bytes of the body, `%D` the duration in
+    /// microseconds, `%T` in seconds, `%v` the host and `%{Name}i` a
+    /// request header.
+    Template(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    Text(String),
+    Remote,
+    Time,
+    RequestLine,
+    Method,
+    Path,
+    Query,
+    Protocol,
+    Status,
+    // "-" for no bytes, as in the Common Log Format.
+    Bytes(bool),
+    Micros,
+    Seconds,
+    Host,
+    Header(String),
+}
+
+fn parse_template(template: &str) -> Vec<Part> {
+    let mut parts = Vec::new();
+    let mut text = String::new();
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            text.push(c);
+            continue;
+        }
+        // The final status, the only one there is here.
+        if chars.peek() == Some(&'>') {
+            chars.next();
+        }
+        let part = match chars.next() {
+            Some('%') => {
+                text.push('%');
+                continue;
+            }
+            Some('h') | Some('a') => Part::Remote,
+            Some('t') => Part::Time,
+            Some('r') => Part::RequestLine,
+            Some('m') => Part::Method,
+            Some('U') => Part::Path,
+            Some('q') => Part::Query,
+            Some('H') => Part::Protocol,
+            Some('s') => Part::Status,
+            Some('b') => Part::Bytes(true),
+            Some('B') => Part::Bytes(false),
+            Some('D') => Part::Micros,
+            Some('T') => Part::Seconds,
+            Some('v') => Part::Host,
+            Some('{') => {
+                let name: String = chars.by_ref().take_while(|&c| c != '}').collect();
+                match chars.next() {
+                    Some('i') => Part::Header(name),
+                    other => {
+                        text.push_str(&format!("%{{{}}}", name));
+                        text.extend(other);
+                        continue;
+                    }
+                }
+            }
+            other => {
+                // Unknown, kept as it is.
+                text.push('%');
+                text.extend(other);
+                continue;
+            }
+        };
+        if !text.is_empty() {
+            parts.push(Part::Text(text.clone()));
+            text.clear();
+        }
+        parts.push(part);
+    }
+    if !text.is_empty() {
+        parts.push(Part::Text(text));
+    }
+    parts
+}
+
+enum Output {
+    Stdout,
+    File(Arc<LogFile>),
+}
+
+/// Writes a line for every request answered, to a file or stdout. Files are
+/// reopened on SIGHUP once `WebServer::reopen_logs_on_sighup` was called, so
+/// that they can be rotated.
+pub struct AccessLog {
+    // None for JSON.
+    parts: Option<Vec<Part>>,
+    // The request headers the lines show.
+    headers: Vec<String>,
+    output: Output,
+}
+
+impl AccessLog {
+    // Appends to the file at `path`, created if needed.
+    pub fn open(path: &str, format: LogFormat) -> io::Result<AccessLog> {
+        Ok(AccessLog::with_output(Output::File(LogFile::open(path)?), format))
+    }
+
+    pub fn stdout(format: LogFormat) -> AccessLog {
+        AccessLog::with_output(Output::Stdout, format)
+    }
+
+    fn with_output(output: Output, format: LogFormat) -> AccessLog {
+        let parts = match format {
+            LogFormat::Common => Some(parse_template(COMMON)),
+            LogFormat::Combined => Some(parse_template(COMBINED)),
+            LogFormat::Json => None,
+            LogFormat::Template(ref template) => Some(parse_template(template)),
+        };
+        let headers = match parts {
+            Some(ref parts) => {
+                parts.iter()
+                     .filter_map(|p| match *p {
+                         Part::Header(ref name) => Some(name.clone()),
+                         Part::Host => Some("Host".to_string()),
+                         _ => None,
+                     })
+                     .collect()
+            }
+            None => vec!["Host".to_string(), "Referer".to_string(), "User-Agent".to_string()],
+        };
+        return AccessLog {
+            parts: parts,
+            headers: headers,
+            output: output,
+        };
+    }
+
+    fn header<'a>(&self, record: &'a Record, name: &str) -> Option<&'a str> {
+        self.headers
+            .iter()
+            .position(|h| h.eq_ignore_ascii_case(name))
+            .and_then(|idx| record.headers[idx].as_deref())
+    }
+
+    fn format(&self, record: &Record, status: u32, bytes: u64) -> String {
+        let parts = match self.parts {
+            Some(ref parts) => parts,
+            None => return self.format_json(record, status, bytes),
+        };
+        let elapsed = record.start.elapsed();
+        let mut line = String::new();
+        for part in parts {
+            let _ = match *part {
+                Part::Text(ref text) => write!(line, "{}", text),
+                Part::Remote => {
+                    match record.remote {
+                        Some(addr) => write!(line, "{}", addr.ip()),
+                        None => write!(line, "-"),
+                    }
+                }
+                Part::Time => write!(line, "[{}]", clf_date(record.time)),
+                Part::RequestLine => {
+                    write!(line, "{}", escape(&format!("{} {} {}",
+                                                       record.method,
+                                                       record.target(),
+                                                       record.version)))
+                }
+                Part::Method => write!(line, "{}", escape(&record.method)),
+                Part::Path => write!(line, "{}", escape(&record.uri)),
+                Part::Query => {
+                    match record.query {
+                        Some(ref query) => write!(line, "?{}", escape(query)),
+                        None => Ok(()),
+                    }
+                }
+                Part::Protocol => write!(line, "{}", record.version),
+                Part::Status => write!(line, "{}", status),
+                Part::Bytes(true) if bytes == 0 => write!(line, "-"),
+                Part::Bytes(_) => write!(line, "{}", bytes),
+                Part::Micros => write!(line, "{}", elapsed.as_micros()),
+                Part::Seconds => write!(line, "{}", elapsed.as_secs()),
+                Part::Host => Self::write_header(&mut line, self.header(record, "Host")),
+                Part::Header(ref name) => Self::write_header(&mut line, self.header(record, name)),
+            };
+        }
+        line
+    }
+
+    fn write_header(line: &mut String, value: Option<&str>) -> ::std::fmt::Result {
+        write!(line, "{}", escape(value.unwrap_or("-")))
+    }
+
+    fn format_json(&self, record: &Record, status: u32, bytes: u64) -> String {
+        let optional = |value: Option<&str>| value.map_or("null".to_string(), json_string);
+        format!("{{\"time\":{},\"remote_addr\":{},\"method\":{},\"uri\":{},\"query\":{},\
+                 \"protocol\":{},\"host\":{},\"status\":{},\"bytes\":{},\"duration_ms\":{:.3},\
+                 \"referer\":{},\"user_agent\":{}}}",
+                json_string(&rfc3339(record.time)),
+                optional(record.remote.map(|a| a.ip().to_string()).as_deref()),
+                json_string(&record.method),
+                json_string(&record.uri),
+                optional(record.query.as_deref()),
+                json_string(&record.version),
+                optional(self.header(record, "Host")),
+                status,
+                bytes,
+                record.start.elapsed().as_secs_f64() * 1000.0,
+                optional(self.header(record, "Referer")),
+                optional(self.header(record, "User-Agent")))
+    }
+
+    fn write(&self, line: &str) {
+        let result = match self.output {
+            Output::Stdout => writeln!(io::stdout(), "{}", line),
+            Output::File(ref file) => file.write_line(line),
+        };
+        if let Err(e) = result {
+            warn!("Error while writing the access log: {}", e);
+        }
+    }
+}
+
+// What the access log and the metrics show of a request, taken before it
+// is handled.
+pub(crate) struct Record {
+    log: Option<Arc<AccessLog>>,
+    metrics: Option<Arc<HttpMetrics>>,
+    // The route of the handler, empty when none matched.
+    route: String,
+    start: Instant,
+    time: SystemTime,
+    remote: Option<SocketAddr>,
+    method: String,
+    uri: String,
+    query: Option<String>,
+    version: String,
+    headers: Vec<Option<String>>,
+}
+
+impl Record {
+    pub(crate) fn new(log: Option<&Arc<AccessLog>>,
+                      metrics: Option<&Arc<HttpMetrics>>,
+                      r: &Request)
+                      -> Record {
+        let headers = match log {
+            Some(log) => log.headers.iter().map(|h| r.header(h).map(|v| v.to_string())).collect(),
+            None => Vec::new(),
+        };
+        return Record {
+            log: log.cloned(),
+            metrics: metrics.cloned(),
+            route: String::new(),
+            start: Instant::now(),
+            time: SystemTime::now(),
+            remote: r.peer_addr(),
+            method: r.method().to_string(),
+            uri: r.uri.clone(),
+            query: r.query().map(|q| q.to_string()),
+            version: r.version().to_string(),
+            headers: headers,
+        };
+    }
+
+    pub(crate) fn set_route(&mut self, route: &str) {
+        self.route = route.to_string();
+    }
+
+    // The URI as the client sent it.
+    fn target(&self) -> String {
+        match self.query {
+            Some(ref query) => format!("{}?{}", self.uri, query),
+            None => self.uri.clone(),
+        }
+    }
+
+    // Writes the line of the request, answered with `status` and a body of
+    // `bytes`.
+    pub(crate) fn write(self, status: u32, bytes: u64) {
+        if let Some(ref log) = self.log {
+            log.write(&log.format(&self, status, bytes));
+        }
+        if let Some(ref metrics) = self.metrics {
+            metrics.observe(&self.route, &self.method, status, self.start.elapsed());
+        }
+    }
+}
+
+// Escapes the quotes, backslashes and control characters of a value, as
+// Apache does, so that a line can't be forged.
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' | '\\' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            c if c.is_control() => {
+                let _ = write!(escaped, "\\x{:02x}", c as u32);
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+pub(crate) fn json_string(value: &str) -> String {
+    let mut json = String::with_capacity(value.len() + 2);
+    json.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 || c == '\u{7f}' => {
+                let _ = write!(json, "\\u{:04x}", c as u32);
+            }
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
@@ -1,113 +1,482 @@
-use std::io::prelude::*;
 use mio::*;
-use mio::tcp::*;
+use mio::net::*;
+use mio::unix::UnixReady;
 use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use rustls::{ServerConfig, ServerConnection};
 use event_loop::*;
+use connection::*;
+use http::PendingRequest;
+use metrics::ConnMetrics;
+use status::{ConnStatus, ServerStatus};
+use timer::Timer;
+
+// Timer token of the connection timeouts, tokens above are the app's.
+const CONN_TIMEOUT: usize = 0;
 
 pub trait App : Send + 'static {
-    fn handle(&mut self, stream: &mut TcpStream);
+    fn handle(&mut self, conn: &mut Connection);
+    // Called with a token passed to `Connection::set_timer()`.
+    fn timeout(&mut self, conn: &mut Connection, token: usize);
+    // Called when the rest of a request took too long to arrive.
+    fn request_timeout(&mut self, conn: &mut Connection);
+    // Called after the connection's waker was used.
+    fn wake(&mut self, conn: &mut Connection);
+    // Called when the server shuts down while the connection is busy. It
+    // must close the connection once what is going on is done.
+    fn shutdown(&mut self, conn: &mut Connection);
+    // Called once everything queued on the connection was sent. Returns
+    // whether more was queued.
+    fn drained(&mut self, conn: &mut Connection) -> bool;
+    // Which part of a request is being received, if any.
+    fn pending_request(&self) -> PendingRequest;
+    // Fills in the protocol and requests of the connection, for the status
+    // page.
+    fn report(&self, _status: &mut ConnStatus) {}
     fn duplicate(&self) -> Box<App>;
 }
 
+/// How long a connection can stay in each state before it is closed.
+#[derive(Debug, Clone, Copy)]
+pub struct Timeouts {
+    /// Between two requests on a keep-alive connection.
+    pub idle: Duration,
+    /// To receive the request line and headers, from their first byte.
+    pub header: Duration,
+    /// Between two reads of a request body.
+    pub body: Duration,
+    /// Between two writes of a response the client doesn't read.
+    pub write: Duration,
+}
+
+impl Timeouts {
+    pub fn new() -> Timeouts {
+        return Timeouts {
+            idle: Duration::from_secs(75),
+            header: Duration::from_secs(30),
+            body: Duration::from_secs(30),
+            write: Duration::from_secs(60),
+        };
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Phase {
+    Idle,
+    Headers,
+    Body,
+    // A handler is busy with a request, off the event thread.
+    Processing,
+    Write,
+}
+
+impl Phase {
+    fn name(self) -> &'static str {
+        match self {
+            Phase::Idle => "idle",
+            Phase::Headers => "reading headers",
+            Phase::Body => "reading body",
+            Phase::Processing => "processing",
+            Phase::Write => "writing",
+        }
+    }
+}
+
 struct AppWithStream {
     app: Box<App>,
-    stream: TcpStream,
+    conn: Connection,
+    hup: bool,
+    phase: Phase,
+    timer: Option<Timer>,
+    opened: Instant,
 }
 impl AppWithStream {
-    fn new(app: Box<App>, stream: TcpStream) -> AppWithStream {
+    fn new(app: Box<App>, conn: Connection) -> AppWithStream {
         AppWithStream {
             app: app,
-            stream: stream,
+            conn: conn,
+            hup: false,
+            phase: Phase::Idle,
+            timer: None,
+            opened: Instant::now(),
         }
     }
+    fn status(&self) -> ConnStatus {
+        let mut status = ConnStatus {
+            peer: self.conn.peer_addr(),
+            opened: self.opened,
+            state: self.phase.name(),
+            received: self.conn.bytes_received(),
+            sent: self.conn.bytes_sent(),
+            protocol: "-",
+            request: None,
+            requests: 0,
+        };
+        self.app.report(&mut status);
+        status
+    }
     fn handle(&mut self) {
-        self.app.handle(&mut self.stream);
+        self.app.handle(&mut self.conn);
     }
-    fn shutdown(&self) {
-        let _ = self.stream.shutdown(Shutdown::Both);
+    fn shutdown(&mut self) {
+        self.conn.shutdown();
     }
-    fn flush(&mut self) {
-        let mut buf = Vec::new();
-        let _ = self.stream.read_to_end(&mut buf);
+    // Returns how many bytes were written, or None if the connection broke.
+    fn flush(&mut self) -> Option<usize> {
+        match self.conn.flush() {
+            Ok(n) => Some(n),
+            Err(e) => {
+                warn!("Error while writing: {}", e);
+                None
+            }
+        }
+    }
+    // Sends what the app wrote, and what it has to write once that is sent.
+    // Returns how many bytes were written, or None if the connection broke.
+    fn send_output(&mut self) -> Option<usize> {
+        let mut written = 0;
+        loop {
+            written += self.flush()?;
+            if self.conn.has_pending_output() || !self.app.drained(&mut self.conn) {
+                return Some(written);
+            }
+        }
+    }
+    fn current_phase(&self) -> Phase {
+        if self.conn.has_pending_output() {
+            return Phase::Write;
+        }
+        match self.app.pending_request() {
+            PendingRequest::None => Phase::Idle,
+            PendingRequest::Headers => Phase::Headers,
+            PendingRequest::Body => Phase::Body,
+            PendingRequest::Processing => Phase::Processing,
+        }
+    }
+    // A connection is idle when it is between requests.
+    fn is_idle(&self) -> bool {
+        self.current_phase() == Phase::Idle
+    }
+    fn is_done(&self) -> bool {
+        if self.hup || self.conn.is_peer_closed() {
+            // The peer won't send the rest of a partial request.
+            return !self.conn.has_pending_output();
+        }
+        self.conn.is_closing() && self.is_idle()
+    }
+    // Re-arms the connection timeout after some I/O. The header timeout
+    // counts from the first byte of the request, so a client can't keep it
+    // alive by trickling data, while the body and write timeouts restart
+    // whenever data goes through. There is none while a handler is busy.
+    fn update_timer(&mut self, id: usize, timers: &mut Timers, timeouts: &Timeouts, progress: bool) {
+        let phase = self.current_phase();
+        let restart = match phase {
+            Phase::Body | Phase::Write => progress,
+            Phase::Idle | Phase::Headers | Phase::Processing => false,
+        };
+        if phase == self.phase && self.timer.is_some() && !restart {
+            return;
+        }
+        if let Some(timer) = self.timer.take() {
+            timers.cancel(timer);
+        }
+        self.phase = phase;
+        let timeout = match phase {
+            Phase::Idle => timeouts.idle,
+            Phase::Headers => timeouts.header,
+            Phase::Body => timeouts.body,
+            Phase::Write => timeouts.write,
+            Phase::Processing => return,
+        };
+        self.timer = Some(timers.schedule(timeout, (id, CONN_TIMEOUT)));
+    }
+    fn schedule_app_timers(&mut self, id: usize, timers: &mut Timers) {
+        for (delay, token) in self.conn.take_timers() {
+            timers.schedule(delay, (id, token + 1));
+        }
     }
 }
 
 struct AppEventHandler {
     app: Box<App>,
     conns: HashMap<usize, AppWithStream>,
+    draining: bool,
+    timeouts: Timeouts,
+    notifier: Option<Notifier>,
+    // By listener, None for the plain TCP ones.
+    tls: Vec<Option<Arc<ServerConfig>>>,
+    // The addresses of the listeners, as they were given.
+    addrs: Vec<Option<SocketAddr>>,
+    metrics: Option<ConnMetrics>,
+    status: Option<Arc<ServerStatus>>,
 }
 impl AppEventHandler {
-    fn new(app: Box<App>) -> AppEventHandler {
+    fn new(app: Box<App>,
+           timeouts: Timeouts,
+           tls: Vec<Option<Arc<ServerConfig>>>,
+           addrs: Vec<Option<SocketAddr>>)
+           -> AppEventHandler {
         return AppEventHandler {
             app: app,
             conns: HashMap::new(),
+            draining: false,
+            timeouts: timeouts,
+            notifier: None,
+            tls: tls,
+            addrs: addrs,
+            metrics: None,
+            status: None,
+        };
+    }
+
+    // Tells the status page what became of connection `id`.
+    fn report(&self, id: usize) {
+        if let Some(ref status) = self.status {
+            match self.conns.get(&id) {
+                Some(conn) => status.update(id, conn.status()),
+                None => status.remove(id),
+            }
+        }
+    }
+
+    fn close_conn(&mut self, id: usize, timers: &mut Timers) {
+        if let Some(mut conn) = self.conns.remove(&id) {
+            if let Some(timer) = conn.timer.take() {
+                timers.cancel(timer);
+            }
+            conn.shutdown();
+        }
+        self.report(id);
+    }
+
+    // Sends what the app wrote and re-arms the timeouts. Returns false if the
+    // connection must be closed.
+    fn after_io(conn: &mut AppWithStream,
+                id: usize,
+                timers: &mut Timers,
+                timeouts: &Timeouts,
+                read: bool)
+                -> bool {
+        conn.schedule_app_timers(id, timers);
+        let written = match conn.send_output() {
+            None => return false,
+            Some(n) => n,
         };
+        if conn.is_done() {
+            return false;
+        }
+        conn.update_timer(id, timers, timeouts, read || written > 0);
+        true
     }
 }
 impl EventHandler for AppEventHandler {
-    fn new_conn(&mut self, id: usize, stream: TcpStream) {
-        println!("Got new connection {}", id);
-        self.conns.insert(id, AppWithStream::new(self.app.duplicate(), stream));
-    }
-    fn conn_event(&mut self, id: usize, event: Ready) {
-        println!("Handling event!");
-        if event.is_error() || event.is_hup() {
-            if event.is_error() {
-                println!("Error event on conn {}", id);
-            } else {
-                println!("Hangup event on conn {}", id)
+    fn new_conn(&mut self, id: usize, listener: usize, stream: TcpStream, timers: &mut Timers) {
+        debug!("Got new connection {}", id);
+        let tls = match self.tls[listener] {
+            None => None,
+            Some(ref config) => {
+                match ServerConnection::new(config.clone()) {
+                    Ok(tls) => Some(tls),
+                    Err(e) => {
+                        warn!("Error while starting TLS on conn {}: {}", id, e);
+                        return;
+                    }
+                }
+            }
+        };
+        let waker = self.notifier.as_ref().expect("notifier not set").waker(id);
+        let mut conn = Connection::new(stream, tls, waker);
+        conn.set_listener_addr(self.addrs[listener]);
+        if let Some(ref metrics) = self.metrics {
+            conn.set_metrics(metrics.clone());
+        }
+        if self.draining {
+            // Accepted just before the shutdown, nothing was sent on it yet.
+            conn.shutdown();
+            return;
+        }
+        let mut conn = AppWithStream::new(self.app.duplicate(), conn);
+        conn.update_timer(id, timers, &self.timeouts, false);
+        self.conns.insert(id, conn);
+        self.report(id);
+    }
+    fn conn_event(&mut self, id: usize, event: Ready, timers: &mut Timers) {
+        trace!("Handling event!");
+        let open = match self.conns.get_mut(&id) {
+            None => {
+                debug!("Conn no {} can't be found in conns map for event!", id);
+                return;
             }
-            match self.conns.remove(&id) {
-                None => {
-                    println!("WARNING: conn no {} can't be found in conns map for shutdown event!",
-                             id)
+            Some(ref mut conn) => {
+                if event.is_readable() {
+                    trace!("Handling connection {}", id);
+                    conn.handle();
                 }
-                Some(mut conn) => {
-                    if event.is_readable() {
-                        conn.flush();
+                let unix_event = UnixReady::from(event);
+                if unix_event.is_error() {
+                    debug!("Error event on conn {}", id);
+                    false
+                } else {
+                    if unix_event.is_hup() {
+                        // Let the responses to what was already received go
+                        // out before closing.
+                        debug!("Hangup event on conn {}", id);
+                        conn.hup = true;
                     }
-                    conn.shutdown();
-                    self.conns.remove(&id);
-                    return;
+                    Self::after_io(conn, id, timers, &self.timeouts, event.is_readable())
                 }
             }
+        };
+        if open {
+            self.report(id);
+        } else {
+            self.close_conn(id, timers);
         }
-        if event.is_readable() {
-            match self.conns.get_mut(&id) {
-                None => {
-                    println!("WARNING: conn no {} can't be found in conns map for read event!",
-                             id)
-                }
-                Some(ref mut conn) => {
-                    println!("Handling connection {}", id);
-                    conn.handle();
+    }
+    fn timeout(&mut self, id: usize, token: usize, timers: &mut Timers) {
+        let open = match self.conns.get_mut(&id) {
+            // Closed since, nothing to do.
+            None => return,
+            Some(ref mut conn) => {
+                if token == CONN_TIMEOUT {
+                    conn.timer = None;
+                    debug!("Timeout on conn {} while {:?}", id, conn.phase);
+                    match conn.phase {
+                        Phase::Idle | Phase::Write | Phase::Processing => false,
+                        Phase::Headers | Phase::Body => {
+                            conn.app.request_timeout(&mut conn.conn);
+                            // Give the client a chance to get the 408, the
+                            // write timeout still applies.
+                            Self::after_io(conn, id, timers, &self.timeouts, false)
+                        }
+                    }
+                } else {
+                    conn.app.timeout(&mut conn.conn, token - 1);
+                    Self::after_io(conn, id, timers, &self.timeouts, false)
                 }
             }
+        };
+        if open {
+            self.report(id);
+        } else {
+            self.close_conn(id, timers);
         }
     }
+    fn set_notifier(&mut self, notifier: Notifier) {
+        self.notifier = Some(notifier);
+    }
+    fn notify(&mut self, id: usize, timers: &mut Timers) {
+        let open = match self.conns.get_mut(&id) {
+            // Closed while the app was busy with it.
+            None => return,
+            Some(ref mut conn) => {
+                conn.app.wake(&mut conn.conn);
+                Self::after_io(conn, id, timers, &self.timeouts, false)
+            }
+        };
+        if open {
+            self.report(id);
+        } else {
+            self.close_conn(id, timers);
+        }
+    }
+    fn shutdown(&mut self) {
+        self.draining = true;
+        let idle: Vec<usize> = self.conns
+                                   .iter()
+                                   .filter(|&(_, conn)| conn.is_idle())
+                                   .map(|(id, _)| *id)
+                                   .collect();
+        info!("Closing {} idle connections, waiting for {} in-flight",
+              idle.len(),
+              self.conns.len() - idle.len());
+        for id in idle {
+            // Their timers fire on a missing connection and are ignored.
+            if let Some(mut conn) = self.conns.remove(&id) {
+                conn.shutdown();
+            }
+            self.report(id);
+        }
+        let mut done = Vec::new();
+        for (id, conn) in self.conns.iter_mut() {
+            conn.app.shutdown(&mut conn.conn);
+            // What the app queued has no other event to go out with, and
+            // those it finished with won't get one to close on.
+            if conn.send_output().is_none() || conn.is_done() {
+                done.push(*id);
+            }
+        }
+        for id in done {
+            if let Some(mut conn) = self.conns.remove(&id) {
+                conn.shutdown();
+            }
+            self.report(id);
+        }
+    }
+    fn num_conns(&self) -> usize {
+        self.conns.len()
+    }
     fn duplicate(&self) -> Box<EventHandler> {
-        return Box::new(AppEventHandler::new(self.app.duplicate()));
+        let mut handler = AppEventHandler::new(self.app.duplicate(),
+                                               self.timeouts,
+                                               self.tls.clone(),
+                                               self.addrs.clone());
+        handler.metrics = self.metrics.clone();
+        handler.status = self.status.clone();
+        return Box::new(handler);
     }
 }
 
+// A host to accept connections on, with TLS if it has a config.
+pub struct Listener {
+    pub host: String,
+    pub tls: Option<Arc<ServerConfig>>,
+}
+
 pub struct AppServer {
-    host: String,
-    num_workers: usize,
+    listeners: Vec<Listener>,
     app: Box<App>,
+    timeouts: Timeouts,
+    settings: LoopSettings,
+    metrics: Option<ConnMetrics>,
+    status: Option<Arc<ServerStatus>>,
 }
 impl AppServer {
-    pub fn new(host: &str, num_workers: usize, app: Box<App>) -> AppServer {
+    pub fn new(listeners: Vec<Listener>,
+               app: Box<App>,
+               timeouts: Timeouts,
+               settings: LoopSettings)
+               -> AppServer {
         return AppServer {
-            host: host.to_string(),
-            num_workers: num_workers,
+            listeners: listeners,
             app: app,
+            timeouts: timeouts,
+            settings: settings,
+            metrics: None,
+            status: None,
         };
     }
 
-    pub fn run(self) {
-        let l = EventLoop::new(&self.host,
-                               self.num_workers,
-                               Box::new(AppEventHandler::new(self.app)));
+    // Counts the bytes received and sent on the connections.
+    pub fn set_metrics(&mut self, metrics: ConnMetrics) {
+        self.metrics = Some(metrics);
+    }
+
+    // Reports the connections to `status`.
+    pub fn set_status(&mut self, status: Arc<ServerStatus>) {
+        self.status = Some(status);
+    }
+
+    // Serves on `bound`, the listeners of `self.listeners` in that order.
+    pub fn run(self, bound: BoundListeners) {
+        let addrs = self.listeners.iter().map(|l| l.host.parse().ok()).collect();
+        let tls = self.listeners.into_iter().map(|l| l.tls).collect();
+        let mut handler = AppEventHandler::new(self.app, self.timeouts, tls, addrs);
+        handler.metrics = self.metrics;
+        handler.status = self.status;
+        let l = EventLoop::new(bound, Box::new(handler), self.settings);
         l.run();
     }
 }
@@ -0,0 +1,234 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::mpsc::*;
+use std::thread;
+use std::time::Duration;
+use access_log::Record;
+use compression::Compression;
+use connection::Output;
+use event_loop::Waker;
+use handler_lib::Handler;
+use http::{Request, Response};
+use http2::StreamOutput;
+
+// How much of a response a handler can flush ahead of what the client has
+// taken.
+const MAX_UNSENT: u64 = 256 * 1024;
+
+// Where the handler writes its response, depending on the protocol.
+pub enum JobOutput {
+    Http1(Output),
+    Http2(StreamOutput),
+}
+
+impl JobOutput {
+    fn append(&mut self, other: JobOutput) {
+        match (self, other) {
+            (&mut JobOutput::Http1(ref mut out), JobOutput::Http1(other)) => out.append(other),
+            (&mut JobOutput::Http2(ref mut out), JobOutput::Http2(other)) => out.append(other),
+            _ => unreachable!(),
+        }
+    }
+}
+
+struct Flushed {
+    output: Option<JobOutput>,
+    // Bytes of body flushed and not taken by the connection yet, and taken
+    // but not sent yet.
+    queued: u64,
+    taken: u64,
+    disconnected: bool,
+}
+
+// Hands what a handler wrote so far over to its connection, while it goes
+// on with the rest of the response.
+pub struct Flusher {
+    shared: Arc<(Mutex<Flushed>, Condvar)>,
+    waker: Waker,
+}
+
+impl Flusher {
+    // Returns the flusher for a job, and the connection side of it.
+    pub fn new(waker: Waker) -> (Flusher, FlushedOutput) {
+        let shared = Arc::new((Mutex::new(Flushed {
+            output: None,
+            queued: 0,
+            taken: 0,
+            disconnected: false,
+        }), Condvar::new()));
+        let flusher = Flusher {
+            shared: shared.clone(),
+            waker: waker,
+        };
+        return (flusher, FlushedOutput { shared: shared });
+    }
+
+    // Queues `output`, with `len` bytes of body, for the connection. Waits
+    // while the client is too far behind. Returns false once the
+    // connection is gone.
+    pub fn flush(&self, output: JobOutput, len: u64) -> bool {
+        let (ref flushed, ref sent) = *self.shared;
+        let mut flushed = flushed.lock().unwrap();
+        if flushed.disconnected {
+            return false;
+        }
+        match flushed.output {
+            Some(ref mut queued) => queued.append(output),
+            None => flushed.output = Some(output),
+        }
+        flushed.queued += len;
+        self.waker.wake();
+        while flushed.queued + flushed.taken > MAX_UNSENT && !flushed.disconnected {
+            flushed = sent.wait(flushed).unwrap();
+        }
+        !flushed.disconnected
+    }
+}
+
+// The connection side of a `Flusher`. Dropping it lets the handler know the
+// connection is gone.
+pub struct FlushedOutput {
+    shared: Arc<(Mutex<Flushed>, Condvar)>,
+}
+
+impl FlushedOutput {
+    // Takes what was flushed since the last call.
+    pub fn take(&self) -> Option<JobOutput> {
+        let mut flushed = self.shared.0.lock().unwrap();
+        flushed.taken += flushed.queued;
+        flushed.queued = 0;
+        flushed.output.take()
+    }
+
+    // Called once what was taken is sent, the handler can flush more.
+    pub fn sent(&self) {
+        let (ref flushed, ref sent) = *self.shared;
+        let mut flushed = flushed.lock().unwrap();
+        if flushed.taken > 0 {
+            flushed.taken = 0;
+            sent.notify_one();
+        }
+    }
+
+    // Tells the handler the client is gone, what it flushes is dropped.
+    pub fn disconnect(&self) {
+        let (ref flushed, ref sent) = *self.shared;
+        if let Ok(mut flushed) = flushed.lock() {
+            flushed.disconnected = true;
+        }
+        sent.notify_one();
+    }
+}
+
+impl Drop for FlushedOutput {
+    fn drop(&mut self) {
+        self.disconnect();
+    }
+}
+
+// A request handed to the pool, with the handler that processes it.
+pub struct Job {
+    pub handler: Box<Handler>,
+    pub request: Request,
+    pub compression: Option<Arc<Compression>>,
+    pub access_log: Option<Record>,
+    pub output: JobOutput,
+    pub done: Sender<Done>,
+    pub flusher: Flusher,
+    pub waker: Waker,
+}
+
+// What the handler produced, sent back to the event thread with the handler.
+pub struct Done {
+    pub handler: Box<Handler>,
+    pub output: JobOutput,
+    pub timers: Vec<(Duration, usize)>,
+    // The access log record, if the request wasn't answered yet.
+    pub access_log: Option<Record>,
+}
+
+// Threads that run handlers that may block, so that they don't hold up the
+// I/O of the other connections of their worker.
+#[derive(Clone)]
+pub struct BlockingPool {
+    sender: SyncSender<Job>,
+}
+
+impl BlockingPool {
+    pub fn new(num_threads: usize, queue_size: usize) -> BlockingPool {
+        let (sender, receiver) = sync_channel(queue_size);
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..num_threads {
+            let receiver = receiver.clone();
+            thread::spawn(move || {
+                Self::run_jobs(receiver);
+            });
+        }
+        return BlockingPool { sender: sender };
+    }
+
+    // Queues a job. Returns it back if the queue is full.
+    pub fn try_run(&self, job: Job) -> Option<Job> {
+        match self.sender.try_send(job) {
+            Ok(()) => None,
+            Err(TrySendError::Full(job)) | Err(TrySendError::Disconnected(job)) => Some(job),
+        }
+    }
+
+    fn run_jobs(receiver: Arc<Mutex<Receiver<Job>>>) {
+        loop {
+            // The threads exit once every copy of the pool is dropped.
+            let job = match receiver.lock().unwrap().recv() {
+                Ok(job) => job,
+                Err(_) => return,
+            };
+            let Job {
+                mut handler,
+                request,
+                compression,
+                access_log,
+                mut output,
+                done,
+                flusher,
+                waker,
+            } = job;
+            let (timers, access_log) = {
+                let resp = &mut match output {
+                    JobOutput::Http1(ref mut out) => Response::with_output(out),
+                    JobOutput::Http2(ref mut stream) => Response::for_stream(stream),
+                };
+                if request.method() == "HEAD" {
+                    resp.omit_body();
+                }
+                if let Some(ref compression) = compression {
+                    resp.set_compression(compression, &request);
+                }
+                resp.set_access_log(access_log);
+                resp.set_flusher(&flusher);
+                // A panic doesn't take the thread down, and the connection
+                // still gets its handler back.
+                let uri = request.uri.clone();
+                let process = AssertUnwindSafe(|| handler.process(request, resp));
+                if panic::catch_unwind(process).is_err() {
+                    error!("Handler panicked on {}", uri);
+                    if resp.is_sent() {
+                        resp.abort();
+                    } else {
+                        resp.set_internal_server_error().send();
+                    }
+                }
+                (resp.take_timers(), resp.take_access_log())
+            };
+            let result = Done {
+                handler: handler,
+                output: output,
+                timers: timers,
+                access_log: access_log,
+            };
+            // The connection may have been closed in the meantime.
+            if done.send(result).is_ok() {
+                waker.wake();
+            }
+        }
+    }
+}
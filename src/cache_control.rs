@@ -0,0 +1,212 @@
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use date::http_date;
+use handler_lib::Regex;
+use http::{Request, Response};
+
+/// How long and by whom a response can be kept, sent in its Cache-Control
+/// header, and in Expires for the older caches if asked.
+#[derive(Debug, Clone, Default)]
+pub struct CachePolicy {
+    max_age: Option<u64>,
+    stale_while_revalidate: Option<u64>,
+    immutable: bool,
+    no_store: bool,
+    no_cache: bool,
+    private: bool,
+    expires: bool,
+}
+
+impl CachePolicy {
+    pub fn new() -> CachePolicy {
+        return CachePolicy::default();
+    }
+
+    // How long the response is fresh.
+    pub fn set_max_age(&mut self, max_age: Duration) {
+        self.max_age = Some(max_age.as_secs());
+    }
+
+    // The response never changes while fresh, browsers don't revalidate it
+    // even when the page is reloaded.
+    pub fn set_immutable(&mut self, immutable: bool) {
+        self.immutable = immutable;
+    }
+
+    // The response is not to be kept at all. The other directives are left
+    // out then.
+    pub fn set_no_store(&mut self, no_store: bool) {
+        self.no_store = no_store;
+    }
+
+    // The response is kept but revalidated before every use.
+    pub fn set_no_cache(&mut self, no_cache: bool) {
+        self.no_cache = no_cache;
+    }
+
+    // The response is only for the browser, not shared caches.
+    pub fn set_private(&mut self, private: bool) {
+        self.private = private;
+    }
+
+    // How long a stale response can still be used while it is revalidated
+    // in the background.
+    pub fn set_stale_while_revalidate(&mut self, stale: Duration) {
+        self.stale_while_revalidate = Some(stale.as_secs());
+    }
+
+    // Whether the Expires header is sent too, the date the response stops
+    // being fresh.
+    pub fn set_expires(&mut self, expires: bool) {
+        self.expires = expires;
+    }
+
+    // The value of the Cache-Control header, None if there is nothing to
+    // say.
+    pub fn cache_control(&self) -> Option<String> {
+        let mut directives = Vec::new();
+        if self.private {
+            directives.push("private".to_string());
+        }
+        if self.no_store {
+            directives.push("no-store".to_string());
+            return Some(directives.join(", "));
+        }
+        if self.no_cache {
+            directives.push("no-cache".to_string());
+        }
+        if let Some(max_age) = self.max_age {
+            directives.push(format!("max-age={}", max_age));
+        }
+        if let Some(stale) = self.stale_while_revalidate {
+            directives.push(format!("stale-while-revalidate={}", stale));
+        }
+        if self.immutable {
+            directives.push("immutable".to_string());
+        }
+        if directives.is_empty() {
+            None
+        } else {
+            Some(directives.join(", "))
+        }
+    }
+
+    // The value of the Expires header for a response sent at `now`. A date
+    // in the past for the responses that are never fresh.
+    pub fn expires(&self, now: SystemTime) -> Option<String> {
+        if !self.expires {
+            return None;
+        }
+        if self.no_store || self.no_cache {
+            return Some(http_date(UNIX_EPOCH));
+        }
+        self.max_age.map(|max_age| http_date(now + Duration::from_secs(max_age)))
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Matcher {
+    Route(Regex),
+    // Matched against the file name only when the glob has no '/'.
+    Glob(Regex, bool),
+    Extension(String),
+}
+
+impl Matcher {
+    fn matches(&self, path: &str) -> bool {
+        match *self {
+            Matcher::Route(ref regex) => regex.is_match(path),
+            Matcher::Glob(ref regex, true) => regex.is_match(path.rsplit('/').next().unwrap_or("")),
+            Matcher::Glob(ref regex, false) => regex.is_match(path),
+            Matcher::Extension(ref ext) => {
+                Path::new(path).extension()
+                               .and_then(|e| e.to_str())
+                               .is_some_and(|e| e.eq_ignore_ascii_case(ext))
+            }
+        }
+    }
+}
+
+/// Caching policies chosen by the path of the request, by the first rule
+/// that matches it. File handlers apply them to the files they serve, other
+/// handlers with `apply`.
+#[derive(Debug, Clone, Default)]
+pub struct CachePolicies {
+    rules: Vec<(Matcher, CachePolicy)>,
+}
+
+impl CachePolicies {
+    pub fn new() -> CachePolicies {
+        return CachePolicies::default();
+    }
+
+    // For the paths matching `pattern`, a regular expression like those of
+    // the routes.
+    pub fn add_route(&mut self, pattern: &str, policy: CachePolicy) {
+        let regex = Regex::new(&format!("^{}$", pattern)).unwrap();
+        self.rules.push((Matcher::Route(regex), policy));
+    }
+
+    // For the paths matching `glob`, where '*' matches within a directory,
+    // '**' across them and '?' a single character. A glob without '/', like
+    // "*.min.js", matches the file name.
+    pub fn add_glob(&mut self, glob: &str, policy: CachePolicy) {
+        let name_only = !glob.contains('/');
+        let glob = if name_only || glob.starts_with('/') {
+            glob.to_string()
+        } else {
+            format!("/{}", glob)
+        };
+        let regex = Regex::new(&glob_pattern(&glob)).unwrap();
+        self.rules.push((Matcher::Glob(regex, name_only), policy));
+    }
+
+    // For the files with extension `ext`, like "woff2".
+    pub fn add_extension(&mut self, ext: &str, policy: CachePolicy) {
+        let ext = ext.trim_start_matches('.').to_string();
+        self.rules.push((Matcher::Extension(ext), policy));
+    }
+
+    // The policy of the first rule `path` matches.
+    pub fn find(&self, path: &str) -> Option<&CachePolicy> {
+        self.rules.iter().find(|&&(ref matcher, _)| matcher.matches(path)).map(|&(_, ref p)| p)
+    }
+
+    // Has `resp` follow the policy of `req`, if there is one. It applies
+    // when the response is sent, if it is a success and its handler set no
+    // Cache-Control of its own.
+    pub fn apply(&self, req: &Request, resp: &mut Response) {
+        if let Some(policy) = self.find(&req.uri) {
+            resp.set_cache_policy(policy);
+        }
+    }
+}
+
+// The regular expression matching the same paths as `glob`.
+fn glob_pattern(glob: &str) -> String {
+    let mut pattern = "^".to_string();
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                if chars.peek() == Some(&'/') {
+                    // "a/**/b" matches "a/b" too.
+                    chars.next();
+                    pattern.push_str("(?:.*/)?");
+                } else {
+                    pattern.push_str(".*");
+                }
+            }
+            '*' => pattern.push_str("[^/]*"),
+            '?' => pattern.push_str("[^/]"),
+            c if "\\.+()|[]{}^$".contains(c) => {
+                pattern.push('\\');
+                pattern.push(c);
+            }
+            c => pattern.push(c),
+        }
+    }
+    pattern.push('$');
+    pattern
+}
@@ -0,0 +1,542 @@
+use std::env;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use libc;
+use handler_lib::Handler;
+use http::{Request, Response, Status};
+use proxy::{self, Error, HOP_BY_HOP};
+use util::percent_decode;
+
+// Request headers that are not given as HTTP_* variables. Credentials are
+// not passed on, and Proxy would set HTTP_PROXY for the script (httpoxy).
+const NOT_PASSED: [&str; 4] = ["content-length", "content-type", "authorization", "proxy"];
+const SERVER_SOFTWARE: &str = concat!("webserver/", env!("CARGO_PKG_VERSION"));
+
+// A script a request runs, found from its URI.
+pub(crate) struct Script {
+    pub(crate) filename: PathBuf,
+    // The part of the URI that names the script, SCRIPT_NAME.
+    pub(crate) name: String,
+    // What follows it, PATH_INFO.
+    pub(crate) path_info: String,
+    // Whether the script file was found.
+    pub(crate) exists: bool,
+}
+
+impl Script {
+    // Finds the file `uri` runs under `root`: the first of its leading paths
+    // that is a file, the rest of the URI being passed on to the script. A
+    // URI naming a directory runs `index` in it, if given. When there is no
+    // such file, the whole URI is taken as the script name.
+    //
+    // None if the URI can't be mapped under `root`.
+    pub(crate) fn find(root: &Path, uri: &str, index: Option<&str>) -> Option<Script> {
+        let path = percent_decode(uri)?;
+        if !path.starts_with('/') || path.contains('\0') {
+            return None;
+        }
+        let segments: Vec<&str> = path[1..].split('/').collect();
+        if segments.iter().any(|&s| s == ".." || s == ".") {
+            return None;
+        }
+        let mut filename = root.to_path_buf();
+        let mut name = String::new();
+        for (i, segment) in segments.iter().enumerate() {
+            if segment.is_empty() {
+                continue;
+            }
+            filename.push(segment);
+            name.push('/');
+            name.push_str(segment);
+            if filename.is_file() {
+                let rest = &segments[i + 1..];
+                return Some(Script {
+                    filename: filename,
+                    name: name,
+                    path_info: if rest.is_empty() {
+                        String::new()
+                    } else {
+                        format!("/{}", rest.join("/"))
+                    },
+                    exists: true,
+                });
+            }
+            if !filename.is_dir() {
+                break;
+            }
+        }
+        if let Some(index) = index {
+            if path.ends_with('/') && filename.is_dir() && filename.join(index).is_file() {
+                return Some(Script {
+                    filename: filename.join(index),
+                    name: format!("{}/{}", name, index),
+                    path_info: String::new(),
+                    exists: true,
+                });
+            }
+        }
+        return Some(Script {
+            filename: root.join(&path[1..]),
+            name: path.clone(),
+            path_info: String::new(),
+            exists: false,
+        });
+    }
+}
+
+// The meta-variables of RFC 3875 for running `script` on the request, with
+// the headers as HTTP_* variables and those PHP expects.
+pub(crate) fn environment(req: &Request, script: &Script, root: &Path) -> Vec<(String, String)> {
+    let mut env = Vec::new();
+    let mut set = |name: &str, value: &str| env.push((name.to_string(), value.to_string()));
+    set("GATEWAY_INTERFACE", "CGI/1.1");
+    set("SERVER_SOFTWARE", SERVER_SOFTWARE);
+    set("SERVER_PROTOCOL", req.version());
+    let https = req.tls().is_some();
+    let host = req.header("Host").unwrap_or("");
+    let (server_name, server_port) = split_host(host, if https { 443 } else { 80 });
+    set("SERVER_NAME", server_name);
+    set("SERVER_PORT", &server_port.to_string());
+    if https {
+        set("HTTPS", "on");
+        set("REQUEST_SCHEME", "https");
+    } else {
+        set("REQUEST_SCHEME", "http");
+    }
+    set("REQUEST_METHOD", req.method());
+    let query = req.query().unwrap_or("");
+    set("QUERY_STRING", query);
+    if query.is_empty() {
+        set("REQUEST_URI", &req.uri);
+    } else {
+        set("REQUEST_URI", &format!("{}?{}", req.uri, query));
+    }
+    set("DOCUMENT_ROOT", &root.to_string_lossy());
+    set("SCRIPT_NAME", &script.name);
+    set("SCRIPT_FILENAME", &script.filename.to_string_lossy());
+    if !script.path_info.is_empty() {
+        set("PATH_INFO", &script.path_info);
+        set("PATH_TRANSLATED", &root.join(&script.path_info[1..]).to_string_lossy());
+    }
+    // Tells php-cgi it wasn't called directly.
+    set("REDIRECT_STATUS", "200");
+    if let Some(addr) = req.peer_addr() {
+        set("REMOTE_ADDR", &addr.ip().to_string());
+        set("REMOTE_PORT", &addr.port().to_string());
+    }
+    if !req.body().is_empty() || ["POST", "PUT", "PATCH"].contains(&req.method()) {
+        set("CONTENT_LENGTH", &req.body().len().to_string());
+    }
+    if let Some(content_type) = req.header("Content-Type") {
+        set("CONTENT_TYPE", content_type);
+    }
+    if let Some(auth) = req.header("Authorization") {
+        set("AUTH_TYPE", auth.split(' ').next().unwrap_or(""));
+    }
+    for (name, value) in req.headers() {
+        let lower = name.to_lowercase();
+        // Names with underscores would pass for others once converted.
+        if lower.starts_with(':') || lower.contains('_') || NOT_PASSED.contains(&lower.as_str()) {
+            continue;
+        }
+        set(&format!("HTTP_{}", name.to_uppercase().replace('-', "_")), value);
+    }
+    env
+}
+
+// Reads a CGI response from `reader`, and sends it as the response to the
+// client. Errors are only returned while nothing was sent to the client yet.
+pub(crate) fn send_response<R: BufRead>(reader: &mut R,
+                                        req: &Request,
+                                        resp: &mut Response)
+                                        -> Result<(), Error> {
+    let mut limit = 64 * 1024;
+    let mut status = None;
+    let mut location = false;
+    let mut length = None;
+    let mut headers = Vec::new();
+    loop {
+        let line = proxy::read_line(reader, &mut limit)?;
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = match line.find(':') {
+            Some(idx) => (line[..idx].trim(), line[idx + 1..].trim()),
+            None => return Err(Error::Invalid(format!("header line {:?}", line))),
+        };
+        let lower = name.to_lowercase();
+        if lower == "status" {
+            let code = value.get(..3).and_then(|c| c.parse::<u32>().ok());
+            match code {
+                Some(code) if (200..600).contains(&code) => {
+                    status = Some(Status::new(code, value[3..].trim()));
+                }
+                _ => return Err(Error::Invalid(format!("status {:?}", value))),
+            }
+        } else if lower == "content-length" {
+            match value.parse::<u64>() {
+                Ok(value) => length = Some(value),
+                Err(_) => return Err(Error::Invalid(format!("Content-Length {}", value))),
+            }
+        } else if !HOP_BY_HOP.contains(&lower.as_str()) {
+            location |= lower == "location";
+            headers.push((name.to_string(), value.to_string()));
+        }
+    }
+    // A script that only gives a location redirects the client there.
+    resp.set_status(match status {
+        Some(status) => status,
+        None if location => Status::new(302, "Found"),
+        None => Status::ok(),
+    });
+    for (name, value) in &headers {
+        resp.add_header(name, value);
+    }
+    // The body goes to the client as it comes.
+    let copied = match length {
+        _ if req.method() == "HEAD" => {
+            // The length of the body it doesn't have, if the script gave it.
+            if let Some(length) = length {
+                resp.set_length(length);
+            }
+            resp.send();
+            Ok(true)
+        }
+        Some(length) => {
+            resp.set_length(length).send();
+            proxy::copy_body(reader, Some(length), resp)
+        }
+        None => {
+            resp.send_chunked(req);
+            proxy::copy_body(reader, None, resp)
+        }
+    };
+    match copied {
+        Ok(true) => resp.end(),
+        // The client went away, the rest of the output is not read.
+        Ok(false) => {}
+        Err(e) => {
+            // Too late for an error status.
+            error!("Error while reading the script output: {}", e);
+            resp.abort();
+        }
+    }
+    Ok(())
+}
+
+/// Runs scripts as CGI programs (RFC 3875), with the request body on their
+/// standard input, and sends back what they write on their standard output.
+/// The script is found under the root directory from the URI, the same way
+/// `FileSystemHandler` finds files, and what follows it in the URI is given
+/// in PATH_INFO.
+///
+/// It blocks while the script runs, so it should run on the blocking pool.
+pub struct CgiHandler {
+    root: PathBuf,
+    interpreters: Vec<(String, String)>,
+    env: Vec<(String, String)>,
+    timeout: Duration,
+}
+
+impl CgiHandler {
+    pub fn new(root: &str) -> CgiHandler {
+        return CgiHandler {
+            root: PathBuf::from(root),
+            interpreters: Vec::new(),
+            env: Vec::new(),
+            timeout: Duration::from_secs(60),
+        };
+    }
+
+    // Runs the scripts ending with `.extension` with `program`, given their
+    // path. Other scripts are run directly, they must be executable.
+    pub fn set_interpreter(&mut self, extension: &str, program: &str) {
+        self.interpreters.push((extension.to_string(), program.to_string()));
+    }
+
+    // An environment variable given to every script.
+    pub fn set_env(&mut self, name: &str, value: &str) {
+        self.env.push((name.to_string(), value.to_string()));
+    }
+
+    // How long a script can run. It is killed once it is over, and the
+    // client gets a 504 if nothing was sent yet.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    fn command(&self, script: &Script) -> Command {
+        let extension = script.filename.extension().and_then(|e| e.to_str());
+        let interpreter = self.interpreters
+                              .iter()
+                              .find(|&&(ref ext, _)| Some(ext.as_str()) == extension);
+        let mut command = match interpreter {
+            Some(&(_, ref program)) => {
+                let mut command = Command::new(program);
+                command.arg(&script.filename);
+                command
+            }
+            None => Command::new(&script.filename),
+        };
+        command.env_clear();
+        if let Some(path) = env::var_os("PATH") {
+            command.env("PATH", path);
+        }
+        return command;
+    }
+
+    fn run(&self, script: &Script, req: &Request, resp: &mut Response) -> Result<(), Error> {
+        let mut command = self.command(script);
+        command.envs(environment(req, script, &self.root))
+               .envs(self.env.iter().cloned())
+               .args(search_words(req.query().unwrap_or("")))
+               .current_dir(script.filename.parent().unwrap_or(&self.root))
+               .stdin(Stdio::piped())
+               .stdout(Stdio::piped())
+               .stderr(Stdio::inherit())
+               // In a group of its own, to kill whatever it started too.
+               .process_group(0);
+        let mut child = command.spawn().map_err(Error::Connect)?;
+        let pid = child.id() as libc::pid_t;
+        let mut stdin = child.stdin.take().unwrap();
+        let stdout = child.stdout.take().unwrap();
+        // The body is written while the output is read, the script may not
+        // read all of it first.
+        let body = req.body().to_vec();
+        thread::spawn(move || {
+            let _ = stdin.write_all(&body);
+        });
+        let timed_out = Arc::new(AtomicBool::new(false));
+        let (done, wait) = mpsc::channel::<()>();
+        let timeout = self.timeout;
+        let killed = timed_out.clone();
+        thread::spawn(move || {
+            if let Err(RecvTimeoutError::Timeout) = wait.recv_timeout(timeout) {
+                killed.store(true, Ordering::SeqCst);
+                unsafe {
+                    libc::kill(-pid, libc::SIGKILL);
+                }
+            }
+            let _ = child.wait();
+        });
+        let result = send_response(&mut BufReader::new(stdout), req, resp);
+        let _ = done.send(());
+        match result {
+            Err(_) if timed_out.load(Ordering::SeqCst) => Err(Error::Timeout),
+            result => result,
+        }
+    }
+}
+
+impl Handler for CgiHandler {
+    fn process(&mut self, req: Request, resp: &mut Response) {
+        let script = match Script::find(&self.root, &req.uri, None) {
+            Some(script) => script,
+            None => {
+                resp.set_bad_request().send();
+                return;
+            }
+        };
+        if !script.exists {
+            debug!("Not found: {}", script.filename.display());
+            resp.set_not_found().send();
+            return;
+        }
+        match self.run(&script, &req, resp) {
+            Ok(()) => {}
+            Err(Error::Timeout) => {
+                error!("Script {} timed out", script.filename.display());
+                resp.set_gateway_timeout().send();
+            }
+            Err(Error::Connect(e)) | Err(Error::Unavailable(e)) => {
+                error!("Couldn't run {}: {}", script.filename.display(), e);
+                resp.set_internal_server_error().send();
+            }
+            Err(Error::Invalid(e)) => {
+                error!("Invalid output from {}: {}", script.filename.display(), e);
+                resp.set_internal_server_error().send();
+            }
+            Err(Error::Stale) | Err(Error::Aborted) => unreachable!(),
+        }
+    }
+    fn duplicate(&self) -> Box<Handler> {
+        return Box::new(CgiHandler {
+            root: self.root.clone(),
+            interpreters: self.interpreters.clone(),
+            env: self.env.clone(),
+            timeout: self.timeout,
+        });
+    }
+}
+
+// Splits "host:port", with `default_port` if there is no port.
+fn split_host(host: &str, default_port: u16) -> (&str, u16) {
+    if let Some(idx) = host.rfind(':') {
+        if let Ok(port) = host[idx + 1..].parse() {
+            return (&host[..idx], port);
+        }
+    }
+    (host, default_port)
+}
+
+// A query string without '=' is a search, its words are given to the script
+// as arguments (RFC 3875 4.4).
+fn search_words(query: &str) -> Vec<String> {
+    if query.is_empty() || query.contains('=') {
+        return Vec::new();
+    }
+    query.split('+').filter_map(percent_decode).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::Cursor;
+    use std::process;
+    use connection::Output;
+    use super::*;
+
+    fn request(method: &str, uri: &str, headers: &[(&str, &str)], body: &[u8]) -> Request {
+        let headers = headers.iter().map(|&(n, v)| (n.to_string(), v.to_string())).collect();
+        Request::from_parts(method, uri, "HTTP/1.1", headers, body.to_vec()).unwrap()
+    }
+
+    fn script(name: &str, path_info: &str) -> Script {
+        Script {
+            filename: Path::new("/srv/cgi").join(&name[1..]),
+            name: name.to_string(),
+            path_info: path_info.to_string(),
+            exists: true,
+        }
+    }
+
+    fn var<'a>(env: &'a [(String, String)], name: &str) -> Option<&'a str> {
+        env.iter().find(|&&(ref n, _)| n == name).map(|&(_, ref v)| v.as_str())
+    }
+
+    #[test]
+    fn finds_scripts() {
+        let root = env::temp_dir().join(format!("webserver-cgi-{}", process::id()));
+        fs::create_dir_all(root.join("bin")).unwrap();
+        fs::create_dir_all(root.join("app")).unwrap();
+        fs::write(root.join("bin/run.cgi"), "").unwrap();
+        fs::write(root.join("app/index.php"), "").unwrap();
+
+        let found = Script::find(&root, "/bin/run.cgi/a/b%20c", None).unwrap();
+        assert!(found.exists);
+        assert_eq!(found.filename, root.join("bin/run.cgi"));
+        assert_eq!(found.name, "/bin/run.cgi");
+        assert_eq!(found.path_info, "/a/b c");
+        let found = Script::find(&root, "/bin/run.cgi", None).unwrap();
+        assert_eq!((found.name.as_str(), found.path_info.as_str()), ("/bin/run.cgi", ""));
+        let found = Script::find(&root, "/app/", Some("index.php")).unwrap();
+        assert_eq!(found.filename, root.join("app/index.php"));
+        assert_eq!(found.name, "/app/index.php");
+        // Without a file, the whole URI is the script.
+        let found = Script::find(&root, "/app/missing.php/x", None).unwrap();
+        assert!(!found.exists);
+        assert_eq!(found.name, "/app/missing.php/x");
+        assert!(!Script::find(&root, "/app/", None).unwrap().exists);
+
+        for uri in &["/../bin/run.cgi", "/bin/%2e%2e/bin/run.cgi", "/bin/./run.cgi",
+                     "/bin/run.cgi%00", "bin/run.cgi", "/bin/%zz"] {
+            assert!(Script::find(&root, uri, None).is_none(), "{}", uri);
+        }
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn meta_variables() {
+        let mut req = request("POST",
+                              "/app.cgi/a/b?x=1&y=2",
+                              &[("Host", "example.com:8080"),
+                                ("Content-Type", "text/plain"),
+                                ("Authorization", "Basic eDp5"),
+                                ("Proxy", "evil:80"),
+                                ("X_Forged", "1"),
+                                ("Accept-Language", "fr")],
+                              b"hello");
+        req.set_peer_addr(Some("10.0.0.1:4000".parse().unwrap()));
+        let env = environment(&req, &script("/app.cgi", "/a/b"), Path::new("/srv/cgi"));
+        let expected = [("GATEWAY_INTERFACE", "CGI/1.1"),
+                        ("SERVER_PROTOCOL", "HTTP/1.1"),
+                        ("SERVER_NAME", "example.com"),
+                        ("SERVER_PORT", "8080"),
+                        ("REQUEST_SCHEME", "http"),
+                        ("REQUEST_METHOD", "POST"),
+                        ("QUERY_STRING", "x=1&y=2"),
+                        ("REQUEST_URI", "/app.cgi/a/b?x=1&y=2"),
+                        ("DOCUMENT_ROOT", "/srv/cgi"),
+                        ("SCRIPT_NAME", "/app.cgi"),
+                        ("SCRIPT_FILENAME", "/srv/cgi/app.cgi"),
+                        ("PATH_INFO", "/a/b"),
+                        ("PATH_TRANSLATED", "/srv/cgi/a/b"),
+                        ("REMOTE_ADDR", "10.0.0.1"),
+                        ("REMOTE_PORT", "4000"),
+                        ("CONTENT_LENGTH", "5"),
+                        ("CONTENT_TYPE", "text/plain"),
+                        ("AUTH_TYPE", "Basic"),
+                        ("HTTP_HOST", "example.com:8080"),
+                        ("HTTP_ACCEPT_LANGUAGE", "fr")];
+        for &(name, value) in &expected {
+            assert_eq!(var(&env, name), Some(value), "{}", name);
+        }
+        for name in &["HTTPS", "HTTP_AUTHORIZATION", "HTTP_PROXY", "HTTP_X_FORGED",
+                      "HTTP_CONTENT_TYPE", "HTTP_CONTENT_LENGTH"] {
+            assert_eq!(var(&env, name), None, "{}", name);
+        }
+
+        let req = request("GET", "/app.cgi", &[], b"");
+        let env = environment(&req, &script("/app.cgi", ""), Path::new("/srv/cgi"));
+        assert_eq!(var(&env, "SERVER_PORT"), Some("80"));
+        assert_eq!(var(&env, "QUERY_STRING"), Some(""));
+        assert_eq!(var(&env, "REQUEST_URI"), Some("/app.cgi"));
+        for name in &["PATH_INFO", "PATH_TRANSLATED", "CONTENT_LENGTH", "REMOTE_ADDR"] {
+            assert_eq!(var(&env, name), None, "{}", name);
+        }
+    }
+
+    #[test]
+    fn search_queries_are_arguments() {
+        assert_eq!(search_words("rust+web%20server"), vec!["rust", "web server"]);
+        assert_eq!(search_words("word"), vec!["word"]);
+        assert!(search_words("").is_empty());
+        assert!(search_words("q=rust+web").is_empty());
+    }
+
+    #[test]
+    fn responses_without_a_length_are_chunked() {
+        let req = request("GET", "/app.cgi", &[], b"");
+        let mut out = Output::new();
+        {
+            let mut resp = Response::with_output(&mut out);
+            let mut output = Cursor::new(&b"Status: 201 Made\r\nX-A: b\r\n\r\nhello"[..]);
+            send_response(&mut output, &req, &mut resp).ok().unwrap();
+        }
+        let sent = String::from_utf8(out.queued_data()).unwrap();
+        assert!(sent.starts_with("HTTP/1.1 201 Made\r\n"), "{}", sent);
+        assert!(sent.contains("\r\nTransfer-Encoding: chunked\r\n"), "{}", sent);
+        assert!(sent.contains("\r\nX-A: b\r\n"), "{}", sent);
+        assert!(sent.ends_with("\r\n\r\n5\r\nhello\r\n0\r\n\r\n"), "{}", sent);
+
+        let mut out = Output::new();
+        {
+            let mut resp = Response::with_output(&mut out);
+            let mut output = Cursor::new(&b"Location: /there\r\nContent-Length: 2\r\n\r\nhi"[..]);
+            send_response(&mut output, &req, &mut resp).ok().unwrap();
+        }
+        let sent = String::from_utf8(out.queued_data()).unwrap();
+        assert!(sent.starts_with("HTTP/1.1 302 Found\r\n"), "{}", sent);
+        assert!(sent.contains("\r\nContent-Length: 2\r\n"), "{}", sent);
+        assert!(!sent.contains("chunked"), "{}", sent);
+        assert!(sent.ends_with("\r\n\r\nhi"), "{}", sent);
+    }
+}
@@ -0,0 +1,223 @@
+use std::io::prelude::*;
+use std::mem;
+use brotli::CompressorWriter;
+use flate2::write::GzEncoder;
+use http::Request;
+
+// The MIME types compressed unless set otherwise. Images, fonts and
+// archives are mostly compressed already.
+const MIME_TYPES: [&str; 8] = ["text/*",
+                               "application/javascript",
+                               "application/json",
+                               "application/xml",
+                               "application/xhtml+xml",
+                               "application/rss+xml",
+                               "application/wasm",
+                               "image/svg+xml"];
+const BROTLI_WINDOW: u32 = 22;
+const BROTLI_BUFFER_SIZE: usize = 16 * 1024;
+
+/// A content coding responses can be compressed with.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Gzip,
+    Brotli,
+}
+
+impl Encoding {
+    // The name used in Accept-Encoding and Content-Encoding.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Encoding::Gzip => "gzip",
+            Encoding::Brotli => "br",
+        }
+    }
+
+    // The extension of the files precompressed with it, like "x.js.br".
+    pub fn extension(&self) -> &'static str {
+        match *self {
+            Encoding::Gzip => "gz",
+            Encoding::Brotli => "br",
+        }
+    }
+}
+
+/// When and how responses are compressed. Responses are compressed with the
+/// encoding the client prefers, going by the q-values of its
+/// Accept-Encoding, brotli first when it has no preference.
+///
+/// Only responses with a Content-Length of at least the minimum size and
+/// one of the MIME types are compressed, and not those already encoded or
+/// marked `Cache-Control: no-transform`.
+#[derive(Debug, Clone)]
+pub struct Compression {
+    encodings: Vec<Encoding>,
+    mime_types: Vec<String>,
+    min_size: u64,
+    gzip_level: u32,
+    brotli_quality: u32,
+}
+
+impl Compression {
+    pub fn new() -> Compression {
+        return Compression {
+            encodings: vec![Encoding::Brotli, Encoding::Gzip],
+            mime_types: MIME_TYPES.iter().map(|t| t.to_string()).collect(),
+            min_size: 1024,
+            gzip_level: 6,
+            brotli_quality: 5,
+        };
+    }
+
+    // The encodings used, by order of preference when the client has none.
+    pub fn set_encodings(&mut self, encodings: &[Encoding]) {
+        self.encodings = encodings.to_vec();
+    }
+
+    // The MIME types compressed, like "application/json", or "text/*" for
+    // all the text types.
+    pub fn set_mime_types(&mut self, mime_types: &[&str]) {
+        self.mime_types = mime_types.iter().map(|t| t.to_lowercase()).collect();
+    }
+
+    // Smaller responses are sent as they are, compressing them would gain
+    // little or even make them larger.
+    pub fn set_min_size(&mut self, min_size: u64) {
+        self.min_size = min_size;
+    }
+
+    // From 0, no compression, to 9, the smallest output.
+    pub fn set_gzip_level(&mut self, level: u32) {
+        self.gzip_level = level.min(9);
+    }
+
+    // From 0 to 11, the smallest output. Higher qualities are a lot slower,
+    // they are better used ahead of time.
+    pub fn set_brotli_quality(&mut self, quality: u32) {
+        self.brotli_quality = quality.min(11);
+    }
+
+    pub(crate) fn encodings(&self) -> &[Encoding] {
+        &self.encodings
+    }
+
+    pub(crate) fn mime_types(&self) -> &[String] {
+        &self.mime_types
+    }
+
+    pub(crate) fn min_size(&self) -> u64 {
+        self.min_size
+    }
+
+    // The encoding to use for the response to `req`, None if the client
+    // accepts none of them.
+    pub(crate) fn negotiate(&self, req: &Request) -> Option<Encoding> {
+        negotiate(req, &self.encodings)
+    }
+
+    // Whether a response of this type and length is worth compressing.
+    pub(crate) fn applies(&self, content_type: Option<&str>, length: u64) -> bool {
+        if length < self.min_size {
+            return false;
+        }
+        let content_type = match content_type {
+            Some(content_type) => content_type,
+            None => return false,
+        };
+        let mime = content_type.split(';').next().unwrap_or("").trim().to_lowercase();
+        self.mime_types.iter().any(|t| match t.strip_suffix('*') {
+            Some(prefix) => mime.starts_with(prefix),
+            None => *t == mime,
+        })
+    }
+
+    pub(crate) fn encoder(&self, encoding: Encoding) -> Encoder {
+        match encoding {
+            Encoding::Gzip => {
+                let level = flate2::Compression::new(self.gzip_level);
+                Encoder::Gzip(GzEncoder::new(Vec::new(), level))
+            }
+            Encoding::Brotli => {
+                Encoder::Brotli(Box::new(CompressorWriter::new(Vec::new(),
+                                                               BROTLI_BUFFER_SIZE,
+                                                               self.brotli_quality,
+                                                               BROTLI_WINDOW)))
+            }
+        }
+    }
+}
+
+// The one of `encodings` the client prefers, the first one among those it
+// likes as much.
+pub(crate) fn negotiate(req: &Request, encodings: &[Encoding]) -> Option<Encoding> {
+    let accept = req.header("Accept-Encoding")?;
+    let mut best: Option<(Encoding, f32)> = None;
+    for &encoding in encodings {
+        let q = quality(accept, encoding);
+        if q > 0.0 && best.is_none_or(|(_, best_q)| q > best_q) {
+            best = Some((encoding, q));
+        }
+    }
+    best.map(|(encoding, _)| encoding)
+}
+
+// The q-value of `encoding` in an Accept-Encoding header. A name given
+// explicitly wins over '*'.
+fn quality(accept: &str, encoding: Encoding) -> f32 {
+    let mut wildcard = None;
+    for item in accept.split(',') {
+        let mut params = item.split(';');
+        let name = params.next().unwrap_or("").trim().to_lowercase();
+        let q = params.filter_map(|p| p.trim().strip_prefix("q="))
+                      .filter_map(|q| q.trim().parse::<f32>().ok())
+                      .next()
+                      .unwrap_or(1.0);
+        if name == encoding.name() || (encoding == Encoding::Gzip && name == "x-gzip") {
+            return q;
+        }
+        if name == "*" {
+            wildcard = Some(q);
+        }
+    }
+    wildcard.unwrap_or(0.0)
+}
+
+// Compresses a body as it is written. Output is taken as it comes, so that
+// a large body is never held whole.
+pub(crate) enum Encoder {
+    Gzip(GzEncoder<Vec<u8>>),
+    Brotli(Box<CompressorWriter<Vec<u8>>>),
+}
+
+impl Encoder {
+    // Compresses `data`, returns the output available so far.
+    pub(crate) fn write(&mut self, data: &[u8]) -> Vec<u8> {
+        // Writing to a Vec doesn't fail.
+        match *self {
+            Encoder::Gzip(ref mut e) => {
+                e.write_all(data).unwrap();
+                mem::take(e.get_mut())
+            }
+            Encoder::Brotli(ref mut e) => {
+                e.write_all(data).unwrap();
+                mem::take(e.get_mut())
+            }
+        }
+    }
+
+    // Ends the compressed stream, returns the rest of the output.
+    pub(crate) fn finish(self) -> Vec<u8> {
+        match self {
+            Encoder::Gzip(e) => e.finish().unwrap(),
+            Encoder::Brotli(e) => e.into_inner(),
+        }
+    }
+}
+
+// Whole bodies are compressed at once.
+pub(crate) fn compress(encoder: Encoder, data: &[u8]) -> Vec<u8> {
+    let mut encoder = encoder;
+    let mut out = encoder.write(data);
+    out.extend_from_slice(&encoder.finish());
+    out
+}
@@ -0,0 +1,1690 @@
+use std::error;
+use std::fmt;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+use access_log::{AccessLog, LogFormat};
+use cache_control::{CachePolicies, CachePolicy};
+use compression::Compression;
+use handler_lib::{Handler, HandlerRoute, Regex};
+use handlers::{FileHandler, FileSystemHandler};
+use http_file::FileCache;
+use proxy::ProxyHandler;
+use rewrite::RedirectHandler;
+use status::StatusAccess;
+use tls::{ClientAuth, TlsConfig};
+use toml::{self, Item, Value};
+use upstream::{self, Balancing, HealthCheck, UpstreamGroup};
+use workers::{LeastBusy, LeastConnections, RoundRobin};
+use {Limits, Threading, Timeouts, VirtualHost, WebServer};
+
+/// An invalid configuration, with the file and line it comes from when
+/// known.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigError {
+    path: Option<String>,
+    line: Option<usize>,
+    message: String,
+}
+
+impl ConfigError {
+    fn new(line: usize, message: String) -> ConfigError {
+        return ConfigError {
+            path: None,
+            line: Some(line),
+            message: message,
+        };
+    }
+
+    fn without_line(message: String) -> ConfigError {
+        return ConfigError {
+            path: None,
+            line: None,
+            message: message,
+        };
+    }
+
+    pub fn line(&self) -> Option<usize> {
+        self.line
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (&self.path, self.line) {
+            (&Some(ref path), Some(line)) => write!(f, "{}:{}: {}", path, line, self.message),
+            (&Some(ref path), None) => write!(f, "{}: {}", path, self.message),
+            (&None, Some(line)) => write!(f, "line {}: {}", line, self.message),
+            (&None, None) => write!(f, "{}", self.message),
+        }
+    }
+}
+
+impl error::Error for ConfigError {}
+
+/// How new connections are spread over the worker threads.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WorkerAssignment {
+    RoundRobin,
+    LeastConnections,
+    LeastBusy,
+}
+
+/// The settings of a server, read from a TOML file like this one:
+///
+/// ```toml
+/// workers = 4
+///
+/// [[listener]]
+/// address = "0.0.0.0:443"
+/// cert = "cert.pem"
+/// key = "key.pem"
+///
+/// [timeouts]
+/// idle = "75s"
+///
+/// [[route]]
+/// path = "/old/(.*)"
+/// redirect = "/new/$1"
+///
+/// [[route]]
+/// path = "/.*"
+/// root = "http"
+/// ```
+///
+/// Durations are seconds or strings like "500ms", "30s", "5m" or "1h",
+/// sizes are bytes or strings like "64KB" or "16MB", in units of 1024.
+/// Paths are relative to the working directory. `webserver.toml` at the
+/// root of the repository shows all the settings.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub workers: usize,
+    pub threading: Threading,
+    pub assignment: WorkerAssignment,
+    pub shutdown_timeout: Duration,
+    pub listeners: Vec<ListenerConfig>,
+    pub timeouts: Timeouts,
+    pub limits: Limits,
+    /// The threads and queue size of the pool the handlers run on.
+    pub blocking_pool: Option<(usize, usize)>,
+    pub access_log: Option<AccessLogConfig>,
+    pub compression: Option<CompressionConfig>,
+    pub metrics_route: Option<String>,
+    pub status_route: Option<(String, StatusAccess)>,
+    /// Checked in order, after those of the virtual hosts for their names.
+    pub routes: Vec<RouteConfig>,
+    pub virtual_hosts: Vec<VirtualHostConfig>,
+    // The file it was loaded from, for the errors.
+    path: Option<String>,
+}
+
+/// An address the server accepts connections on, with HTTPS if it has TLS
+/// settings.
+#[derive(Debug, Clone)]
+pub struct ListenerConfig {
+    pub address: String,
+    pub tls: Option<TlsSettings>,
+    line: Option<usize>,
+}
+
+impl ListenerConfig {
+    pub fn new(address: &str) -> ListenerConfig {
+        return ListenerConfig {
+            address: address.to_string(),
+            tls: None,
+            line: None,
+        };
+    }
+}
+
+/// The certificates of a TLS listener, as PEM files.
+#[derive(Debug, Clone)]
+pub struct TlsSettings {
+    pub cert: String,
+    pub key: String,
+    /// Other certificates and keys, by SNI server name.
+    pub certificates: Vec<(String, String, String)>,
+    pub alpn: Option<Vec<String>>,
+    /// The CAs of the client certificates.
+    pub client_ca: Option<(String, ClientAuth)>,
+}
+
+impl TlsSettings {
+    fn load(&self) -> Result<TlsConfig, String> {
+        let mut config = TlsConfig::new(&self.cert, &self.key)
+                             .map_err(|e| format!("can't load {}: {}", self.cert, e))?;
+        for &(ref name, ref cert, ref key) in &self.certificates {
+            config.add_certificate(name, cert, key)
+                  .map_err(|e| format!("can't load {}: {}", cert, e))?;
+        }
+        if let Some(ref alpn) = self.alpn {
+            config.set_alpn_protocols(&alpn.iter().map(|p| p.as_str()).collect::<Vec<_>>());
+        }
+        if let Some((ref ca, auth)) = self.client_ca {
+            config.set_client_auth(ca, auth).map_err(|e| format!("can't load {}: {}", ca, e))?;
+        }
+        Ok(config)
+    }
+}
+
+/// Where the access log goes, the standard output if it has no path.
+#[derive(Debug, Clone)]
+pub struct AccessLogConfig {
+    pub path: Option<String>,
+    pub format: LogFormat,
+    line: Option<usize>,
+}
+
+impl AccessLogConfig {
+    pub fn new(path: Option<&str>, format: LogFormat) -> AccessLogConfig {
+        return AccessLogConfig {
+            path: path.map(|p| p.to_string()),
+            format: format,
+            line: None,
+        };
+    }
+}
+
+/// Which responses are compressed with gzip or brotli, when the client
+/// accepts it.
+#[derive(Debug, Clone)]
+pub struct CompressionConfig {
+    pub enabled: bool,
+    pub min_size: usize,
+    /// MIME types like "application/json", or "text/*" for all the text
+    /// types.
+    pub types: Vec<String>,
+}
+
+impl CompressionConfig {
+    // The defaults of `Compression`, enabled.
+    pub fn new() -> CompressionConfig {
+        let defaults = Compression::new();
+        return CompressionConfig {
+            enabled: true,
+            min_size: defaults.min_size() as usize,
+            types: defaults.mime_types().to_vec(),
+        };
+    }
+
+    fn compression(&self) -> Compression {
+        let mut compression = Compression::new();
+        compression.set_min_size(self.min_size as u64);
+        compression.set_mime_types(&self.types.iter().map(|t| t.as_str()).collect::<Vec<_>>());
+        compression
+    }
+}
+
+/// The requests whose path matches the regular expression `path`, and what
+/// answers them.
+#[derive(Debug, Clone)]
+pub struct RouteConfig {
+    pub path: String,
+    pub action: RouteAction,
+}
+
+#[derive(Debug, Clone)]
+pub enum RouteAction {
+    /// Serves the files of a directory, by the path of the request.
+    Directory(StaticFiles),
+    /// Serves a single file.
+    File(StaticFiles),
+    /// Redirects to a target, which can use the groups of the path, with a
+    /// 301, 302, 303, 307 or 308 status.
+    Redirect(String, u32),
+    Proxy(ProxyConfig),
+}
+
+#[derive(Debug, Clone)]
+pub struct StaticFiles {
+    /// The directory or the file.
+    pub path: String,
+    pub precompressed: bool,
+    /// The size of the memory cache of the files, if they have one.
+    pub cache_size: Option<usize>,
+    /// Types for extensions, over the usual ones.
+    pub mime_types: Vec<(String, String)>,
+    /// Whether the directories without an index.html are listed.
+    pub listing: bool,
+    /// The Cache-Control of the files, by the first rule matching their
+    /// path.
+    pub cache_control: Vec<CacheRule>,
+}
+
+impl StaticFiles {
+    pub fn new(path: &str) -> StaticFiles {
+        return StaticFiles {
+            path: path.to_string(),
+            precompressed: true,
+            cache_size: None,
+            mime_types: Vec::new(),
+            listing: false,
+            cache_control: Vec::new(),
+        };
+    }
+
+    fn cache_policies(&self) -> CachePolicies {
+        let mut policies = CachePolicies::new();
+        for rule in &self.cache_control {
+            let policy = rule.policy();
+            match rule.pattern {
+                CachePattern::Path(ref path) => policies.add_route(path, policy),
+                CachePattern::Glob(ref glob) => policies.add_glob(glob, policy),
+                CachePattern::Extension(ref ext) => policies.add_extension(ext, policy),
+            }
+        }
+        policies
+    }
+}
+
+/// The caching of the files matching a pattern, the directives of their
+/// Cache-Control.
+#[derive(Debug, Clone)]
+pub struct CacheRule {
+    pub pattern: CachePattern,
+    pub max_age: Option<Duration>,
+    pub stale_while_revalidate: Option<Duration>,
+    pub immutable: bool,
+    pub no_store: bool,
+    pub no_cache: bool,
+    pub private: bool,
+    /// Whether Expires is sent too.
+    pub expires: bool,
+}
+
+impl CacheRule {
+    pub fn new(pattern: CachePattern) -> CacheRule {
+        return CacheRule {
+            pattern: pattern,
+            max_age: None,
+            stale_while_revalidate: None,
+            immutable: false,
+            no_store: false,
+            no_cache: false,
+            private: false,
+            expires: false,
+        };
+    }
+
+    fn policy(&self) -> CachePolicy {
+        let mut policy = CachePolicy::new();
+        if let Some(max_age) = self.max_age {
+            policy.set_max_age(max_age);
+        }
+        if let Some(stale) = self.stale_while_revalidate {
+            policy.set_stale_while_revalidate(stale);
+        }
+        policy.set_immutable(self.immutable);
+        policy.set_no_store(self.no_store);
+        policy.set_no_cache(self.no_cache);
+        policy.set_private(self.private);
+        policy.set_expires(self.expires);
+        policy
+    }
+}
+
+/// What the path of a request is matched with for a `CacheRule`.
+#[derive(Debug, Clone)]
+pub enum CachePattern {
+    /// A regular expression like those of the routes.
+    Path(String),
+    /// A glob like "/vendor/**", or "*.min.js" for the file name.
+    Glob(String),
+    /// An extension like "woff2".
+    Extension(String),
+}
+
+/// The upstream servers of a proxy route, as "host:port" or
+/// "unix:/path/to/socket" with their weight, and how they are used.
+#[derive(Debug, Clone)]
+pub struct ProxyConfig {
+    pub servers: Vec<(String, u32)>,
+    pub balancing: Balancing,
+    /// The Host header sent to the upstreams, their address by default.
+    pub host: Option<String>,
+    pub connect_timeout: Duration,
+    pub read_timeout: Duration,
+    pub max_idle: usize,
+    pub max_fails: u32,
+    pub fail_timeout: Duration,
+    pub max_active: usize,
+    pub health_check: Option<HealthCheck>,
+}
+
+impl ProxyConfig {
+    pub fn new(servers: Vec<(String, u32)>) -> ProxyConfig {
+        // A single upstream is tried whatever happened to it before, as
+        // with `ProxyHandler::new`.
+        let max_fails = if servers.len() > 1 { 1 } else { 0 };
+        return ProxyConfig {
+            servers: servers,
+            balancing: Balancing::RoundRobin,
+            host: None,
+            connect_timeout: Duration::from_secs(5),
+            read_timeout: Duration::from_secs(60),
+            max_idle: 16,
+            max_fails: max_fails,
+            fail_timeout: Duration::from_secs(10),
+            max_active: 0,
+            health_check: None,
+        };
+    }
+}
+
+/// A site served for its names, like "example.com" or "*.example.com".
+#[derive(Debug, Clone)]
+pub struct VirtualHostConfig {
+    pub names: Vec<String>,
+    pub routes: Vec<RouteConfig>,
+}
+
+impl Config {
+    // The defaults, without listeners or routes.
+    pub fn new() -> Config {
+        return Config {
+            workers: thread::available_parallelism().map_or(4, |n| n.get()),
+            threading: Threading::Dispatcher,
+            assignment: WorkerAssignment::RoundRobin,
+            shutdown_timeout: Duration::from_secs(30),
+            listeners: Vec::new(),
+            timeouts: Timeouts::new(),
+            limits: Limits::new(),
+            blocking_pool: None,
+            access_log: None,
+            compression: None,
+            metrics_route: None,
+            status_route: None,
+            routes: Vec::new(),
+            virtual_hosts: Vec::new(),
+            path: None,
+        };
+    }
+
+    // Reads the configuration file at `path`. Errors name the file.
+    pub fn load(path: &str) -> Result<Config, ConfigError> {
+        let with_path = |mut e: ConfigError| {
+            e.path = Some(path.to_string());
+            e
+        };
+        let text = fs::read_to_string(path)
+                       .map_err(|e| with_path(ConfigError::without_line(e.to_string())))?;
+        let mut config = Config::parse(&text).map_err(with_path)?;
+        config.path = Some(path.to_string());
+        Ok(config)
+    }
+
+    // Reads a configuration from its TOML text. The static files and
+    // certificates are checked when the server is built.
+    pub fn parse(text: &str) -> Result<Config, ConfigError> {
+        let root = toml::parse(text).map_err(|e| ConfigError::new(e.line, e.message))?;
+        let mut root = Section {
+            what: "the configuration".to_string(),
+            line: 1,
+            entries: root.entries,
+        };
+        let mut config = Config::new();
+        if let Some(workers) = root.take("workers") {
+            config.workers = positive(&workers, "workers")?;
+        }
+        if let Some(item) = root.take("threading") {
+            config.threading = match string(&item, "threading")?.as_str() {
+                "dispatcher" => Threading::Dispatcher,
+                "reuse_port" => Threading::ReusePort,
+                _ => return one_of(&item, "threading", &["dispatcher", "reuse_port"]),
+            };
+        }
+        if let Some(item) = root.take("assignment") {
+            config.assignment = match string(&item, "assignment")?.as_str() {
+                "round_robin" => WorkerAssignment::RoundRobin,
+                "least_connections" => WorkerAssignment::LeastConnections,
+                "least_busy" => WorkerAssignment::LeastBusy,
+                _ => {
+                    let names = ["round_robin", "least_connections", "least_busy"];
+                    return one_of(&item, "assignment", &names);
+                }
+            };
+        }
+        if let Some(item) = root.take("shutdown_timeout") {
+            config.shutdown_timeout = duration(&item, "shutdown_timeout")?;
+        }
+        for item in root.take_array("listener")? {
+            config.listeners.push(parse_listener(item)?);
+        }
+        if let Some(mut section) = root.section("timeouts", "[timeouts]")? {
+            let timeouts = &mut config.timeouts;
+            for (key, value) in [("idle", &mut timeouts.idle),
+                                 ("header", &mut timeouts.header),
+                                 ("body", &mut timeouts.body),
+                                 ("write", &mut timeouts.write)] {
+                if let Some(item) = section.take(key) {
+                    *value = positive_duration(&item, key)?;
+                }
+            }
+            section.finish()?;
+        }
+        if let Some(mut section) = root.section("limits", "[limits]")? {
+            if let Some(item) = section.take("max_header_size") {
+                config.limits.max_header_size = size(&item, "max_header_size")?;
+            }
+            if let Some(item) = section.take("max_body_size") {
+                config.limits.max_body_size = size(&item, "max_body_size")?;
+            }
+            section.finish()?;
+        }
+        if let Some(mut section) = root.section("blocking_pool", "[blocking_pool]")? {
+            let threads = match section.take("threads") {
+                Some(item) => positive(&item, "threads")?,
+                None => return Err(section.missing("threads")),
+            };
+            let queue_size = match section.take("queue_size") {
+                Some(item) => positive(&item, "queue_size")?,
+                None => 1024,
+            };
+            config.blocking_pool = Some((threads, queue_size));
+            section.finish()?;
+        }
+        if let Some(mut section) = root.section("access_log", "[access_log]")? {
+            let path = match section.take("path") {
+                Some(item) => Some(string(&item, "path")?).filter(|p| p != "-"),
+                None => None,
+            };
+            let format = match section.take("format") {
+                Some(item) => log_format(&item)?,
+                None => LogFormat::Combined,
+            };
+            config.access_log = Some(AccessLogConfig {
+                path: path,
+                format: format,
+                line: Some(section.line),
+            });
+            section.finish()?;
+        }
+        if let Some(mut section) = root.section("compression", "[compression]")? {
+            let mut compression = CompressionConfig::new();
+            if let Some(item) = section.take("enabled") {
+                compression.enabled = boolean(&item, "enabled")?;
+            }
+            if let Some(item) = section.take("min_size") {
+                compression.min_size = size(&item, "min_size")?;
+            }
+            if let Some(item) = section.take("types") {
+                compression.types = strings(&item, "types")?;
+                if compression.types.iter().any(|t| t.is_empty()) {
+                    return Err(section.invalid("types", "a MIME type can't be empty"));
+                }
+            }
+            config.compression = Some(compression);
+            section.finish()?;
+        }
+        if let Some(mut section) = root.section("metrics", "[metrics]")? {
+            config.metrics_route = Some(section.required_string("path")?);
+            section.finish()?;
+        }
+        if let Some(mut section) = root.section("status", "[status]")? {
+            let path = section.required_string("path")?;
+            let access = match section.take("access") {
+                Some(item) => status_access(&item)?,
+                None => StatusAccess::Localhost,
+            };
+            config.status_route = Some((path, access));
+            section.finish()?;
+        }
+        config.routes = parse_routes(&mut root, "route")?;
+        for item in root.take_array("virtual_host")? {
+            let mut section = Section::new(item, "a [[virtual_host]]")?;
+            let names = match section.take("names") {
+                Some(item) => strings(&item, "names")?,
+                None => return Err(section.missing("names")),
+            };
+            if names.is_empty() || names.iter().any(|n| n.is_empty()) {
+                return Err(section.invalid("names", "a virtual host needs names"));
+            }
+            let routes = parse_routes(&mut section, "route")?;
+            section.finish()?;
+            config.virtual_hosts.push(VirtualHostConfig {
+                names: names,
+                routes: routes,
+            });
+        }
+        root.finish()?;
+        if config.listeners.is_empty() {
+            return Err(ConfigError::without_line("no [[listener]] is configured".to_string()));
+        }
+        Ok(config)
+    }
+
+    // Builds the server. This loads the certificates, opens the access log
+    // and starts the threads of the blocking pool and of the health checks.
+    pub fn build(&self) -> Result<WebServer, ConfigError> {
+        self.build_server().map_err(|mut e| {
+            e.path = self.path.clone();
+            e
+        })
+    }
+
+    fn build_server(&self) -> Result<WebServer, ConfigError> {
+        if self.listeners.is_empty() {
+            return Err(ConfigError::without_line("no listener is configured".to_string()));
+        }
+        let mut server = WebServer::new(&self.listeners[0].address, self.workers);
+        // The listeners are added in order below, TLS ones included.
+        server.listeners.clear();
+        for listener in &self.listeners {
+            let error = |message: String| {
+                match listener.line {
+                    Some(line) => ConfigError::new(line, message),
+                    None => ConfigError::without_line(message),
+                }
+            };
+            if listener.address.parse::<SocketAddr>().is_err() {
+                return Err(error(format!("`{}` is not an address like \"127.0.0.1:8080\" \
+                                          or \"[::]:8080\"",
+                                         listener.address)));
+            }
+            match listener.tls {
+                Some(ref tls) => {
+                    let config = tls.load().map_err(&error)?;
+                    server.add_tls_listener(&listener.address, config)
+                          .map_err(|e| error(format!("invalid certificate: {}", e)))?;
+                }
+                None => server.add_listener(&listener.address),
+            }
+        }
+        server.set_threading(self.threading);
+        match self.assignment {
+            WorkerAssignment::RoundRobin => server.set_assignment(RoundRobin::new()),
+            WorkerAssignment::LeastConnections => server.set_assignment(LeastConnections),
+            WorkerAssignment::LeastBusy => server.set_assignment(LeastBusy),
+        }
+        server.set_shutdown_timeout(self.shutdown_timeout);
+        server.set_timeouts(self.timeouts);
+        server.set_limits(self.limits);
+        if let Some((threads, queue_size)) = self.blocking_pool {
+            server.set_blocking_pool(threads, queue_size);
+        }
+        if let Some(ref log) = self.access_log {
+            let access_log = match log.path {
+                Some(ref path) => {
+                    AccessLog::open(path, log.format.clone()).map_err(|e| {
+                        let message = format!("can't open {}: {}", path, e);
+                        match log.line {
+                            Some(line) => ConfigError::new(line, message),
+                            None => ConfigError::without_line(message),
+                        }
+                    })?
+                }
+                None => AccessLog::stdout(log.format.clone()),
+            };
+            server.set_access_log(access_log);
+        }
+        if let Some(ref compression) = self.compression {
+            if compression.enabled {
+                server.set_compression(compression.compression());
+            }
+        }
+        if let Some(ref route) = self.metrics_route {
+            server.set_metrics_route(route);
+        }
+        if let Some((ref route, access)) = self.status_route {
+            server.set_status_route(route, access);
+        }
+        for route in &self.routes {
+            server.handlers.push(route.handler());
+        }
+        for site in &self.virtual_hosts {
+            let mut host = VirtualHost::new(&site.names[0]);
+            for name in &site.names[1..] {
+                host.add_alias(name);
+            }
+            for route in &site.routes {
+                host.handlers.push(route.handler());
+            }
+            server.add_virtual_host(host);
+        }
+        let proxies = self.routes
+                          .iter()
+                          .chain(self.virtual_hosts.iter().flat_map(|h| h.routes.iter()))
+                          .any(|r| matches!(r.action, RouteAction::Proxy(_)));
+        if proxies && self.blocking_pool.is_none() {
+            warn!("Proxy routes block their worker thread without a [blocking_pool]");
+        }
+        Ok(server)
+    }
+}
+
+// The TOML of the configuration, which `Config::parse` reads back, with
+// all the settings.
+impl fmt::Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "workers = {}", self.workers)?;
+        let threading = match self.threading {
+            Threading::Dispatcher => "dispatcher",
+            Threading::ReusePort => "reuse_port",
+        };
+        writeln!(f, "threading = \"{}\"", threading)?;
+        let assignment = match self.assignment {
+            WorkerAssignment::RoundRobin => "round_robin",
+            WorkerAssignment::LeastConnections => "least_connections",
+            WorkerAssignment::LeastBusy => "least_busy",
+        };
+        writeln!(f, "assignment = \"{}\"", assignment)?;
+        writeln!(f, "shutdown_timeout = {}", show_duration(self.shutdown_timeout))?;
+        for listener in &self.listeners {
+            writeln!(f, "\n[[listener]]")?;
+            writeln!(f, "address = {}", quote(&listener.address))?;
+            if let Some(ref tls) = listener.tls {
+                writeln!(f, "cert = {}", quote(&tls.cert))?;
+                writeln!(f, "key = {}", quote(&tls.key))?;
+                if !tls.certificates.is_empty() {
+                    let certificates = tls.certificates
+                                          .iter()
+                                          .map(|&(ref name, ref cert, ref key)| {
+                                              format!("{{ name = {}, cert = {}, key = {} }}",
+                                                      quote(name),
+                                                      quote(cert),
+                                                      quote(key))
+                                          })
+                                          .collect::<Vec<_>>();
+                    writeln!(f, "certificates = [{}]", certificates.join(", "))?;
+                }
+                if let Some(ref alpn) = tls.alpn {
+                    writeln!(f, "alpn = {}", quote_all(alpn))?;
+                }
+                if let Some((ref ca, auth)) = tls.client_ca {
+                    writeln!(f, "client_ca = {}", quote(ca))?;
+                    let auth = match auth {
+                        ClientAuth::Required => "required",
+                        ClientAuth::Optional => "optional",
+                    };
+                    writeln!(f, "client_auth = \"{}\"", auth)?;
+                }
+            }
+        }
+        writeln!(f, "\n[timeouts]")?;
+        writeln!(f, "idle = {}", show_duration(self.timeouts.idle))?;
+        writeln!(f, "header = {}", show_duration(self.timeouts.header))?;
+        writeln!(f, "body = {}", show_duration(self.timeouts.body))?;
+        writeln!(f, "write = {}", show_duration(self.timeouts.write))?;
+        writeln!(f, "\n[limits]")?;
+        writeln!(f, "max_header_size = {}", show_size(self.limits.max_header_size))?;
+        writeln!(f, "max_body_size = {}", show_size(self.limits.max_body_size))?;
+        if let Some((threads, queue_size)) = self.blocking_pool {
+            writeln!(f, "\n[blocking_pool]")?;
+            writeln!(f, "threads = {}", threads)?;
+            writeln!(f, "queue_size = {}", queue_size)?;
+        }
+        if let Some(ref log) = self.access_log {
+            writeln!(f, "\n[access_log]")?;
+            writeln!(f, "path = {}", quote(log.path.as_deref().unwrap_or("-")))?;
+            let format = match log.format {
+                LogFormat::Common => "\"common\"".to_string(),
+                LogFormat::Combined => "\"combined\"".to_string(),
+                LogFormat::Json => "\"json\"".to_string(),
+                LogFormat::Template(ref template) => quote(template),
+            };
+            writeln!(f, "format = {}", format)?;
+        }
+        if let Some(ref compression) = self.compression {
+            writeln!(f, "\n[compression]")?;
+            writeln!(f, "enabled = {}", compression.enabled)?;
+            writeln!(f, "min_size = {}", show_size(compression.min_size))?;
+            writeln!(f, "types = {}", quote_all(&compression.types))?;
+        }
+        if let Some(ref route) = self.metrics_route {
+            writeln!(f, "\n[metrics]")?;
+            writeln!(f, "path = {}", quote(route))?;
+        }
+        if let Some((ref route, access)) = self.status_route {
+            writeln!(f, "\n[status]")?;
+            writeln!(f, "path = {}", quote(route))?;
+            let access = match access {
+                StatusAccess::Localhost => "localhost".to_string(),
+                StatusAccess::Anyone => "anyone".to_string(),
+                StatusAccess::Listener(addr) => addr.to_string(),
+            };
+            writeln!(f, "access = {}", quote(&access))?;
+        }
+        for route in &self.routes {
+            writeln!(f, "\n[[route]]")?;
+            route.write(f)?;
+        }
+        for site in &self.virtual_hosts {
+            writeln!(f, "\n[[virtual_host]]")?;
+            writeln!(f, "names = {}", quote_all(&site.names))?;
+            for route in &site.routes {
+                writeln!(f, "\n[[virtual_host.route]]")?;
+                route.write(f)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl RouteConfig {
+    fn handler(&self) -> HandlerRoute {
+        let handler: Box<Handler> = match self.action {
+            RouteAction::Directory(ref files) => {
+                let mut handler = FileSystemHandler::new(&files.path);
+                handler.set_precompressed(files.precompressed);
+                handler.set_listing(files.listing);
+                if let Some(size) = files.cache_size {
+                    handler.set_cache(FileCache::new(size));
+                }
+                for &(ref ext, ref mime) in &files.mime_types {
+                    handler.add_mime_type(ext, mime);
+                }
+                if !files.cache_control.is_empty() {
+                    handler.set_cache_policies(files.cache_policies());
+                }
+                Box::new(handler)
+            }
+            RouteAction::File(ref files) => {
+                let mut handler = FileHandler::new(&files.path);
+                handler.set_precompressed(files.precompressed);
+                if let Some(size) = files.cache_size {
+                    handler.set_cache(FileCache::new(size));
+                }
+                for &(ref ext, ref mime) in &files.mime_types {
+                    handler.add_mime_type(ext, mime);
+                }
+                if !files.cache_control.is_empty() {
+                    handler.set_cache_policies(files.cache_policies());
+                }
+                Box::new(handler)
+            }
+            RouteAction::Redirect(ref target, status) => {
+                Box::new(RedirectHandler::new(&self.path, target, status))
+            }
+            RouteAction::Proxy(ref proxy) => {
+                let mut group = UpstreamGroup::new(proxy.balancing.clone());
+                for &(ref address, weight) in &proxy.servers {
+                    group.add_server(address, weight);
+                }
+                group.set_max_fails(proxy.max_fails, proxy.fail_timeout);
+                group.set_max_active(proxy.max_active);
+                if let Some(ref check) = proxy.health_check {
+                    group.set_health_check(check.clone());
+                }
+                let mut handler = ProxyHandler::with_group(group);
+                if let Some(ref host) = proxy.host {
+                    handler.set_host(host);
+                }
+                handler.set_timeouts(proxy.connect_timeout, proxy.read_timeout);
+                handler.set_max_idle(proxy.max_idle);
+                Box::new(handler)
+            }
+        };
+        HandlerRoute(format!("^{}$", self.path), handler)
+    }
+}
+
+impl RouteConfig {
+    // Writes the keys of the route, for `Config`'s Display.
+    fn write(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "path = {}", quote(&self.path))?;
+        match self.action {
+            RouteAction::Directory(ref files) | RouteAction::File(ref files) => {
+                let directory = matches!(self.action, RouteAction::Directory(_));
+                let key = if directory { "root" } else { "file" };
+                writeln!(f, "{} = {}", key, quote(&files.path))?;
+                writeln!(f, "precompressed = {}", files.precompressed)?;
+                if let Some(size) = files.cache_size {
+                    writeln!(f, "cache = {}", show_size(size))?;
+                }
+                if directory {
+                    writeln!(f, "listing = {}", files.listing)?;
+                }
+                if !files.mime_types.is_empty() {
+                    let types = files.mime_types
+                                     .iter()
+                                     .map(|&(ref ext, ref mime)| {
+                                         format!("{} = {}", show_key(ext), quote(mime))
+                                     })
+                                     .collect::<Vec<_>>();
+                    writeln!(f, "mime_types = {{ {} }}", types.join(", "))?;
+                }
+                if !files.cache_control.is_empty() {
+                    let rules = files.cache_control.iter().map(show_cache_rule).collect::<Vec<_>>();
+                    writeln!(f, "cache_control = [{}]", rules.join(", "))?;
+                }
+            }
+            RouteAction::Redirect(ref target, status) => {
+                writeln!(f, "redirect = {}", quote(target))?;
+                writeln!(f, "status = {}", status)?;
+            }
+            RouteAction::Proxy(ref proxy) => {
+                if proxy.servers.len() == 1 && proxy.servers[0].1 == 1 {
+                    writeln!(f, "proxy = {}", quote(&proxy.servers[0].0))?;
+                } else {
+                    let servers = proxy.servers
+                                       .iter()
+                                       .map(|&(ref address, weight)| {
+                                           format!("{{ address = {}, weight = {} }}",
+                                                   quote(address),
+                                                   weight)
+                                       })
+                                       .collect::<Vec<_>>();
+                    writeln!(f, "proxy = [{}]", servers.join(", "))?;
+                }
+                let balancing = match proxy.balancing {
+                    Balancing::RoundRobin => "round_robin".to_string(),
+                    Balancing::Weighted => "weighted".to_string(),
+                    Balancing::LeastConnections => "least_connections".to_string(),
+                    Balancing::HashHeader(ref name) => format!("header:{}", name),
+                    Balancing::HashCookie(ref name) => format!("cookie:{}", name),
+                };
+                writeln!(f, "balancing = {}", quote(&balancing))?;
+                if let Some(ref host) = proxy.host {
+                    writeln!(f, "host = {}", quote(host))?;
+                }
+                writeln!(f, "connect_timeout = {}", show_duration(proxy.connect_timeout))?;
+                writeln!(f, "read_timeout = {}", show_duration(proxy.read_timeout))?;
+                writeln!(f, "max_idle = {}", proxy.max_idle)?;
+                writeln!(f, "max_fails = {}", proxy.max_fails)?;
+                writeln!(f, "fail_timeout = {}", show_duration(proxy.fail_timeout))?;
+                writeln!(f, "max_active = {}", proxy.max_active)?;
+                if let Some(ref check) = proxy.health_check {
+                    writeln!(f,
+                             "health_check = {{ path = {}, interval = {}, timeout = {}, \
+                              fails = {}, passes = {} }}",
+                             quote(&check.path),
+                             show_duration(check.interval),
+                             show_duration(check.timeout),
+                             check.fails,
+                             check.passes)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// A table of the file. Its keys are taken as they are read, those left
+// over are unknown.
+struct Section {
+    // What the table is, for the messages, like "a [[route]]".
+    what: String,
+    line: usize,
+    entries: Vec<(String, Item)>,
+}
+
+impl Section {
+    fn new(item: Item, what: &str) -> Result<Section, ConfigError> {
+        match item.value {
+            Value::Table(table) => {
+                Ok(Section {
+                    what: what.to_string(),
+                    line: item.line,
+                    entries: table.entries,
+                })
+            }
+            ref value => {
+                Err(ConfigError::new(item.line,
+                                     format!("{} must be a table, not {}",
+                                             what,
+                                             value.type_name())))
+            }
+        }
+    }
+
+    fn take(&mut self, key: &str) -> Option<Item> {
+        let idx = self.entries.iter().position(|e| e.0 == key)?;
+        Some(self.entries.remove(idx).1)
+    }
+
+    fn section(&mut self, key: &str, what: &str) -> Result<Option<Section>, ConfigError> {
+        self.take(key).map(|item| Section::new(item, what)).transpose()
+    }
+
+    // The tables of `[[key]]` headers, or of an array of inline tables.
+    fn take_array(&mut self, key: &str) -> Result<Vec<Item>, ConfigError> {
+        match self.take(key) {
+            None => Ok(Vec::new()),
+            Some(Item { value: Value::Tables(items), .. }) |
+            Some(Item { value: Value::Array(items), .. }) => Ok(items),
+            Some(item) => {
+                Err(ConfigError::new(item.line,
+                                     format!("`{}` must be an array of tables, not {}",
+                                             key,
+                                             item.value.type_name())))
+            }
+        }
+    }
+
+    fn required_string(&mut self, key: &str) -> Result<String, ConfigError> {
+        match self.take(key) {
+            Some(item) => string(&item, key),
+            None => Err(self.missing(key)),
+        }
+    }
+
+    fn missing(&self, key: &str) -> ConfigError {
+        ConfigError::new(self.line, format!("{} needs `{}`", self.what, key))
+    }
+
+    fn invalid(&self, key: &str, message: &str) -> ConfigError {
+        ConfigError::new(self.line, format!("invalid `{}`: {}", key, message))
+    }
+
+    fn finish(self) -> Result<(), ConfigError> {
+        match self.entries.first() {
+            Some(&(ref key, ref item)) => {
+                Err(ConfigError::new(item.line,
+                                     format!("unknown setting `{}` in {}", key, self.what)))
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+fn parse_listener(item: Item) -> Result<ListenerConfig, ConfigError> {
+    let mut section = Section::new(item, "a [[listener]]")?;
+    let address = match section.take("address") {
+        Some(item) => listen_address(&item)?,
+        None => return Err(section.missing("address")),
+    };
+    let mut listener = ListenerConfig::new(&address);
+    listener.line = Some(section.line);
+    let cert = section.take("cert");
+    let key = section.take("key");
+    let tls = match (cert, key) {
+        (Some(cert), Some(key)) => {
+            TlsSettings {
+                cert: string(&cert, "cert")?,
+                key: string(&key, "key")?,
+                certificates: Vec::new(),
+                alpn: None,
+                client_ca: None,
+            }
+        }
+        (None, None) => {
+            section.finish()?;
+            return Ok(listener);
+        }
+        (Some(_), None) => return Err(section.missing("key")),
+        (None, Some(_)) => return Err(section.missing("cert")),
+    };
+    listener.tls = Some(tls);
+    let tls = listener.tls.as_mut().unwrap();
+    for item in section.take_array("certificates")? {
+        let mut cert = Section::new(item, "a TLS certificate")?;
+        tls.certificates.push((cert.required_string("name")?,
+                               cert.required_string("cert")?,
+                               cert.required_string("key")?));
+        cert.finish()?;
+    }
+    if let Some(item) = section.take("alpn") {
+        tls.alpn = Some(strings(&item, "alpn")?);
+    }
+    if let Some(item) = section.take("client_ca") {
+        let auth = match section.take("client_auth") {
+            Some(item) => {
+                match string(&item, "client_auth")?.as_str() {
+                    "required" => ClientAuth::Required,
+                    "optional" => ClientAuth::Optional,
+                    _ => return one_of(&item, "client_auth", &["required", "optional"]),
+                }
+            }
+            None => ClientAuth::Required,
+        };
+        tls.client_ca = Some((string(&item, "client_ca")?, auth));
+    }
+    section.finish()?;
+    Ok(listener)
+}
+
+fn parse_routes(parent: &mut Section, key: &str) -> Result<Vec<RouteConfig>, ConfigError> {
+    let mut routes = Vec::new();
+    for item in parent.take_array(key)? {
+        let mut section = Section::new(item, &format!("a [[{}]]", key))?;
+        let path = match section.take("path") {
+            Some(item) => {
+                let path = string(&item, "path")?;
+                if let Err(e) = Regex::new(&format!("^{}$", path)) {
+                    return Err(ConfigError::new(item.line,
+                                                format!("invalid `path` pattern: {}", e)));
+                }
+                path
+            }
+            None => return Err(section.missing("path")),
+        };
+        let actions = ["root", "file", "redirect", "proxy"];
+        let mut found = actions.iter()
+                               .filter_map(|&a| section.take(a).map(|item| (a, item)))
+                               .collect::<Vec<_>>();
+        if found.len() > 1 {
+            return Err(ConfigError::new(found[1].1.line,
+                                        format!("a route can't have both `{}` and `{}`",
+                                                found[0].0,
+                                                found[1].0)));
+        }
+        let (action, item) = match found.pop() {
+            Some(action) => action,
+            None => {
+                let message = format!("{} needs one of `root`, `file`, `redirect` or `proxy`",
+                                      section.what);
+                return Err(ConfigError::new(section.line, message));
+            }
+        };
+        let action = match action {
+            "root" | "file" => {
+                let files = parse_static_files(&mut section, &item, action)?;
+                if action == "root" {
+                    section.what = "a static files route".to_string();
+                    RouteAction::Directory(files)
+                } else {
+                    section.what = "a file route".to_string();
+                    RouteAction::File(files)
+                }
+            }
+            "redirect" => {
+                section.what = "a redirect route".to_string();
+                let status = match section.take("status") {
+                    Some(item) => {
+                        let status = integer(&item, "status")?;
+                        if ![301, 302, 303, 307, 308].contains(&status) {
+                            return Err(ConfigError::new(item.line,
+                                                        format!("{} is not a redirect status",
+                                                                status)));
+                        }
+                        status as u32
+                    }
+                    None => 301,
+                };
+                RouteAction::Redirect(string(&item, "redirect")?, status)
+            }
+            _ => {
+                section.what = "a proxy route".to_string();
+                RouteAction::Proxy(parse_proxy(&mut section, &item)?)
+            }
+        };
+        section.finish()?;
+        routes.push(RouteConfig {
+            path: path,
+            action: action,
+        });
+    }
+    Ok(routes)
+}
+
+fn parse_static_files(section: &mut Section,
+                      item: &Item,
+                      key: &str)
+                      -> Result<StaticFiles, ConfigError> {
+    let path = string(item, key)?;
+    let exists = if key == "root" {
+        Path::new(&path).is_dir()
+    } else {
+        Path::new(&path).is_file()
+    };
+    if !exists {
+        let kind = if key == "root" { "directory" } else { "file" };
+        return Err(ConfigError::new(item.line, format!("no {} at {}", kind, path)));
+    }
+    let mut files = StaticFiles::new(&path);
+    if let Some(item) = section.take("precompressed") {
+        files.precompressed = boolean(&item, "precompressed")?;
+    }
+    if let Some(item) = section.take("cache") {
+        files.cache_size = Some(size(&item, "cache")?);
+    }
+    if key == "root" {
+        if let Some(item) = section.take("listing") {
+            files.listing = boolean(&item, "listing")?;
+        }
+    }
+    if let Some(item) = section.take("mime_types") {
+        let types = Section::new(item, "`mime_types`")?;
+        for (ext, item) in types.entries {
+            let mime = string(&item, &ext)?;
+            files.mime_types.push((ext, mime));
+        }
+    }
+    for item in section.take_array("cache_control")? {
+        files.cache_control.push(parse_cache_rule(item)?);
+    }
+    Ok(files)
+}
+
+fn parse_cache_rule(item: Item) -> Result<CacheRule, ConfigError> {
+    let mut section = Section::new(item, "a `cache_control` rule")?;
+    let patterns = ["path", "glob", "extension"];
+    let mut found = patterns.iter()
+                            .filter_map(|&p| section.take(p).map(|item| (p, item)))
+                            .collect::<Vec<_>>();
+    if found.len() > 1 {
+        return Err(ConfigError::new(found[1].1.line,
+                                    format!("a `cache_control` rule can't have both `{}` and \
+                                             `{}`",
+                                            found[0].0,
+                                            found[1].0)));
+    }
+    let pattern = match found.pop() {
+        Some(("path", item)) => {
+            let path = string(&item, "path")?;
+            if let Err(e) = Regex::new(&format!("^{}$", path)) {
+                return Err(ConfigError::new(item.line,
+                                            format!("invalid `path` pattern: {}", e)));
+            }
+            CachePattern::Path(path)
+        }
+        Some(("glob", item)) => CachePattern::Glob(string(&item, "glob")?),
+        Some((_, item)) => CachePattern::Extension(string(&item, "extension")?),
+        None => {
+            let message = format!("{} needs one of `path`, `glob` or `extension`",
+                                  section.what);
+            return Err(ConfigError::new(section.line, message));
+        }
+    };
+    let mut rule = CacheRule::new(pattern);
+    if let Some(item) = section.take("max_age") {
+        rule.max_age = Some(duration(&item, "max_age")?);
+    }
+    if let Some(item) = section.take("stale_while_revalidate") {
+        rule.stale_while_revalidate = Some(duration(&item, "stale_while_revalidate")?);
+    }
+    for (key, value) in [("immutable", &mut rule.immutable),
+                         ("no_store", &mut rule.no_store),
+                         ("no_cache", &mut rule.no_cache),
+                         ("private", &mut rule.private),
+                         ("expires", &mut rule.expires)] {
+        if let Some(item) = section.take(key) {
+            *value = boolean(&item, key)?;
+        }
+    }
+    section.finish()?;
+    Ok(rule)
+}
+
+fn parse_proxy(section: &mut Section, item: &Item) -> Result<ProxyConfig, ConfigError> {
+    let servers = match item.value {
+        Value::String(_) => vec![(address(item)?, 1)],
+        Value::Array(ref items) if !items.is_empty() => {
+            let mut servers = Vec::new();
+            for item in items {
+                match item.value {
+                    Value::Table(_) => {
+                        let mut server = Section::new(item.clone(), "an upstream server")?;
+                        let addr = match server.take("address") {
+                            Some(item) => address(&item)?,
+                            None => return Err(server.missing("address")),
+                        };
+                        let weight = match server.take("weight") {
+                            Some(item) => weight(&item)?,
+                            None => 1,
+                        };
+                        server.finish()?;
+                        servers.push((addr, weight));
+                    }
+                    _ => servers.push((address(item)?, 1)),
+                }
+            }
+            servers
+        }
+        _ => {
+            return Err(ConfigError::new(item.line,
+                                        "`proxy` must be an address or an array of them"
+                                            .to_string()))
+        }
+    };
+    let mut proxy = ProxyConfig::new(servers);
+    if let Some(item) = section.take("balancing") {
+        let balancing = string(&item, "balancing")?;
+        let header = balancing.strip_prefix("header:").filter(|name| !name.is_empty());
+        let cookie = balancing.strip_prefix("cookie:").filter(|name| !name.is_empty());
+        proxy.balancing = match balancing.as_str() {
+            "round_robin" => Balancing::RoundRobin,
+            "weighted" => Balancing::Weighted,
+            "least_connections" => Balancing::LeastConnections,
+            _ if header.is_some() => Balancing::HashHeader(header.unwrap().to_string()),
+            _ if cookie.is_some() => Balancing::HashCookie(cookie.unwrap().to_string()),
+            _ => {
+                let names = ["round_robin",
+                             "weighted",
+                             "least_connections",
+                             "header:<name>",
+                             "cookie:<name>"];
+                return one_of(&item, "balancing", &names);
+            }
+        };
+    }
+    if let Some(item) = section.take("host") {
+        proxy.host = Some(string(&item, "host")?);
+    }
+    if let Some(item) = section.take("connect_timeout") {
+        proxy.connect_timeout = positive_duration(&item, "connect_timeout")?;
+    }
+    if let Some(item) = section.take("read_timeout") {
+        proxy.read_timeout = positive_duration(&item, "read_timeout")?;
+    }
+    if let Some(item) = section.take("max_idle") {
+        proxy.max_idle = unsigned(&item, "max_idle")?;
+    }
+    if let Some(item) = section.take("max_fails") {
+        proxy.max_fails = unsigned(&item, "max_fails")? as u32;
+    }
+    if let Some(item) = section.take("fail_timeout") {
+        proxy.fail_timeout = duration(&item, "fail_timeout")?;
+    }
+    if let Some(item) = section.take("max_active") {
+        proxy.max_active = unsigned(&item, "max_active")?;
+    }
+    if let Some(mut check) = section.section("health_check", "`health_check`")? {
+        let mut health_check = HealthCheck::new(&check.required_string("path")?);
+        if let Some(item) = check.take("interval") {
+            health_check.interval = positive_duration(&item, "interval")?;
+        }
+        if let Some(item) = check.take("timeout") {
+            health_check.timeout = positive_duration(&item, "timeout")?;
+        }
+        if let Some(item) = check.take("fails") {
+            health_check.fails = positive(&item, "fails")? as u32;
+        }
+        if let Some(item) = check.take("passes") {
+            health_check.passes = positive(&item, "passes")? as u32;
+        }
+        check.finish()?;
+        proxy.health_check = Some(health_check);
+    }
+    Ok(proxy)
+}
+
+fn log_format(item: &Item) -> Result<LogFormat, ConfigError> {
+    let format = string(item, "format")?;
+    match format.as_str() {
+        "common" => Ok(LogFormat::Common),
+        "combined" => Ok(LogFormat::Combined),
+        "json" => Ok(LogFormat::Json),
+        _ if format.contains('%') => Ok(LogFormat::Template(format)),
+        _ => one_of(item, "format", &["common", "combined", "json", "a template with %"]),
+    }
+}
+
+fn status_access(item: &Item) -> Result<StatusAccess, ConfigError> {
+    let access = string(item, "access")?;
+    match access.as_str() {
+        "localhost" => Ok(StatusAccess::Localhost),
+        "anyone" => Ok(StatusAccess::Anyone),
+        _ => {
+            match access.parse::<SocketAddr>() {
+                Ok(addr) => Ok(StatusAccess::Listener(addr)),
+                Err(_) => one_of(item, "access", &["localhost", "anyone", "a listener address"]),
+            }
+        }
+    }
+}
+
+fn type_error(item: &Item, key: &str, expected: &str) -> ConfigError {
+    ConfigError::new(item.line,
+                     format!("`{}` must be {}, not {}", key, expected, item.value.type_name()))
+}
+
+fn one_of<T>(item: &Item, key: &str, values: &[&str]) -> Result<T, ConfigError> {
+    let values = values.iter().map(|v| format!("\"{}\"", v)).collect::<Vec<_>>();
+    Err(ConfigError::new(item.line,
+                         format!("`{}` must be one of {}", key, values.join(", "))))
+}
+
+fn string(item: &Item, key: &str) -> Result<String, ConfigError> {
+    match item.value {
+        Value::String(ref s) => Ok(s.clone()),
+        _ => Err(type_error(item, key, "a string")),
+    }
+}
+
+fn strings(item: &Item, key: &str) -> Result<Vec<String>, ConfigError> {
+    match item.value {
+        Value::Array(ref items) => items.iter().map(|i| string(i, key)).collect(),
+        _ => Err(type_error(item, key, "an array of strings")),
+    }
+}
+
+fn boolean(item: &Item, key: &str) -> Result<bool, ConfigError> {
+    match item.value {
+        Value::Boolean(b) => Ok(b),
+        _ => Err(type_error(item, key, "true or false")),
+    }
+}
+
+fn integer(item: &Item, key: &str) -> Result<i64, ConfigError> {
+    match item.value {
+        Value::Integer(n) => Ok(n),
+        _ => Err(type_error(item, key, "an integer")),
+    }
+}
+
+fn unsigned(item: &Item, key: &str) -> Result<usize, ConfigError> {
+    let n = integer(item, key)?;
+    if n < 0 || n > u32::MAX as i64 {
+        return Err(ConfigError::new(item.line, format!("`{}` is out of range", key)));
+    }
+    Ok(n as usize)
+}
+
+fn positive(item: &Item, key: &str) -> Result<usize, ConfigError> {
+    let n = unsigned(item, key)?;
+    if n == 0 {
+        return Err(ConfigError::new(item.line, format!("`{}` can't be 0", key)));
+    }
+    Ok(n)
+}
+
+fn weight(item: &Item) -> Result<u32, ConfigError> {
+    let weight = positive(item, "weight")?;
+    if weight > upstream::MAX_WEIGHT as usize {
+        let message = format!("`weight` can't be over {}", upstream::MAX_WEIGHT);
+        return Err(ConfigError::new(item.line, message));
+    }
+    Ok(weight as u32)
+}
+
+// "host:port", checked without resolving the host, or a Unix socket.
+fn address(item: &Item) -> Result<String, ConfigError> {
+    let address = string(item, "address")?;
+    if address.starts_with("unix:/") || address.parse::<SocketAddr>().is_ok() {
+        return Ok(address);
+    }
+    let valid = match address.rsplit_once(':') {
+        Some((host, port)) => {
+            !host.is_empty() && !host.contains([':', '[', ']', '/']) && port.parse::<u16>().is_ok()
+        }
+        None => false,
+    };
+    if !valid {
+        return Err(ConfigError::new(item.line,
+                                    format!("`{}` is not an address like \"127.0.0.1:8080\"",
+                                            address)));
+    }
+    Ok(address)
+}
+
+// An IP address and port. Listeners don't resolve host names.
+fn listen_address(item: &Item) -> Result<String, ConfigError> {
+    let address = string(item, "address")?;
+    if address.parse::<SocketAddr>().is_err() {
+        return Err(ConfigError::new(item.line,
+                                    format!("`{}` is not an address like \"127.0.0.1:8080\" \
+                                             or \"[::]:8080\"",
+                                            address)));
+    }
+    Ok(address)
+}
+
+// Seconds, or a number with the unit "ms", "s", "m", "h" or "d".
+fn duration(item: &Item, key: &str) -> Result<Duration, ConfigError> {
+    let text = match item.value {
+        Value::Integer(n) if n >= 0 => return Ok(Duration::from_secs(n as u64)),
+        Value::Integer(_) => {
+            return Err(ConfigError::new(item.line, format!("`{}` can't be negative", key)))
+        }
+        Value::String(ref s) => s.trim(),
+        _ => return Err(type_error(item, key, "a duration")),
+    };
+    let split = text.find(|c: char| !c.is_ascii_digit()).unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+    let millis = match unit.trim() {
+        "ms" => Some(1),
+        "s" | "" => Some(1000),
+        "m" => Some(60 * 1000),
+        "h" => Some(3600 * 1000),
+        "d" => Some(86400 * 1000),
+        _ => None,
+    };
+    match (number.parse::<u64>(), millis) {
+        (Ok(n), Some(millis)) => Ok(Duration::from_millis(n.saturating_mul(millis))),
+        _ => {
+            Err(ConfigError::new(item.line,
+                                 format!("`{}` must be a duration like \"30s\" or \"500ms\"",
+                                         key)))
+        }
+    }
+}
+
+// A duration that can't be 0: sockets take no zero timeout, connections
+// would time out at once and health checks would never sleep.
+fn positive_duration(item: &Item, key: &str) -> Result<Duration, ConfigError> {
+    let duration = duration(item, key)?;
+    if duration == Duration::from_secs(0) {
+        return Err(ConfigError::new(item.line, format!("`{}` can't be 0", key)));
+    }
+    Ok(duration)
+}
+
+// Bytes, or a number with the unit "KB", "MB" or "GB", in units of 1024.
+fn size(item: &Item, key: &str) -> Result<usize, ConfigError> {
+    let text = match item.value {
+        Value::Integer(n) if n >= 0 => return Ok(n as usize),
+        Value::Integer(_) => {
+            return Err(ConfigError::new(item.line, format!("`{}` can't be negative", key)))
+        }
+        Value::String(ref s) => s.trim(),
+        _ => return Err(type_error(item, key, "a size")),
+    };
+    let split = text.find(|c: char| !c.is_ascii_digit()).unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+    let scale = match unit.trim().to_uppercase().as_str() {
+        "" | "B" => Some(1),
+        "K" | "KB" | "KIB" => Some(1 << 10),
+        "M" | "MB" | "MIB" => Some(1 << 20),
+        "G" | "GB" | "GIB" => Some(1 << 30),
+        _ => None,
+    };
+    match (number.parse::<usize>(), scale) {
+        (Ok(n), Some(scale)) => Ok(n.saturating_mul(scale)),
+        _ => {
+            Err(ConfigError::new(item.line,
+                                 format!("`{}` must be a size like \"64KB\" or \"16MB\"", key)))
+        }
+    }
+}
+
+// A TOML basic string.
+fn quote(text: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            '\r' => quoted.push_str("\\r"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04X}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn quote_all(texts: &[String]) -> String {
+    format!("[{}]", texts.iter().map(|t| quote(t)).collect::<Vec<_>>().join(", "))
+}
+
+// A key, quoted unless it is a bare one.
+fn show_key(key: &str) -> String {
+    if !key.is_empty() && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+        return key.to_string();
+    }
+    quote(key)
+}
+
+// A duration in the largest unit it is a whole number of.
+fn show_duration(duration: Duration) -> String {
+    let millis = duration.as_millis();
+    if !millis.is_multiple_of(1000) {
+        return format!("\"{}ms\"", millis);
+    }
+    let secs = millis / 1000;
+    for &(unit, scale) in &[("d", 86400), ("h", 3600), ("m", 60)] {
+        if secs != 0 && secs.is_multiple_of(scale) {
+            return format!("\"{}{}\"", secs / scale, unit);
+        }
+    }
+    format!("\"{}s\"", secs)
+}
+
+// A size in the largest unit it is a whole number of, in bytes otherwise.
+// A `cache_control` rule as an inline table.
+fn show_cache_rule(rule: &CacheRule) -> String {
+    let mut keys = vec![match rule.pattern {
+                            CachePattern::Path(ref path) => format!("path = {}", quote(path)),
+                            CachePattern::Glob(ref glob) => format!("glob = {}", quote(glob)),
+                            CachePattern::Extension(ref ext) => {
+                                format!("extension = {}", quote(ext))
+                            }
+                        }];
+    if let Some(max_age) = rule.max_age {
+        keys.push(format!("max_age = {}", show_duration(max_age)));
+    }
+    if let Some(stale) = rule.stale_while_revalidate {
+        keys.push(format!("stale_while_revalidate = {}", show_duration(stale)));
+    }
+    for &(key, value) in &[("immutable", rule.immutable),
+                           ("no_store", rule.no_store),
+                           ("no_cache", rule.no_cache),
+                           ("private", rule.private),
+                           ("expires", rule.expires)] {
+        if value {
+            keys.push(format!("{} = true", key));
+        }
+    }
+    format!("{{ {} }}", keys.join(", "))
+}
+
+fn show_size(size: usize) -> String {
+    for &(unit, scale) in &[("GB", 1 << 30), ("MB", 1 << 20), ("KB", 1 << 10)] {
+        if size != 0 && size.is_multiple_of(scale) {
+            return format!("\"{}{}\"", size / scale, unit);
+        }
+    }
+    size.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LISTENER: &str = "[[listener]]\naddress = \"127.0.0.1:8080\"\n";
+
+    // Parses `text` with a listener after it, so the lines are those of
+    // `text`.
+    fn parse(text: &str) -> Result<Config, ConfigError> {
+        Config::parse(&format!("{}{}", text, LISTENER))
+    }
+
+    fn error(text: &str) -> ConfigError {
+        parse(text).unwrap_err()
+    }
+
+    #[test]
+    fn parses_settings() {
+        let config = parse("workers = 3\nshutdown_timeout = 0\n[timeouts]\nidle = \"500ms\"\n\
+                            [limits]\nmax_body_size = \"1MB\"\n")
+                         .unwrap();
+        assert_eq!(config.workers, 3);
+        assert_eq!(config.shutdown_timeout, Duration::from_secs(0));
+        assert_eq!(config.timeouts.idle, Duration::from_millis(500));
+        assert_eq!(config.limits.max_body_size, 1 << 20);
+        assert_eq!(config.listeners.len(), 1);
+    }
+
+    #[test]
+    fn toml_errors_keep_their_line() {
+        let e = error("workers = 3\nworkers = 4\n");
+        assert_eq!(e.line(), Some(2));
+        assert_eq!(e.message(), "duplicate key `workers`");
+    }
+
+    #[test]
+    fn invalid_values_have_their_line() {
+        assert_eq!(error("\nthreading = \"forked\"\n").line(), Some(2));
+        assert_eq!(error("[limits]\n\nmax_body_size = \"12XB\"\n").line(), Some(3));
+        let e = error("[timeouts]\nidel = \"5s\"\n");
+        assert_eq!(e.line(), Some(2));
+        assert_eq!(e.to_string(), "line 2: unknown setting `idel` in [timeouts]");
+    }
+
+    #[test]
+    fn zero_durations_are_rejected() {
+        let e = error("[timeouts]\nbody = 0\n");
+        assert_eq!(e.line(), Some(2));
+        assert_eq!(e.message(), "`body` can't be 0");
+        let e = error("[[route]]\npath = \"/.*\"\nproxy = \"127.0.0.1:9000\"\n\
+                       connect_timeout = \"0s\"\n");
+        assert_eq!(e.line(), Some(4));
+        let e = error("[[route]]\npath = \"/.*\"\nproxy = \"127.0.0.1:9000\"\n\
+                       health_check = { path = \"/health\", interval = \"0ms\" }\n");
+        assert_eq!(e.line(), Some(4));
+        assert_eq!(e.message(), "`interval` can't be 0");
+    }
+
+    #[test]
+    fn upstream_weights_are_bounded() {
+        let route = "[[route]]\npath = \"/.*\"\n\
+                     proxy = [{ address = \"127.0.0.1:9000\", weight = 1000 }]\n";
+        assert!(parse(route).is_ok());
+        let e = error(&route.replace("1000", "1001"));
+        assert_eq!(e.line(), Some(3));
+        assert_eq!(e.message(), "`weight` can't be over 1000");
+        let e = error(&route.replace("1000", "0"));
+        assert_eq!(e.message(), "`weight` can't be 0");
+    }
+
+    #[test]
+    fn compression_settings() {
+        assert!(parse("").unwrap().compression.is_none());
+        let config = parse("[compression]\n").unwrap();
+        let compression = config.compression.unwrap();
+        assert!(compression.enabled);
+        assert_eq!(compression.min_size, 1024);
+        assert!(compression.types.iter().any(|t| t == "text/*"));
+        let config = parse("[compression]\nmin_size = \"4KB\"\n\
+                            types = [\"text/css\", \"application/json\"]\n")
+                         .unwrap();
+        let printed = Config::parse(&config.to_string()).unwrap();
+        let compression = printed.compression.unwrap();
+        assert_eq!(compression.min_size, 4096);
+        assert_eq!(compression.types, ["text/css", "application/json"]);
+        assert_eq!(error("[compression]\nenabled = \"yes\"\n").line(), Some(2));
+        assert_eq!(error("[compression]\ntypes = [\"\"]\n").line(), Some(1));
+    }
+
+    #[test]
+    fn cache_control_rules() {
+        let config = parse("[[route]]\npath = \"/.*\"\nroot = \"http\"\n\
+                            cache_control = [{ glob = \"/vendor/**\", max_age = \"365d\", \
+                            immutable = true }, { extension = \"html\", no_cache = true }]\n")
+                         .unwrap();
+        let printed = Config::parse(&config.to_string()).unwrap();
+        let files = match printed.routes[0].action {
+            RouteAction::Directory(ref files) => files,
+            _ => panic!("not a static files route"),
+        };
+        let policies = files.cache_policies();
+        let vendor = policies.find("/vendor/jquery/jquery.min.js").unwrap();
+        assert_eq!(vendor.cache_control().unwrap(), "max-age=31536000, immutable");
+        let page = policies.find("/index.html").unwrap();
+        assert_eq!(page.cache_control().unwrap(), "no-cache");
+        assert!(policies.find("/css/new-age.css").is_none());
+        let route = "[[route]]\npath = \"/.*\"\nroot = \"http\"\n";
+        let e = error(&format!("{}cache_control = [{{ max_age = 60 }}]\n", route));
+        assert_eq!(e.message(),
+                   "a `cache_control` rule needs one of `path`, `glob` or `extension`");
+        let e = error(&format!("{}cache_control = [{{ path = \"(\", max_age = 60 }}]\n", route));
+        assert_eq!(e.line(), Some(4));
+    }
+
+    #[test]
+    fn missing_settings() {
+        assert_eq!(error("\n[[route]]\npath = \"/\"\n").line(), Some(2));
+        let e = Config::parse("workers = 1\n").unwrap_err();
+        assert_eq!(e.line(), None);
+        assert_eq!(e.to_string(), "no [[listener]] is configured");
+    }
+}
@@ -0,0 +1,417 @@
+use std::io::prelude::*;
+use std::io::{self, ErrorKind};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::cmp;
+use std::mem;
+use std::net::{Shutdown, SocketAddr};
+use std::time::Duration;
+use mio::net::TcpStream;
+use rustls::ServerConnection;
+use event_loop::Waker;
+use metrics::ConnMetrics;
+use tls::TlsInfo;
+
+const FILE_CHUNK_SIZE: usize = 64 * 1024;
+
+enum Chunk {
+    Data(Vec<u8>),
+    File(File, u64),
+}
+
+// Data queued to be sent on a connection. It can be filled away from the
+// connection, by a handler running on another thread, and appended to it
+// later.
+pub struct Output {
+    chunks: VecDeque<Chunk>,
+    closing: bool,
+}
+
+impl Output {
+    pub fn new() -> Output {
+        return Output {
+            chunks: VecDeque::new(),
+            closing: false,
+        };
+    }
+
+    // Queues data to be sent.
+    pub fn write(&mut self, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        if let Some(&mut Chunk::Data(ref mut buf)) = self.chunks.back_mut() {
+            buf.extend_from_slice(data);
+            return;
+        }
+        self.chunks.push_back(Chunk::Data(data.to_vec()));
+    }
+
+    // Queues the next `len` bytes of a file, read as the socket drains.
+    pub fn write_file(&mut self, file: File, len: u64) {
+        if len > 0 {
+            self.chunks.push_back(Chunk::File(file, len));
+        }
+    }
+
+    // Closes the connection once all the queued data has been sent.
+    pub fn close(&mut self) {
+        self.closing = true;
+    }
+
+    pub fn is_closing(&self) -> bool {
+        self.closing
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chunks.is_empty()
+    }
+
+    // Takes what was queued so far, to send it from another output.
+    pub fn take_queued(&mut self) -> Output {
+        return Output {
+            chunks: mem::take(&mut self.chunks),
+            closing: self.closing,
+        };
+    }
+
+    // Queues what was written to `other` after what is already queued.
+    pub fn append(&mut self, mut other: Output) {
+        self.chunks.append(&mut other.chunks);
+        self.closing = self.closing || other.closing;
+    }
+}
+
+#[cfg(test)]
+impl Output {
+    // The data queued so far, files left out.
+    pub(crate) fn queued_data(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        for chunk in &self.chunks {
+            if let Chunk::Data(ref buf) = *chunk {
+                data.extend_from_slice(buf);
+            }
+        }
+        data
+    }
+}
+
+// The bytes that went through the socket of a connection, TLS included.
+struct Traffic {
+    received: u64,
+    sent: u64,
+    metrics: Option<ConnMetrics>,
+}
+
+impl Traffic {
+    fn received(&mut self, n: usize) {
+        self.received += n as u64;
+        if let Some(ref metrics) = self.metrics {
+            metrics.received.inc_by(n as u64);
+        }
+    }
+
+    fn sent(&mut self, n: usize) {
+        self.sent += n as u64;
+        if let Some(ref metrics) = self.metrics {
+            metrics.sent.inc_by(n as u64);
+        }
+    }
+}
+
+// A client connection. Data written to it is queued and sent whenever the
+// non-blocking stream accepts it, so a response is never cut short by a
+// full socket buffer. On TLS connections, data goes through the TLS session
+// which handshakes and encrypts as the stream is read and flushed.
+pub struct Connection {
+    stream: TcpStream,
+    tls: Option<ServerConnection>,
+    out: Output,
+    peer_closed: bool,
+    timers: Vec<(Duration, usize)>,
+    waker: Waker,
+    traffic: Traffic,
+    // Asked once, they don't change.
+    peer_addr: Option<SocketAddr>,
+    local_addr: Option<SocketAddr>,
+    listener_addr: Option<SocketAddr>,
+}
+
+impl Connection {
+    pub fn new(stream: TcpStream, tls: Option<ServerConnection>, waker: Waker) -> Connection {
+        let peer_addr = stream.peer_addr().ok();
+        let local_addr = stream.local_addr().ok();
+        return Connection {
+            stream: stream,
+            tls: tls,
+            out: Output::new(),
+            peer_closed: false,
+            timers: Vec::new(),
+            waker: waker,
+            traffic: Traffic {
+                received: 0,
+                sent: 0,
+                metrics: None,
+            },
+            peer_addr: peer_addr,
+            local_addr: local_addr,
+            listener_addr: None,
+        };
+    }
+
+    // Counts the bytes that go through the socket in `metrics` too.
+    pub(crate) fn set_metrics(&mut self, metrics: ConnMetrics) {
+        self.traffic.metrics = Some(metrics);
+    }
+
+    pub fn bytes_received(&self) -> u64 {
+        self.traffic.received
+    }
+
+    pub fn bytes_sent(&self) -> u64 {
+        self.traffic.sent
+    }
+
+    pub fn write(&mut self, data: &[u8]) {
+        self.out.write(data);
+    }
+
+    pub fn write_file(&mut self, file: File, len: u64) {
+        self.out.write_file(file, len);
+    }
+
+    pub fn close(&mut self) {
+        self.out.close();
+    }
+
+    pub fn is_closing(&self) -> bool {
+        self.out.is_closing()
+    }
+
+    pub fn output(&mut self) -> &mut Output {
+        &mut self.out
+    }
+
+    // Returns a waker that makes the app's `wake()` be called for this
+    // connection, from any thread.
+    pub fn waker(&self) -> Waker {
+        self.waker.clone()
+    }
+
+    // Whether the peer is done sending. Responses can still be written.
+    pub fn is_peer_closed(&self) -> bool {
+        self.peer_closed
+    }
+
+    pub fn has_pending_output(&self) -> bool {
+        let tls_pending = match self.tls {
+            Some(ref tls) => tls.wants_write(),
+            None => false,
+        };
+        !self.out.is_empty() || tls_pending
+    }
+
+    // None if the connection is not over TLS.
+    pub fn tls_info(&self) -> Option<TlsInfo> {
+        self.tls.as_ref().map(|tls| {
+            let certs = match tls.peer_certificates() {
+                Some(certs) => certs.iter().map(|c| c.to_vec()).collect(),
+                None => Vec::new(),
+            };
+            TlsInfo {
+                server_name: tls.server_name().map(|s| s.to_string()),
+                alpn_protocol: tls.alpn_protocol().map(|p| String::from_utf8_lossy(p).into_owned()),
+                client_certificates: certs,
+            }
+        })
+    }
+
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
+    }
+
+    // The address the connection was accepted on.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+
+    // The address of the listener that accepted the connection, as it was
+    // given, which can be a wildcard one.
+    pub fn listener_addr(&self) -> Option<SocketAddr> {
+        self.listener_addr
+    }
+
+    pub(crate) fn set_listener_addr(&mut self, addr: Option<SocketAddr>) {
+        self.listener_addr = addr;
+    }
+
+    // Asks for the app's `timeout()` to be called with `token` after `delay`.
+    pub fn set_timer(&mut self, delay: Duration, token: usize) {
+        self.timers.push((delay, token));
+    }
+
+    pub fn take_timers(&mut self) -> Vec<(Duration, usize)> {
+        mem::take(&mut self.timers)
+    }
+
+    // Reads everything available without blocking.
+    pub fn read_available(&mut self, data: &mut Vec<u8>) -> io::Result<()> {
+        if self.tls.is_some() {
+            return self.read_tls(data);
+        }
+        let mut buf = [0; 4096];
+        loop {
+            match self.stream.read(&mut buf) {
+                Ok(0) => {
+                    self.peer_closed = true;
+                    return Ok(());
+                }
+                Ok(n) => {
+                    self.traffic.received(n);
+                    data.extend_from_slice(&buf[..n]);
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn read_tls(&mut self, data: &mut Vec<u8>) -> io::Result<()> {
+        let tls = self.tls.as_mut().unwrap();
+        let mut buf = [0; 4096];
+        loop {
+            match tls.read_tls(&mut self.stream) {
+                Ok(0) => self.peer_closed = true,
+                Ok(n) => self.traffic.received(n),
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+            if let Err(e) = tls.process_new_packets() {
+                // An alert telling the client why is queued, it goes out
+                // before the connection is closed.
+                self.peer_closed = true;
+                return Err(io::Error::new(ErrorKind::InvalidData, e));
+            }
+            loop {
+                match tls.reader().read(&mut buf) {
+                    // The client sent close_notify.
+                    Ok(0) => {
+                        self.peer_closed = true;
+                        return Ok(());
+                    }
+                    Ok(n) => data.extend_from_slice(&buf[..n]),
+                    Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                    // The stream closed without close_notify.
+                    Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+                    Err(e) => return Err(e),
+                }
+            }
+            if self.peer_closed {
+                return Ok(());
+            }
+        }
+    }
+
+    // Sends as much queued data as the stream accepts. Returns how many
+    // bytes were sent.
+    pub fn flush(&mut self) -> io::Result<usize> {
+        // Handshake messages and alerts are queued without any output.
+        self.write_tls()?;
+        let mut written = 0;
+        loop {
+            let chunk = match self.out.chunks.pop_front() {
+                None => return Ok(written),
+                Some(chunk) => chunk,
+            };
+            match chunk {
+                Chunk::Data(data) => {
+                    let n = self.write_some(&data)?;
+                    written += n;
+                    if n < data.len() {
+                        self.out.chunks.push_front(Chunk::Data(data[n..].to_vec()));
+                        return Ok(written);
+                    }
+                }
+                Chunk::File(mut f, remaining) => {
+                    let mut buf = vec![0; cmp::min(remaining, FILE_CHUNK_SIZE as u64) as usize];
+                    let read = f.read(&mut buf)?;
+                    if read == 0 {
+                        return Err(io::Error::new(ErrorKind::UnexpectedEof,
+                                                  "file shrank while being sent"));
+                    }
+                    let remaining = remaining - read as u64;
+                    if remaining > 0 {
+                        self.out.chunks.push_front(Chunk::File(f, remaining));
+                    }
+                    let n = self.write_some(&buf[..read])?;
+                    written += n;
+                    if n < read {
+                        self.out.chunks.push_front(Chunk::Data(buf[n..read].to_vec()));
+                        return Ok(written);
+                    }
+                }
+            }
+        }
+    }
+
+    fn write_some(&mut self, data: &[u8]) -> io::Result<usize> {
+        if self.tls.is_none() {
+            return self.write_stream(data);
+        }
+        // Encrypt no more than the stream takes, so that the TLS session
+        // doesn't buffer whole files. The session takes a limited amount at
+        // once, keep going until the stream would block or no writable event
+        // comes to send the rest.
+        let mut written = 0;
+        while written < data.len() && self.write_tls()? {
+            written += self.tls.as_mut().unwrap().writer().write(&data[written..])?;
+        }
+        self.write_tls()?;
+        Ok(written)
+    }
+
+    fn write_stream(&mut self, data: &[u8]) -> io::Result<usize> {
+        let mut written = 0;
+        while written < data.len() {
+            match self.stream.write(&data[written..]) {
+                Ok(0) => return Err(io::Error::new(ErrorKind::WriteZero, "connection closed")),
+                Ok(n) => {
+                    self.traffic.sent(n);
+                    written += n;
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(written)
+    }
+
+    // Sends the pending TLS records. Returns whether they were all sent.
+    fn write_tls(&mut self) -> io::Result<bool> {
+        let tls = match self.tls {
+            Some(ref mut tls) => tls,
+            None => return Ok(true),
+        };
+        while tls.wants_write() {
+            match tls.write_tls(&mut self.stream) {
+                Ok(0) => return Err(io::Error::new(ErrorKind::WriteZero, "connection closed")),
+                Ok(n) => self.traffic.sent(n),
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(false),
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(true)
+    }
+
+    pub fn shutdown(&mut self) {
+        if let Some(ref mut tls) = self.tls {
+            tls.send_close_notify();
+        }
+        let _ = self.write_tls();
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+}
@@ -0,0 +1,108 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct",
+                            "Nov", "Dec"];
+
+// A time in UTC, to the second.
+struct Date {
+    year: u64,
+    // From 1.
+    month: u64,
+    day: u64,
+    hour: u64,
+    minute: u64,
+    second: u64,
+    // From Thursday, the day of the epoch.
+    weekday: u64,
+}
+
+impl Date {
+    fn utc(time: SystemTime) -> Date {
+        let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        let (days, secs) = (secs / 86400, secs % 86400);
+        // The civil date of a day since the epoch, in years starting in
+        // March so that leap days come last.
+        let z = days + 719468;
+        let era = z / 146097;
+        let doe = z - era * 146097;
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        return Date {
+            year: yoe + era * 400 + if month <= 2 { 1 } else { 0 },
+            month: month,
+            day: doy - (153 * mp + 2) / 5 + 1,
+            hour: secs / 3600,
+            minute: secs % 3600 / 60,
+            second: secs % 60,
+            weekday: days % 7,
+        };
+    }
+}
+
+// Formats `time` as an HTTP date, like "Sun, 06 Nov 1994 08:49:37 GMT".
+pub(crate) fn http_date(time: SystemTime) -> String {
+    let d = Date::utc(time);
+    format!("{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+            DAYS[d.weekday as usize],
+            d.day,
+            MONTHS[d.month as usize - 1],
+            d.year,
+            d.hour,
+            d.minute,
+            d.second)
+}
+
+// Parses an HTTP date in the format `http_date()` writes. The obsolete
+// formats give None, like invalid dates.
+pub(crate) fn parse_http_date(value: &str) -> Option<SystemTime> {
+    let parts: Vec<&str> = value.trim().split(' ').collect();
+    if parts.len() != 6 || !parts[0].ends_with(',') || parts[5] != "GMT" {
+        return None;
+    }
+    let day: u64 = parts[1].parse().ok()?;
+    let month = MONTHS.iter().position(|&m| m == parts[2])? as u64 + 1;
+    let year: u64 = parts[3].parse().ok()?;
+    let time = parts[4].split(':').map(|p| p.parse().ok()).collect::<Option<Vec<u64>>>()?;
+    if !(1..=31).contains(&day) || year < 1970 || time.len() != 3 || time[0] > 23 ||
+       time[1] > 59 || time[2] > 60 {
+        return None;
+    }
+    // The days since the epoch, the other way around from `Date::utc()`.
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y / 400;
+    let yoe = y - era * 400;
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let days = era * 146097 + yoe * 365 + yoe / 4 - yoe / 100 + doy - 719468;
+    let secs = days * 86400 + time[0] * 3600 + time[1] * 60 + time[2];
+    Some(UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+// The time of the Common Log Format, like "10/Oct/2000:13:55:36 +0000".
+pub(crate) fn clf_date(time: SystemTime) -> String {
+    let d = Date::utc(time);
+    format!("{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+            d.day,
+            MONTHS[d.month as usize - 1],
+            d.year,
+            d.hour,
+            d.minute,
+            d.second)
+}
+
+// The RFC 3339 time, like "2000-10-10T13:55:36.123Z".
+pub(crate) fn rfc3339(time: SystemTime) -> String {
+    let d = Date::utc(time);
+    let millis = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.subsec_millis());
+    format!("{}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+            d.year,
+            d.month,
+            d.day,
+            d.hour,
+            d.minute,
+            d.second,
+            millis)
+}
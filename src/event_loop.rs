@@ -1,110 +1,777 @@
 use mio::*;
-use mio::tcp::*;
+use mio::net::*;
 use std::thread;
 use std::sync::mpsc::*;
 use std::net::SocketAddr;
 use std::str::FromStr;
+use std::io::{self, ErrorKind};
+use std::time::{Duration, Instant};
+use std::cmp;
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use libc;
+use net2::TcpBuilder;
+use net2::unix::UnixTcpBuilderExt;
+use metrics::Counter;
+use timer::*;
+use workers::*;
 
-const SERVER: Token = Token(0);
+// mio reserves usize::MAX for itself.
+const SHUTDOWN: Token = Token(usize::MAX - 1);
+const NOTIFY: Token = Token(usize::MAX - 2);
+// Listeners get the tokens below, counting down, and connections the ones
+// from 1 up.
+const FIRST_LISTENER: usize = usize::MAX - 3;
+
+// Timers of a worker thread, scheduled with a connection id and a token
+// that are passed back to `EventHandler::timeout()`.
+pub type Timers = TimerWheel<(usize, usize)>;
 
 pub trait EventHandler : Send + 'static {
-    fn new_conn(&mut self, id: usize, conn: TcpStream);
-    fn conn_event(&mut self, id: usize, event: Ready);
+    // `listener` is the index of the host the connection was accepted on.
+    fn new_conn(&mut self, id: usize, listener: usize, conn: TcpStream, timers: &mut Timers);
+    fn conn_event(&mut self, id: usize, event: Ready, timers: &mut Timers);
+    fn timeout(&mut self, id: usize, token: usize, timers: &mut Timers);
+    // Called once on the worker thread before any connection is handed to it.
+    fn set_notifier(&mut self, notifier: Notifier);
+    // Called after `Notifier::notify()` for the connection.
+    fn notify(&mut self, id: usize, timers: &mut Timers);
+    // Called once when the server stops: idle connections should be closed
+    // and the others closed as soon as their current request is done.
+    fn shutdown(&mut self);
+    fn num_conns(&self) -> usize;
     fn duplicate(&self) -> Box<EventHandler>;
 }
 
 #[derive(Debug)]
 enum Msg {
-    NewConn(usize, TcpStream),
+    NewConn(usize, usize, TcpStream),
     ConnEvent(usize, Ready),
+    Notify(usize),
+    // Stop taking new work and exit once all connections are closed.
+    Shutdown,
+    // Exit now, dropping the remaining connections.
+    Stop,
+}
+
+/// Stops a running server from any thread.
+///
+/// `run()` stops accepting connections, waits for in-flight requests to
+/// complete (up to the shutdown timeout) and then returns.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    requested: Arc<AtomicBool>,
+    // Wake up the polls of the server, one per polling thread.
+    wakers: Arc<Mutex<Vec<SetReadiness>>>,
+}
+
+impl ShutdownHandle {
+    pub fn new() -> ShutdownHandle {
+        return ShutdownHandle {
+            requested: Arc::new(AtomicBool::new(false)),
+            wakers: Arc::new(Mutex::new(Vec::new())),
+        };
+    }
+
+    pub fn shutdown(&self) {
+        self.requested.store(true, Ordering::SeqCst);
+        for waker in self.wakers.lock().unwrap().iter() {
+            let _ = waker.set_readiness(Ready::readable());
+        }
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    // Makes `poll` return a SHUTDOWN event on shutdown, for as long as the
+    // returned registration is kept. Callers must check `is_shutdown()`
+    // afterwards in case it was requested before.
+    fn register(&self, poll: &Poll) -> Registration {
+        let (registration, readiness) = Registration::new2();
+        poll.register(&registration, SHUTDOWN, Ready::readable(), PollOpt::edge()).unwrap();
+        self.wakers.lock().unwrap().push(readiness);
+        registration
+    }
+}
+
+enum Wakeup {
+    // Workers of the dispatcher wait on their channel.
+    Channel(Sender<Msg>, WorkerLoads, usize),
+    // Workers with their own poll get a NOTIFY event, then handle the ids
+    // queued for them.
+    Poll(Arc<Mutex<Vec<usize>>>, SetReadiness),
+}
+
+// Wakes up a worker thread from another thread so that it calls
+// `EventHandler::notify()` for one of its connections.
+#[derive(Clone)]
+pub struct Notifier {
+    wakeup: Arc<Wakeup>,
+}
+
+impl Notifier {
+    pub fn notify(&self, id: usize) {
+        // Errors mean the worker is gone, and the connection with it.
+        match *self.wakeup {
+            Wakeup::Channel(ref sender, ref loads, worker) => {
+                loads.queued(worker);
+                if sender.send(Msg::Notify(id)).is_err() {
+                    loads.dequeued(worker);
+                }
+            }
+            Wakeup::Poll(ref ids, ref readiness) => {
+                ids.lock().unwrap().push(id);
+                let _ = readiness.set_readiness(Ready::readable());
+            }
+        }
+    }
+
+    pub fn waker(&self, id: usize) -> Waker {
+        return Waker {
+            id: id,
+            notifier: self.clone(),
+        };
+    }
+}
+
+// Notifies the worker of one connection.
+#[derive(Clone)]
+pub struct Waker {
+    id: usize,
+    notifier: Notifier,
+}
+
+impl Waker {
+    pub fn wake(&self) {
+        self.notifier.notify(self.id);
+    }
+}
+
+#[cfg(test)]
+impl Waker {
+    // A waker whose notifications go nowhere.
+    pub(crate) fn detached() -> Waker {
+        let (_, readiness) = Registration::new2();
+        let wakeup = Wakeup::Poll(Arc::new(Mutex::new(Vec::new())), readiness);
+        Notifier { wakeup: Arc::new(wakeup) }.waker(0)
+    }
+}
+
+// The channels to the worker threads. Connection ids are picked so that
+// `id % number of workers` is the worker the connection was assigned to.
+struct Workers {
+    senders: Vec<Sender<Msg>>,
+    loads: WorkerLoads,
+    assignment: Box<Assignment>,
+    next_seq: usize,
+}
+
+impl Workers {
+    fn worker_of(&self, id: usize) -> usize {
+        id % self.senders.len()
+    }
+
+    // Picks the worker of a new connection and returns the connection id.
+    fn next_conn_id(&mut self) -> usize {
+        let n = self.senders.len();
+        let worker = self.assignment.assign(&self.loads.stats()) % n;
+        self.next_seq += 1;
+        self.next_seq * n + worker
+    }
+
+    fn send(&self, worker: usize, msg: Msg) {
+        self.loads.queued(worker);
+        if self.senders[worker].send(msg).is_err() {
+            // The worker is gone, which happens when draining.
+            self.loads.dequeued(worker);
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.senders.len()
+    }
+}
+
+/// How connections and their events get to the worker threads.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Threading {
+    /// One thread accepts and polls all the connections, and forwards their
+    /// events to the workers over channels.
+    Dispatcher,
+    /// Each worker has its own poll and `SO_REUSEPORT` listener, and the
+    /// kernel spreads new connections over them. This saves a thread hop per
+    /// event, but the assignment strategy is not used.
+    ReusePort,
+}
+
+// The sockets the server accepts connections on.
+struct Listeners {
+    sockets: Vec<TcpListener>,
+}
+
+impl Listeners {
+    fn bind(hosts: &[String]) -> io::Result<Listeners> {
+        let sockets = hosts.iter()
+                           .map(|h| bind_host(h, TcpListener::bind))
+                           .collect::<io::Result<_>>()?;
+        return Ok(Listeners { sockets: sockets });
+    }
+
+    fn bind_reuse_port(hosts: &[String]) -> io::Result<Listeners> {
+        let sockets = hosts.iter()
+                           .map(|h| bind_host(h, bind_reuse_port))
+                           .collect::<io::Result<_>>()?;
+        return Ok(Listeners { sockets: sockets });
+    }
+
+    fn register(&self, poll: &Poll) {
+        for (i, socket) in self.sockets.iter().enumerate() {
+            poll.register(socket, Token(FIRST_LISTENER - i), Ready::readable(), PollOpt::edge())
+                .unwrap();
+        }
+    }
+
+    fn deregister(&self, poll: &Poll) {
+        for socket in &self.sockets {
+            let _ = poll.deregister(socket);
+        }
+    }
+
+    // The index of the listener of a token, None for connection tokens.
+    fn index_of(&self, token: Token) -> Option<usize> {
+        let Token(t) = token;
+        if t <= FIRST_LISTENER && FIRST_LISTENER - t < self.sockets.len() {
+            return Some(FIRST_LISTENER - t);
+        }
+        None
+    }
+}
+
+// The listeners of the poll loops, bound before any of them runs: one set
+// for the dispatcher, or one per worker with `Threading::ReusePort`.
+pub struct BoundListeners {
+    sets: Vec<Listeners>,
+}
+
+impl BoundListeners {
+    // Fails on the first host that can't be bound, with the host in the
+    // error message.
+    pub fn bind(hosts: &[String],
+                threading: Threading,
+                num_workers: usize)
+                -> io::Result<BoundListeners> {
+        let sets = match threading {
+            Threading::Dispatcher => vec![Listeners::bind(hosts)?],
+            Threading::ReusePort => {
+                (0..num_workers).map(|_| Listeners::bind_reuse_port(hosts))
+                                .collect::<io::Result<_>>()?
+            }
+        };
+        return Ok(BoundListeners { sets: sets });
+    }
+}
+
+// What the event loop gets from the `WebServer` besides the listeners.
+pub struct LoopSettings {
+    pub shutdown: ShutdownHandle,
+    pub shutdown_timeout: Duration,
+    pub loads: WorkerLoads,
+    pub assignment: Box<Assignment>,
+    pub threading: Threading,
+    pub accept_errors: Counter,
 }
 
 pub struct EventLoop {
-    host: String,
+    listeners: BoundListeners,
     num_workers: usize,
     event_handler: Box<EventHandler>,
+    shutdown: ShutdownHandle,
+    shutdown_timeout: Duration,
+    loads: WorkerLoads,
+    assignment: Box<Assignment>,
+    threading: Threading,
+    accept_errors: Counter,
 }
 
 impl EventLoop {
-    pub fn new(host: &str, num_workers: usize, event_handler: Box<EventHandler>) -> EventLoop {
+    pub fn new(listeners: BoundListeners,
+               event_handler: Box<EventHandler>,
+               settings: LoopSettings)
+               -> EventLoop {
         return EventLoop {
-            host: host.to_string(),
-            num_workers: num_workers,
+            listeners: listeners,
+            num_workers: settings.loads.len(),
             event_handler: event_handler,
+            shutdown: settings.shutdown,
+            shutdown_timeout: settings.shutdown_timeout,
+            loads: settings.loads,
+            assignment: settings.assignment,
+            threading: settings.threading,
+            accept_errors: settings.accept_errors,
         };
     }
 
-    fn process_events(channel: Receiver<Msg>, mut event_handler: Box<EventHandler>) {
+    fn process_events(worker: usize,
+                      channel: Receiver<Msg>,
+                      notifier: Notifier,
+                      mut event_handler: Box<EventHandler>,
+                      loads: WorkerLoads) {
+        event_handler.set_notifier(notifier);
+        let mut draining = false;
+        let mut timers = Timers::new();
         loop {
-            let msg = channel.recv().unwrap();
+            let msg = match timers.next_timeout() {
+                None => {
+                    match channel.recv() {
+                        Ok(msg) => Some(msg),
+                        Err(_) => return,
+                    }
+                }
+                Some(timeout) => {
+                    match channel.recv_timeout(timeout) {
+                        Ok(msg) => Some(msg),
+                        Err(RecvTimeoutError::Timeout) => None,
+                        Err(RecvTimeoutError::Disconnected) => return,
+                    }
+                }
+            };
+            let before = event_handler.num_conns();
+            let mut added = 0;
+            if msg.is_some() {
+                loads.dequeued(worker);
+            }
             match msg {
-                Msg::NewConn(id, conn) => {
-                    event_handler.new_conn(id, conn);
+                Some(Msg::NewConn(id, listener, conn)) => {
+                    // Counted by the event loop when it assigned it.
+                    added = 1;
+                    event_handler.new_conn(id, listener, conn, &mut timers);
+                }
+                Some(Msg::ConnEvent(id, event)) => {
+                    event_handler.conn_event(id, event, &mut timers);
+                }
+                Some(Msg::Notify(id)) => {
+                    event_handler.notify(id, &mut timers);
+                }
+                Some(Msg::Shutdown) => {
+                    draining = true;
+                    event_handler.shutdown();
                 }
-                Msg::ConnEvent(id, event) => {
-                    event_handler.conn_event(id, event);
+                Some(Msg::Stop) => {
+                    warn!("Dropping {} connections", event_handler.num_conns());
+                    return;
                 }
+                None => {}
+            }
+            for (id, token) in timers.expired() {
+                event_handler.timeout(id, token, &mut timers);
+            }
+            loads.closed(worker, before + added - event_handler.num_conns());
+            if draining && event_handler.num_conns() == 0 {
+                return;
             }
         }
     }
 
     pub fn run(self) {
+        match self.threading {
+            Threading::Dispatcher => self.run_dispatcher(),
+            Threading::ReusePort => self.run_reuse_port(),
+        }
+    }
+
+    fn run_dispatcher(self) {
         let poll = Poll::new().unwrap();
-        let server = TcpListener::bind(&SocketAddr::from_str(&self.host).unwrap()).unwrap();
-        poll.register(&server, SERVER, Ready::readable(), PollOpt::edge()).unwrap();
+        let listeners = self.listeners.sets.into_iter().next().unwrap();
+        listeners.register(&poll);
+        let _shutdown_registration = self.shutdown.register(&poll);
         let mut events = Events::with_capacity(1024);
-        let mut next_conn: usize = 1;
-        let mut workers = Vec::new();
+        let mut senders = Vec::new();
+        let mut threads = Vec::new();
+        let (done_tx, done_rx) = channel();
         // Create worker threads.
-        for _ in 0..self.num_workers {
+        for worker in 0..self.num_workers {
             let (tx, rx) = channel();
             let worker_handler = self.event_handler.duplicate();
-            thread::spawn(move || {
-                Self::process_events(rx, worker_handler);
-            });
-            workers.push(tx);
+            let done = done_tx.clone();
+            let loads = self.loads.clone();
+            let notifier = Notifier {
+                wakeup: Arc::new(Wakeup::Channel(tx.clone(), loads.clone(), worker)),
+            };
+            threads.push(thread::spawn(move || {
+                Self::process_events(worker, rx, notifier, worker_handler, loads);
+                let _ = done.send(());
+            }));
+            senders.push(tx);
         }
+        let mut workers = Workers {
+            senders: senders,
+            loads: self.loads,
+            assignment: self.assignment,
+            next_seq: 0,
+        };
+        let mut workers_done = 0;
+        let mut accept_backoff = AcceptBackoff::new(self.accept_errors);
+        let mut deadline = None;
+        // The handle may have been triggered before we registered it.
+        let mut shutdown_requested = self.shutdown.is_shutdown();
         loop {
-            println!("Polling...");
-            match poll.poll(&mut events, None) {
+            if shutdown_requested && deadline.is_none() {
+                info!("Shutting down, waiting up to {:?} for in-flight requests",
+                      self.shutdown_timeout);
+                listeners.deregister(&poll);
+                for worker in 0..workers.len() {
+                    workers.send(worker, Msg::Shutdown);
+                }
+                deadline = Some(Instant::now() + self.shutdown_timeout);
+            }
+            if let Some(deadline) = deadline {
+                workers_done += done_rx.try_iter().count();
+                if workers_done == threads.len() || Instant::now() >= deadline {
+                    break;
+                }
+            }
+            trace!("Polling...");
+            let timeout = match deadline {
+                // Check regularly whether the workers are done.
+                Some(_) => Some(Duration::from_millis(50)),
+                None => accept_backoff.timeout(),
+            };
+            match poll.poll(&mut events, timeout) {
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
                 Err(e) => panic!("Error during poll(): {}", e),
                 Ok(_) => {}
             }
+            if deadline.is_none() && accept_backoff.expired() {
+                // The listeners won't signal again for connections that were
+                // already pending when we backed off, so retry them now.
+                for (i, server) in listeners.sockets.iter().enumerate() {
+                    Self::accept_conns(server, &mut accept_backoff, |stream| {
+                        Self::dispatch_conn(&poll, &mut workers, i, stream);
+                    });
+                }
+            }
             for event in events.iter() {
                 match event.token() {
-                    SERVER => {
-                        println!("Accepting..");
-                        match server.accept() {
-                            Ok((stream, _)) => {
-                                println!("Registering new connection...");
-                                match poll.register(&stream,
-                                                    Token(next_conn),
-                                                    Ready::readable(),
-                                                    PollOpt::edge()) {
-                                    Err(e) => panic!("Error during register(): {}", e),
-                                    Ok(_) => {
-                                        println!("New connection on worker {} ",
-                                                 next_conn % self.num_workers);
-                                        workers[next_conn % self.num_workers]
-                                            .send(Msg::NewConn(next_conn, stream))
-                                            .unwrap();
-                                        next_conn += 1;
-                                    }
+                    SHUTDOWN => {
+                        shutdown_requested = true;
+                    }
+                    token => {
+                        match listeners.index_of(token) {
+                            Some(i) => {
+                                if deadline.is_none() && !accept_backoff.is_active() {
+                                    let server = &listeners.sockets[i];
+                                    Self::accept_conns(server, &mut accept_backoff, |stream| {
+                                        Self::dispatch_conn(&poll, &mut workers, i, stream);
+                                    });
                                 }
                             }
-                            Err(e) => panic!("Error during accept() : {}", e),
+                            None => {
+                                let Token(id) = token;
+                                let worker = workers.worker_of(id);
+                                trace!("Sending event on conn {} to worker {}", id, worker);
+                                workers.send(worker, Msg::ConnEvent(id, event.readiness()));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        for worker in 0..workers.len() {
+            workers.send(worker, Msg::Stop);
+        }
+        for t in threads {
+            let _ = t.join();
+        }
+        info!("Server stopped");
+    }
+
+    fn run_reuse_port(self) {
+        let mut threads = Vec::new();
+        for (worker, listeners) in self.listeners.sets.into_iter().enumerate() {
+            let worker_handler = self.event_handler.duplicate();
+            let shutdown = self.shutdown.clone();
+            let shutdown_timeout = self.shutdown_timeout;
+            let loads = self.loads.clone();
+            let accept_errors = self.accept_errors.clone();
+            threads.push(thread::spawn(move || {
+                Self::poll_events(worker,
+                                  listeners,
+                                  worker_handler,
+                                  shutdown,
+                                  shutdown_timeout,
+                                  loads,
+                                  accept_errors);
+            }));
+        }
+        for t in threads {
+            let _ = t.join();
+        }
+        info!("Server stopped");
+    }
+
+    // The loop of a worker in the `ReusePort` mode: it accepts, polls and
+    // handles its own connections.
+    fn poll_events(worker: usize,
+                   listeners: Listeners,
+                   mut event_handler: Box<EventHandler>,
+                   shutdown: ShutdownHandle,
+                   shutdown_timeout: Duration,
+                   loads: WorkerLoads,
+                   accept_errors: Counter) {
+        let poll = Poll::new().unwrap();
+        listeners.register(&poll);
+        let _shutdown_registration = shutdown.register(&poll);
+        let (notify_registration, notify_readiness) = Registration::new2();
+        poll.register(&notify_registration, NOTIFY, Ready::readable(), PollOpt::edge()).unwrap();
+        let notified = Arc::new(Mutex::new(Vec::new()));
+        event_handler.set_notifier(Notifier {
+            wakeup: Arc::new(Wakeup::Poll(notified.clone(), notify_readiness.clone())),
+        });
+        let mut events = Events::with_capacity(1024);
+        let mut timers = Timers::new();
+        let mut accept_backoff = AcceptBackoff::new(accept_errors);
+        let mut next_seq = 0;
+        let mut deadline = None;
+        let mut shutdown_requested = shutdown.is_shutdown();
+        loop {
+            let before = event_handler.num_conns();
+            let mut added = 0;
+            if shutdown_requested && deadline.is_none() {
+                info!("Worker {} shutting down, waiting up to {:?} for in-flight requests",
+                      worker,
+                      shutdown_timeout);
+                listeners.deregister(&poll);
+                event_handler.shutdown();
+                deadline = Some(Instant::now() + shutdown_timeout);
+            }
+            if let Some(deadline) = deadline {
+                if event_handler.num_conns() == 0 || Instant::now() >= deadline {
+                    warn!("Dropping {} connections", event_handler.num_conns());
+                    loads.closed(worker, before);
+                    return;
+                }
+            }
+            let timeout = [timers.next_timeout(),
+                           accept_backoff.timeout(),
+                           deadline.map(|d| d.saturating_duration_since(Instant::now()))]
+                              .iter()
+                              .filter_map(|t| *t)
+                              .min();
+            match poll.poll(&mut events, timeout) {
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => panic!("Error during poll(): {}", e),
+                Ok(_) => {}
+            }
+            {
+                let mut accept = |listener: usize,
+                                  backoff: &mut AcceptBackoff,
+                                  event_handler: &mut Box<EventHandler>,
+                                  timers: &mut Timers| {
+                    Self::accept_conns(&listeners.sockets[listener], backoff, |stream| {
+                        next_seq += 1;
+                        let id = next_seq * loads.len() + worker;
+                        if register_conn(&poll, &stream, id) {
+                            loads.assigned(worker);
+                            added += 1;
+                            event_handler.new_conn(id, listener, stream, timers);
                         }
+                    });
+                };
+                if deadline.is_none() && accept_backoff.expired() {
+                    for i in 0..listeners.sockets.len() {
+                        accept(i, &mut accept_backoff, &mut event_handler, &mut timers);
                     }
-                    Token(id) => {
-                        println!("Sending event on conn {} to worker {}",
-                                 id,
-                                 id % self.num_workers);
-                        workers[id % self.num_workers]
-                            .send(Msg::ConnEvent(id, event.kind()))
-                            .unwrap();
+                }
+                for event in events.iter() {
+                    match event.token() {
+                        SHUTDOWN => {
+                            shutdown_requested = true;
+                        }
+                        NOTIFY => {
+                            // Cleared first so that a notification arriving
+                            // from now on triggers another event.
+                            let _ = notify_readiness.set_readiness(Ready::empty());
+                            let ids = mem::take(&mut *notified.lock().unwrap());
+                            for id in ids {
+                                loads.handled(worker);
+                                event_handler.notify(id, &mut timers);
+                            }
+                        }
+                        token => {
+                            match listeners.index_of(token) {
+                                Some(i) => {
+                                    if deadline.is_none() && !accept_backoff.is_active() {
+                                        accept(i, &mut accept_backoff, &mut event_handler, &mut timers);
+                                    }
+                                }
+                                None => {
+                                    let Token(id) = token;
+                                    loads.handled(worker);
+                                    event_handler.conn_event(id, event.readiness(), &mut timers);
+                                }
+                            }
+                        }
                     }
                 }
             }
+            for (id, token) in timers.expired() {
+                event_handler.timeout(id, token, &mut timers);
+            }
+            loads.closed(worker, before + added - event_handler.num_conns());
+        }
+    }
+
+    fn dispatch_conn(poll: &Poll, workers: &mut Workers, listener: usize, stream: TcpStream) {
+        let id = workers.next_conn_id();
+        if !register_conn(poll, &stream, id) {
+            return;
+        }
+        let worker = workers.worker_of(id);
+        debug!("New connection on worker {} ", worker);
+        workers.loads.assigned(worker);
+        workers.send(worker, Msg::NewConn(id, listener, stream));
+    }
+
+    // Accepts connections until the listener would block. The listener is
+    // registered edge-triggered, so anything left in the backlog would
+    // otherwise wait for the next incoming connection.
+    fn accept_conns<F>(server: &TcpListener, backoff: &mut AcceptBackoff, mut new_conn: F)
+        where F: FnMut(TcpStream)
+    {
+        loop {
+            trace!("Accepting..");
+            match server.accept() {
+                Ok((stream, _)) => {
+                    backoff.reset();
+                    new_conn(stream);
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return,
+                Err(ref e) if is_fd_exhausted(e) => {
+                    backoff.errors.inc();
+                    backoff.start();
+                    error!("Out of file descriptors, pausing accept() for {:?}",
+                           backoff.delay);
+                    return;
+                }
+                Err(e) => {
+                    backoff.errors.inc();
+                    // Errors like ECONNABORTED only concern the connection
+                    // being accepted, keep going with the rest of the backlog.
+                    error!("Error during accept(): {}", e);
+                }
+            }
+        }
+    }
+}
+
+fn register_conn(poll: &Poll, stream: &TcpStream, id: usize) -> bool {
+    trace!("Registering new connection...");
+    match poll.register(stream,
+                        Token(id),
+                        Ready::readable() | Ready::writable(),
+                        PollOpt::edge()) {
+        Ok(_) => true,
+        Err(e) => {
+            error!("Error during register(): {}", e);
+            false
+        }
+    }
+}
+
+// Binds `host` with `bind`, the error saying which host failed.
+fn bind_host<F>(host: &str, bind: F) -> io::Result<TcpListener>
+    where F: FnOnce(&SocketAddr) -> io::Result<TcpListener>
+{
+    let addr = SocketAddr::from_str(host).map_err(|e| {
+        io::Error::new(ErrorKind::InvalidInput, format!("invalid address {}: {}", host, e))
+    })?;
+    bind(&addr).map_err(|e| io::Error::new(e.kind(), format!("can't listen on {}: {}", host, e)))
+}
+
+// Opens a listener that shares its port with the ones of the other workers.
+fn bind_reuse_port(addr: &SocketAddr) -> io::Result<TcpListener> {
+    let builder = match *addr {
+        SocketAddr::V4(_) => TcpBuilder::new_v4()?,
+        SocketAddr::V6(_) => TcpBuilder::new_v6()?,
+    };
+    builder.reuse_address(true)?;
+    builder.reuse_port(true)?;
+    builder.bind(addr)?;
+    let listener = builder.listen(1024)?;
+    TcpListener::from_std(listener)
+}
+
+fn is_fd_exhausted(e: &io::Error) -> bool {
+    matches!(e.raw_os_error(),
+             Some(libc::EMFILE) | Some(libc::ENFILE) | Some(libc::ENOBUFS) | Some(libc::ENOMEM))
+}
+
+const MIN_ACCEPT_BACKOFF_MS: u64 = 10;
+const MAX_ACCEPT_BACKOFF_MS: u64 = 1000;
+
+// Delays accept() retries while the process is out of file descriptors. The
+// delay doubles on each consecutive failure and resets on the first success.
+struct AcceptBackoff {
+    delay: Duration,
+    until: Option<Instant>,
+    failing: bool,
+    // All the accept() failures, for the metrics.
+    errors: Counter,
+}
+
+impl AcceptBackoff {
+    fn new(errors: Counter) -> AcceptBackoff {
+        return AcceptBackoff {
+            delay: Duration::from_millis(MIN_ACCEPT_BACKOFF_MS),
+            until: None,
+            failing: false,
+            errors: errors,
+        };
+    }
+
+    fn start(&mut self) {
+        if self.failing {
+            let doubled = self.delay * 2;
+            self.delay = cmp::min(doubled, Duration::from_millis(MAX_ACCEPT_BACKOFF_MS));
+        }
+        self.failing = true;
+        self.until = Some(Instant::now() + self.delay);
+    }
+
+    fn reset(&mut self) {
+        self.delay = Duration::from_millis(MIN_ACCEPT_BACKOFF_MS);
+        self.until = None;
+        self.failing = false;
+    }
+
+    fn is_active(&self) -> bool {
+        self.until.is_some()
+    }
+
+    // Returns true once, when a pending backoff has run out.
+    fn expired(&mut self) -> bool {
+        match self.until {
+            Some(until) if Instant::now() >= until => {
+                self.until = None;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn timeout(&self) -> Option<Duration> {
+        match self.until {
+            None => None,
+            Some(until) => {
+                let now = Instant::now();
+                if until > now {
+                    Some(until - now)
+                } else {
+                    Some(Duration::from_millis(0))
+                }
+            }
         }
     }
 }
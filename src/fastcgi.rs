@@ -0,0 +1,405 @@
+use std::io::prelude::*;
+use std::io::{self, BufReader, ErrorKind};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use cgi::{self, Script};
+use handler_lib::Handler;
+use http::{Request, Response};
+use proxy::{self, Error, Stream, Upstream};
+
+// Record types.
+const BEGIN_REQUEST: u8 = 1;
+const END_REQUEST: u8 = 3;
+const PARAMS: u8 = 4;
+const STDIN: u8 = 5;
+const STDOUT: u8 = 6;
+const STDERR: u8 = 7;
+const RESPONDER: u16 = 1;
+// Asks the application to keep the connection open after the request.
+const KEEP_CONN: u8 = 1;
+// Requests are not multiplexed, they all get the same id.
+const REQUEST_ID: u16 = 1;
+const MAX_CONTENT: usize = 65535;
+
+// Appends a record, padded to a multiple of 8 bytes.
+fn record(out: &mut Vec<u8>, kind: u8, content: &[u8]) {
+    let padding = (8 - content.len() % 8) % 8;
+    out.extend_from_slice(&[1, kind]);
+    out.extend_from_slice(&REQUEST_ID.to_be_bytes());
+    out.extend_from_slice(&(content.len() as u16).to_be_bytes());
+    out.extend_from_slice(&[padding as u8, 0]);
+    out.extend_from_slice(content);
+    out.extend_from_slice(&[0; 8][..padding]);
+}
+
+// Appends the records of a stream, ended by an empty one.
+fn stream_records(out: &mut Vec<u8>, kind: u8, data: &[u8]) {
+    for chunk in data.chunks(MAX_CONTENT) {
+        record(out, kind, chunk);
+    }
+    record(out, kind, &[]);
+}
+
+fn encode_length(out: &mut Vec<u8>, length: usize) {
+    if length < 128 {
+        out.push(length as u8);
+    } else {
+        out.extend_from_slice(&(length as u32 | 0x8000_0000).to_be_bytes());
+    }
+}
+
+fn encode_params(params: &[(String, String)]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for &(ref name, ref value) in params {
+        encode_length(&mut out, name.len());
+        encode_length(&mut out, value.len());
+        out.extend_from_slice(name.as_bytes());
+        out.extend_from_slice(value.as_bytes());
+    }
+    out
+}
+
+// Reads the records the application sends back for a request, as the data
+// of its STDOUT stream. The end of the data is the end of the request.
+struct Records<'a, R: 'a + Read> {
+    stream: &'a mut R,
+    // What is left of the STDOUT record being read, and its padding.
+    remaining: usize,
+    padding: usize,
+    ended: bool,
+    // The protocol status of the END_REQUEST record, 0 if the request was
+    // completed.
+    status: u8,
+}
+
+impl<'a, R: Read> Records<'a, R> {
+    fn new(stream: &'a mut R) -> Records<'a, R> {
+        return Records {
+            stream: stream,
+            remaining: 0,
+            padding: 0,
+            ended: false,
+            status: 0,
+        };
+    }
+
+    fn skip(&mut self, length: usize) -> io::Result<()> {
+        let copied = io::copy(&mut Read::by_ref(self.stream).take(length as u64), &mut io::sink())?;
+        if copied < length as u64 {
+            return Err(io::Error::new(ErrorKind::UnexpectedEof, "connection closed"));
+        }
+        Ok(())
+    }
+}
+
+impl<'a, R: Read> Read for Records<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.ended {
+                return Ok(0);
+            }
+            if self.remaining > 0 {
+                let max = self.remaining.min(buf.len());
+                let read = self.stream.read(&mut buf[..max])?;
+                if read == 0 {
+                    return Err(io::Error::new(ErrorKind::UnexpectedEof, "connection closed"));
+                }
+                self.remaining -= read;
+                return Ok(read);
+            }
+            let padding = self.padding;
+            self.skip(padding)?;
+            self.padding = 0;
+            let mut header = [0; 8];
+            self.stream.read_exact(&mut header)?;
+            let id = u16::from_be_bytes([header[2], header[3]]);
+            let length = u16::from_be_bytes([header[4], header[5]]) as usize;
+            self.padding = header[6] as usize;
+            match header[1] {
+                STDOUT if id == REQUEST_ID => self.remaining = length,
+                STDERR if id == REQUEST_ID => {
+                    let mut message = vec![0; length];
+                    self.stream.read_exact(&mut message)?;
+                    warn!("FastCGI error: {}", String::from_utf8_lossy(&message).trim_end());
+                }
+                END_REQUEST if id == REQUEST_ID && length >= 8 => {
+                    let mut body = vec![0; length];
+                    self.stream.read_exact(&mut body)?;
+                    let padding = self.padding;
+                    self.skip(padding)?;
+                    self.status = body[4];
+                    self.ended = true;
+                }
+                _ => self.skip(length)?,
+            }
+        }
+    }
+}
+
+/// Sends requests to a FastCGI application, like php-fpm, over TCP or a Unix
+/// socket, and sends back its responses. The scripts are given with their
+/// path under the root directory, found from the URI as `CgiHandler` does.
+/// Connections to the application are kept alive and shared by the copies of
+/// the handler.
+///
+/// It blocks while waiting for the application, so it should run on the
+/// blocking pool.
+pub struct FastCgiHandler {
+    upstream: Arc<Upstream>,
+    root: PathBuf,
+    index: Option<String>,
+    env: Vec<(String, String)>,
+    connect_timeout: Duration,
+    read_timeout: Duration,
+    max_idle: usize,
+}
+
+impl FastCgiHandler {
+    // `address` is either "host:port" or "unix:/path/to/socket", `root` is
+    // where the application finds the scripts.
+    pub fn new(address: &str, root: &str) -> FastCgiHandler {
+        return FastCgiHandler {
+            upstream: Arc::new(Upstream::new(address)),
+            root: PathBuf::from(root),
+            index: None,
+            env: Vec::new(),
+            connect_timeout: Duration::from_secs(5),
+            read_timeout: Duration::from_secs(60),
+            max_idle: 16,
+        };
+    }
+
+    // The script run for URIs naming a directory, like "index.php".
+    pub fn set_index(&mut self, index: &str) {
+        self.index = Some(index.to_string());
+    }
+
+    // A parameter given with every request.
+    pub fn set_env(&mut self, name: &str, value: &str) {
+        self.env.push((name.to_string(), value.to_string()));
+    }
+
+    // How long to wait for a connection to the application, and for each
+    // read or write on it. The client gets a 504 when they run out.
+    pub fn set_timeouts(&mut self, connect: Duration, read: Duration) {
+        self.connect_timeout = connect;
+        self.read_timeout = read;
+    }
+
+    // How many idle connections are kept for reuse.
+    pub fn set_max_idle(&mut self, max_idle: usize) {
+        self.max_idle = max_idle;
+    }
+
+    fn request(&self, script: &Script, req: &Request) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut begin = RESPONDER.to_be_bytes().to_vec();
+        begin.extend_from_slice(&[KEEP_CONN, 0, 0, 0, 0, 0]);
+        record(&mut out, BEGIN_REQUEST, &begin);
+        let mut params = cgi::environment(req, script, &self.root);
+        params.extend(self.env.iter().cloned());
+        stream_records(&mut out, PARAMS, &encode_params(&params));
+        stream_records(&mut out, STDIN, req.body());
+        out
+    }
+
+    fn send(&self, request: &[u8], req: &Request, resp: &mut Response) -> Result<(), Error> {
+        loop {
+            let (mut stream, reused) = self.upstream.connect(self.connect_timeout,
+                                                             self.read_timeout)?;
+            match self.forward(&mut stream, reused, request, req, resp) {
+                Ok(keep_alive) => {
+                    if keep_alive {
+                        self.upstream.release(stream, self.max_idle);
+                    }
+                    return Ok(());
+                }
+                Err(Error::Stale) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // Sends the request on `stream` and the application's response to the
+    // client. Returns whether the connection can be reused.
+    fn forward(&self,
+               stream: &mut Stream,
+               reused: bool,
+               request: &[u8],
+               req: &Request,
+               resp: &mut Response)
+               -> Result<bool, Error> {
+        match stream.write_all(request) {
+            Err(_) if reused => return Err(Error::Stale),
+            Err(e) => return Err(Error::from(e)),
+            Ok(()) => {}
+        }
+        let mut reader = BufReader::new(Records::new(stream));
+        match cgi::send_response(&mut reader, req, resp) {
+            Err(Error::Unavailable(_)) if reader.get_ref().ended => {
+                let status = reader.get_ref().status;
+                return Err(Error::Invalid(format!("request rejected with status {}", status)));
+            }
+            // An idle connection the application closed, requests that are
+            // safe to repeat are sent again.
+            Err(Error::Unavailable(_)) if reused && proxy::is_idempotent(req) => {
+                return Err(Error::Stale)
+            }
+            result => result?,
+        }
+        // Whatever follows a body of known length is dropped.
+        if io::copy(&mut reader, &mut io::sink()).is_err() || !reader.buffer().is_empty() {
+            return Ok(false);
+        }
+        let records = reader.get_ref();
+        Ok(records.ended && records.status == 0)
+    }
+}
+
+impl Handler for FastCgiHandler {
+    fn process(&mut self, req: Request, resp: &mut Response) {
+        let index = self.index.as_deref();
+        let script = match Script::find(&self.root, &req.uri, index) {
+            Some(script) => script,
+            None => {
+                resp.set_bad_request().send();
+                return;
+            }
+        };
+        // The application may see other files than this server, a script
+        // that isn't found here is left for it to find.
+        let request = self.request(&script, &req);
+        match self.send(&request, &req, resp) {
+            Ok(()) => {}
+            Err(e) => {
+                let address = self.upstream.address();
+                match e {
+                    Error::Connect(ref e) | Error::Unavailable(ref e) => {
+                        error!("FastCGI application {} unavailable: {}", address, e);
+                    }
+                    Error::Timeout => error!("FastCGI application {} timed out", address),
+                    Error::Invalid(ref e) => {
+                        error!("Invalid response from FastCGI application {}: {}", address, e);
+                    }
+                    Error::Stale | Error::Aborted => unreachable!(),
+                }
+                match e {
+                    Error::Timeout => resp.set_gateway_timeout().send(),
+                    Error::Connect(ref e) if e.kind() == ErrorKind::TimedOut => {
+                        resp.set_gateway_timeout().send()
+                    }
+                    _ => resp.set_bad_gateway().send(),
+                }
+            }
+        }
+    }
+    fn duplicate(&self) -> Box<Handler> {
+        return Box::new(FastCgiHandler {
+            upstream: self.upstream.clone(),
+            root: self.root.clone(),
+            index: self.index.clone(),
+            env: self.env.clone(),
+            connect_timeout: self.connect_timeout,
+            read_timeout: self.read_timeout,
+            max_idle: self.max_idle,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use super::*;
+
+    // Gives its data a byte at a time, so that every record is split across
+    // reads.
+    struct Trickle(Cursor<Vec<u8>>);
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let max = buf.len().min(1);
+            self.0.read(&mut buf[..max])
+        }
+    }
+
+    fn end_request(out: &mut Vec<u8>, protocol_status: u8) {
+        record(out, END_REQUEST, &[0, 0, 0, 0, protocol_status, 0, 0, 0]);
+    }
+
+    #[test]
+    fn params_lengths() {
+        let short = "n".repeat(127);
+        let long = "v".repeat(128);
+        let encoded = encode_params(&[(short.clone(), long.clone())]);
+        assert_eq!(encoded[0], 127);
+        assert_eq!(encoded[1..5], [0x80, 0, 0, 128]);
+        assert_eq!(&encoded[5..132], short.as_bytes());
+        assert_eq!(&encoded[132..], long.as_bytes());
+        let encoded = encode_params(&[("A".to_string(), "x".repeat(70000))]);
+        assert_eq!(encoded[..6], [1, 0x80, 0x01, 0x11, 0x70, b'A']);
+        assert_eq!(encoded.len(), 6 + 70000);
+    }
+
+    #[test]
+    fn records_are_padded() {
+        let mut out = Vec::new();
+        record(&mut out, STDIN, b"hello");
+        assert_eq!(out, b"\x01\x05\x00\x01\x00\x05\x03\x00hello\x00\x00\x00");
+        let mut out = Vec::new();
+        record(&mut out, STDIN, b"12345678");
+        assert_eq!(out.len(), 16);
+        assert_eq!(out[6], 0);
+
+        let mut out = Vec::new();
+        stream_records(&mut out, STDIN, &vec![b'x'; MAX_CONTENT + 3]);
+        // A full record needs a byte of padding, the last one five.
+        assert_eq!(out[4..7], [0xff, 0xff, 1]);
+        let second = 8 + MAX_CONTENT + 1;
+        assert_eq!(out[second + 4..second + 7], [0, 3, 5]);
+        let last = second + 16;
+        assert_eq!(out[last..], [1, STDIN, 0, 1, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn records_split_across_reads() {
+        let mut data = Vec::new();
+        record(&mut data, STDOUT, b"Status: 404 Not Found\r\n\r\nhel");
+        record(&mut data, STDERR, b"oops\n");
+        // Another request's record is skipped.
+        data.extend_from_slice(&[1, STDOUT, 0, 2, 0, 3, 5, 0, b'b', b'a', b'd', 0, 0, 0, 0, 0]);
+        record(&mut data, STDOUT, b"lo");
+        record(&mut data, STDOUT, b"");
+        end_request(&mut data, 0);
+        data.extend_from_slice(b"next");
+        let mut stream = Trickle(Cursor::new(data));
+        let mut records = Records::new(&mut stream);
+        let mut output = Vec::new();
+        records.read_to_end(&mut output).unwrap();
+        assert_eq!(output, b"Status: 404 Not Found\r\n\r\nhello");
+        assert!(records.ended);
+        assert_eq!(records.status, 0);
+        let mut rest = Vec::new();
+        stream.0.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, b"next");
+    }
+
+    #[test]
+    fn rejected_and_truncated_requests() {
+        // FCGI_CANT_MPX_CONN, without any output.
+        let mut data = Vec::new();
+        end_request(&mut data, 1);
+        let mut stream = Trickle(Cursor::new(data));
+        let mut records = Records::new(&mut stream);
+        assert_eq!(records.read(&mut [0; 16]).unwrap(), 0);
+        assert_eq!(records.status, 1);
+
+        let mut data = Vec::new();
+        record(&mut data, STDOUT, b"hello");
+        data.truncate(10);
+        let mut stream = Trickle(Cursor::new(data));
+        let mut output = Vec::new();
+        let e = Records::new(&mut stream).read_to_end(&mut output).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::UnexpectedEof);
+    }
+}
@@ -1,56 +1,959 @@
-use std::io::prelude::*;
-use mio::tcp::*;
+use std::collections::{HashMap, VecDeque};
+use std::mem;
+use std::sync::Arc;
+use std::sync::mpsc::*;
+use std::time::Duration;
 use http::*;
+use access_log::{AccessLog, Record};
+use http2::{self, Http2, StreamOutput};
 use app_server::*;
+use blocking::*;
+use connection::*;
+use compression::Compression;
+use metrics::HttpMetrics;
+use status::ConnStatus;
+use rewrite::{self, Outcome, RewriteRule};
+use sse::{EventStream, SseHandler};
+use virtual_host::{self, HostName, VirtualHost};
+use websocket::{Handshake, WebSocketHandler, WsConn};
 pub use regex::Regex;
 
 pub trait Handler : Send + 'static {
     fn process(&mut self, request: Request, response: &mut Response);
+    // Called when a timer set with `Response::set_timer()` fires.
+    fn timeout(&mut self, _token: usize, _response: &mut Response) {}
+    // Whether requests are handed over as soon as their head arrives, with
+    // `Request::take_body_reader()` to read the body as it comes. Only
+    // handlers on the blocking pool get them.
+    fn streams_body(&self) -> bool {
+        false
+    }
     fn duplicate(&self) -> Box<Handler>;
 }
 
-struct HandlerRule(Regex, Box<Handler>);
+// Seconds a client is asked to wait when the blocking pool is full.
+const RETRY_AFTER_SECS: u64 = 1;
+// How many bytes of HTTP/2 frames are queued at once. More are framed as
+// the connection drains, so the streams share it.
+const HTTP2_WRITE_BUDGET: usize = 64 * 1024;
+
+// The handler is None while it runs on the blocking pool. Rules end with
+// the site they belong to, 0 for the default one.
+struct HandlerRule(Regex, Option<Box<Handler>>, usize);
 pub struct HandlerRoute(pub String, pub Box<Handler>);
+struct WebSocketRule(Regex, Box<WebSocketHandler>, usize);
+pub struct WebSocketRoute(pub String, pub Box<WebSocketHandler>);
+struct SseRule(Regex, Box<SseHandler>, usize);
+pub struct SseRoute(pub String, pub Box<SseHandler>);
+
+// A handler running on the pool: its index, the HTTP/2 stream of the
+// request, and where what it sends comes back.
+struct InFlight {
+    idx: usize,
+    stream: Option<u32>,
+    done: Receiver<Done>,
+    flushed: FlushedOutput,
+}
+
+enum Protocol {
+    // Nothing but part of the HTTP/2 preface received yet.
+    Unknown(Vec<u8>),
+    Http1,
+    Http2(Box<Http2>),
+    // Upgraded by a request on a WebSocket route.
+    WebSocket(Box<WsConn>),
+}
 
 pub struct HandlerApp {
     handlers: Vec<HandlerRule>,
+    ws_handlers: Vec<WebSocketRule>,
+    sse_handlers: Vec<SseRule>,
+    // The names of the virtual hosts, with their site.
+    hosts: Vec<(HostName, usize)>,
+    num_sites: usize,
+    // The rewrite rules of each site.
+    rewrite_rules: Vec<Vec<RewriteRule>>,
+    compression: Option<Arc<Compression>>,
+    access_log: Option<Arc<AccessLog>>,
+    metrics: Option<Arc<HttpMetrics>>,
+    // The records of the requests not answered yet, for the access log and
+    // the metrics, by HTTP/2 stream, None for HTTP/1.
+    records: HashMap<Option<u32>, Record>,
+    protocol: Protocol,
     builder: RequestBuilder,
+    limits: Limits,
+    // Pending handler timers, by connection timer token: the index of the
+    // handler that set it, the handler's own token and the HTTP/2 stream.
+    timers: HashMap<usize, (usize, usize, Option<u32>)>,
+    next_timer: usize,
+    pool: Option<BlockingPool>,
+    // Requests received but not processed yet, with their HTTP/2 stream, and
+    // the handler running on the pool. Responses go out in the order of the
+    // requests, so nothing else is processed until it is done.
+    requests: VecDeque<(Option<u32>, Request)>,
+    in_flight: Option<InFlight>,
+    // Open event streams by HTTP/2 stream, None for the HTTP/1 one, and the
+    // streams of the pending heartbeat timers, by connection timer token.
+    event_streams: HashMap<Option<u32>, EventStream>,
+    heartbeats: HashMap<usize, Option<u32>>,
+    // The last request processed and the number so far, for the status
+    // page.
+    last_request: Option<String>,
+    num_requests: u64,
 }
 impl HandlerApp {
     pub fn new(handler_defs: Vec<HandlerRoute>) -> HandlerApp {
         let mut handlers = Vec::new();
         for &HandlerRoute(ref s, ref h) in &handler_defs {
-            handlers.push(HandlerRule(Regex::new(&s).unwrap(), h.duplicate()));
+            handlers.push(HandlerRule(Regex::new(s).unwrap(), Some(h.duplicate()), 0));
         }
+        return HandlerApp::with_rules(handlers, None);
+    }
+
+    fn with_rules(handlers: Vec<HandlerRule>, pool: Option<BlockingPool>) -> HandlerApp {
         return HandlerApp {
             handlers: handlers,
+            ws_handlers: Vec::new(),
+            sse_handlers: Vec::new(),
+            hosts: Vec::new(),
+            num_sites: 1,
+            rewrite_rules: vec![Vec::new()],
+            compression: None,
+            access_log: None,
+            metrics: None,
+            records: HashMap::new(),
+            protocol: Protocol::Unknown(Vec::new()),
             builder: RequestBuilder::new(),
+            limits: Limits::new(),
+            timers: HashMap::new(),
+            next_timer: 0,
+            pool: pool,
+            requests: VecDeque::new(),
+            in_flight: None,
+            event_streams: HashMap::new(),
+            heartbeats: HashMap::new(),
+            last_request: None,
+            num_requests: 0,
+        };
+    }
+
+    // Runs the handlers on `pool` instead of the event threads.
+    pub(crate) fn set_blocking_pool(&mut self, pool: BlockingPool) {
+        self.pool = Some(pool);
+    }
+
+    // Answers the requests over `limits` with a 413 or a 431.
+    pub(crate) fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+        self.builder = RequestBuilder::with_limits(limits);
+    }
+
+    // Requests matching one of `routes` are upgraded to WebSocket
+    // connections. They are checked before the handlers.
+    pub(crate) fn set_websocket_routes(&mut self, routes: Vec<WebSocketRoute>) {
+        for WebSocketRoute(s, h) in routes {
+            self.ws_handlers.push(WebSocketRule(Regex::new(&s).unwrap(), h, 0));
+        }
+    }
+
+    // Requests matching one of `routes` get a Server-Sent Events stream.
+    pub(crate) fn set_sse_routes(&mut self, routes: Vec<SseRoute>) {
+        for SseRoute(s, h) in routes {
+            self.sse_handlers.push(SseRule(Regex::new(&s).unwrap(), h, 0));
+        }
+    }
+
+    // Compresses the responses of the handlers.
+    pub(crate) fn set_compression(&mut self, compression: Compression) {
+        self.compression = Some(Arc::new(compression));
+    }
+
+    pub(crate) fn set_access_log(&mut self, access_log: AccessLog) {
+        self.access_log = Some(Arc::new(access_log));
+    }
+
+    // Counts the requests and their latencies by route.
+    pub(crate) fn set_metrics(&mut self, metrics: Arc<HttpMetrics>) {
+        self.metrics = Some(metrics);
+    }
+
+    // Rewrites the requests of the default site before they are routed.
+    pub(crate) fn set_rewrite_rules(&mut self, rules: Vec<RewriteRule>) {
+        self.rewrite_rules[0] = rules;
+    }
+
+    // Requests to the names of `host` get its routes rather than the
+    // default ones.
+    pub(crate) fn add_virtual_host(&mut self, host: VirtualHost) {
+        let site = self.num_sites;
+        self.num_sites += 1;
+        for name in host.names {
+            self.hosts.push((name, site));
+        }
+        self.rewrite_rules.push(host.rewrite_rules);
+        for HandlerRoute(s, h) in host.handlers {
+            self.handlers.push(HandlerRule(Regex::new(&s).unwrap(), Some(h), site));
+        }
+        for WebSocketRoute(s, h) in host.ws_handlers {
+            self.ws_handlers.push(WebSocketRule(Regex::new(&s).unwrap(), h, site));
+        }
+        for SseRoute(s, h) in host.sse_handlers {
+            self.sse_handlers.push(SseRule(Regex::new(&s).unwrap(), h, site));
+        }
+    }
+
+    // The site `r` goes to, from its Host header or else the name the client
+    // gave with SNI. None if they name different sites: a client can't use
+    // a connection made for one site, and its certificate, for another.
+    fn site(&self, r: &Request) -> Option<usize> {
+        let sni = r.tls().and_then(|tls| tls.server_name.as_deref());
+        let host = match r.header("Host").or(sni) {
+            Some(host) => host,
+            None => return Some(0),
+        };
+        let site = virtual_host::find_site(&self.hosts, host);
+        match sni {
+            Some(sni) if virtual_host::find_site(&self.hosts, sni) != site => None,
+            _ => Some(site),
+        }
+    }
+
+    // Picks HTTP/2 if it was negotiated with ALPN or if the client starts
+    // with the HTTP/2 preface, HTTP/1 otherwise. Returns the data received
+    // so far once the protocol is known.
+    fn detect_protocol(&mut self, data: Vec<u8>, conn: &mut Connection) -> Option<Vec<u8>> {
+        let mut received = match self.protocol {
+            Protocol::Unknown(ref mut received) => mem::take(received),
+            _ => return Some(data),
+        };
+        received.extend_from_slice(&data);
+        let alpn_h2 = conn.tls_info()
+                          .and_then(|tls| tls.alpn_protocol)
+                          .is_some_and(|p| p == "h2");
+        if alpn_h2 || received.starts_with(http2::PREFACE) {
+            self.protocol = Protocol::Http2(Box::new(Http2::new(self.limits, conn.output())));
+        } else if http2::PREFACE.starts_with(&received) {
+            self.protocol = Protocol::Unknown(received);
+            return None;
+        } else {
+            self.protocol = Protocol::Http1;
+        }
+        Some(received)
+    }
+
+    // Switches to HTTP/2 if the request asks for it with `Upgrade: h2c`. The
+    // request is then answered on stream 1.
+    fn upgrade(&mut self, r: &Request, conn: &mut Connection) -> bool {
+        if r.tls().is_some() {
+            return false;
+        }
+        let upgrade = r.header("Upgrade").is_some_and(|u| u.split(',').any(|p| p.trim() == "h2c"));
+        let settings = r.header("HTTP2-Settings").and_then(http2::decode_settings);
+        let settings = match settings {
+            Some(ref settings) if upgrade => settings,
+            _ => return false,
+        };
+        let mut out = Output::new();
+        out.write(b"HTTP/1.1 101 Switching Protocols\r\n\
+                    Connection: Upgrade\r\n\
+                    Upgrade: h2c\r\n\r\n");
+        let mut http2 = match Http2::upgrade(settings, self.limits, &mut out) {
+            Some(http2) => http2,
+            None => return false,
+        };
+        conn.output().append(out);
+        // Requests can't follow an upgrade request on HTTP/1.
+        self.requests.clear();
+        let remaining = self.builder.take_remaining();
+        for (id, r) in http2.read(&remaining, conn.output()) {
+            self.requests.push_back((Some(id), r));
+        }
+        self.protocol = Protocol::Http2(Box::new(http2));
+        true
+    }
+
+    // Whether the handler of `r` takes its body as it arrives.
+    fn streams_body(&self, r: &Request) -> bool {
+        if self.pool.is_none() || r.header("Upgrade").is_some() {
+            return false;
+        }
+        let site = match self.site(r) {
+            Some(site) => site,
+            None => return false,
+        };
+        let mut r = r.clone();
+        if let Outcome::Route = rewrite::apply(&self.rewrite_rules[site], &mut r) {
+        } else {
+            return false;
+        }
+        let ws_matched = self.ws_handlers.iter().any(|&WebSocketRule(ref regex, _, s)| {
+            s == site && regex.is_match(&r.uri)
+        });
+        let sse_matched = self.sse_handlers.iter().any(|&SseRule(ref regex, _, s)| {
+            s == site && regex.is_match(&r.uri)
+        });
+        if ws_matched || sse_matched {
+            return false;
+        }
+        let handler = self.handlers.iter().find(|&&HandlerRule(ref regex, _, s)| {
+            s == site && regex.is_match(&r.uri)
+        });
+        match handler {
+            Some(&HandlerRule(_, Some(ref handler), _)) => handler.streams_body(),
+            _ => false,
+        }
+    }
+
+    // Queues the request whose body is being received if its handler takes
+    // the body as it arrives.
+    fn stream_request(&mut self, conn: &mut Connection) {
+        let r = match self.protocol {
+            Protocol::Http1 => self.builder.head().cloned(),
+            Protocol::Http2(ref http2) => http2.receiving().and_then(|id| http2.head(id)),
+            _ => None,
+        };
+        match r {
+            Some(ref r) if self.streams_body(r) => {}
+            _ => return,
+        }
+        let (stream, r) = match self.protocol {
+            Protocol::Http1 => (None, Some(self.builder.stream_body())),
+            Protocol::Http2(ref mut http2) => {
+                let id = http2.receiving().unwrap();
+                (Some(id), http2.stream_body(id))
+            }
+            _ => return,
+        };
+        if let Some(mut r) = r {
+            r.set_tls(conn.tls_info());
+            r.set_peer_addr(conn.peer_addr());
+            r.set_local_addr(conn.local_addr());
+            r.set_listener_addr(conn.listener_addr());
+            self.requests.push_back((stream, r));
+        }
+    }
+
+    fn process_pending(&mut self, conn: &mut Connection) {
+        while self.in_flight.is_none() {
+            if self.requests.is_empty() {
+                self.stream_request(conn);
+            }
+            match self.requests.pop_front() {
+                Some((None, r)) => {
+                    if self.upgrade(&r, conn) {
+                        self.process(Some(1), r, conn);
+                    } else {
+                        self.process(None, r, conn);
+                    }
+                }
+                Some((stream, r)) => self.process(stream, r, conn),
+                None => break,
+            }
+        }
+        if let Protocol::Http1 = self.protocol {
+            let error = self.builder.error_code();
+            if self.in_flight.is_none() && error.is_some() && self.builder.is_streamed() {
+                // The handler had the response, there is nothing to add.
+                conn.close();
+            } else if self.in_flight.is_none() && error.is_some() && !conn.is_closing() {
+                let resp = &mut Response::new(conn);
+                resp.close();
+                match error {
+                    Some(413) => resp.set_content_too_large(),
+                    Some(431) => resp.set_header_fields_too_large(),
+                    Some(501) => resp.set_not_implemented(),
+                    _ => resp.set_bad_request(),
+                }.send();
+            }
+        }
+    }
+
+    fn process(&mut self, stream: Option<u32>, mut r: Request, conn: &mut Connection) {
+        let head = r.method() == "HEAD";
+        self.last_request = Some(format!("{} {}", r.method(), r.uri));
+        self.num_requests += 1;
+        if self.access_log.is_some() || self.metrics.is_some() {
+            let record = Record::new(self.access_log.as_ref(), self.metrics.as_ref(), &r);
+            self.records.insert(stream, record);
+        }
+        let site = match self.site(&r) {
+            Some(site) => site,
+            None => {
+                info!("Misdirected request for {}", r.header("Host").unwrap_or(""));
+                respond(&mut self.protocol, &mut self.records, stream, conn, |resp| {
+                    if head {
+                        resp.omit_body();
+                    }
+                    resp.set_misdirected_request().send()
+                });
+                return;
+            }
+        };
+        match rewrite::apply(&self.rewrite_rules[site], &mut r) {
+            Outcome::Route => {}
+            outcome => {
+                respond(&mut self.protocol, &mut self.records, stream, conn, |resp| {
+                    if head {
+                        resp.omit_body();
+                    }
+                    outcome.respond(resp)
+                });
+                return;
+            }
+        }
+        let ws_matched = self.ws_handlers.iter().position(|&WebSocketRule(ref regex, _, s)| {
+            s == site && regex.is_match(&r.uri)
+        });
+        if let Some(idx) = ws_matched {
+            set_route(&mut self.records, stream, &self.ws_handlers[idx].0);
+            self.open_websocket(idx, stream, r, conn);
+            return;
+        }
+        let sse_matched = self.sse_handlers.iter().position(|&SseRule(ref regex, _, s)| {
+            s == site && regex.is_match(&r.uri)
+        });
+        if let Some(idx) = sse_matched {
+            set_route(&mut self.records, stream, &self.sse_handlers[idx].0);
+            self.open_event_stream(idx, stream, r, conn);
+            return;
+        }
+        let matched = self.handlers.iter().position(|&HandlerRule(ref regex, _, s)| {
+            s == site && regex.is_match(&r.uri)
+        });
+        match matched {
+            None => {
+                respond(&mut self.protocol, &mut self.records, stream, conn, |resp| {
+                    if head {
+                        resp.omit_body();
+                    }
+                    resp.set_not_found().send()
+                });
+            }
+            Some(idx) => {
+                set_route(&mut self.records, stream, &self.handlers[idx].0);
+                if self.pool.is_some() {
+                    self.process_blocking(idx, stream, r, conn);
+                    return;
+                }
+                let handler = self.handlers[idx].1.as_mut().unwrap();
+                let compression = &self.compression;
+                let timers = respond(&mut self.protocol, &mut self.records, stream, conn, |resp| {
+                    if head {
+                        resp.omit_body();
+                    }
+                    if let Some(ref compression) = *compression {
+                        resp.set_compression(compression, &r);
+                    }
+                    handler.process(r, resp)
+                });
+                self.set_timers(idx, stream, timers, conn);
+            }
+        }
+    }
+
+    // Hands the connection over to a WebSocket handler if `r` is a valid
+    // upgrade request. There is no upgrading an HTTP/2 stream.
+    fn open_websocket(&mut self,
+                      idx: usize,
+                      stream: Option<u32>,
+                      r: Request,
+                      conn: &mut Connection) {
+        if stream.is_some() {
+            respond(&mut self.protocol,
+                    &mut self.records,
+                    stream,
+                    conn,
+                    |resp| resp.set_bad_request().send());
+            return;
+        }
+        let record = self.records.remove(&None);
+        let handshake = match Handshake::check(&r) {
+            Ok(handshake) => handshake,
+            Err(e) => {
+                let resp = &mut Response::new(conn);
+                resp.set_access_log(record);
+                e.respond(resp);
+                return;
+            }
+        };
+        if let Some(record) = record {
+            record.write(101, 0);
+        }
+        let handler = self.ws_handlers[idx].1.duplicate();
+        let waker = conn.waker();
+        let mut ws = WsConn::open(handler, handshake, &r, conn.output(), waker);
+        // Nothing after the upgrade request is HTTP, and the timers of the
+        // HTTP handlers have no response to write to.
+        self.requests.clear();
+        self.timers.clear();
+        ws.read(&self.builder.take_remaining(), conn.output());
+        self.protocol = Protocol::WebSocket(Box::new(ws));
+        self.set_websocket_timers(conn);
+    }
+
+    fn set_websocket_timers(&mut self, conn: &mut Connection) {
+        if let Protocol::WebSocket(ref mut ws) = self.protocol {
+            for (delay, token) in ws.take_timers() {
+                let id = self.next_timer;
+                self.next_timer += 1;
+                ws.add_timer(id, token);
+                conn.set_timer(delay, id);
+            }
+        }
+    }
+
+    // Answers `r` with an event stream. On HTTP/1 it lasts as long as the
+    // connection, nothing else is read from it.
+    fn open_event_stream(&mut self,
+                         idx: usize,
+                         stream: Option<u32>,
+                         r: Request,
+                         conn: &mut Connection) {
+        let events = EventStream::open(&mut self.sse_handlers[idx].1, &r, conn.waker());
+        let headers = [("Content-Type", "text/event-stream"), ("Cache-Control", "no-cache")];
+        match (stream, &mut self.protocol) {
+            (Some(id), &mut Protocol::Http2(ref mut http2)) => {
+                match http2.stream(id) {
+                    Some(out) => {
+                        let resp = &mut Response::for_stream(out);
+                        resp.set_access_log(self.records.remove(&stream));
+                        resp.set_headers(&headers).send();
+                    }
+                    // Reset by the client in the meantime.
+                    None => {
+                        self.records.remove(&stream);
+                        return;
+                    }
+                }
+            }
+            _ => {
+                let resp = &mut Response::new(conn);
+                resp.set_access_log(self.records.remove(&stream));
+                resp.close();
+                resp.set_headers(&headers).send();
+                self.requests.clear();
+            }
+        }
+        let id = self.next_timer;
+        self.next_timer += 1;
+        self.heartbeats.insert(id, stream);
+        conn.set_timer(events.heartbeat_interval(), id);
+        self.event_streams.insert(stream, events);
+        self.write_events(conn);
+    }
+
+    // Sends what was queued on the event streams, and drops those that were
+    // closed or reset by the client, so that their senders know.
+    fn write_events(&mut self, conn: &mut Connection) {
+        let streams: Vec<Option<u32>> = self.event_streams.keys().cloned().collect();
+        for stream in streams {
+            let (events, closing) = self.event_streams.get_mut(&stream).unwrap().take();
+            let open = write_event_data(&mut self.protocol, stream, conn, &events, closing);
+            if closing || !open {
+                self.event_streams.remove(&stream);
+            }
+        }
+    }
+
+    fn heartbeat(&mut self, id: usize, stream: Option<u32>, conn: &mut Connection) {
+        let (comment, interval) = match self.event_streams.get_mut(&stream) {
+            Some(events) => (events.heartbeat(), events.heartbeat_interval()),
+            // Closed since.
+            None => return,
+        };
+        if write_event_data(&mut self.protocol, stream, conn, comment, false) {
+            self.heartbeats.insert(id, stream);
+            conn.set_timer(interval, id);
+        } else {
+            self.event_streams.remove(&stream);
+        }
+    }
+
+    fn process_blocking(&mut self,
+                        idx: usize,
+                        stream: Option<u32>,
+                        r: Request,
+                        conn: &mut Connection) {
+        let (done, result) = channel();
+        let (flusher, flushed) = Flusher::new(conn.waker());
+        let output = match stream {
+            None => JobOutput::Http1(Output::new()),
+            Some(_) => JobOutput::Http2(StreamOutput::new()),
+        };
+        let job = Job {
+            handler: self.handlers[idx].1.take().unwrap(),
+            request: r,
+            compression: self.compression.clone(),
+            access_log: self.records.remove(&stream),
+            output: output,
+            done: done,
+            flusher: flusher,
+            waker: conn.waker(),
         };
+        match self.pool.as_ref().unwrap().try_run(job) {
+            None => {
+                self.in_flight = Some(InFlight {
+                    idx: idx,
+                    stream: stream,
+                    done: result,
+                    flushed: flushed,
+                });
+            }
+            Some(job) => {
+                warn!("Blocking pool full, rejecting request");
+                self.handlers[idx].1 = Some(job.handler);
+                if let Some(record) = job.access_log {
+                    self.records.insert(stream, record);
+                }
+                respond(&mut self.protocol,
+                        &mut self.records,
+                        stream,
+                        conn,
+                        |resp| resp.set_service_unavailable(RETRY_AFTER_SECS).send());
+            }
+        }
+    }
+
+    fn set_timers(&mut self,
+                  idx: usize,
+                  stream: Option<u32>,
+                  timers: Vec<(Duration, usize)>,
+                  conn: &mut Connection) {
+        for (delay, token) in timers {
+            let id = self.next_timer;
+            self.next_timer += 1;
+            self.timers.insert(id, (idx, token, stream));
+            conn.set_timer(delay, id);
+        }
+    }
+}
+
+// Labels the record of the request of `stream` with the route `regex` of
+// its handler, without the anchors the routes were given.
+fn set_route(records: &mut HashMap<Option<u32>, Record>, stream: Option<u32>, regex: &Regex) {
+    if let Some(record) = records.get_mut(&stream) {
+        let pattern = regex.as_str();
+        let route = pattern.strip_prefix('^').and_then(|r| r.strip_suffix('$'));
+        record.set_route(route.unwrap_or(pattern));
+    }
+}
+
+// Runs `f` with the response to the request of `stream`, or to the HTTP/1
+// request if None. Returns the timers set on the response. The record of
+// the request is kept in `records` until it is answered.
+fn respond<F>(protocol: &mut Protocol,
+              records: &mut HashMap<Option<u32>, Record>,
+              stream: Option<u32>,
+              conn: &mut Connection,
+              f: F)
+              -> Vec<(Duration, usize)>
+    where F: FnOnce(&mut Response)
+{
+    let out = match (stream, protocol) {
+        (Some(id), &mut Protocol::Http2(ref mut http2)) => {
+            match http2.stream(id) {
+                Some(out) => out,
+                // Reset by the client in the meantime.
+                None => {
+                    records.remove(&stream);
+                    return Vec::new();
+                }
+            }
+        }
+        _ => {
+            let resp = &mut Response::new(conn);
+            resp.set_access_log(records.remove(&stream));
+            f(resp);
+            if let Some(record) = resp.take_access_log() {
+                records.insert(stream, record);
+            }
+            return resp.take_timers();
+        }
+    };
+    let timers = {
+        let resp = &mut Response::for_stream(out);
+        resp.set_access_log(records.remove(&stream));
+        f(resp);
+        if let Some(record) = resp.take_access_log() {
+            records.insert(stream, record);
+        }
+        resp.take_timers()
+    };
+    out.finish();
+    timers
+}
+
+// Adds what a handler on the pool wrote to the response to the request of
+// `stream`, and ends it there if `done`. Returns false if the client reset
+// the stream.
+fn append_output(protocol: &mut Protocol,
+                 stream: Option<u32>,
+                 conn: &mut Connection,
+                 output: JobOutput,
+                 done: bool)
+                 -> bool {
+    match (output, protocol) {
+        (JobOutput::Http1(output), _) => conn.output().append(output),
+        (JobOutput::Http2(output), &mut Protocol::Http2(ref mut http2)) => {
+            match stream.and_then(|id| http2.stream(id)) {
+                Some(out) => {
+                    out.append(output);
+                    if done {
+                        out.finish();
+                    }
+                }
+                None => return false,
+            }
+        }
+        (JobOutput::Http2(_), _) => return false,
+    }
+    true
+}
+
+// Writes `data` on the event stream of `stream`, and ends it if `end`.
+// Returns false if the client reset the stream.
+fn write_event_data(protocol: &mut Protocol,
+                    stream: Option<u32>,
+                    conn: &mut Connection,
+                    data: &[u8],
+                    end: bool)
+                    -> bool {
+    match (stream, protocol) {
+        (Some(id), &mut Protocol::Http2(ref mut http2)) => {
+            match http2.stream(id) {
+                Some(out) => {
+                    out.data(data);
+                    if end {
+                        out.finish();
+                    }
+                    true
+                }
+                None => false,
+            }
+        }
+        // The connection was set to close when the stream started.
+        _ => {
+            conn.output().write(data);
+            true
+        }
     }
 }
+
 impl App for HandlerApp {
-    fn handle(&mut self, stream: &mut TcpStream) {
+    fn handle(&mut self, conn: &mut Connection) {
         let mut data = Vec::new();
-        let _ = stream.read_to_end(&mut data);
-        if let Some(r) = self.builder.read(&data) {
-            let resp = &mut Response::new(stream);
-            for &mut HandlerRule(ref regex, ref mut handler) in &mut self.handlers {
-                if regex.is_match(&r.uri) {
-                    handler.process(r, resp);
-                    return;
+        if let Err(e) = conn.read_available(&mut data) {
+            debug!("Error while reading: {}", e);
+        }
+        let data = match self.detect_protocol(data, conn) {
+            Some(data) => data,
+            None => return,
+        };
+        if let Protocol::WebSocket(ref mut ws) = self.protocol {
+            ws.read(&data, conn.output());
+            self.set_websocket_timers(conn);
+            return;
+        }
+        if self.event_streams.contains_key(&None) {
+            // The client has nothing more to say on an HTTP/1 event stream.
+            return;
+        }
+        let tls = conn.tls_info();
+        let peer_addr = conn.peer_addr();
+        let local_addr = conn.local_addr();
+        let listener_addr = conn.listener_addr();
+        if let Protocol::Http2(ref mut http2) = self.protocol {
+            for (id, mut r) in http2.read(&data, conn.output()) {
+                r.set_tls(tls.clone());
+                r.set_peer_addr(peer_addr);
+                r.set_local_addr(local_addr);
+                r.set_listener_addr(listener_addr);
+                self.requests.push_back((Some(id), r));
+            }
+        } else {
+            let mut next = self.builder.read(&data);
+            while let Some(mut r) = next {
+                r.set_tls(tls.clone());
+                r.set_peer_addr(peer_addr);
+                r.set_local_addr(local_addr);
+                r.set_listener_addr(listener_addr);
+                self.requests.push_back((None, r));
+                next = self.builder.read(&[]);
+            }
+        }
+        self.process_pending(conn);
+        self.write_events(conn);
+    }
+    fn timeout(&mut self, conn: &mut Connection, token: usize) {
+        if let Protocol::WebSocket(ref mut ws) = self.protocol {
+            ws.timeout(token, conn.output());
+            self.set_websocket_timers(conn);
+            return;
+        }
+        if let Some(stream) = self.heartbeats.remove(&token) {
+            self.heartbeat(token, stream, conn);
+            return;
+        }
+        if let Some((idx, handler_token, stream)) = self.timers.remove(&token) {
+            if self.handlers[idx].1.is_none() {
+                // The handler is busy on the pool, try again a bit later.
+                self.timers.insert(token, (idx, handler_token, stream));
+                conn.set_timer(Duration::from_millis(10), token);
+                return;
+            }
+            let handler = self.handlers[idx].1.as_mut().unwrap();
+            let timers = respond(&mut self.protocol,
+                                 &mut self.records,
+                                 stream,
+                                 conn,
+                                 |resp| handler.timeout(handler_token, resp));
+            self.set_timers(idx, stream, timers, conn);
+        }
+    }
+    fn wake(&mut self, conn: &mut Connection) {
+        if let Protocol::WebSocket(ref mut ws) = self.protocol {
+            ws.wake(conn.output());
+            self.set_websocket_timers(conn);
+            return;
+        }
+        self.write_events(conn);
+        let (idx, stream, done) = match self.in_flight {
+            Some(ref in_flight) => {
+                // Checked first, so that what was flushed before it is taken
+                // with the rest.
+                let done = in_flight.done.try_recv().ok();
+                if let Some(output) = in_flight.flushed.take() {
+                    if !append_output(&mut self.protocol, in_flight.stream, conn, output, false) {
+                        in_flight.flushed.disconnect();
+                    }
+                }
+                match done {
+                    Some(done) => (in_flight.idx, in_flight.stream, done),
+                    None => return,
+                }
+            }
+            None => return,
+        };
+        self.in_flight = None;
+        self.handlers[idx].1 = Some(done.handler);
+        if let Some(record) = done.access_log {
+            self.records.insert(stream, record);
+        }
+        append_output(&mut self.protocol, stream, conn, done.output, true);
+        self.set_timers(idx, stream, done.timers, conn);
+        self.process_pending(conn);
+    }
+    fn drained(&mut self, conn: &mut Connection) -> bool {
+        let more = match self.protocol {
+            Protocol::Http2(ref mut http2) => http2.write(conn.output(), HTTP2_WRITE_BUDGET),
+            _ => false,
+        };
+        // The handler on the pool can flush more once what it flushed is
+        // sent.
+        if let Some(ref in_flight) = self.in_flight {
+            let sent = match (in_flight.stream, &mut self.protocol) {
+                (Some(id), &mut Protocol::Http2(ref mut http2)) => {
+                    http2.stream(id).is_none_or(|out| out.is_sent())
                 }
+                _ => true,
+            };
+            if sent {
+                in_flight.flushed.sent();
+            }
+        }
+        more
+    }
+    fn request_timeout(&mut self, conn: &mut Connection) {
+        match self.protocol {
+            // No status to send for a stream that is not complete, or to a
+            // WebSocket client that didn't answer our close frame.
+            Protocol::Http2(ref mut http2) => {
+                http2.abort_bodies();
+                conn.close();
+                return;
             }
-            resp.set_not_found().send();
+            Protocol::WebSocket(_) => {
+                conn.close();
+                return;
+            }
+            // The handler reading the body answers, if anything.
+            Protocol::Http1 if self.builder.is_streamed() => {
+                self.builder.abort_body();
+                conn.close();
+                return;
+            }
+            _ => {}
+        }
+        let resp = &mut Response::new(conn);
+        resp.close();
+        resp.set_request_timeout().send();
+    }
+    fn shutdown(&mut self, conn: &mut Connection) {
+        if let Protocol::WebSocket(ref mut ws) = self.protocol {
+            ws.shutdown(conn.output());
+            return;
+        }
+        // Event streams don't end by themselves, the clients reconnect to
+        // another server.
+        for (stream, _) in self.event_streams.drain() {
+            write_event_data(&mut self.protocol, stream, conn, &[], true);
         }
+        conn.close();
+    }
+    fn pending_request(&self) -> PendingRequest {
+        // A body read by the handler as it arrives has the body timeout.
+        let streaming = match self.protocol {
+            Protocol::Http1 => {
+                self.builder.is_streamed() && self.builder.pending() == PendingRequest::Body
+            }
+            Protocol::Http2(ref http2) => http2.is_streaming(),
+            _ => false,
+        };
+        if streaming {
+            return PendingRequest::Body;
+        }
+        if self.in_flight.is_some() || !self.requests.is_empty() ||
+           !self.event_streams.is_empty() {
+            return PendingRequest::Processing;
+        }
+        match self.protocol {
+            Protocol::Unknown(ref received) if received.is_empty() => PendingRequest::None,
+            Protocol::Unknown(_) => PendingRequest::Headers,
+            Protocol::Http1 => self.builder.pending(),
+            Protocol::Http2(ref http2) => http2.pending(),
+            Protocol::WebSocket(ref ws) => ws.pending(),
+        }
+    }
+    fn report(&self, status: &mut ConnStatus) {
+        status.protocol = match self.protocol {
+            Protocol::Unknown(_) => "-",
+            Protocol::Http1 => "HTTP/1",
+            Protocol::Http2(_) => "HTTP/2",
+            Protocol::WebSocket(_) => "WebSocket",
+        };
+        status.request = self.last_request.clone();
+        status.requests = self.num_requests;
     }
     fn duplicate(&self) -> Box<App> {
         let mut handlers = Vec::new();
-        for &HandlerRule(ref r, ref h) in &self.handlers {
-            handlers.push(HandlerRule(r.clone(), h.duplicate()));
+        for &HandlerRule(ref r, ref h, site) in &self.handlers {
+            let h = h.as_ref().expect("duplicating a busy handler");
+            handlers.push(HandlerRule(r.clone(), Some(h.duplicate()), site));
         }
-        Box::new(HandlerApp {
-            handlers: handlers,
-            builder: RequestBuilder::new(),
-        })
+        let mut app = HandlerApp::with_rules(handlers, self.pool.clone());
+        for &WebSocketRule(ref r, ref h, site) in &self.ws_handlers {
+            app.ws_handlers.push(WebSocketRule(r.clone(), h.duplicate(), site));
+        }
+        for &SseRule(ref r, ref h, site) in &self.sse_handlers {
+            app.sse_handlers.push(SseRule(r.clone(), h.duplicate(), site));
+        }
+        app.hosts = self.hosts.clone();
+        app.num_sites = self.num_sites;
+        app.rewrite_rules = self.rewrite_rules.clone();
+        app.compression = self.compression.clone();
+        app.access_log = self.access_log.clone();
+        app.metrics = self.metrics.clone();
+        app.set_limits(self.limits);
+        Box::new(app)
     }
 }
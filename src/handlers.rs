@@ -1,7 +1,18 @@
+use std::io;
+use cache_control::CachePolicies;
+use compression::Compression;
 use http_file::*;
+use mime::MimeTypes;
 use http::*;
 use handler_lib::*;
 
+// Files can only be read.
+const READ_METHODS: &str = "GET, HEAD";
+
+fn is_read(req: &Request) -> bool {
+    req.method() == "GET" || req.method() == "HEAD"
+}
+
 pub struct FileSystemHandler {
     path: String,
     fs: FileSystem,
@@ -13,13 +24,58 @@ impl FileSystemHandler {
             fs: FileSystem::new(path),
         }
     }
+
+    // Whether the precompressed "x.br" or "x.gz" is served for "x", when
+    // there is one. They are by default.
+    pub fn set_precompressed(&mut self, precompressed: bool) {
+        self.fs.set_precompressed(precompressed);
+    }
+
+    // Whether the directories without an index.html get a page listing
+    // their files. They are not found by default.
+    pub fn set_listing(&mut self, listing: bool) {
+        self.fs.set_listing(listing);
+    }
+
+    // The types the files are served with.
+    pub fn set_mime_types(&mut self, mime_types: MimeTypes) {
+        self.fs.set_mime_types(mime_types);
+    }
+
+    // Serves the files with extension `ext` as `mime`.
+    pub fn add_mime_type(&mut self, ext: &str, mime: &str) {
+        self.fs.add_mime_type(ext, mime);
+    }
+
+    // Serves the small files from `cache`, which other handlers can share.
+    pub fn set_cache(&mut self, cache: FileCache) {
+        self.fs.set_cache(cache);
+    }
+
+    // The Cache-Control and Expires headers of the files, by their path.
+    pub fn set_cache_policies(&mut self, policies: CachePolicies) {
+        self.fs.set_cache_policies(policies);
+    }
+
+    // Writes the missing precompressed files, to call before serving.
+    // Returns how many were written.
+    pub fn precompress(&self, compression: &Compression) -> io::Result<usize> {
+        self.fs.precompress(compression)
+    }
 }
 impl Handler for FileSystemHandler {
     fn process(&mut self, req: Request, resp: &mut Response) {
-        self.fs.serve(&req.uri, resp);
+        if !is_read(&req) {
+            resp.set_method_not_allowed(READ_METHODS).send();
+            return;
+        }
+        self.fs.serve(&req.uri, &req, resp);
     }
     fn duplicate(&self) -> Box<Handler> {
-        return Box::new(FileSystemHandler::new(&self.path));
+        return Box::new(FileSystemHandler {
+            path: self.path.clone(),
+            fs: self.fs.clone(),
+        });
     }
 }
 
@@ -34,12 +90,45 @@ impl FileHandler {
             fs: FileSystem::new(path),
         }
     }
+
+    // Whether the precompressed "x.br" or "x.gz" is served for "x", when
+    // there is one. They are by default.
+    pub fn set_precompressed(&mut self, precompressed: bool) {
+        self.fs.set_precompressed(precompressed);
+    }
+
+    // The types the files are served with.
+    pub fn set_mime_types(&mut self, mime_types: MimeTypes) {
+        self.fs.set_mime_types(mime_types);
+    }
+
+    // Serves the files with extension `ext` as `mime`.
+    pub fn add_mime_type(&mut self, ext: &str, mime: &str) {
+        self.fs.add_mime_type(ext, mime);
+    }
+
+    // Serves the small files from `cache`, which other handlers can share.
+    pub fn set_cache(&mut self, cache: FileCache) {
+        self.fs.set_cache(cache);
+    }
+
+    // The Cache-Control and Expires headers of the files, by their path.
+    pub fn set_cache_policies(&mut self, policies: CachePolicies) {
+        self.fs.set_cache_policies(policies);
+    }
 }
 impl Handler for FileHandler {
-    fn process(&mut self, _: Request, resp: &mut Response) {
-        self.fs.serve("", resp);
+    fn process(&mut self, req: Request, resp: &mut Response) {
+        if !is_read(&req) {
+            resp.set_method_not_allowed(READ_METHODS).send();
+            return;
+        }
+        self.fs.serve("", &req, resp);
     }
     fn duplicate(&self) -> Box<Handler> {
-        return Box::new(FileHandler::new(&self.path));
+        return Box::new(FileHandler {
+            path: self.path.clone(),
+            fs: self.fs.clone(),
+        });
     }
 }
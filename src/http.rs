@@ -1,7 +1,20 @@
-use std::io::prelude::*;
+use std::cmp;
 use std::collections::HashMap;
-use mio::tcp::*;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::{self, ErrorKind};
+use std::net::SocketAddr;
 use std::str;
+use std::mem;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, SystemTime};
+use access_log::Record;
+use blocking::{Flusher, JobOutput};
+use cache_control::CachePolicy;
+use compression::{self, Compression, Encoder, Encoding};
+use connection::*;
+use http2::StreamOutput;
+use tls::TlsInfo;
 
 const CR: u8 = 13;
 const LF: u8 = 10;
@@ -12,51 +25,353 @@ pub struct Status {
 }
 
 impl Status {
+    // Any other status, such as one received from another server.
+    pub fn new(code: u32, desc: &str) -> Status {
+        return Status {
+            code: code,
+            desc: desc.to_string(),
+        };
+    }
+    pub fn code(&self) -> u32 {
+        self.code
+    }
+    pub fn desc(&self) -> &str {
+        &self.desc
+    }
+    // A status with its usual reason phrase.
+    pub fn from_code(code: u32) -> Status {
+        let desc = match code {
+            100 => "Continue",
+            101 => "Switching Protocols",
+            200 => "OK",
+            201 => "Created",
+            202 => "Accepted",
+            204 => "No Content",
+            206 => "Partial Content",
+            301 => "Moved Permanently",
+            302 => "Found",
+            303 => "See Other",
+            304 => "Not Modified",
+            307 => "Temporary Redirect",
+            308 => "Permanent Redirect",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            403 => "Forbidden",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            408 => "Request Timeout",
+            409 => "Conflict",
+            410 => "Gone",
+            411 => "Length Required",
+            412 => "Precondition Failed",
+            413 => "Content Too Large",
+            414 => "URI Too Long",
+            415 => "Unsupported Media Type",
+            416 => "Range Not Satisfiable",
+            421 => "Misdirected Request",
+            426 => "Upgrade Required",
+            429 => "Too Many Requests",
+            431 => "Request Header Fields Too Large",
+            451 => "Unavailable For Legal Reasons",
+            500 => "Internal Server Error",
+            501 => "Not Implemented",
+            502 => "Bad Gateway",
+            503 => "Service Unavailable",
+            504 => "Gateway Timeout",
+            _ => "",
+        };
+        return Status::new(code, desc);
+    }
+    pub fn switching_protocols() -> Status {
+        return Status {
+            code: 101,
+            desc: "Switching Protocols".to_string(),
+        };
+    }
     pub fn ok() -> Status {
         return Status {
             code: 200,
             desc: "OK".to_string(),
         };
     }
+    pub fn internal_server_error() -> Status {
+        return Status {
+            code: 500,
+            desc: "Internal Server Error".to_string(),
+        };
+    }
+    pub fn moved_permanently() -> Status {
+        return Status {
+            code: 301,
+            desc: "Moved Permanently".to_string(),
+        };
+    }
+    pub fn found() -> Status {
+        return Status {
+            code: 302,
+            desc: "Found".to_string(),
+        };
+    }
+    pub fn see_other() -> Status {
+        return Status {
+            code: 303,
+            desc: "See Other".to_string(),
+        };
+    }
+    pub fn not_modified() -> Status {
+        return Status {
+            code: 304,
+            desc: "Not Modified".to_string(),
+        };
+    }
+    pub fn temporary_redirect() -> Status {
+        return Status {
+            code: 307,
+            desc: "Temporary Redirect".to_string(),
+        };
+    }
+    pub fn permanent_redirect() -> Status {
+        return Status {
+            code: 308,
+            desc: "Permanent Redirect".to_string(),
+        };
+    }
+    pub fn bad_gateway() -> Status {
+        return Status {
+            code: 502,
+            desc: "Bad Gateway".to_string(),
+        };
+    }
+    pub fn gateway_timeout() -> Status {
+        return Status {
+            code: 504,
+            desc: "Gateway Timeout".to_string(),
+        };
+    }
+    pub fn bad_request() -> Status {
+        return Status {
+            code: 400,
+            desc: "Bad Request".to_string(),
+        };
+    }
     pub fn not_found() -> Status {
         return Status {
             code: 404,
             desc: "Not Found".to_string(),
         };
     }
+    pub fn request_timeout() -> Status {
+        return Status {
+            code: 408,
+            desc: "Request Timeout".to_string(),
+        };
+    }
+    pub fn method_not_allowed() -> Status {
+        return Status {
+            code: 405,
+            desc: "Method Not Allowed".to_string(),
+        };
+    }
+    pub fn misdirected_request() -> Status {
+        return Status {
+            code: 421,
+            desc: "Misdirected Request".to_string(),
+        };
+    }
+    pub fn upgrade_required() -> Status {
+        return Status {
+            code: 426,
+            desc: "Upgrade Required".to_string(),
+        };
+    }
+    pub fn service_unavailable() -> Status {
+        return Status {
+            code: 503,
+            desc: "Service Unavailable".to_string(),
+        };
+    }
+}
+
+// Where a response is written: on the connection for HTTP/1, or on its
+// stream for HTTP/2.
+enum Target<'a> {
+    Http1(&'a mut Output),
+    Http2(&'a mut StreamOutput),
 }
 
 pub struct Response<'a> {
     version: String,
     status: Status,
-    headers: HashMap<String, String>,
+    headers: Vec<(String, String)>,
     body: Vec<u8>,
-    stream: &'a mut TcpStream,
+    conn: Target<'a>,
+    timers: Vec<(Duration, usize)>,
+    // Answering a HEAD request, only the status and headers are sent.
+    omit_body: bool,
+    // Set when the response can be compressed, with the encoding the client
+    // prefers if it accepts one.
+    compression: Option<Arc<Compression>>,
+    encoding: Option<Encoding>,
+    // Whether the body can be sent in chunks of unknown length, which
+    // streamed compression needs on HTTP/1.
+    can_chunk: bool,
+    // Compresses a body that comes after the head, with what is left of it
+    // before compression.
+    encoder: Option<Encoder>,
+    remaining: u64,
+    // Whether the body is sent in chunks, its length not being known.
+    chunked: bool,
+    cache_policy: Option<CachePolicy>,
+    // The access log record of the request, written when the response is
+    // dropped with the status and the bytes of body sent.
+    access_log: Option<Record>,
+    sent_status: Option<u32>,
+    bytes_sent: u64,
+    // Set on the blocking pool, where what was written can be sent before
+    // the handler returns.
+    flusher: Option<&'a Flusher>,
+    bytes_flushed: u64,
 }
 
 impl<'a> Response<'a> {
-    pub fn new(stream: &'a mut TcpStream) -> Response<'a> {
+    pub fn new(conn: &'a mut Connection) -> Response<'a> {
+        Response::with_output(conn.output())
+    }
+
+    // A response written to an output that is not on its connection yet.
+    pub fn with_output(conn: &'a mut Output) -> Response<'a> {
+        Response::with_target(Target::Http1(conn))
+    }
+
+    // A response on an HTTP/2 stream.
+    pub fn for_stream(stream: &'a mut StreamOutput) -> Response<'a> {
+        Response::with_target(Target::Http2(stream))
+    }
+
+    fn with_target(conn: Target<'a>) -> Response<'a> {
         return Response {
             version: "HTTP/1.1".to_string(),
             status: Status::ok(),
-            headers: HashMap::new(),
+            headers: Vec::new(),
             body: Vec::new(),
-            stream: stream,
+            conn: conn,
+            timers: Vec::new(),
+            omit_body: false,
+            compression: None,
+            encoding: None,
+            can_chunk: false,
+            encoder: None,
+            remaining: 0,
+            chunked: false,
+            cache_policy: None,
+            access_log: None,
+            sent_status: None,
+            bytes_sent: 0,
+            flusher: None,
+            bytes_flushed: 0,
         };
     }
 
+    // Sends the client to `location` with a 3xx `status`.
+    pub fn set_redirect(&mut self, status: Status, location: &str) -> &mut Response<'a> {
+        let body = format!("<html><h1>{} {}</h1></html>", status.code, status.desc);
+        self.set_status(status)
+            .set_header("Location", location)
+            .set_header("Content-Type", "text/html")
+            .set_body_str(&body)
+    }
+
     pub fn set_not_found(&mut self) -> &mut Response<'a> {
         self.set_status(Status::not_found())
             .set_header("Content-Type", "text/html")
             .set_body_str("<html><h1>404 Not found</h1></html>")
     }
 
+    pub fn set_bad_request(&mut self) -> &mut Response<'a> {
+        self.set_status(Status::bad_request())
+            .set_header("Content-Type", "text/html")
+            .set_body_str("<html><h1>400 Bad Request</h1></html>")
+    }
+
+    pub fn set_content_too_large(&mut self) -> &mut Response<'a> {
+        self.set_status(Status::from_code(413))
+            .set_header("Content-Type", "text/html")
+            .set_body_str("<html><h1>413 Content Too Large</h1></html>")
+    }
+
+    pub fn set_header_fields_too_large(&mut self) -> &mut Response<'a> {
+        self.set_status(Status::from_code(431))
+            .set_header("Content-Type", "text/html")
+            .set_body_str("<html><h1>431 Request Header Fields Too Large</h1></html>")
+    }
+
+    pub fn set_not_implemented(&mut self) -> &mut Response<'a> {
+        self.set_status(Status::from_code(501))
+            .set_header("Content-Type", "text/html")
+            .set_body_str("<html><h1>501 Not Implemented</h1></html>")
+    }
+
+    pub fn set_request_timeout(&mut self) -> &mut Response<'a> {
+        self.set_status(Status::request_timeout())
+            .set_header("Content-Type", "text/html")
+            .set_body_str("<html><h1>408 Request Timeout</h1></html>")
+    }
+
+    // `allow` lists the methods the resource supports.
+    pub fn set_method_not_allowed(&mut self, allow: &str) -> &mut Response<'a> {
+        self.set_status(Status::method_not_allowed())
+            .set_header("Allow", allow)
+            .set_header("Content-Type", "text/html")
+            .set_body_str("<html><h1>405 Method Not Allowed</h1></html>")
+    }
+
+    // The request was for a site this connection can't be used for.
+    pub fn set_misdirected_request(&mut self) -> &mut Response<'a> {
+        self.set_status(Status::misdirected_request())
+            .set_header("Content-Type", "text/html")
+            .set_body_str("<html><h1>421 Misdirected Request</h1></html>")
+    }
+
+    pub fn set_internal_server_error(&mut self) -> &mut Response<'a> {
+        self.set_status(Status::internal_server_error())
+            .set_header("Content-Type", "text/html")
+            .set_body_str("<html><h1>500 Internal Server Error</h1></html>")
+    }
+
+    pub fn set_bad_gateway(&mut self) -> &mut Response<'a> {
+        self.set_status(Status::bad_gateway())
+            .set_header("Content-Type", "text/html")
+            .set_body_str("<html><h1>502 Bad Gateway</h1></html>")
+    }
+
+    pub fn set_gateway_timeout(&mut self) -> &mut Response<'a> {
+        self.set_status(Status::gateway_timeout())
+            .set_header("Content-Type", "text/html")
+            .set_body_str("<html><h1>504 Gateway Timeout</h1></html>")
+    }
+
+    // Asks the client to retry after `retry_after` seconds.
+    pub fn set_service_unavailable(&mut self, retry_after: u64) -> &mut Response<'a> {
+        self.set_status(Status::service_unavailable())
+            .set_header("Retry-After", &retry_after.to_string())
+            .set_header("Content-Type", "text/html")
+            .set_body_str("<html><h1>503 Service Unavailable</h1></html>")
+    }
+
     pub fn set_status(&mut self, status: Status) -> &mut Response<'a> {
         self.status = status;
         self
     }
+    // Replaces the header if it was set, whatever the case of its name.
     pub fn set_header(&mut self, header_name: &str, value: &str) -> &mut Response<'a> {
-        self.headers.insert(header_name.to_string(), value.to_string());
+        self.headers.retain(|&(ref name, _)| !name.eq_ignore_ascii_case(header_name));
+        self.add_header(header_name, value)
+    }
+
+    // Adds a header even if it was set already, for those like `Set-Cookie`
+    // that can be repeated.
+    pub fn add_header(&mut self, header_name: &str, value: &str) -> &mut Response<'a> {
+        self.headers.push((header_name.to_string(), value.to_string()));
         self
     }
 
@@ -95,29 +410,472 @@ impl<'a> Response<'a> {
             b.extend_from_slice(format!("{}: {}\r\n", name, value).as_bytes());
         }
         b.extend_from_slice("\r\n".as_bytes());
-        b.extend_from_slice(self.body.as_slice());
+        if !self.omit_body {
+            b.extend_from_slice(self.body.as_slice());
+        }
         return b;
     }
 
     pub fn send_data(&mut self, data: &[u8]) {
-        let _ = self.stream.write(data);
-
+        if self.omit_body {
+            return;
+        }
+        if self.encoder.is_some() {
+            self.send_encoded(data);
+            return;
+        }
+        self.bytes_sent += data.len() as u64;
+        match self.conn {
+            Target::Http1(ref mut out) if self.chunked => {
+                if !data.is_empty() {
+                    out.write(format!("{:x}\r\n", data.len()).as_bytes());
+                    out.write(data);
+                    out.write(b"\r\n");
+                }
+            }
+            Target::Http1(ref mut out) => out.write(data),
+            Target::Http2(ref mut stream) => stream.data(data),
+        }
     }
     pub fn send_str(&mut self, data: &str) {
-        let _ = self.stream.write(data.as_bytes());
+        self.send_data(data.as_bytes());
+    }
+
+    // Sends the next `len` bytes of the file as they can be written, without
+    // reading the whole file in memory.
+    pub fn send_file(&mut self, file: File, len: u64) {
+        if self.omit_body {
+            return;
+        }
+        if self.encoder.is_some() {
+            // Compressed as it is read, the output can't be sent from the
+            // file.
+            let mut file = file.take(len);
+            let mut buf = vec![0; 64 * 1024];
+            loop {
+                match file.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(read) => self.send_encoded(&buf[..read]),
+                    Err(e) => {
+                        warn!("Error while reading a file to compress: {}", e);
+                        break;
+                    }
+                }
+            }
+            if self.encoder.is_some() {
+                // Cut short, the client can tell with the missing end.
+                self.encoder = None;
+                self.close();
+            }
+            return;
+        }
+        self.bytes_sent += len;
+        match self.conn {
+            Target::Http1(ref mut out) if self.chunked => {
+                if len > 0 {
+                    out.write(format!("{:x}\r\n", len).as_bytes());
+                    out.write_file(file, len);
+                    out.write(b"\r\n");
+                }
+            }
+            Target::Http1(ref mut out) => out.write_file(file, len),
+            Target::Http2(ref mut stream) => stream.file(file, len),
+        }
+    }
+
+    // Sends the status and headers of a response whose body length is not
+    // known. The body then goes with `send_data()` as it comes, until
+    // `end()`: in chunks to HTTP/1.1 clients, until the connection closes to
+    // HTTP/1.0 ones.
+    pub fn send_chunked(&mut self, req: &Request) {
+        self.headers.retain(|&(ref name, _)| !name.eq_ignore_ascii_case("Content-Length"));
+        match self.conn {
+            Target::Http1(_) if req.version() == "HTTP/1.0" => self.close(),
+            Target::Http1(_) => {
+                self.set_header("Transfer-Encoding", "chunked");
+                self.chunked = true;
+            }
+            Target::Http2(_) => {}
+        }
+        self.send();
+    }
+
+    // Ends a body sent with `send_chunked()`.
+    pub fn end(&mut self) {
+        match self.conn {
+            Target::Http1(ref mut out) if self.chunked && !self.omit_body => {
+                out.write(b"0\r\n\r\n");
+            }
+            Target::Http1(_) => {}
+            Target::Http2(ref mut stream) => stream.finish(),
+        }
+        self.chunked = false;
+    }
+
+    // Hands what was written so far to the client, waiting while it is too
+    // far behind. Only handlers on the blocking pool can, elsewhere the
+    // response goes out once the handler returns. Returns false if the
+    // client went away, there is no point in going on then.
+    pub fn flush(&mut self) -> bool {
+        let flusher = match self.flusher {
+            Some(flusher) => flusher,
+            None => return true,
+        };
+        let output = match self.conn {
+            Target::Http1(ref mut out) => JobOutput::Http1(out.take_queued()),
+            Target::Http2(ref mut stream) => JobOutput::Http2(stream.take_written()),
+        };
+        let len = self.bytes_sent - self.bytes_flushed;
+        self.bytes_flushed = self.bytes_sent;
+        flusher.flush(output, len)
+    }
+
+    pub(crate) fn set_flusher(&mut self, flusher: &'a Flusher) {
+        self.flusher = Some(flusher);
+    }
+
+    // Calls the handler's `timeout()` with `token` after `delay`, with a new
+    // response on the same connection.
+    pub fn set_timer(&mut self, delay: Duration, token: usize) {
+        self.timers.push((delay, token));
+    }
+
+    pub fn take_timers(&mut self) -> Vec<(Duration, usize)> {
+        mem::take(&mut self.timers)
+    }
+
+    // Closes the connection once the response is sent. HTTP/2 streams end
+    // with their response anyway, the connection stays open.
+    pub fn close(&mut self) {
+        if let Target::Http1(ref mut out) = self.conn {
+            out.close();
+        }
+    }
+
+    // Whether the status and headers went out already.
+    pub(crate) fn is_sent(&self) -> bool {
+        self.sent_status.is_some()
+    }
+
+    // Ends a response that was cut short, closing the HTTP/1 connection once
+    // what was written is sent, or ending the HTTP/2 stream there.
+    pub(crate) fn abort(&mut self) {
+        match self.conn {
+            Target::Http1(ref mut out) => out.close(),
+            Target::Http2(ref mut stream) => stream.abort(),
+        }
+    }
+
+    // Drops the bodies, for a response to a HEAD request.
+    pub(crate) fn omit_body(&mut self) {
+        self.omit_body = true;
+    }
+
+    // Sends the caching headers of `policy` if the response is a success
+    // and has no Cache-Control already.
+    pub fn set_cache_policy(&mut self, policy: &CachePolicy) -> &mut Response<'a> {
+        self.cache_policy = Some(policy.clone());
+        self
+    }
+
+    fn apply_cache_policy(&mut self) {
+        let policy = match self.cache_policy.take() {
+            Some(policy) => policy,
+            None => return,
+        };
+        let cacheable = [200, 203, 204, 206, 301, 304, 308].contains(&self.status.code);
+        if !cacheable || self.header("Cache-Control").is_some() {
+            return;
+        }
+        if let Some(cache_control) = policy.cache_control() {
+            self.set_header("Cache-Control", &cache_control);
+        }
+        if let Some(expires) = policy.expires(SystemTime::now()) {
+            self.set_header("Expires", &expires);
+        }
+    }
+
+    // Logs the response to the request of `record` once it is sent.
+    pub(crate) fn set_access_log(&mut self, record: Option<Record>) {
+        self.access_log = record;
+    }
+
+    // The access log record, if the response wasn't sent, for the response
+    // that will answer the request later.
+    pub(crate) fn take_access_log(&mut self) -> Option<Record> {
+        match self.sent_status {
+            Some(_) => None,
+            None => self.access_log.take(),
+        }
+    }
+
+    // Compresses the response to `req` if `compression` applies to it.
+    pub(crate) fn set_compression(&mut self, compression: &Arc<Compression>, req: &Request) {
+        self.compression = Some(compression.clone());
+        self.encoding = compression.negotiate(req);
+        self.can_chunk = match self.conn {
+            Target::Http1(_) => req.version() != "HTTP/1.0",
+            Target::Http2(_) => true,
+        };
+    }
+
+    fn header(&self, header_name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|&&(ref name, _)| name.eq_ignore_ascii_case(header_name))
+            .map(|&(_, ref value)| value.as_str())
+    }
+
+    // Sets up the compression of the body, when the head is sent. A body
+    // that is all there is compressed at once, one that comes later goes
+    // through an encoder, returned with what of it is there already.
+    fn compress(&mut self) -> Option<Vec<u8>> {
+        let compression = self.compression.take()?;
+        let code = self.status.code;
+        if code < 200 || code == 204 || code == 206 || code == 304 {
+            return None;
+        }
+        let length = self.header("Content-Length").and_then(|l| l.parse().ok())?;
+        let no_transform = self.header("Cache-Control")
+                               .is_some_and(|c| c.to_lowercase().contains("no-transform"));
+        if !compression.applies(self.header("Content-Type"), length) ||
+           self.header("Content-Encoding").is_some() || no_transform {
+            return None;
+        }
+        // Caches must keep the encodings apart.
+        match self.header("Vary").map(|v| v.to_string()) {
+            Some(ref vary) if vary.split(',').any(|v| {
+                let v = v.trim();
+                v == "*" || v.eq_ignore_ascii_case("Accept-Encoding")
+            }) => {}
+            Some(vary) => {
+                self.set_header("Vary", &format!("{}, Accept-Encoding", vary));
+            }
+            None => {
+                self.set_header("Vary", "Accept-Encoding");
+            }
+        }
+        let encoding = self.encoding?;
+        let whole = self.body.len() as u64 >= length;
+        if !whole && !self.can_chunk {
+            return None;
+        }
+        self.set_header("Content-Encoding", encoding.name());
+        // The compressed body is another representation, with a tag of its
+        // own.
+        if let Some(etag) = self.header("ETag").map(|e| e.to_string()) {
+            if let Some(etag) = etag.strip_suffix('"') {
+                self.set_header("ETag", &format!("{}-{}\"", etag, encoding.name()));
+            }
+        }
+        let encoder = compression.encoder(encoding);
+        if whole {
+            let body = compression::compress(encoder, &self.body);
+            self.body.clear();
+            self.set_body(&body);
+            return None;
+        }
+        self.headers.retain(|&(ref name, _)| !name.eq_ignore_ascii_case("Content-Length"));
+        if let Target::Http1(_) = self.conn {
+            self.set_header("Transfer-Encoding", "chunked");
+            self.chunked = true;
+        }
+        if self.omit_body {
+            return None;
+        }
+        self.encoder = Some(encoder);
+        self.remaining = length;
+        Some(mem::take(&mut self.body))
+    }
+
+    // Compresses and sends the next part of the body, and the end of the
+    // compressed body once it is all there.
+    fn send_encoded(&mut self, data: &[u8]) {
+        let mut out = self.encoder.as_mut().unwrap().write(data);
+        self.remaining = self.remaining.saturating_sub(data.len() as u64);
+        let end = self.remaining == 0;
+        if end {
+            out.extend_from_slice(&self.encoder.take().unwrap().finish());
+        }
+        let chunked = self.chunked;
+        self.chunked = self.chunked && !end;
+        self.bytes_sent += out.len() as u64;
+        match self.conn {
+            Target::Http1(ref mut out_conn) if chunked => {
+                if !out.is_empty() {
+                    out_conn.write(format!("{:x}\r\n", out.len()).as_bytes());
+                    out_conn.write(&out);
+                    out_conn.write(b"\r\n");
+                }
+                if end {
+                    out_conn.write(b"0\r\n\r\n");
+                }
+            }
+            Target::Http1(ref mut out_conn) => out_conn.write(&out),
+            Target::Http2(ref mut stream) => stream.data(&out),
+        }
+    }
+
+    fn is_closing(&self) -> bool {
+        match self.conn {
+            Target::Http1(ref out) => out.is_closing(),
+            Target::Http2(_) => false,
+        }
     }
 
     pub fn send(&mut self) {
-        let bytes = self.as_bytes();
-        let _ = self.stream.write(&bytes);
+        self.apply_cache_policy();
+        let pending = self.compress();
+        self.sent_status = Some(self.status.code);
+        if !self.omit_body {
+            self.bytes_sent += self.body.len() as u64;
+        }
+        if let Target::Http2(ref mut stream) = self.conn {
+            stream.head(self.status.code, &self.headers);
+            if self.omit_body {
+                stream.omit_body();
+            } else {
+                stream.data(&self.body);
+            }
+        } else {
+            if self.is_closing() {
+                self.set_header("Connection", "close");
+            }
+            let bytes = self.as_bytes();
+            if let Target::Http1(ref mut out) = self.conn {
+                out.write(&bytes);
+            }
+        }
         self.headers.clear();
         self.body.clear();
+        if let Some(body) = pending {
+            if !body.is_empty() {
+                self.send_encoded(&body);
+            }
+        }
     }
 }
 
-#[derive(Debug, Clone)]
+impl<'a> Drop for Response<'a> {
+    fn drop(&mut self) {
+        if let (Some(record), Some(status)) = (self.access_log.take(), self.sent_status) {
+            record.write(status, self.bytes_sent);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 enum Method {
     Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+    Options,
+    Patch,
+}
+
+impl Method {
+    fn parse(method: &str) -> Option<Method> {
+        match method {
+            "GET" => Some(Method::Get),
+            "HEAD" => Some(Method::Head),
+            "POST" => Some(Method::Post),
+            "PUT" => Some(Method::Put),
+            "DELETE" => Some(Method::Delete),
+            "OPTIONS" => Some(Method::Options),
+            "PATCH" => Some(Method::Patch),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match *self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+            Method::Options => "OPTIONS",
+            Method::Patch => "PATCH",
+        }
+    }
+}
+
+#[derive(Debug)]
+struct BodyPipe {
+    data: Vec<u8>,
+    done: bool,
+    // The connection went away before the end of the body.
+    aborted: bool,
+}
+
+/// The body of a request, read as it arrives from the client. Reads block
+/// until there is more, and fail if the client goes away before the end.
+#[derive(Debug, Clone)]
+pub struct BodyReader {
+    shared: Arc<(Mutex<BodyPipe>, Condvar)>,
+}
+
+impl Read for BodyReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let (ref pipe, ref arrived) = *self.shared;
+        let mut pipe = pipe.lock().unwrap();
+        while pipe.data.is_empty() && !pipe.done && !pipe.aborted {
+            pipe = arrived.wait(pipe).unwrap();
+        }
+        if pipe.data.is_empty() && pipe.aborted {
+            return Err(io::Error::new(ErrorKind::ConnectionAborted, "request body cut short"));
+        }
+        let len = cmp::min(buf.len(), pipe.data.len());
+        buf[..len].copy_from_slice(&pipe.data[..len]);
+        pipe.data.drain(..len);
+        Ok(len)
+    }
+}
+
+// The connection side of a `BodyReader`. Dropping it before `finish()`
+// aborts the body.
+pub(crate) struct BodySender {
+    shared: Arc<(Mutex<BodyPipe>, Condvar)>,
+}
+
+impl BodySender {
+    pub(crate) fn new() -> (BodySender, BodyReader) {
+        let shared = Arc::new((Mutex::new(BodyPipe {
+            data: Vec::new(),
+            done: false,
+            aborted: false,
+        }), Condvar::new()));
+        let reader = BodyReader { shared: shared.clone() };
+        return (BodySender { shared: shared }, reader);
+    }
+
+    // Passes `data` on, unless the handler dropped the reader.
+    pub(crate) fn send(&self, data: &[u8]) {
+        if data.is_empty() || Arc::strong_count(&self.shared) == 1 {
+            return;
+        }
+        let (ref pipe, ref arrived) = *self.shared;
+        pipe.lock().unwrap().data.extend_from_slice(data);
+        arrived.notify_one();
+    }
+
+    pub(crate) fn finish(self) {
+        let (ref pipe, ref arrived) = *self.shared;
+        pipe.lock().unwrap().done = true;
+        arrived.notify_one();
+    }
+}
+
+impl Drop for BodySender {
+    fn drop(&mut self) {
+        let (ref pipe, ref arrived) = *self.shared;
+        if let Ok(mut pipe) = pipe.lock() {
+            pipe.aborted = !pipe.done;
+        }
+        arrived.notify_one();
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -125,9 +883,17 @@ pub struct Request {
     method: Method,
     pub uri: String,
     params: HashMap<String, String>,
+    query: Option<String>,
     version: String,
     headers: HashMap<String, String>,
     body: Vec<u8>,
+    // Set instead of the body when the request was handed over before its
+    // body was received.
+    body_reader: Option<BodyReader>,
+    tls: Option<TlsInfo>,
+    peer_addr: Option<SocketAddr>,
+    local_addr: Option<SocketAddr>,
+    listener_addr: Option<SocketAddr>,
 }
 
 impl Request {
@@ -136,11 +902,105 @@ impl Request {
             method: Method::Get,
             uri: String::new(),
             params: HashMap::new(),
+            query: None,
             version: String::new(),
             headers: HashMap::new(),
             body: Vec::new(),
+            body_reader: None,
+            tls: None,
+            peer_addr: None,
+            local_addr: None,
+            listener_addr: None,
         };
     }
+    // A request received on an HTTP/2 stream. None if the method is not
+    // supported.
+    pub(crate) fn from_parts(method: &str,
+                             uri: &str,
+                             version: &str,
+                             headers: Vec<(String, String)>,
+                             body: Vec<u8>)
+                             -> Option<Request> {
+        let mut r = Request::new();
+        r.set_method(Method::parse(method)?);
+        r.parse_uri(uri);
+        r.set_version(version);
+        for (name, value) in headers {
+            r.set_header(&name, &value);
+        }
+        r.set_body(&body);
+        Some(r)
+    }
+    // The value of a header, whatever the case of its name.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|&(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+    // All the headers, with their names as received.
+    pub fn headers(&self) -> &HashMap<String, String> {
+        &self.headers
+    }
+    pub fn method(&self) -> &str {
+        self.method.as_str()
+    }
+    // The query string as received, without the '?'.
+    pub fn query(&self) -> Option<&str> {
+        self.query.as_deref()
+    }
+    pub fn version(&self) -> &str {
+        &self.version
+    }
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+    // The body as it arrives, for a request given to a handler whose
+    // `streams_body()` is true before its body was received. `body()` is
+    // empty then.
+    pub fn take_body_reader(&mut self) -> Option<BodyReader> {
+        self.body_reader.take()
+    }
+    // The address of the client, None if unknown.
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
+    }
+    pub(crate) fn set_body_reader(&mut self, reader: BodyReader) {
+        self.body_reader = Some(reader);
+    }
+    pub(crate) fn set_peer_addr(&mut self, addr: Option<SocketAddr>) {
+        self.peer_addr = addr;
+    }
+    // The address the connection of the request was accepted on, None if
+    // unknown.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+    pub(crate) fn set_local_addr(&mut self, addr: Option<SocketAddr>) {
+        self.local_addr = addr;
+    }
+    // The address of the listener the request came in on, as the server was
+    // given it: "0.0.0.0:80" rather than the local address of a listener on
+    // all interfaces.
+    pub fn listener_addr(&self) -> Option<SocketAddr> {
+        self.listener_addr
+    }
+    pub(crate) fn set_listener_addr(&mut self, addr: Option<SocketAddr>) {
+        self.listener_addr = addr;
+    }
+    // None if the request didn't come over TLS.
+    pub fn tls(&self) -> Option<&TlsInfo> {
+        self.tls.as_ref()
+    }
+    pub(crate) fn set_tls(&mut self, tls: Option<TlsInfo>) {
+        self.tls = tls;
+    }
+    // Routes the request with another URI, query string included.
+    pub(crate) fn rewrite_uri(&mut self, uri: &str) {
+        self.query = None;
+        self.params.clear();
+        self.parse_uri(uri);
+    }
     fn set_version(&mut self, version: &str) {
         self.version = version.to_string();
     }
@@ -156,6 +1016,7 @@ impl Request {
                 let params_str = &uri[idx + 1..];
                 let base_uri = &uri[..idx];
                 self.set_uri(base_uri);
+                self.query = Some(params_str.to_string());
                 for param in params_str.split('&') {
                     let parts: Vec<&str> = param.split('=').collect();
                     if parts.len() == 2 {
@@ -174,24 +1035,52 @@ impl Request {
     fn set_header(&mut self, header_name: &str, value: &str) {
         self.headers.insert(header_name.to_string(), value.to_string());
     }
-    fn get_header(&self, header_name: &str) -> Option<String> {
-        if let Some(s) = self.headers.get(header_name) {
-            return Some(s.clone());
-        }
-        return None;
-    }
     fn set_body(&mut self, body: &[u8]) {
         self.body = body.to_vec();
     }
 }
 
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub enum PendingRequest {
+    None,
+    Headers,
+    Body,
+    // Received, a handler is still working on it.
+    Processing,
+}
+
+/// The largest requests the server accepts.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// The request line and headers, in bytes. Clients sending more get a
+    /// 431, or lose the connection with HTTP/2.
+    pub max_header_size: usize,
+    /// The body, in bytes. Clients sending more get a 413.
+    pub max_body_size: usize,
+}
+
+impl Limits {
+    pub fn new() -> Limits {
+        return Limits {
+            max_header_size: 64 * 1024,
+            max_body_size: 16 * 1024 * 1024,
+        };
+    }
+}
+
 #[derive(PartialEq, Debug, Copy, Clone)]
 enum State {
     ParseRequestLine,
     ParseHeaders,
     ParseBody,
+    // A chunked body: the size line, the rest of a chunk of that size, and
+    // the trailer after the last one.
+    ParseChunkSize,
+    ParseChunk(usize),
+    ParseTrailer,
     Done,
-    Error,
+    // With the status to answer.
+    Error(u32),
 }
 
 pub struct RequestBuilder {
@@ -200,29 +1089,106 @@ pub struct RequestBuilder {
     body_size: usize,
     request: Request,
     state: State,
+    limits: Limits,
+    // Whether the request being received was handed over before its body,
+    // which then goes through `body_sender`.
+    streamed: bool,
+    body_sender: Option<BodySender>,
 }
 
 impl RequestBuilder {
     pub fn new() -> RequestBuilder {
+        return RequestBuilder::with_limits(Limits::new());
+    }
+
+    pub fn with_limits(limits: Limits) -> RequestBuilder {
         return RequestBuilder {
             state: State::ParseRequestLine,
             data: Vec::new(),
             parsed: 0,
             body_size: 0,
             request: Request::new(),
+            limits: limits,
+            streamed: false,
+            body_sender: None,
         };
     }
 
+    // The next line of the request head, unless it gets too large.
+    fn get_head_line(&mut self) -> Option<Vec<u8>> {
+        let line = self.get_line();
+        let head_size = if line.is_some() { self.parsed } else { self.data.len() };
+        if head_size > self.limits.max_header_size {
+            debug!("Request head over {} bytes", self.limits.max_header_size);
+            self.state = State::Error(431);
+            return None;
+        }
+        line
+    }
+
+    // The next line of a chunked body, outside of the chunks themselves.
+    fn get_body_line(&mut self) -> Option<Vec<u8>> {
+        let line = self.get_line();
+        if line.is_none() && self.data.len() - self.parsed > self.limits.max_header_size {
+            debug!("Chunk size or trailer line over {} bytes", self.limits.max_header_size);
+            self.state = State::Error(400);
+        }
+        line
+    }
+
+    // How the body that follows the head is delimited. A body sent in
+    // chunks must not have a length too, and no other transfer coding is
+    // supported.
+    fn body_state(&mut self) -> State {
+        let length = self.request.header("Content-Length").map(|l| l.to_string());
+        let coding = self.request.header("Transfer-Encoding").map(|t| t.trim().to_lowercase());
+        match (coding, length) {
+            (Some(_), Some(_)) => {
+                debug!("Request with both a Transfer-Encoding and a Content-Length");
+                State::Error(400)
+            }
+            (Some(ref coding), None) if coding == "chunked" => State::ParseChunkSize,
+            (Some(coding), None) => {
+                debug!("Unsupported transfer coding {}", coding);
+                State::Error(501)
+            }
+            (None, Some(length)) => {
+                match length.parse() {
+                    Ok(u) if u > self.limits.max_body_size => {
+                        debug!("Request body of {} bytes", u);
+                        State::Error(413)
+                    }
+                    Ok(0) => State::Done,
+                    Ok(u) => {
+                        self.body_size = u;
+                        State::ParseBody
+                    }
+                    Err(_) => {
+                        debug!("Invalid Content-Length header value: {}", length);
+                        State::Error(400)
+                    }
+                }
+            }
+            (None, None) => State::Done,
+        }
+    }
+
+    // Whether `name` is Content-Length or Transfer-Encoding and the request
+    // has it already, in any case. Two of them could be read differently by
+    // a proxy in front, and the body delimited differently.
+    fn is_repeated_framing(&self, name: &str) -> bool {
+        let framing = name.eq_ignore_ascii_case("Content-Length") ||
+                      name.eq_ignore_ascii_case("Transfer-Encoding");
+        framing && self.request.header(name).is_some()
+    }
+
     fn get_line(&mut self) -> Option<Vec<u8>> {
         for i in self.parsed..self.data.len() {
             if i == self.parsed {
-                match self.data[i] {
-                    LF => {
-                        let res = &self.data[self.parsed..i];
-                        self.parsed = i + 1;
-                        return Some(res.to_vec());
-                    }
-                    _ => {}
+                if self.data[i] == LF {
+                    let res = &self.data[self.parsed..i];
+                    self.parsed = i + 1;
+                    return Some(res.to_vec());
                 }
             } else {
                 match (self.data[i - 1], self.data[i]) {
@@ -248,22 +1214,22 @@ impl RequestBuilder {
             let old_state = self.state;
             match old_state {
                 State::ParseRequestLine => {
-                    match self.get_line() {
+                    match self.get_head_line() {
                         None => return None,
                         Some(vec) => {
                             match str::from_utf8(&vec) {
                                 Ok(s) => {
                                     let parts = s.split(" ").collect::<Vec<_>>();
                                     if parts.len() != 3 {
-                                        println!("Invalid request: {}", s);
-                                        self.state = State::Error;
+                                        debug!("Invalid request: {}", s);
+                                        self.state = State::Error(400);
                                         return None;
                                     }
-                                    match parts[0] {
-                                        "GET" => self.request.set_method(Method::Get),
-                                        _ => {
-                                            println!("Unsupported method {}", parts[0]);
-                                            self.state = State::Error;
+                                    match Method::parse(parts[0]) {
+                                        Some(method) => self.request.set_method(method),
+                                        None => {
+                                            debug!("Unsupported method {}", parts[0]);
+                                            self.state = State::Error(400);
                                             return None;
                                         }
                                     }
@@ -272,8 +1238,8 @@ impl RequestBuilder {
                                     self.state = State::ParseHeaders;
                                 }
                                 Err(e) => {
-                                    println!("Invalid utf8 request line: {}", e);
-                                    self.state = State::Error;
+                                    debug!("Invalid utf8 request line: {}", e);
+                                    self.state = State::Error(400);
                                     return None;
                                 }
                             }
@@ -281,7 +1247,7 @@ impl RequestBuilder {
                     }
                 }
                 State::ParseHeaders => {
-                    match self.get_line() {
+                    match self.get_head_line() {
                         None => return None,
                         Some(vec) => {
                             match str::from_utf8(&vec) {
@@ -289,34 +1255,22 @@ impl RequestBuilder {
                                     match s {
                                         "" => {
                                             // We parsed the last header.
-                                            if let Some(s) = self.request
-                                                                 .get_header("Content-Length") {
-                                                match s.parse() {
-                                                    Ok(u) => {
-                                                        if u > 0 {
-                                                            self.body_size = u;
-                                                            self.state = State::ParseBody;
-                                                        }
-                                                    }
-                                                    _ => {
-                                                        println!("Invalid Content-Length header \
-                                                                  value: {}",
-                                                                 s);
-                                                        self.state = State::Error;
-                                                    }
-                                                }
-                                            }
-                                            self.state = State::Done;
+                                            self.state = self.body_state();
                                         }
                                         _ => {
                                             match s.find(": ") {
                                                 Some(idx) => {
                                                     let (name, value) = s.split_at(idx);
+                                                    if self.is_repeated_framing(name) {
+                                                        debug!("Repeated {} header", name);
+                                                        self.state = State::Error(400);
+                                                        return None;
+                                                    }
                                                     self.request.set_header(name, &value[2..]);
                                                 }
                                                 None => {
-                                                    println!("Invalid header line: {}", s);
-                                                    self.state = State::Error;
+                                                    debug!("Invalid header line: {}", s);
+                                                    self.state = State::Error(400);
                                                     return None;
                                                 }
                                             }
@@ -324,8 +1278,8 @@ impl RequestBuilder {
                                     }
                                 }
                                 Err(e) => {
-                                    println!("Invalid utf8 header line: {}", e);
-                                    self.state = State::Error;
+                                    debug!("Invalid utf8 header line: {}", e);
+                                    self.state = State::Error(400);
                                     return None;
                                 }
                             }
@@ -333,29 +1287,212 @@ impl RequestBuilder {
                     }
                 }
                 State::ParseBody => {
-                    if self.request.body.len() < self.parsed + self.body_size {
+                    let len = cmp::min(self.data.len() - self.parsed, self.body_size);
+                    self.take_body(len);
+                    self.body_size -= len;
+                    if self.body_size > 0 {
                         return None;
                     }
-                    self.request
-                        .set_body(&self.data[self.parsed..self.parsed + self.body_size]);
-                    self.parsed += self.body_size;
                     self.state = State::Done;
                 }
+                State::ParseChunkSize => {
+                    let line = self.get_body_line()?;
+                    let size = str::from_utf8(&line)
+                                   .ok()
+                                   .and_then(|l| l.split(';').next())
+                                   .and_then(|s| usize::from_str_radix(s.trim(), 16).ok());
+                    match size {
+                        Some(0) => self.state = State::ParseTrailer,
+                        Some(size) if size <= self.limits.max_body_size - self.body_size => {
+                            self.body_size += size;
+                            self.state = State::ParseChunk(size);
+                        }
+                        Some(_) => {
+                            debug!("Chunked request body over {} bytes",
+                                   self.limits.max_body_size);
+                            self.state = State::Error(413);
+                            return None;
+                        }
+                        None => {
+                            debug!("Invalid chunk size line");
+                            self.state = State::Error(400);
+                            return None;
+                        }
+                    }
+                }
+                State::ParseChunk(0) => {
+                    // The line break after the chunk.
+                    if self.data.len() < self.parsed + 2 {
+                        return None;
+                    }
+                    if &self.data[self.parsed..self.parsed + 2] != b"\r\n" {
+                        debug!("Chunk longer than its size");
+                        self.state = State::Error(400);
+                        return None;
+                    }
+                    self.parsed += 2;
+                    self.state = State::ParseChunkSize;
+                }
+                State::ParseChunk(size) => {
+                    let len = cmp::min(self.data.len() - self.parsed, size);
+                    if len == 0 {
+                        return None;
+                    }
+                    self.take_body(len);
+                    self.state = State::ParseChunk(size - len);
+                }
+                State::ParseTrailer => {
+                    // Trailer fields are not kept.
+                    match self.get_body_line() {
+                        None => return None,
+                        Some(ref line) if line.is_empty() => self.state = State::Done,
+                        Some(_) => {}
+                    }
+                }
                 State::Done => {
-                    let parsed_request = self.request.clone();
-                    self.request = Request::new();
-                    self.data = (&self.data[self.parsed..]).to_vec();
+                    let parsed_request = mem::replace(&mut self.request, Request::new());
+                    self.data = self.data[self.parsed..].to_vec();
                     self.parsed = 0;
+                    self.body_size = 0;
                     self.state = State::ParseRequestLine;
+                    // A streamed request was returned with its head.
+                    if self.streamed {
+                        self.streamed = false;
+                        if let Some(sender) = self.body_sender.take() {
+                            sender.finish();
+                        }
+                        continue;
+                    }
                     return Some(parsed_request);
                 }
-                _ => panic!("Unknown state: {:?}", self.state),
+                State::Error(_) => return None,
             }
         }
     }
 
+    // Which part of a request is being received, if any.
+    pub fn pending(&self) -> PendingRequest {
+        match self.state {
+            State::ParseRequestLine if self.parsed == self.data.len() => PendingRequest::None,
+            State::ParseRequestLine | State::ParseHeaders => PendingRequest::Headers,
+            State::ParseBody | State::ParseChunkSize | State::ParseChunk(_) |
+            State::ParseTrailer => PendingRequest::Body,
+            State::Done | State::Error(_) => PendingRequest::None,
+        }
+    }
+
+    // Whether the data received is not a valid request. Nothing more can be
+    // parsed once this happens.
+    pub fn is_error(&self) -> bool {
+        self.error_code().is_some()
+    }
+
+    // The status to answer the invalid request with: 400, 413 or 431 for
+    // one over the limits, or 501 for a transfer coding we don't know.
+    pub fn error_code(&self) -> Option<u32> {
+        match self.state {
+            State::Error(code) => Some(code),
+            _ => None,
+        }
+    }
+
     pub fn read(&mut self, data: &[u8]) -> Option<Request> {
+        if self.pending() == PendingRequest::Body {
+            // The part of the body parsed so far is not needed anymore.
+            self.data.drain(..self.parsed);
+            self.parsed = 0;
+        }
         self.data.extend_from_slice(data);
-        return self.parse_request();
+        let request = self.parse_request();
+        if self.is_error() {
+            // The handler of a streamed request sees its body cut short.
+            self.body_sender = None;
+        }
+        return request;
+    }
+
+    // The request whose body is being received, unless it was handed over
+    // already.
+    pub(crate) fn head(&self) -> Option<&Request> {
+        match self.pending() {
+            PendingRequest::Body if !self.streamed => Some(&self.request),
+            _ => None,
+        }
+    }
+
+    // Returns the request whose body is being received, to hand it over
+    // right away. The body goes to its reader as it arrives, and the
+    // request is not returned again once complete.
+    pub(crate) fn stream_body(&mut self) -> Request {
+        let (sender, reader) = BodySender::new();
+        sender.send(&mem::take(&mut self.request.body));
+        let mut request = self.request.clone();
+        request.set_body_reader(reader);
+        self.body_sender = Some(sender);
+        self.streamed = true;
+        request
+    }
+
+    // Whether the request being received was handed over before its body,
+    // which its handler answers.
+    pub(crate) fn is_streamed(&self) -> bool {
+        self.streamed
+    }
+
+    // Gives up on the body of a streamed request that takes too long.
+    pub(crate) fn abort_body(&mut self) {
+        self.body_sender = None;
+        self.state = State::Error(408);
+    }
+
+    // Passes `len` bytes of body on to the request or its reader.
+    fn take_body(&mut self, len: usize) {
+        let data = &self.data[self.parsed..self.parsed + len];
+        match self.body_sender {
+            Some(ref sender) => sender.send(data),
+            None => self.request.body.extend_from_slice(data),
+        }
+        self.parsed += len;
+    }
+
+    // Takes the data received after the requests parsed so far, when the
+    // connection switches to another protocol.
+    pub fn take_remaining(&mut self) -> Vec<u8> {
+        let remaining = self.data.split_off(self.parsed);
+        *self = RequestBuilder::with_limits(self.limits);
+        remaining
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error_code(head: &str) -> Option<u32> {
+        let mut builder = RequestBuilder::new();
+        builder.read(head.as_bytes());
+        builder.error_code()
+    }
+
+    #[test]
+    fn reads_a_request_with_a_body() {
+        let mut builder = RequestBuilder::new();
+        let r = builder.read(b"POST /a HTTP/1.1\r\nContent-Length: 2\r\n\r\nab").unwrap();
+        assert_eq!(r.body, b"ab");
+        assert_eq!(builder.error_code(), None);
+    }
+
+    #[test]
+    fn rejects_repeated_framing_headers() {
+        let head = "POST / HTTP/1.1\r\nContent-Length: 2\r\nContent-Length: 20\r\n\r\n";
+        assert_eq!(error_code(head), Some(400));
+        let head = "POST / HTTP/1.1\r\nContent-Length: 2\r\ncontent-length: 20\r\n\r\n";
+        assert_eq!(error_code(head), Some(400));
+        let head = "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\
+                    TRANSFER-ENCODING: chunked\r\n\r\n";
+        assert_eq!(error_code(head), Some(400));
+        let head = "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\
+                    content-length: 2\r\n\r\n";
+        assert_eq!(error_code(head), Some(400));
     }
 }
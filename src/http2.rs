@@ -0,0 +1,1123 @@
+use std::cmp;
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::File;
+use std::io::{self, Read};
+use std::mem;
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as BASE64URL;
+use hpack::{Decoder, Encoder};
+use connection::Output;
+use http::{BodySender, Limits, PendingRequest, Request, Response};
+
+pub const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+const FRAME_HEADER_LEN: usize = 9;
+
+// Frame types.
+const DATA: u8 = 0x0;
+const HEADERS: u8 = 0x1;
+const PRIORITY: u8 = 0x2;
+const RST_STREAM: u8 = 0x3;
+const SETTINGS: u8 = 0x4;
+const PUSH_PROMISE: u8 = 0x5;
+const PING: u8 = 0x6;
+const GOAWAY: u8 = 0x7;
+const WINDOW_UPDATE: u8 = 0x8;
+const CONTINUATION: u8 = 0x9;
+
+// Frame flags.
+const END_STREAM: u8 = 0x1;
+const ACK: u8 = 0x1;
+const END_HEADERS: u8 = 0x4;
+const PADDED: u8 = 0x8;
+const PRIORITY_FLAG: u8 = 0x20;
+
+// Settings.
+const SETTINGS_HEADER_TABLE_SIZE: u16 = 0x1;
+const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
+const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+
+// Error codes.
+const NO_ERROR: u32 = 0x0;
+const PROTOCOL_ERROR: u32 = 0x1;
+const INTERNAL_ERROR: u32 = 0x2;
+const FLOW_CONTROL_ERROR: u32 = 0x3;
+const STREAM_CLOSED: u32 = 0x5;
+const FRAME_SIZE_ERROR: u32 = 0x6;
+const REFUSED_STREAM: u32 = 0x7;
+const CANCEL: u32 = 0x8;
+const COMPRESSION_ERROR: u32 = 0x9;
+
+const DEFAULT_WINDOW: i64 = 65535;
+const MAX_WINDOW: i64 = (1 << 31) - 1;
+// The largest frame we accept, we don't raise SETTINGS_MAX_FRAME_SIZE.
+const MAX_FRAME_SIZE: usize = 16384;
+const MAX_CONCURRENT_STREAMS: usize = 100;
+
+// Headers that only make sense for HTTP/1 connections.
+const CONNECTION_HEADERS: [&str; 5] = ["connection",
+                                      "keep-alive",
+                                      "proxy-connection",
+                                      "transfer-encoding",
+                                      "upgrade"];
+
+enum Body {
+    Data(Vec<u8>),
+    File(File, u64),
+}
+
+// The response of a stream, as written by its handler. It is sent in frames
+// as the flow control windows allow.
+pub struct StreamOutput {
+    head: Option<(u32, Vec<(String, String)>)>,
+    head_sent: bool,
+    body: VecDeque<Body>,
+    // What is left of the Content-Length, if there was one.
+    remaining: Option<u64>,
+    complete: bool,
+}
+
+impl StreamOutput {
+    pub fn new() -> StreamOutput {
+        return StreamOutput {
+            head: None,
+            head_sent: false,
+            body: VecDeque::new(),
+            remaining: None,
+            complete: false,
+        };
+    }
+
+    // Sets the status and headers. Only the first response of a stream is
+    // sent, later ones only add to its body.
+    pub fn head(&mut self, status: u32, headers: &[(String, String)]) {
+        if self.head.is_some() || self.head_sent {
+            return;
+        }
+        let mut fields = Vec::new();
+        for (name, value) in headers {
+            let name = name.to_lowercase();
+            if CONNECTION_HEADERS.contains(&name.as_str()) {
+                continue;
+            }
+            if name == "content-length" {
+                self.remaining = value.parse().ok();
+            }
+            fields.push((name, value.clone()));
+        }
+        self.head = Some((status, fields));
+        if self.remaining == Some(0) {
+            self.complete = true;
+        }
+    }
+
+    // The response to a HEAD request has no body, whatever its
+    // Content-Length.
+    pub fn omit_body(&mut self) {
+        self.remaining = None;
+        self.complete = true;
+    }
+
+    pub fn data(&mut self, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        self.consumed(data.len() as u64);
+        self.body.push_back(Body::Data(data.to_vec()));
+    }
+
+    pub fn file(&mut self, file: File, len: u64) {
+        if len > 0 {
+            self.consumed(len);
+            self.body.push_back(Body::File(file, len));
+        }
+    }
+
+    // Called when the handler returns. A response without a Content-Length
+    // ends there, a response that was not started yet may still come from
+    // a timer.
+    pub fn finish(&mut self) {
+        if (self.head.is_some() || self.head_sent) && self.remaining.is_none() {
+            self.complete = true;
+        }
+    }
+
+    // Ends the stream with what was written, whatever the Content-Length.
+    pub fn abort(&mut self) {
+        self.remaining = None;
+        self.complete = true;
+    }
+
+    // Takes what was written so far, to send it while the handler goes on.
+    // What is left of the Content-Length is still counted here.
+    pub fn take_written(&mut self) -> StreamOutput {
+        let head = self.head.take();
+        self.head_sent = self.head_sent || head.is_some();
+        return StreamOutput {
+            head: head,
+            head_sent: false,
+            body: mem::take(&mut self.body),
+            remaining: self.remaining,
+            complete: self.complete,
+        };
+    }
+
+    // Adds what was written to `other`, by a handler running elsewhere, at
+    // once or in parts taken with `take_written()`.
+    pub fn append(&mut self, other: StreamOutput) {
+        if other.head.is_some() && self.head.is_none() && !self.head_sent {
+            self.head = other.head;
+        }
+        self.remaining = other.remaining;
+        let mut other_body = other.body;
+        self.body.append(&mut other_body);
+        self.complete = self.complete || other.complete;
+    }
+
+    // Whether all that was written went out in frames.
+    pub fn is_sent(&self) -> bool {
+        self.head.is_none() && self.body.is_empty()
+    }
+
+    fn consumed(&mut self, len: u64) {
+        if let Some(remaining) = self.remaining {
+            let remaining = remaining.saturating_sub(len);
+            self.remaining = Some(remaining);
+            if remaining == 0 {
+                self.complete = true;
+            }
+        }
+    }
+
+    fn is_finished(&self) -> bool {
+        self.complete && self.body.is_empty()
+    }
+
+    // Takes up to `max` bytes of the body.
+    fn next_data(&mut self, max: usize) -> io::Result<Vec<u8>> {
+        match self.body.pop_front() {
+            None => Ok(Vec::new()),
+            Some(Body::Data(mut data)) => {
+                if data.len() > max {
+                    let rest = data.split_off(max);
+                    self.body.push_front(Body::Data(rest));
+                }
+                Ok(data)
+            }
+            Some(Body::File(mut f, remaining)) => {
+                let mut buf = vec![0; cmp::min(remaining, max as u64) as usize];
+                let read = f.read(&mut buf)?;
+                if read == 0 {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                              "file shrank while being sent"));
+                }
+                buf.truncate(read);
+                let remaining = remaining - read as u64;
+                if remaining > 0 {
+                    self.body.push_front(Body::File(f, remaining));
+                }
+                Ok(buf)
+            }
+        }
+    }
+}
+
+struct Stream {
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    body_size: usize,
+    // Where the body goes once the request was handed over before it.
+    body_sender: Option<BodySender>,
+    streamed: bool,
+    // END_STREAM was received, the request is complete.
+    received: bool,
+    // END_STREAM was sent, the response is complete.
+    sent: bool,
+    window: i64,
+    out: StreamOutput,
+}
+
+impl Stream {
+    fn new(window: i64) -> Stream {
+        return Stream {
+            headers: Vec::new(),
+            body: Vec::new(),
+            body_size: 0,
+            body_sender: None,
+            streamed: false,
+            received: false,
+            sent: false,
+            window: window,
+            out: StreamOutput::new(),
+        };
+    }
+}
+
+enum Error {
+    // Ends the whole connection with GOAWAY.
+    Connection(u32),
+    // Resets one stream with RST_STREAM.
+    Stream(u32, u32),
+}
+
+// The server side of an HTTP/2 connection. It turns the frames read from
+// the client into requests, and the stream outputs written by the handlers
+// into frames.
+pub struct Http2 {
+    decoder: Decoder<'static>,
+    encoder: Encoder<'static>,
+    data: Vec<u8>,
+    preface_received: bool,
+    // The client's settings.
+    max_frame_size: usize,
+    initial_window: i64,
+    // How much the connection flow control window lets us send.
+    window: i64,
+    streams: BTreeMap<u32, Stream>,
+    last_stream: u32,
+    // A header block waiting for CONTINUATION frames: its stream, whether it
+    // ends the stream and the block so far.
+    continuation: Option<(u32, bool, Vec<u8>)>,
+    closed: bool,
+    limits: Limits,
+}
+
+impl Http2 {
+    // Starts the connection by sending our settings. The client preface is
+    // expected first in the data read.
+    pub fn new(limits: Limits, out: &mut Output) -> Http2 {
+        let http2 = Http2 {
+            decoder: Decoder::new(),
+            encoder: Encoder::new(),
+            data: Vec::new(),
+            preface_received: false,
+            max_frame_size: MAX_FRAME_SIZE,
+            initial_window: DEFAULT_WINDOW,
+            window: DEFAULT_WINDOW,
+            streams: BTreeMap::new(),
+            last_stream: 0,
+            continuation: None,
+            closed: false,
+            limits: limits,
+        };
+        let mut settings = Vec::new();
+        settings.extend_from_slice(&SETTINGS_MAX_CONCURRENT_STREAMS.to_be_bytes());
+        settings.extend_from_slice(&(MAX_CONCURRENT_STREAMS as u32).to_be_bytes());
+        write_frame(out, SETTINGS, 0, 0, &settings);
+        http2
+    }
+
+    // Switches an HTTP/1.1 connection that asked for `Upgrade: h2c`. The
+    // request that asked for it becomes stream 1, whose response is sent over
+    // HTTP/2. `settings` is the decoded HTTP2-Settings header.
+    pub fn upgrade(settings: &[u8], limits: Limits, out: &mut Output) -> Option<Http2> {
+        let mut http2 = Http2::new(limits, out);
+        if !settings.len().is_multiple_of(6) || http2.apply_settings(settings).is_err() {
+            return None;
+        }
+        let mut stream = Stream::new(http2.initial_window);
+        stream.received = true;
+        http2.streams.insert(1, stream);
+        http2.last_stream = 1;
+        Some(http2)
+    }
+
+    // Processes the frames in `data` and returns the requests that are now
+    // complete, by stream. Control frames are answered on `out`.
+    pub fn read(&mut self, data: &[u8], out: &mut Output) -> Vec<(u32, Request)> {
+        let mut requests = Vec::new();
+        if self.closed {
+            return requests;
+        }
+        self.data.extend_from_slice(data);
+        let mut pos = 0;
+        if !self.preface_received {
+            let len = cmp::min(self.data.len(), PREFACE.len());
+            if self.data[..len] != PREFACE[..len] {
+                debug!("Invalid HTTP/2 connection preface");
+                self.goaway(PROTOCOL_ERROR, out);
+                return requests;
+            }
+            if len < PREFACE.len() {
+                return requests;
+            }
+            self.preface_received = true;
+            pos = PREFACE.len();
+        }
+        while self.data.len() - pos >= FRAME_HEADER_LEN {
+            let header = &self.data[pos..pos + FRAME_HEADER_LEN];
+            let len = (header[0] as usize) << 16 | (header[1] as usize) << 8 | header[2] as usize;
+            let kind = header[3];
+            let flags = header[4];
+            let id = u32::from_be_bytes([header[5], header[6], header[7], header[8]]) & 0x7fff_ffff;
+            if len > MAX_FRAME_SIZE {
+                self.goaway(FRAME_SIZE_ERROR, out);
+                return requests;
+            }
+            if self.data.len() - pos < FRAME_HEADER_LEN + len {
+                break;
+            }
+            let start = pos + FRAME_HEADER_LEN;
+            let payload = self.data[start..start + len].to_vec();
+            pos = start + len;
+            match self.frame(kind, flags, id, &payload, out, &mut requests) {
+                Ok(()) => {}
+                Err(Error::Stream(id, code)) => self.reset(id, code, out),
+                Err(Error::Connection(code)) => {
+                    self.goaway(code, out);
+                    return requests;
+                }
+            }
+        }
+        self.data.drain(..pos);
+        requests
+    }
+
+    // The output of a stream whose response is not complete yet.
+    pub fn stream(&mut self, id: u32) -> Option<&mut StreamOutput> {
+        match self.streams.get_mut(&id) {
+            Some(stream) if !stream.sent => Some(&mut stream.out),
+            _ => None,
+        }
+    }
+
+    // Frames as much of the responses as the flow control windows allow, up
+    // to about `budget` bytes. Returns whether anything was written.
+    pub fn write(&mut self, out: &mut Output, budget: usize) -> bool {
+        // After an upgrade, wait for the client to start HTTP/2: some clients
+        // only take so much data right after the 101 response.
+        if !self.preface_received {
+            return false;
+        }
+        let mut written = 0;
+        let ids: Vec<u32> = self.streams.keys().cloned().collect();
+        for id in ids {
+            if written >= budget {
+                break;
+            }
+            match self.write_stream(id, out, budget - written) {
+                Ok(n) => written += n,
+                Err(e) => {
+                    warn!("Error while sending stream {}: {}", id, e);
+                    written += 1;
+                    self.reset(id, INTERNAL_ERROR, out);
+                }
+            }
+        }
+        let done: Vec<u32> = self.streams
+                                 .iter()
+                                 .filter(|&(_, s)| s.sent && s.received)
+                                 .map(|(id, _)| *id)
+                                 .collect();
+        for id in done {
+            self.streams.remove(&id);
+        }
+        written > 0
+    }
+
+    // The first stream whose body is being received, unless its request was
+    // handed over already.
+    pub fn receiving(&self) -> Option<u32> {
+        self.streams
+            .iter()
+            .find(|&(_, s)| !s.received && !s.streamed)
+            .map(|(id, _)| *id)
+    }
+
+    // The request of a stream whose body is being received, without it.
+    pub fn head(&self, id: u32) -> Option<Request> {
+        let stream = self.streams.get(&id)?;
+        request(&stream.headers, Vec::new())
+    }
+
+    // Returns the request of a stream whose body is being received, to hand
+    // it over right away. The body goes to its reader as it arrives, and
+    // the request is not returned again once complete.
+    pub fn stream_body(&mut self, id: u32) -> Option<Request> {
+        let stream = self.streams.get_mut(&id)?;
+        let mut r = request(&stream.headers, Vec::new())?;
+        let (sender, reader) = BodySender::new();
+        sender.send(&mem::take(&mut stream.body));
+        r.set_body_reader(reader);
+        stream.body_sender = Some(sender);
+        stream.streamed = true;
+        Some(r)
+    }
+
+    // Whether the body of a request handed over before it is still being
+    // received.
+    pub fn is_streaming(&self) -> bool {
+        self.streams.values().any(|s| s.streamed && !s.received)
+    }
+
+    // Gives up on the bodies of the requests handed over before them.
+    pub fn abort_bodies(&mut self) {
+        for stream in self.streams.values_mut() {
+            stream.body_sender = None;
+        }
+    }
+
+    // Processing while responses are not all sent, the body of a request
+    // still being received, or the headers with part of a frame.
+    pub fn pending(&self) -> PendingRequest {
+        if self.streams.values().any(|s| s.received) {
+            return PendingRequest::Processing;
+        }
+        if !self.streams.is_empty() {
+            return PendingRequest::Body;
+        }
+        if !self.data.is_empty() || self.continuation.is_some() {
+            return PendingRequest::Headers;
+        }
+        PendingRequest::None
+    }
+
+    // Writes the frames of one stream, returns how many bytes of frames.
+    fn write_stream(&mut self, id: u32, out: &mut Output, budget: usize) -> io::Result<usize> {
+        let mut written = 0;
+        let head = match self.streams.get_mut(&id) {
+            Some(stream) => stream.out.head.take(),
+            None => return Ok(0),
+        };
+        if let Some((status, fields)) = head {
+            let mut headers = vec![(b":status".to_vec(), status.to_string().into_bytes())];
+            for (name, value) in fields {
+                headers.push((name.into_bytes(), value.into_bytes()));
+            }
+            let block = self.encoder.encode(&headers);
+            let stream = self.streams.get_mut(&id).unwrap();
+            stream.out.head_sent = true;
+            let end = stream.out.is_finished();
+            let flags = if end {
+                END_STREAM
+            } else {
+                0
+            };
+            stream.sent = end;
+            written += write_headers(out, id, flags, &block, self.max_frame_size);
+        }
+        let stream = self.streams.get_mut(&id).unwrap();
+        while stream.out.head_sent && !stream.sent && written < budget {
+            if stream.out.body.is_empty() {
+                if stream.out.complete {
+                    write_frame(out, DATA, END_STREAM, id, &[]);
+                    stream.sent = true;
+                    written += FRAME_HEADER_LEN;
+                }
+                break;
+            }
+            let allowed = cmp::min(cmp::min(stream.window, self.window),
+                                   self.max_frame_size as i64);
+            if allowed <= 0 {
+                break;
+            }
+            let data = stream.out.next_data(allowed as usize)?;
+            let end = stream.out.is_finished();
+            let flags = if end {
+                END_STREAM
+            } else {
+                0
+            };
+            write_frame(out, DATA, flags, id, &data);
+            stream.window -= data.len() as i64;
+            self.window -= data.len() as i64;
+            stream.sent = end;
+            written += FRAME_HEADER_LEN + data.len();
+        }
+        Ok(written)
+    }
+
+    fn frame(&mut self,
+             kind: u8,
+             flags: u8,
+             id: u32,
+             payload: &[u8],
+             out: &mut Output,
+             requests: &mut Vec<(u32, Request)>)
+             -> Result<(), Error> {
+        if let Some((continued, _, _)) = self.continuation {
+            if kind != CONTINUATION || id != continued {
+                return Err(Error::Connection(PROTOCOL_ERROR));
+            }
+        }
+        match kind {
+            DATA => self.data_frame(flags, id, payload, out, requests),
+            HEADERS => {
+                if id == 0 || id.is_multiple_of(2) {
+                    return Err(Error::Connection(PROTOCOL_ERROR));
+                }
+                let mut block = strip_padding(flags, payload)?;
+                if flags & PRIORITY_FLAG != 0 {
+                    if block.len() < 5 {
+                        return Err(Error::Connection(FRAME_SIZE_ERROR));
+                    }
+                    block = &block[5..];
+                }
+                let end_stream = flags & END_STREAM != 0;
+                if flags & END_HEADERS != 0 {
+                    self.headers_done(id, end_stream, block, out, requests)
+                } else {
+                    self.continuation = Some((id, end_stream, block.to_vec()));
+                    Ok(())
+                }
+            }
+            CONTINUATION => {
+                let (id, end_stream, mut block) = match self.continuation.take() {
+                    Some(c) => c,
+                    None => return Err(Error::Connection(PROTOCOL_ERROR)),
+                };
+                block.extend_from_slice(payload);
+                // Header blocks split over CONTINUATION frames are buffered
+                // up to the limit.
+                if block.len() > self.limits.max_header_size {
+                    return Err(Error::Connection(PROTOCOL_ERROR));
+                }
+                if flags & END_HEADERS != 0 {
+                    self.headers_done(id, end_stream, &block, out, requests)
+                } else {
+                    self.continuation = Some((id, end_stream, block));
+                    Ok(())
+                }
+            }
+            PRIORITY => Ok(()),
+            RST_STREAM => {
+                if id == 0 {
+                    return Err(Error::Connection(PROTOCOL_ERROR));
+                }
+                self.streams.remove(&id);
+                Ok(())
+            }
+            SETTINGS => {
+                if id != 0 {
+                    return Err(Error::Connection(PROTOCOL_ERROR));
+                }
+                if flags & ACK != 0 {
+                    return Ok(());
+                }
+                if !payload.len().is_multiple_of(6) {
+                    return Err(Error::Connection(FRAME_SIZE_ERROR));
+                }
+                self.apply_settings(payload)?;
+                write_frame(out, SETTINGS, ACK, 0, &[]);
+                Ok(())
+            }
+            PUSH_PROMISE => Err(Error::Connection(PROTOCOL_ERROR)),
+            PING => {
+                if payload.len() != 8 {
+                    return Err(Error::Connection(FRAME_SIZE_ERROR));
+                }
+                if flags & ACK == 0 {
+                    write_frame(out, PING, ACK, 0, payload);
+                }
+                Ok(())
+            }
+            GOAWAY => {
+                // Finish the streams in progress, then close.
+                self.closed = true;
+                out.close();
+                Ok(())
+            }
+            WINDOW_UPDATE => {
+                if payload.len() != 4 {
+                    return Err(Error::Connection(FRAME_SIZE_ERROR));
+                }
+                let increment = u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]);
+                let increment = (increment & 0x7fff_ffff) as i64;
+                if id == 0 {
+                    if increment == 0 {
+                        return Err(Error::Connection(PROTOCOL_ERROR));
+                    }
+                    self.window += increment;
+                    if self.window > MAX_WINDOW {
+                        return Err(Error::Connection(FLOW_CONTROL_ERROR));
+                    }
+                } else if let Some(stream) = self.streams.get_mut(&id) {
+                    if increment == 0 {
+                        return Err(Error::Stream(id, PROTOCOL_ERROR));
+                    }
+                    stream.window += increment;
+                    if stream.window > MAX_WINDOW {
+                        return Err(Error::Stream(id, FLOW_CONTROL_ERROR));
+                    }
+                }
+                Ok(())
+            }
+            // Unknown frame types must be ignored.
+            _ => Ok(()),
+        }
+    }
+
+    fn data_frame(&mut self,
+                  flags: u8,
+                  id: u32,
+                  payload: &[u8],
+                  out: &mut Output,
+                  requests: &mut Vec<(u32, Request)>)
+                  -> Result<(), Error> {
+        if id == 0 {
+            return Err(Error::Connection(PROTOCOL_ERROR));
+        }
+        let data = strip_padding(flags, payload)?;
+        // Give back what the frame used of the receive windows right away:
+        // bodies are buffered until the request is complete anyway.
+        if !payload.is_empty() {
+            write_window_update(out, 0, payload.len());
+        }
+        let end_stream = flags & END_STREAM != 0;
+        match self.streams.get_mut(&id) {
+            Some(stream) if !stream.received => {
+                stream.body_size += data.len();
+                if stream.body_size > self.limits.max_body_size && stream.streamed {
+                    // The handler has the response, it sees the body cut
+                    // short.
+                    debug!("Request body over {} bytes", self.limits.max_body_size);
+                    return Err(Error::Stream(id, CANCEL));
+                }
+                if stream.body_size > self.limits.max_body_size {
+                    // Answered right away, the rest of the body gets the
+                    // stream reset.
+                    debug!("Request body over {} bytes", self.limits.max_body_size);
+                    stream.received = true;
+                    stream.body = Vec::new();
+                    Response::for_stream(&mut stream.out).set_content_too_large().send();
+                    stream.out.finish();
+                    return Ok(());
+                }
+                match stream.body_sender {
+                    Some(ref sender) => sender.send(data),
+                    None => stream.body.extend_from_slice(data),
+                }
+                if !end_stream && !payload.is_empty() {
+                    write_window_update(out, id, payload.len());
+                }
+            }
+            // A stream the client never opened is idle, DATA on it is a
+            // connection error (RFC 7540, 5.1).
+            None if id > self.last_stream => return Err(Error::Connection(PROTOCOL_ERROR)),
+            _ => return Err(Error::Stream(id, STREAM_CLOSED)),
+        }
+        if end_stream {
+            self.request_done(id, out, requests);
+        }
+        Ok(())
+    }
+
+    fn headers_done(&mut self,
+                    id: u32,
+                    end_stream: bool,
+                    block: &[u8],
+                    out: &mut Output,
+                    requests: &mut Vec<(u32, Request)>)
+                    -> Result<(), Error> {
+        // Decoded even if the stream is refused, to keep the HPACK state
+        // in sync with the client.
+        let headers = match self.decoder.decode(block) {
+            Ok(headers) => headers,
+            Err(_) => return Err(Error::Connection(COMPRESSION_ERROR)),
+        };
+        let headers = headers.into_iter()
+                             .map(|(n, v)| {
+                                 (String::from_utf8_lossy(&n).into_owned(),
+                                  String::from_utf8_lossy(&v).into_owned())
+                             })
+                             .collect();
+        if self.streams.contains_key(&id) {
+            // Trailers, which we ignore.
+            if self.streams[&id].received {
+                return Err(Error::Stream(id, STREAM_CLOSED));
+            }
+            if !end_stream {
+                return Err(Error::Stream(id, PROTOCOL_ERROR));
+            }
+        } else {
+            if id <= self.last_stream {
+                return Err(Error::Connection(PROTOCOL_ERROR));
+            }
+            self.last_stream = id;
+            if self.closed || self.streams.len() >= MAX_CONCURRENT_STREAMS {
+                return Err(Error::Stream(id, REFUSED_STREAM));
+            }
+            let mut stream = Stream::new(self.initial_window);
+            stream.headers = headers;
+            self.streams.insert(id, stream);
+        }
+        if end_stream {
+            self.request_done(id, out, requests);
+        }
+        Ok(())
+    }
+
+    fn request_done(&mut self, id: u32, out: &mut Output, requests: &mut Vec<(u32, Request)>) {
+        let stream = self.streams.get_mut(&id).unwrap();
+        stream.received = true;
+        if stream.streamed {
+            // The request was handed over already.
+            if let Some(sender) = stream.body_sender.take() {
+                sender.finish();
+            }
+            return;
+        }
+        let headers = mem::take(&mut stream.headers);
+        if !headers.iter().any(|&(ref n, _)| n == ":method") ||
+           !headers.iter().any(|&(ref n, _)| n == ":path") {
+            self.reset(id, PROTOCOL_ERROR, out);
+            return;
+        }
+        match request(&headers, mem::take(&mut stream.body)) {
+            Some(request) => requests.push((id, request)),
+            None => {
+                debug!("Unsupported method in stream {}", id);
+                Response::for_stream(&mut stream.out).set_bad_request().send();
+                stream.out.finish();
+            }
+        }
+    }
+
+    fn apply_settings(&mut self, payload: &[u8]) -> Result<(), Error> {
+        for setting in payload.chunks(6) {
+            let id = u16::from_be_bytes([setting[0], setting[1]]);
+            let value = u32::from_be_bytes([setting[2], setting[3], setting[4], setting[5]]);
+            match id {
+                SETTINGS_INITIAL_WINDOW_SIZE => {
+                    if value as i64 > MAX_WINDOW {
+                        return Err(Error::Connection(FLOW_CONTROL_ERROR));
+                    }
+                    let delta = value as i64 - self.initial_window;
+                    self.initial_window = value as i64;
+                    for stream in self.streams.values_mut() {
+                        stream.window += delta;
+                    }
+                }
+                SETTINGS_MAX_FRAME_SIZE => {
+                    if !(16384..=16_777_215).contains(&value) {
+                        return Err(Error::Connection(PROTOCOL_ERROR));
+                    }
+                    self.max_frame_size = value as usize;
+                }
+                // Our encoder never uses more than the default 4096 bytes of
+                // dynamic table.
+                SETTINGS_HEADER_TABLE_SIZE => {}
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn reset(&mut self, id: u32, code: u32, out: &mut Output) {
+        self.streams.remove(&id);
+        write_frame(out, RST_STREAM, 0, id, &code.to_be_bytes());
+    }
+
+    fn goaway(&mut self, code: u32, out: &mut Output) {
+        let mut payload = Vec::new();
+        payload.extend_from_slice(&self.last_stream.to_be_bytes());
+        payload.extend_from_slice(&code.to_be_bytes());
+        write_frame(out, GOAWAY, 0, 0, &payload);
+        if code != NO_ERROR {
+            debug!("HTTP/2 connection error {}", code);
+        }
+        self.streams.clear();
+        self.closed = true;
+        out.close();
+    }
+}
+
+fn strip_padding(flags: u8, payload: &[u8]) -> Result<&[u8], Error> {
+    if flags & PADDED == 0 {
+        return Ok(payload);
+    }
+    if payload.is_empty() || payload[0] as usize >= payload.len() {
+        return Err(Error::Connection(PROTOCOL_ERROR));
+    }
+    Ok(&payload[1..payload.len() - payload[0] as usize])
+}
+
+fn write_frame(out: &mut Output, kind: u8, flags: u8, id: u32, payload: &[u8]) {
+    let len = payload.len();
+    let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + len);
+    frame.extend_from_slice(&[(len >> 16) as u8, (len >> 8) as u8, len as u8, kind, flags]);
+    frame.extend_from_slice(&id.to_be_bytes());
+    frame.extend_from_slice(payload);
+    out.write(&frame);
+}
+
+fn write_window_update(out: &mut Output, id: u32, increment: usize) {
+    write_frame(out, WINDOW_UPDATE, 0, id, &(increment as u32).to_be_bytes());
+}
+
+// Sends a header block, split in CONTINUATION frames if needed.
+fn write_headers(out: &mut Output,
+                 id: u32,
+                 flags: u8,
+                 block: &[u8],
+                 max_frame_size: usize)
+                 -> usize {
+    let mut chunks = block.chunks(max_frame_size).peekable();
+    let mut kind = HEADERS;
+    let mut flags = flags;
+    if chunks.peek().is_none() {
+        write_frame(out, HEADERS, flags | END_HEADERS, id, &[]);
+        return FRAME_HEADER_LEN;
+    }
+    while let Some(chunk) = chunks.next() {
+        let last = if chunks.peek().is_none() {
+            END_HEADERS
+        } else {
+            0
+        };
+        write_frame(out, kind, flags | last, id, chunk);
+        // END_STREAM only goes on the HEADERS frame.
+        kind = CONTINUATION;
+        flags = 0;
+    }
+    block.len() + FRAME_HEADER_LEN
+}
+
+// Builds the request of a stream from its header fields, None without a
+// method and a path or with an unsupported method.
+fn request(fields: &[(String, String)], body: Vec<u8>) -> Option<Request> {
+    let mut method = None;
+    let mut path = None;
+    let mut headers = Vec::new();
+    for &(ref name, ref value) in fields {
+        match name.as_str() {
+            ":method" => method = Some(value),
+            ":path" => path = Some(value),
+            ":authority" => headers.push(("host".to_string(), value.clone())),
+            ":scheme" => {}
+            _ => headers.push((name.clone(), value.clone())),
+        }
+    }
+    Request::from_parts(method?, path?, "HTTP/2.0", headers, body)
+}
+
+// Decodes the HTTP2-Settings header of an h2c upgrade, in base64url. The
+// padding should be left out, it is tolerated.
+pub fn decode_settings(value: &str) -> Option<Vec<u8>> {
+    BASE64URL.decode(value.trim().trim_end_matches('=')).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(kind: u8, flags: u8, id: u32, payload: &[u8]) -> Vec<u8> {
+        let mut out = Output::new();
+        write_frame(&mut out, kind, flags, id, payload);
+        out.queued_data()
+    }
+
+    fn header_block(fields: &[(&str, &str)]) -> Vec<u8> {
+        let fields = fields.iter()
+                           .map(|&(n, v)| (n.as_bytes().to_vec(), v.as_bytes().to_vec()))
+                           .collect();
+        Encoder::new().encode(&fields)
+    }
+
+    // The frames written to `out` since the last call, as (kind, flags,
+    // stream, payload).
+    fn written(out: &mut Output) -> Vec<(u8, u8, u32, Vec<u8>)> {
+        let data = out.take_queued().queued_data();
+        let mut frames = Vec::new();
+        let mut pos = 0;
+        while pos < data.len() {
+            let header = &data[pos..pos + FRAME_HEADER_LEN];
+            let len = (header[0] as usize) << 16 | (header[1] as usize) << 8 | header[2] as usize;
+            let id = u32::from_be_bytes([header[5], header[6], header[7], header[8]]);
+            let start = pos + FRAME_HEADER_LEN;
+            frames.push((header[3], header[4], id, data[start..start + len].to_vec()));
+            pos = start + len;
+        }
+        frames
+    }
+
+    // A connection past the preface and the settings exchange.
+    fn connection() -> (Http2, Output) {
+        let mut out = Output::new();
+        let mut http2 = Http2::new(Limits::new(), &mut out);
+        let mut data = PREFACE.to_vec();
+        data.extend_from_slice(&frame(SETTINGS, 0, 0, &[]));
+        assert!(http2.read(&data, &mut out).is_empty());
+        let frames = written(&mut out);
+        assert_eq!(frames[0].0, SETTINGS);
+        assert_eq!(frames[1], (SETTINGS, ACK, 0, Vec::new()));
+        (http2, out)
+    }
+
+    fn get(path: &str) -> Vec<u8> {
+        header_block(&[(":method", "GET"),
+                       (":scheme", "https"),
+                       (":path", path),
+                       (":authority", "example.com")])
+    }
+
+    #[test]
+    fn decodes_requests() {
+        let (mut http2, mut out) = connection();
+        let block = header_block(&[(":method", "GET"),
+                                   (":scheme", "https"),
+                                   (":path", "/a?b=1"),
+                                   (":authority", "example.com"),
+                                   ("accept", "*/*")]);
+        let requests = http2.read(&frame(HEADERS, END_HEADERS | END_STREAM, 1, &block),
+                                  &mut out);
+        assert_eq!(requests.len(), 1);
+        let (id, ref r) = requests[0];
+        assert_eq!(id, 1);
+        assert_eq!(r.method(), "GET");
+        assert_eq!(r.uri, "/a");
+        assert_eq!(r.query(), Some("b=1"));
+        assert_eq!(r.version(), "HTTP/2.0");
+        assert_eq!(r.header("Host"), Some("example.com"));
+        assert_eq!(r.header("accept"), Some("*/*"));
+    }
+
+    #[test]
+    fn headers_continue_and_bodies_follow() {
+        let (mut http2, mut out) = connection();
+        let block = header_block(&[(":method", "POST"), (":path", "/upload")]);
+        let (first, rest) = block.split_at(block.len() / 2);
+        let mut data = frame(HEADERS, 0, 3, first);
+        data.extend_from_slice(&frame(CONTINUATION, END_HEADERS, 3, rest));
+        data.extend_from_slice(&frame(DATA, 0, 3, b"hello "));
+        assert!(http2.read(&data, &mut out).is_empty());
+        // Padded: the length, the data, then the padding.
+        let requests = http2.read(&frame(DATA, END_STREAM | PADDED, 3, b"\x02world\0\0"),
+                                  &mut out);
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].1.method(), "POST");
+        assert_eq!(requests[0].1.body(), b"hello world");
+        // The windows given back for the data received.
+        let updates: Vec<_> = written(&mut out)
+                                  .into_iter()
+                                  .filter(|f| f.0 == WINDOW_UPDATE)
+                                  .map(|f| (f.2, f.3))
+                                  .collect();
+        assert_eq!(updates,
+                   vec![(0, 6u32.to_be_bytes().to_vec()),
+                        (3, 6u32.to_be_bytes().to_vec()),
+                        (0, 8u32.to_be_bytes().to_vec())]);
+    }
+
+    #[test]
+    fn frames_between_continuations_end_the_connection() {
+        let (mut http2, mut out) = connection();
+        let mut data = frame(HEADERS, 0, 1, &get("/"));
+        data.extend_from_slice(&frame(PING, 0, 0, &[0; 8]));
+        http2.read(&data, &mut out);
+        let frames = written(&mut out);
+        assert_eq!(frames.last().unwrap().0, GOAWAY);
+        assert_eq!(&frames.last().unwrap().3[4..], &PROTOCOL_ERROR.to_be_bytes());
+        assert!(out.is_closing());
+    }
+
+    #[test]
+    fn requests_without_a_path_are_reset() {
+        let (mut http2, mut out) = connection();
+        let block = header_block(&[(":method", "GET")]);
+        assert!(http2.read(&frame(HEADERS, END_HEADERS | END_STREAM, 1, &block), &mut out)
+                     .is_empty());
+        assert_eq!(written(&mut out),
+                   vec![(RST_STREAM, 0, 1, PROTOCOL_ERROR.to_be_bytes().to_vec())]);
+    }
+
+    #[test]
+    fn data_on_idle_and_closed_streams() {
+        let (mut http2, mut out) = connection();
+        http2.read(&frame(HEADERS, END_HEADERS | END_STREAM, 1, &get("/")), &mut out);
+        http2.read(&frame(DATA, 0, 1, b"late"), &mut out);
+        let frames = written(&mut out);
+        assert_eq!(frames.last().unwrap(),
+                   &(RST_STREAM, 0, 1, STREAM_CLOSED.to_be_bytes().to_vec()));
+        assert!(!out.is_closing());
+        // Stream 5 was never opened.
+        http2.read(&frame(DATA, 0, 5, b"early"), &mut out);
+        let frames = written(&mut out);
+        let mut goaway = 1u32.to_be_bytes().to_vec();
+        goaway.extend_from_slice(&PROTOCOL_ERROR.to_be_bytes());
+        assert_eq!(frames.last().unwrap(), &(GOAWAY, 0, 0, goaway));
+        assert!(out.is_closing());
+    }
+
+    #[test]
+    fn pings_are_answered() {
+        let (mut http2, mut out) = connection();
+        http2.read(&frame(PING, 0, 0, b"12345678"), &mut out);
+        http2.read(&frame(PING, ACK, 0, b"12345678"), &mut out);
+        assert_eq!(written(&mut out), vec![(PING, ACK, 0, b"12345678".to_vec())]);
+        http2.read(&frame(PING, 0, 0, b"1234"), &mut out);
+        assert_eq!(&written(&mut out)[0].3[4..], &FRAME_SIZE_ERROR.to_be_bytes());
+    }
+
+    #[test]
+    fn invalid_preface() {
+        let mut out = Output::new();
+        let mut http2 = Http2::new(Limits::new(), &mut out);
+        written(&mut out);
+        assert!(http2.read(b"PRI * HTTP/2.0\r\n", &mut out).is_empty());
+        assert!(written(&mut out).is_empty());
+        http2.read(b"\r\nXX", &mut out);
+        assert_eq!(written(&mut out)[0].0, GOAWAY);
+        assert!(out.is_closing());
+    }
+
+    #[test]
+    fn encodes_responses() {
+        let (mut http2, mut out) = connection();
+        http2.read(&frame(HEADERS, END_HEADERS | END_STREAM, 1, &get("/")), &mut out);
+        {
+            let stream = http2.stream(1).unwrap();
+            stream.head(200,
+                        &[("Content-Length".to_string(), "5".to_string()),
+                          ("Connection".to_string(), "close".to_string())]);
+            stream.data(b"hello");
+        }
+        assert!(http2.write(&mut out, 1 << 20));
+        let frames = written(&mut out);
+        assert_eq!(frames.len(), 2);
+        assert_eq!((frames[0].0, frames[0].1, frames[0].2), (HEADERS, END_HEADERS, 1));
+        let headers = Decoder::new().decode(&frames[0].3).unwrap();
+        assert_eq!(headers,
+                   vec![(b":status".to_vec(), b"200".to_vec()),
+                        (b"content-length".to_vec(), b"5".to_vec())]);
+        assert_eq!(frames[1], (DATA, END_STREAM, 1, b"hello".to_vec()));
+        assert!(http2.stream(1).is_none());
+    }
+
+    #[test]
+    fn responses_wait_for_the_window() {
+        let (mut http2, mut out) = connection();
+        let mut settings = SETTINGS_INITIAL_WINDOW_SIZE.to_be_bytes().to_vec();
+        settings.extend_from_slice(&3u32.to_be_bytes());
+        http2.read(&frame(SETTINGS, 0, 0, &settings), &mut out);
+        http2.read(&frame(HEADERS, END_HEADERS | END_STREAM, 1, &get("/")), &mut out);
+        {
+            let stream = http2.stream(1).unwrap();
+            stream.head(200, &[]);
+            stream.data(b"hello");
+            stream.finish();
+        }
+        written(&mut out);
+        http2.write(&mut out, 1 << 20);
+        let frames = written(&mut out);
+        assert_eq!(frames[1], (DATA, 0, 1, b"hel".to_vec()));
+        assert!(!http2.write(&mut out, 1 << 20));
+        http2.read(&frame(WINDOW_UPDATE, 0, 1, &10u32.to_be_bytes()), &mut out);
+        http2.write(&mut out, 1 << 20);
+        assert_eq!(written(&mut out), vec![(DATA, END_STREAM, 1, b"lo".to_vec())]);
+    }
+
+    #[test]
+    fn decodes_upgrade_settings() {
+        let settings = vec![0, 4, 0, 0, 0xff, 0xff, 0, 5, 0, 0, 0x40, 0];
+        assert_eq!(decode_settings("AAQAAP__AAUAAEAA"), Some(settings.clone()));
+        assert_eq!(decode_settings(" AAQAAP__AAUAAEAA=\r\n"), Some(settings));
+        assert_eq!(decode_settings("AAQAAP//AAUAAEAA"), None);
+        let mut out = Output::new();
+        assert!(Http2::upgrade(&[0, 4, 0, 0, 0xff], Limits::new(), &mut out).is_none());
+        let http2 = Http2::upgrade(&[0, 4, 0, 0, 0xff, 0xff], Limits::new(), &mut out).unwrap();
+        assert_eq!(http2.initial_window, 0xffff);
+        assert_eq!(http2.pending(), PendingRequest::Processing);
+    }
+}
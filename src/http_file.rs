@@ -1,58 +1,981 @@
-use std::io::prelude::*;
+use cache_control::CachePolicies;
+use compression::{self, Compression, Encoding};
+use date::{http_date, parse_http_date};
 use http::*;
+use mime::MimeTypes;
+use util::{escape_html, percent_decode, percent_encode};
+use std::collections::{BTreeMap, HashMap};
 use std::fs::*;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::slice;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+// The encodings of the sidecar files, by order of preference.
+const SIDECARS: [Encoding; 2] = [Encoding::Brotli, Encoding::Gzip];
+
+#[derive(Clone)]
 pub struct FileSystem {
     path: String,
+    precompressed: bool,
+    listing: bool,
+    mime_types: Arc<MimeTypes>,
+    cache: Option<FileCache>,
+    cache_policies: Option<Arc<CachePolicies>>,
 }
 impl FileSystem {
     pub fn new(path: &str) -> FileSystem {
-        FileSystem { path: path.to_string() }
+        FileSystem {
+            path: path.to_string(),
+            precompressed: true,
+            listing: false,
+            mime_types: Arc::new(MimeTypes::new()),
+            cache: None,
+            cache_policies: None,
+        }
+    }
+
+    // The caching headers of the files, by their path.
+    pub fn set_cache_policies(&mut self, policies: CachePolicies) {
+        self.cache_policies = Some(Arc::new(policies));
+    }
+
+    // Keeps the small files served in `cache`, which can be shared with
+    // other file systems.
+    pub fn set_cache(&mut self, cache: FileCache) {
+        self.cache = Some(cache);
+    }
+
+    // The types the files are served with.
+    pub fn set_mime_types(&mut self, mime_types: MimeTypes) {
+        self.mime_types = Arc::new(mime_types);
+    }
+
+    // Serves the files with extension `ext` as `mime`, whatever the types
+    // say.
+    pub fn add_mime_type(&mut self, ext: &str, mime: &str) {
+        Arc::make_mut(&mut self.mime_types).add(ext, mime);
+    }
+
+    // Whether "x.js.br" or "x.js.gz" is served for "x.js" to the clients
+    // that accept it, when it is there and not older.
+    pub fn set_precompressed(&mut self, precompressed: bool) {
+        self.precompressed = precompressed;
+    }
+
+    // Whether the directories without an index.html get a page listing
+    // their files.
+    pub fn set_listing(&mut self, listing: bool) {
+        self.listing = listing;
     }
-    pub fn serve(&mut self, uri: &str, resp: &mut Response) {
+
+    // Serves the file at `uri` under the root, or the index.html of the
+    // directory there.
+    pub fn serve(&mut self, uri: &str, req: &Request, resp: &mut Response) {
+        // The files outside the root are not found, whatever the URI says.
+        let path = match percent_decode(uri) {
+            Some(path) if is_under_root(&path) => path,
+            _ => {
+                debug!("Invalid path: {}", uri);
+                resp.set_not_found().send();
+                return;
+            }
+        };
         let mut full_path = self.path.clone();
-        full_path.push_str(uri);
+        full_path.push_str(&path);
+        if let Some(ref policies) = self.cache_policies {
+            policies.apply(req, resp);
+        }
+        if let Some(ref cache) = self.cache {
+            if let Some(entry) = cache.get(&full_path) {
+                entry.serve(req, resp);
+                return;
+            }
+        }
         if let Ok(m) = metadata(&full_path) {
+            if m.is_dir() && !path.is_empty() {
+                self.serve_dir(uri, &path, &full_path, req, resp);
+                return;
+            }
             if m.is_file() {
-                if let Ok(ref mut f) = File::open(&full_path) {
+                if let Some(ref cache) = self.cache {
+                    let loaded = cache.prepare(&full_path).and_then(|changes| {
+                        Some((self.load(&full_path, &m, cache)?, changes))
+                    });
+                    if let Some((entry, changes)) = loaded {
+                        cache.insert(&full_path, entry, changes).serve(req, resp);
+                        return;
+                    }
+                }
+                if self.precompressed && self.serve_sidecar(&full_path, &m, req, resp) {
+                    return;
+                }
+                if let Ok(f) = File::open(&full_path) {
+                    let modified = m.modified().ok();
+                    if not_modified(req, resp, &identity_tags(&etag(&m, None)), modified) {
+                        return;
+                    }
                     resp.set_status(Status::ok());
-                    resp.set_header("Content-Type", Self::get_mime(&full_path));
+                    resp.set_header("Content-Type", &self.mime_types.content_type(&full_path));
+                    resp.set_header("ETag", &etag(&m, None));
+                    set_last_modified(resp, modified);
                     resp.set_length(m.len());
                     resp.send();
-                    let buf_size = 10 * 1024 * 1024; // 10MB;
-                    let mut buf: Vec<u8> = Vec::with_capacity(buf_size);
-                    buf.resize(buf_size, 0);
-                    while let Ok(n) = f.read(&mut buf) {
-                        if n > 0 {
-                            resp.send_data(&buf[0..n]);
+                    resp.send_file(f, m.len());
+                    return;
+                }
+            }
+        }
+        debug!("Not found: {}", full_path);
+        resp.set_not_found().send();
+    }
+
+    // Serves the index.html of a directory, or else its listing. The URI
+    // must end with a slash for the relative links to work, clients are
+    // redirected to it first.
+    fn serve_dir(&mut self,
+                 uri: &str,
+                 path: &str,
+                 full_path: &str,
+                 req: &Request,
+                 resp: &mut Response) {
+        if !path.ends_with('/') {
+            let location = match req.query() {
+                Some(query) => format!("{}/?{}", uri, query),
+                None => format!("{}/", uri),
+            };
+            resp.set_redirect(Status::moved_permanently(), &location).send();
+            return;
+        }
+        if metadata(format!("{}index.html", full_path)).is_ok_and(|m| m.is_file()) {
+            self.serve(&format!("{}index.html", uri), req, resp);
+            return;
+        }
+        if !self.listing {
+            resp.set_not_found().send();
+            return;
+        }
+        match listing(full_path, path) {
+            Ok(page) => {
+                resp.set_header("Content-Type", "text/html; charset=utf-8")
+                    .set_body(page.as_bytes())
+                    .send();
+            }
+            Err(e) => {
+                debug!("Can't list {}: {}", full_path, e);
+                resp.set_not_found().send();
+            }
+        }
+    }
+
+    // Serves the sidecar of the file the client prefers, if there is one.
+    // Returns false when the file itself is to be served.
+    fn serve_sidecar(&self,
+                     full_path: &str,
+                     m: &Metadata,
+                     req: &Request,
+                     resp: &mut Response)
+                     -> bool {
+        let sidecars: Vec<(Encoding, Metadata)> =
+            SIDECARS.iter()
+                    .filter_map(|&encoding| {
+                        let sidecar = metadata(sidecar_path(full_path, encoding)).ok()?;
+                        if sidecar.is_file() && is_fresh(&sidecar, m) {
+                            Some((encoding, sidecar))
                         } else {
-                            break;
+                            None
                         }
+                    })
+                    .collect();
+        if sidecars.is_empty() {
+            return false;
+        }
+        // The file is served either way, its representation depends on the
+        // client.
+        resp.set_header("Vary", "Accept-Encoding");
+        let encodings: Vec<Encoding> = sidecars.iter().map(|&(encoding, _)| encoding).collect();
+        let encoding = match compression::negotiate(req, &encodings) {
+            Some(encoding) => encoding,
+            None => return false,
+        };
+        let sidecar = &sidecars.iter().find(|&&(e, _)| e == encoding).unwrap().1;
+        let f = match File::open(sidecar_path(full_path, encoding)) {
+            Ok(f) => f,
+            Err(_) => return false,
+        };
+        let tag = etag(m, Some(encoding));
+        if not_modified(req, resp, slice::from_ref(&tag), m.modified().ok()) {
+            return true;
+        }
+        resp.set_status(Status::ok());
+        resp.set_header("Content-Type", &self.mime_types.content_type(full_path));
+        resp.set_header("Content-Encoding", encoding.name());
+        resp.set_header("ETag", &tag);
+        set_last_modified(resp, m.modified().ok());
+        resp.set_length(sidecar.len());
+        resp.send();
+        resp.send_file(f, sidecar.len());
+        true
+    }
+
+    // Reads a file to cache, with its sidecars or the variants the cache
+    // compresses. None if the file can't be cached.
+    fn load(&self, full_path: &str, m: &Metadata, cache: &FileCache) -> Option<Entry> {
+        if m.len() > cache.max_file_size {
+            return None;
+        }
+        let modified = m.modified().ok()?;
+        let content_type = self.mime_types.content_type(full_path);
+        let body = read_file(full_path, m.len())?;
+        // The file may have changed after `m` and before it was watched.
+        let read = metadata(full_path).ok()?;
+        if read.len() != m.len() || read.modified().ok() != Some(modified) {
+            return None;
+        }
+        let mut variants = Vec::new();
+        if self.precompressed {
+            for &encoding in &SIDECARS {
+                let path = sidecar_path(full_path, encoding);
+                let sidecar = match metadata(&path) {
+                    Ok(ref sidecar) if sidecar.is_file() && is_fresh(sidecar, m) => sidecar.len(),
+                    _ => continue,
+                };
+                if let Some(body) = read_file(&path, sidecar) {
+                    variants.push(Variant {
+                        encoding: encoding,
+                        body: body,
+                        etag: etag(m, Some(encoding)),
+                    });
+                }
+            }
+        }
+        if let Some(ref compression) = cache.compression {
+            if compression.applies(Some(&content_type), m.len()) {
+                for &encoding in compression.encodings() {
+                    if variants.iter().any(|v| v.encoding == encoding) {
+                        continue;
+                    }
+                    let compressed = compression::compress(compression.encoder(encoding), &body);
+                    if compressed.len() < body.len() {
+                        variants.push(Variant {
+                            encoding: encoding,
+                            body: compressed,
+                            etag: etag(m, Some(encoding)),
+                        });
                     }
-                    return;
                 }
             }
         }
-        println!("Not found: {}", full_path);
-        resp.set_not_found().send();
+        Some(Entry {
+            content_type: content_type,
+            etag: etag(m, None),
+            modified: modified,
+            len: m.len(),
+            body: body,
+            variants: variants,
+        })
     }
 
-    fn get_mime(path: &str) -> &str {
-        if let Some(idx) = path.rfind('.') {
-            let ext = &path[idx + 1..];
-            match ext {
-                "html" => "text/html",
-                "css" => "text/css",
-                "js" => "text/javascript",
-                "jpg" | "jpeg" => "image/jpeg",
-                "png" => "image/png",
-                "svg" => "image/svg+xml",
-                "woff" | "woff2" => "application/x-font-woff",
-                _ => "application/octet-stream",
+    // Writes the sidecars that are missing or older than their file, for the
+    // files under the root `compression` applies to and each of its
+    // encodings. Sidecars that would not be smaller are left out. Returns
+    // how many were written.
+    pub fn precompress(&self, compression: &Compression) -> io::Result<usize> {
+        let mut written = 0;
+        let mut dirs = vec![PathBuf::from(&self.path)];
+        while let Some(dir) = dirs.pop() {
+            if dir.is_file() {
+                written += self.precompress_file(&dir, compression)?;
+                continue;
             }
-        } else {
-            // Default to binary data.
-            "application/octet-stream"
+            for entry in read_dir(&dir)? {
+                let path = entry?.path();
+                if path.is_dir() {
+                    dirs.push(path);
+                } else if !is_sidecar(&path) {
+                    written += self.precompress_file(&path, compression)?;
+                }
+            }
+        }
+        Ok(written)
+    }
+
+    fn precompress_file(&self, path: &Path, compression: &Compression) -> io::Result<usize> {
+        let name = match path.to_str() {
+            Some(name) => name,
+            None => return Ok(0),
+        };
+        let m = metadata(path)?;
+        if !compression.applies(Some(&self.mime_types.content_type(name)), m.len()) {
+            return Ok(0);
+        }
+        let mut data = None;
+        let mut written = 0;
+        for &encoding in compression.encodings() {
+            let sidecar = sidecar_path(name, encoding);
+            if metadata(&sidecar).is_ok_and(|s| is_fresh(&s, &m)) {
+                continue;
+            }
+            if data.is_none() {
+                let mut buf = Vec::new();
+                File::open(path)?.read_to_end(&mut buf)?;
+                data = Some(buf);
+            }
+            let data = data.as_ref().unwrap();
+            let body = compression::compress(compression.encoder(encoding), data);
+            if body.len() >= data.len() {
+                continue;
+            }
+            // Written aside first, a sidecar is never served half done.
+            let tmp = format!("{}.tmp", sidecar);
+            File::create(&tmp)?.write_all(&body)?;
+            rename(&tmp, &sidecar)?;
+            written += 1;
+        }
+        Ok(written)
+    }
+}
+
+// Tags a file by its modification time and size, and the encoding of its
+// sidecar.
+fn etag(m: &Metadata, encoding: Option<Encoding>) -> String {
+    let mtime = m.modified()
+                 .ok()
+                 .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                 .map_or(0, |d| d.as_secs());
+    match encoding {
+        Some(encoding) => format!("\"{:x}-{:x}-{}\"", mtime, m.len(), encoding.name()),
+        None => format!("\"{:x}-{:x}\"", mtime, m.len()),
+    }
+}
+
+// The tags of a file served unencoded, which the response can still be
+// compressed on the way with, and tagged by encoding then.
+fn identity_tags(etag: &str) -> Vec<String> {
+    let mut tags = vec![etag.to_string()];
+    if let Some(tag) = etag.strip_suffix('"') {
+        tags.extend(SIDECARS.iter().map(|encoding| format!("{}-{}\"", tag, encoding.name())));
+    }
+    tags
+}
+
+// Answers 304 if the client has the file already: it sends one of `tags`
+// in If-None-Match, or else the file is not newer than If-Modified-Since.
+// Returns whether it did.
+fn not_modified(req: &Request,
+                resp: &mut Response,
+                tags: &[String],
+                modified: Option<SystemTime>)
+                -> bool {
+    let matched = match req.header("If-None-Match") {
+        Some(value) => {
+            value.split(',')
+                 .map(|t| t.trim())
+                 .map(|t| t.strip_prefix("W/").unwrap_or(t))
+                 .find_map(|t| {
+                     if t == "*" {
+                         tags.first()
+                     } else {
+                         tags.iter().find(|&tag| tag == t)
+                     }
+                 })
+        }
+        None => {
+            let since = req.header("If-Modified-Since").and_then(parse_http_date);
+            let secs = |t: SystemTime| t.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+            match (modified, since) {
+                (Some(modified), Some(since)) if secs(modified) <= secs(since) => tags.first(),
+                _ => None,
+            }
+        }
+    };
+    let tag = match matched {
+        Some(tag) => tag,
+        None => return false,
+    };
+    resp.set_status(Status::not_modified());
+    resp.set_header("ETag", tag);
+    set_last_modified(resp, modified);
+    resp.send();
+    true
+}
+
+fn set_last_modified(resp: &mut Response, modified: Option<SystemTime>) {
+    if let Some(modified) = modified {
+        resp.set_header("Last-Modified", &http_date(modified));
+    }
+}
+
+// Whether a decoded path stays under the root it is appended to. The empty
+// one is the root itself, for the file systems of a single file.
+fn is_under_root(path: &str) -> bool {
+    path.is_empty() ||
+    (path.starts_with('/') && !path.contains('\0') &&
+     !path.split('/').any(|s| s == ".." || s == "."))
+}
+
+// A page linking to the entries of the directory `full_path`, whose path
+// is `path`.
+fn listing(full_path: &str, path: &str) -> io::Result<String> {
+    let mut names = Vec::new();
+    for entry in read_dir(full_path)? {
+        let entry = entry?;
+        let mut name = entry.file_name().to_string_lossy().into_owned();
+        if entry.path().is_dir() {
+            name.push('/');
+        }
+        names.push(name);
+    }
+    names.sort();
+    let title = escape_html(path);
+    let mut page = format!("<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\">\
+                            <title>Index of {}</title></head><body>\n\
+                            <h1>Index of {}</h1>\n<ul>\n",
+                           title,
+                           title);
+    if path != "/" {
+        page.push_str("<li><a href=\"../\">../</a></li>\n");
+    }
+    for name in names {
+        page.push_str(&format!("<li><a href=\"{}\">{}</a></li>\n",
+                               percent_encode(&name),
+                               escape_html(&name)));
+    }
+    page.push_str("</ul>\n</body></html>\n");
+    Ok(page)
+}
+
+// The whole file, None if it isn't `len` bytes long anymore.
+fn read_file(path: &str, len: u64) -> Option<Vec<u8>> {
+    let mut body = Vec::with_capacity(len as usize);
+    File::open(path).ok()?.take(len + 1).read_to_end(&mut body).ok()?;
+    if body.len() as u64 == len {
+        Some(body)
+    } else {
+        None
+    }
+}
+
+fn sidecar_path(path: &str, encoding: Encoding) -> String {
+    format!("{}.{}", path, encoding.extension())
+}
+
+fn is_sidecar(path: &Path) -> bool {
+    let ext = path.extension().and_then(|e| e.to_str());
+    SIDECARS.iter().any(|encoding| ext == Some(encoding.extension()))
+}
+
+// A sidecar as old as its file was made from it.
+fn is_fresh(sidecar: &Metadata, m: &Metadata) -> bool {
+    match (sidecar.modified(), m.modified()) {
+        (Ok(sidecar), Ok(modified)) => sidecar >= modified,
+        _ => false,
+    }
+}
+
+/// How a `FileCache` finds out that the files it holds changed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Invalidation {
+    /// The modification time and size of a file are checked on every hit.
+    Stat,
+    /// They are checked at most once per interval, a change can go unseen
+    /// for that long.
+    Interval(Duration),
+    /// The system reports the changes, hits cost no system call. Linux
+    /// only.
+    Inotify,
+}
+
+/// The use of a `FileCache` so far.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FileCacheStats {
+    pub entries: usize,
+    pub size: usize,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+// A file in the cache, with what its responses need.
+struct Entry {
+    content_type: String,
+    etag: String,
+    modified: SystemTime,
+    len: u64,
+    body: Vec<u8>,
+    variants: Vec<Variant>,
+}
+
+// The file compressed.
+struct Variant {
+    encoding: Encoding,
+    body: Vec<u8>,
+    etag: String,
+}
+
+impl Entry {
+    fn size(&self) -> usize {
+        self.body.len() + self.variants.iter().map(|v| v.body.len()).sum::<usize>()
+    }
+
+    fn serve(&self, req: &Request, resp: &mut Response) {
+        if !self.variants.is_empty() {
+            resp.set_header("Vary", "Accept-Encoding");
+        }
+        let encodings: Vec<Encoding> = self.variants.iter().map(|v| v.encoding).collect();
+        let variant = compression::negotiate(req, &encodings)
+                          .and_then(|e| self.variants.iter().find(|v| v.encoding == e));
+        let tags = match variant {
+            Some(variant) => vec![variant.etag.clone()],
+            None => identity_tags(&self.etag),
+        };
+        if not_modified(req, resp, &tags, Some(self.modified)) {
+            return;
+        }
+        resp.set_status(Status::ok());
+        resp.set_header("Content-Type", &self.content_type);
+        set_last_modified(resp, Some(self.modified));
+        match variant {
+            Some(variant) => {
+                resp.set_header("Content-Encoding", variant.encoding.name());
+                resp.set_header("ETag", &variant.etag);
+                resp.set_body(&variant.body);
+            }
+            None => {
+                resp.set_header("ETag", &self.etag);
+                resp.set_body(&self.body);
+            }
+        }
+        resp.send();
+    }
+}
+
+struct Slot {
+    entry: Arc<Entry>,
+    // When it was last used, in cache lookups.
+    used: u64,
+    checked: Instant,
+}
+
+struct Cache {
+    slots: HashMap<String, Slot>,
+    // The paths of the slots, by when they were last used.
+    lru: BTreeMap<u64, String>,
+    size: usize,
+    tick: u64,
+    hits: u64,
+    misses: u64,
+    // The inotify instance and the directories it watches, by descriptor,
+    // and the number of changes it reported.
+    inotify: Option<inotify::Inotify>,
+    watches: HashMap<i32, PathBuf>,
+    changes: u64,
+}
+
+impl Cache {
+    // Drops the file at `key`.
+    fn remove_slot(&mut self, key: &str) -> Option<Slot> {
+        let slot = self.slots.remove(key)?;
+        self.lru.remove(&slot.used);
+        self.size -= slot.entry.size();
+        Some(slot)
+    }
+
+    // Drops the files at `path`, or under it.
+    fn remove(&mut self, path: &Path) {
+        let keys: Vec<String> = self.slots
+                                    .keys()
+                                    .filter(|key| Path::new(key).starts_with(path))
+                                    .cloned()
+                                    .collect();
+        for key in keys {
+            self.remove_slot(&key);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.slots.clear();
+        self.lru.clear();
+        self.size = 0;
+    }
+
+    // Handles an inotify event.
+    fn changed(&mut self, wd: i32, mask: u32, name: &str) {
+        self.changes += 1;
+        if mask & libc::IN_Q_OVERFLOW != 0 {
+            // Events were lost.
+            self.clear();
+            return;
+        }
+        let dir = match self.watches.get(&wd) {
+            Some(dir) => dir.clone(),
+            None => return,
+        };
+        if mask & libc::IN_IGNORED != 0 {
+            self.watches.remove(&wd);
+            self.remove(&dir);
+            return;
+        }
+        self.remove(&dir.join(name));
+        // A sidecar changes the variants of its file.
+        let file = SIDECARS.iter().find_map(|e| name.strip_suffix(&format!(".{}", e.extension())));
+        if let Some(file) = file {
+            self.remove(&dir.join(file));
+        }
+    }
+}
+
+/// Holds small static files in memory, with their headers and compressed
+/// variants, so that they are served without reading them again. It is
+/// bounded by a memory budget, the least recently used files are dropped to
+/// stay under it.
+///
+/// Copies of a cache share the files, one cache can serve several handlers.
+#[derive(Clone)]
+pub struct FileCache {
+    cache: Arc<Mutex<Cache>>,
+    budget: usize,
+    max_file_size: u64,
+    invalidation: Invalidation,
+    compression: Option<Arc<Compression>>,
+}
+
+impl FileCache {
+    // `budget` is the most bytes the files take, all variants included.
+    pub fn new(budget: usize) -> FileCache {
+        return FileCache {
+            cache: Arc::new(Mutex::new(Cache {
+                slots: HashMap::new(),
+                lru: BTreeMap::new(),
+                size: 0,
+                tick: 0,
+                hits: 0,
+                misses: 0,
+                inotify: None,
+                watches: HashMap::new(),
+                changes: 0,
+            })),
+            budget: budget,
+            max_file_size: 256 * 1024,
+            invalidation: Invalidation::Stat,
+            compression: None,
+        };
+    }
+
+    // Larger files are read from disk every time.
+    pub fn set_max_file_size(&mut self, max_file_size: u64) {
+        self.max_file_size = max_file_size;
+    }
+
+    // Fails when the system can't report changes.
+    pub fn set_invalidation(&mut self, invalidation: Invalidation) -> io::Result<()> {
+        if invalidation == Invalidation::Inotify {
+            let mut cache = self.cache.lock().unwrap();
+            if cache.inotify.is_none() {
+                cache.inotify = Some(inotify::watch(&self.cache)?);
+            }
+        }
+        self.invalidation = invalidation;
+        Ok(())
+    }
+
+    // Keeps the files compressed with each of the encodings of
+    // `compression` it applies to, when they don't have their sidecar.
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.compression = Some(Arc::new(compression));
+    }
+
+    pub fn stats(&self) -> FileCacheStats {
+        let cache = self.cache.lock().unwrap();
+        return FileCacheStats {
+            entries: cache.slots.len(),
+            size: cache.size,
+            hits: cache.hits,
+            misses: cache.misses,
+        };
+    }
+
+    pub fn clear(&self) {
+        self.cache.lock().unwrap().clear();
+    }
+
+    // The file at `path` if it is there and didn't change.
+    fn get(&self, path: &str) -> Option<Arc<Entry>> {
+        let (entry, check) = {
+            let mut cache = self.cache.lock().unwrap();
+            cache.tick += 1;
+            let tick = cache.tick;
+            let found = cache.slots.get_mut(path).map(|slot| {
+                let used = slot.used;
+                slot.used = tick;
+                let check = match self.invalidation {
+                    Invalidation::Stat => true,
+                    Invalidation::Interval(interval) => slot.checked.elapsed() >= interval,
+                    Invalidation::Inotify => false,
+                };
+                (slot.entry.clone(), check, used)
+            });
+            match found {
+                Some((entry, check, used)) => {
+                    let key = cache.lru.remove(&used).unwrap();
+                    cache.lru.insert(tick, key);
+                    (entry, check)
+                }
+                None => {
+                    cache.misses += 1;
+                    return None;
+                }
+            }
+        };
+        // The file is looked at without holding the cache.
+        let unchanged = !check || metadata(path).is_ok_and(|m| {
+            m.is_file() && m.len() == entry.len && m.modified().ok() == Some(entry.modified)
+        });
+        let mut cache = self.cache.lock().unwrap();
+        if !unchanged {
+            if cache.slots.get(path).is_some_and(|slot| Arc::ptr_eq(&slot.entry, &entry)) {
+                cache.remove_slot(path);
+            }
+            cache.misses += 1;
+            return None;
+        }
+        if check {
+            if let Some(slot) = cache.slots.get_mut(path) {
+                slot.checked = Instant::now();
+            }
+        }
+        cache.hits += 1;
+        Some(entry)
+    }
+
+    // Watches the directory of `path` before the file is read, so that no
+    // change to it goes unseen. Returns what to give `insert()`, None if
+    // the file can't be cached.
+    fn prepare(&self, path: &str) -> Option<u64> {
+        let mut cache = self.cache.lock().unwrap();
+        let fd = match cache.inotify {
+            Some(ref inotify) => inotify.fd,
+            None => return Some(cache.changes),
+        };
+        let dir = Path::new(path).parent().unwrap_or(Path::new("."));
+        if !cache.watches.values().any(|watched| watched == dir) {
+            match inotify::add_watch(fd, dir) {
+                Ok(wd) => {
+                    cache.watches.insert(wd, dir.to_path_buf());
+                }
+                Err(e) => {
+                    // Changes would go unseen.
+                    warn!("Cannot watch {} for changes: {}", dir.display(), e);
+                    return None;
+                }
+            }
+        }
+        Some(cache.changes)
+    }
+
+    // Keeps `entry` for `path`, if it fits, dropping the least recently
+    // used files to make room for it. `changes` is what `prepare()`
+    // returned before the file was read: a change reported since then may
+    // have been to this file, which is not kept then.
+    fn insert(&self, path: &str, entry: Entry, changes: u64) -> Arc<Entry> {
+        let entry = Arc::new(entry);
+        let size = entry.size();
+        if size > self.budget {
+            return entry;
+        }
+        let mut cache = self.cache.lock().unwrap();
+        if cache.changes != changes {
+            return entry;
+        }
+        cache.remove_slot(path);
+        while cache.size + size > self.budget {
+            let oldest = match cache.lru.values().next() {
+                Some(key) => key.clone(),
+                None => break,
+            };
+            cache.remove_slot(&oldest);
+        }
+        cache.tick += 1;
+        let tick = cache.tick;
+        cache.slots.insert(path.to_string(),
+                           Slot {
+                               entry: entry.clone(),
+                               used: tick,
+                               checked: Instant::now(),
+                           });
+        cache.lru.insert(tick, path.to_string());
+        cache.size += size;
+        entry
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod inotify {
+    use std::ffi::CString;
+    use std::io;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use super::Cache;
+
+    const EVENT_SIZE: usize = 16;
+
+    // An inotify instance. Its thread stops once it is dropped.
+    pub(super) struct Inotify {
+        pub(super) fd: i32,
+        // The write end of a pipe the thread polls along with the instance.
+        stop: i32,
+    }
+
+    impl Drop for Inotify {
+        fn drop(&mut self) {
+            unsafe { libc::close(self.stop) };
+        }
+    }
+
+    // Starts an inotify instance, and a thread applying its events to
+    // `cache` for as long as it is used.
+    pub(super) fn watch(cache: &Arc<Mutex<Cache>>) -> io::Result<Inotify> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut stop = [0; 2];
+        if unsafe { libc::pipe2(stop.as_mut_ptr(), libc::O_CLOEXEC) } < 0 {
+            let e = io::Error::last_os_error();
+            unsafe { libc::close(fd) };
+            return Err(e);
+        }
+        let cache = Arc::downgrade(cache);
+        let stopped = stop[0];
+        thread::spawn(move || {
+            let mut buf = vec![0u8; 64 * 1024];
+            let mut fds = [libc::pollfd {
+                               fd: fd,
+                               events: libc::POLLIN,
+                               revents: 0,
+                           },
+                           libc::pollfd {
+                               fd: stopped,
+                               events: libc::POLLIN,
+                               revents: 0,
+                           }];
+            loop {
+                if unsafe { libc::poll(fds.as_mut_ptr(), 2, -1) } < 0 {
+                    if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+                        continue;
+                    }
+                    break;
+                }
+                // The write end was closed with the cache.
+                if fds[1].revents != 0 {
+                    break;
+                }
+                let n = unsafe { libc::read(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+                if n < 0 {
+                    if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+                        continue;
+                    }
+                    break;
+                }
+                let cache = match cache.upgrade() {
+                    Some(cache) => cache,
+                    None => break,
+                };
+                let mut cache = cache.lock().unwrap();
+                let mut events = &buf[..n as usize];
+                while events.len() >= EVENT_SIZE {
+                    let field = |i: usize| {
+                        u32::from_ne_bytes([events[i], events[i + 1], events[i + 2], events[i + 3]])
+                    };
+                    let (wd, mask, len) = (field(0) as i32, field(4), field(12) as usize);
+                    let name = &events[EVENT_SIZE..(EVENT_SIZE + len).min(events.len())];
+                    let name = name.split(|&b| b == 0).next().unwrap_or(&[]);
+                    cache.changed(wd, mask, &String::from_utf8_lossy(name));
+                    events = &events[(EVENT_SIZE + len).min(events.len())..];
+                }
+            }
+            unsafe {
+                libc::close(fd);
+                libc::close(stopped);
+            }
+        });
+        return Ok(Inotify {
+            fd: fd,
+            stop: stop[1],
+        });
+    }
+
+    pub(super) fn add_watch(fd: i32, dir: &Path) -> io::Result<i32> {
+        let dir = CString::new(dir.as_os_str().as_bytes())
+                      .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let mask = libc::IN_MODIFY | libc::IN_ATTRIB | libc::IN_CLOSE_WRITE | libc::IN_CREATE |
+                   libc::IN_DELETE | libc::IN_MOVED_FROM | libc::IN_MOVED_TO |
+                   libc::IN_DELETE_SELF | libc::IN_MOVE_SELF;
+        let wd = unsafe { libc::inotify_add_watch(fd, dir.as_ptr(), mask) };
+        if wd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(wd)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod inotify {
+    use std::io;
+    use std::path::Path;
+    use std::sync::{Arc, Mutex};
+    use super::Cache;
+
+    pub(super) struct Inotify {
+        pub(super) fd: i32,
+    }
+
+    pub(super) fn watch(_cache: &Arc<Mutex<Cache>>) -> io::Result<Inotify> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "inotify is only available on Linux"))
+    }
+
+    pub(super) fn add_watch(_fd: i32, _dir: &Path) -> io::Result<i32> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "inotify is only available on Linux"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+    use connection::Output;
+    use super::*;
+
+    #[test]
+    fn paths_under_root() {
+        for path in &["", "/", "/a.txt", "/dir/", "/a..b", "/.hidden", "//a"] {
+            assert!(is_under_root(path), "{:?}", path);
+        }
+        for path in &["a.txt", "/..", "/../etc/passwd", "/a/../../b", "/a/./b", "/a\0.txt"] {
+            assert!(!is_under_root(path), "{:?}", path);
+        }
+    }
+
+    #[test]
+    fn files_outside_the_root_are_not_found() {
+        let dir = env::temp_dir().join(format!("webserver-http-file-{}", process::id()));
+        create_dir_all(dir.join("root")).unwrap();
+        File::create(dir.join("root/a.txt")).unwrap().write_all(b"a").unwrap();
+        File::create(dir.join("secret")).unwrap().write_all(b"secret").unwrap();
+        let mut fs = FileSystem::new(dir.join("root").to_str().unwrap());
+        let mut status = |uri: &str| {
+            let req = Request::from_parts("GET", uri, "HTTP/1.1", Vec::new(), Vec::new()).unwrap();
+            let mut out = Output::new();
+            fs.serve(&req.uri, &req, &mut Response::with_output(&mut out));
+            let data = out.queued_data();
+            String::from_utf8_lossy(&data[..12]).into_owned()
+        };
+        assert_eq!(status("/a.txt"), "HTTP/1.1 200");
+        for uri in &["/../secret", "/%2e%2e/secret", "/x/%2E%2E/../secret", "/a.txt%00"] {
+            assert_eq!(status(uri), "HTTP/1.1 404", "{}", uri);
         }
+        remove_dir_all(&dir).unwrap();
     }
 }
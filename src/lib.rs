@@ -1,28 +1,131 @@
+// The code base predates `dyn` and sticks to explicit returns and field
+// names.
+#![allow(bare_trait_objects)]
+#![allow(clippy::needless_return, clippy::redundant_field_names, clippy::new_without_default)]
+#![allow(clippy::needless_borrowed_reference)]
+
 extern crate mio;
 extern crate regex;
+extern crate libc;
+extern crate net2;
+extern crate rustls;
+extern crate rustls_pemfile;
+extern crate hpack;
+extern crate ring;
+extern crate flate2;
+extern crate base64;
+extern crate fnv;
+extern crate brotli;
+#[macro_use]
+extern crate log;
 
 pub mod http;
 mod event_loop;
 mod app_server;
+mod blocking;
+mod connection;
+mod http2;
+mod signal;
+mod timer;
+pub mod workers;
+pub mod tls;
 pub mod http_file;
 pub mod handlers;
 pub mod handler_lib;
+pub mod websocket;
+pub mod sse;
+pub mod proxy;
+pub mod upstream;
+pub mod cgi;
+pub mod fastcgi;
+pub mod rewrite;
+pub mod compression;
+pub mod mime;
+pub mod cache_control;
+pub mod logging;
+pub mod access_log;
+pub mod metrics;
+pub mod status;
+pub mod config;
+mod toml;
+mod date;
+mod virtual_host;
+mod util;
 
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
 use app_server::*;
+use blocking::BlockingPool;
 use handler_lib::*;
+pub use event_loop::{ShutdownHandle, Threading, Waker};
+use event_loop::{BoundListeners, LoopSettings};
+pub use app_server::Timeouts;
+pub use http::Limits;
+use workers::*;
+use tls::TlsConfig;
+use websocket::WebSocketHandler;
+use sse::SseHandler;
+use rewrite::RewriteRule;
+use compression::Compression;
+use access_log::AccessLog;
+use metrics::{Counter, MetricsHandler, Registry, ServerMetrics};
+use status::{ServerStatus, StatusAccess, StatusHandler};
+pub use virtual_host::VirtualHost;
 
 pub struct WebServer {
-    host: String,
+    listeners: Vec<Listener>,
     handlers: Vec<HandlerRoute>,
-    num_workers: usize,
+    ws_handlers: Vec<WebSocketRoute>,
+    sse_handlers: Vec<SseRoute>,
+    virtual_hosts: Vec<VirtualHost>,
+    rewrite_rules: Vec<RewriteRule>,
+    compression: Option<Compression>,
+    access_log: Option<AccessLog>,
+    registry: Registry,
+    metrics_route: Option<String>,
+    status_route: Option<(String, StatusAccess)>,
+    loads: WorkerLoads,
+    assignment: Box<Assignment>,
+    shutdown: ShutdownHandle,
+    shutdown_timeout: Duration,
+    shutdown_on_signals: bool,
+    reopen_logs_on_sighup: bool,
+    timeouts: Timeouts,
+    limits: Limits,
+    threading: Threading,
+    blocking_pool: Option<BlockingPool>,
+    bound: Option<BoundListeners>,
 }
 
 impl WebServer {
     pub fn new(host: &str, num_workers: usize) -> WebServer {
         return WebServer {
-            host: host.to_string(),
+            listeners: vec![Listener {
+                                host: host.to_string(),
+                                tls: None,
+                            }],
             handlers: Vec::new(),
-            num_workers: num_workers,
+            ws_handlers: Vec::new(),
+            sse_handlers: Vec::new(),
+            virtual_hosts: Vec::new(),
+            rewrite_rules: Vec::new(),
+            compression: None,
+            access_log: None,
+            registry: Registry::new(),
+            metrics_route: None,
+            status_route: None,
+            loads: WorkerLoads::new(num_workers),
+            assignment: Box::new(RoundRobin::new()),
+            shutdown: ShutdownHandle::new(),
+            shutdown_timeout: Duration::from_secs(30),
+            shutdown_on_signals: false,
+            reopen_logs_on_sighup: false,
+            timeouts: Timeouts::new(),
+            limits: Limits::new(),
+            threading: Threading::Dispatcher,
+            blocking_pool: None,
+            bound: None,
         };
     }
 
@@ -32,10 +135,212 @@ impl WebServer {
         self.handlers.push(HandlerRoute(format!("^{}$", pattern), Box::new(handler)));
     }
 
-    pub fn run(self) {
-        let app_server = AppServer::new(&self.host,
-                                        self.num_workers,
-                                        Box::new(HandlerApp::new(self.handlers)));
-        app_server.run();
+    // Upgrades requests matching `pattern` to WebSocket connections run by
+    // `handler`. These routes are checked before the others.
+    pub fn add_websocket_handler<T>(&mut self, pattern: &str, handler: T)
+        where T: WebSocketHandler
+    {
+        self.ws_handlers.push(WebSocketRoute(format!("^{}$", pattern), Box::new(handler)));
+    }
+
+    // Answers requests matching `pattern` with Server-Sent Events streams
+    // opened by `handler`. These routes are also checked before the others.
+    pub fn add_sse_handler<T>(&mut self, pattern: &str, handler: T)
+        where T: SseHandler
+    {
+        self.sse_handlers.push(SseRoute(format!("^{}$", pattern), Box::new(handler)));
+    }
+
+    // Rewrites the requests before they are routed, after the rules added
+    // before. Virtual hosts have rules of their own.
+    pub fn add_rewrite_rule(&mut self, rule: RewriteRule) {
+        self.rewrite_rules.push(rule);
+    }
+
+    // Compresses the responses of the handlers for the clients that accept
+    // it.
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.compression = Some(compression);
+    }
+
+    // Writes a line for every request answered to `log`.
+    pub fn set_access_log(&mut self, log: AccessLog) {
+        self.access_log = Some(log);
+    }
+
+    // The registry of the metrics of the server, where handlers can add
+    // their own.
+    pub fn metrics(&self) -> Registry {
+        self.registry.clone()
+    }
+
+    // Serves the metrics in the Prometheus text format at `route`, a path
+    // checked before the other routes, and collects those of the server:
+    // requests, latencies, bytes, connections, queues and accept errors.
+    pub fn set_metrics_route(&mut self, route: &str) {
+        self.metrics_route = Some(route.to_string());
+    }
+
+    // Serves a page with the uptime, workers, open connections and request
+    // rates at `route`, in HTML or JSON, to the clients `access` allows.
+    pub fn set_status_route(&mut self, route: &str, access: StatusAccess) {
+        self.status_route = Some((route.to_string(), access));
+    }
+
+    // Serves `host` to the requests for its names. Requests for no virtual
+    // host get the routes of the server.
+    pub fn add_virtual_host(&mut self, host: VirtualHost) {
+        self.virtual_hosts.push(host);
+    }
+
+    // Also accepts connections on `host`, like one for admins only.
+    pub fn add_listener(&mut self, host: &str) {
+        self.listeners.push(Listener {
+            host: host.to_string(),
+            tls: None,
+        });
+    }
+
+    // Also accepts HTTPS connections on `host`.
+    pub fn add_tls_listener(&mut self, host: &str, config: TlsConfig) -> io::Result<()> {
+        self.listeners.push(Listener {
+            host: host.to_string(),
+            tls: Some(config.server_config()?),
+        });
+        Ok(())
+    }
+
+    // Returns a handle that makes `run()` return once in-flight requests
+    // are done.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    // How long a shutdown waits for in-flight requests before dropping them.
+    pub fn set_shutdown_timeout(&mut self, timeout: Duration) {
+        self.shutdown_timeout = timeout;
+    }
+
+    // Sets how new connections are spread over the worker threads.
+    pub fn set_assignment<T>(&mut self, assignment: T)
+        where T: Assignment
+    {
+        self.assignment = Box::new(assignment);
+    }
+
+    // Returns the load counters of the worker threads, updated while the
+    // server runs.
+    pub fn worker_loads(&self) -> WorkerLoads {
+        self.loads.clone()
+    }
+
+    // Sets how connection events get to the worker threads. With
+    // `Threading::ReusePort`, use one worker per core.
+    pub fn set_threading(&mut self, threading: Threading) {
+        self.threading = threading;
+    }
+
+    // Runs the handlers on `num_threads` threads of their own rather than on
+    // the workers, so that a blocking handler doesn't delay the other
+    // connections. Requests that find `queue_size` others waiting get a 503.
+    pub fn set_blocking_pool(&mut self, num_threads: usize, queue_size: usize) {
+        self.blocking_pool = Some(BlockingPool::new(num_threads, queue_size));
+    }
+
+    pub fn set_timeouts(&mut self, timeouts: Timeouts) {
+        self.timeouts = timeouts;
+    }
+
+    // The largest request heads and bodies accepted.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    // Shut down gracefully on SIGTERM and SIGINT.
+    pub fn shutdown_on_signals(&mut self) {
+        self.shutdown_on_signals = true;
+    }
+
+    // Reopen the log files on SIGHUP, once they were moved away by log
+    // rotation.
+    pub fn reopen_logs_on_sighup(&mut self) {
+        self.reopen_logs_on_sighup = true;
+    }
+
+    // Binds the listeners now rather than in `run()`, to know they could be
+    // before going on. The listeners and the threading must be set already.
+    pub fn bind(&mut self) -> io::Result<()> {
+        let hosts: Vec<_> = self.listeners.iter().map(|l| l.host.clone()).collect();
+        self.bound = Some(BoundListeners::bind(&hosts, self.threading, self.loads.len())?);
+        Ok(())
+    }
+
+    // Returns once shut down, or at once if a listener can't be bound.
+    pub fn run(mut self) -> io::Result<()> {
+        if self.bound.is_none() {
+            self.bind()?;
+        }
+        let bound = self.bound.take().unwrap();
+        if self.shutdown_on_signals {
+            signal::shutdown_on_signals(self.shutdown.clone());
+        }
+        if self.reopen_logs_on_sighup {
+            signal::reopen_logs_on_sighup();
+        }
+        let mut metrics = None;
+        if let Some(ref route) = self.metrics_route {
+            metrics = Some(ServerMetrics::register(&self.registry, self.loads.clone()));
+            let handler = MetricsHandler::new(self.registry.clone());
+            let pattern = format!("^{}$", regex::quote(route));
+            self.handlers.insert(0, HandlerRoute(pattern, Box::new(handler)));
+        }
+        let mut status = None;
+        if let Some((ref route, access)) = self.status_route {
+            let server_status = Arc::new(ServerStatus::new(self.loads.clone()));
+            let handler = StatusHandler::new(server_status.clone(), access);
+            let pattern = format!("^{}$", regex::quote(route));
+            self.handlers.insert(0, HandlerRoute(pattern, Box::new(handler)));
+            status = Some(server_status);
+        }
+        let settings = LoopSettings {
+            shutdown: self.shutdown,
+            shutdown_timeout: self.shutdown_timeout,
+            loads: self.loads,
+            assignment: self.assignment,
+            threading: self.threading,
+            accept_errors: metrics.as_ref().map_or_else(Counter::new, |m| m.accept_errors.clone()),
+        };
+        let mut app = HandlerApp::new(self.handlers);
+        app.set_websocket_routes(self.ws_handlers);
+        app.set_sse_routes(self.sse_handlers);
+        app.set_rewrite_rules(self.rewrite_rules);
+        app.set_limits(self.limits);
+        if let Some(compression) = self.compression {
+            app.set_compression(compression);
+        }
+        if let Some(log) = self.access_log {
+            app.set_access_log(log);
+        }
+        for host in self.virtual_hosts {
+            app.add_virtual_host(host);
+        }
+        if let Some(pool) = self.blocking_pool {
+            app.set_blocking_pool(pool);
+        }
+        if let Some(ref metrics) = metrics {
+            app.set_metrics(metrics.http.clone());
+        }
+        let mut app_server = AppServer::new(self.listeners,
+                                            Box::new(app),
+                                            self.timeouts,
+                                            settings);
+        if let Some(metrics) = metrics {
+            app_server.set_metrics(metrics.conns);
+        }
+        if let Some(status) = status {
+            app_server.set_status(status);
+        }
+        app_server.run(bound);
+        Ok(())
     }
 }
@@ -0,0 +1,131 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::SystemTime;
+use log::{self, LevelFilter, Log, Metadata, Record};
+use date::rfc3339;
+
+// The files open for logging, to reopen when they are rotated.
+static LOG_FILES: Mutex<Vec<Weak<LogFile>>> = Mutex::new(Vec::new());
+
+// A file lines are appended to, that can be reopened at the same path once
+// it was moved away.
+pub(crate) struct LogFile {
+    path: String,
+    file: Mutex<File>,
+}
+
+impl LogFile {
+    pub(crate) fn open(path: &str) -> io::Result<Arc<LogFile>> {
+        let file = Arc::new(LogFile {
+            path: path.to_string(),
+            file: Mutex::new(open_append(path)?),
+        });
+        let mut files = LOG_FILES.lock().unwrap();
+        files.retain(|f| f.strong_count() > 0);
+        files.push(Arc::downgrade(&file));
+        Ok(file)
+    }
+
+    // Writes `line` at once, so that the lines of several threads or
+    // processes don't mix.
+    pub(crate) fn write_line(&self, line: &str) -> io::Result<()> {
+        let mut data = Vec::with_capacity(line.len() + 1);
+        data.extend_from_slice(line.as_bytes());
+        data.push(b'\n');
+        self.file.lock().unwrap().write_all(&data)
+    }
+
+    fn reopen(&self) -> io::Result<()> {
+        let file = open_append(&self.path)?;
+        *self.file.lock().unwrap() = file;
+        Ok(())
+    }
+}
+
+fn open_append(path: &str) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// Reopens the log files, at the paths they were opened with. It is what
+/// SIGHUP does once `WebServer::reopen_logs_on_sighup` was called, after the
+/// files were moved away by log rotation.
+pub fn reopen_files() {
+    let files: Vec<Arc<LogFile>> =
+        LOG_FILES.lock().unwrap().iter().filter_map(|f| f.upgrade()).collect();
+    for file in files {
+        match file.reopen() {
+            Ok(()) => info!("Reopened log file {}", file.path),
+            Err(e) => error!("Error while reopening log file {}: {}", file.path, e),
+        }
+    }
+}
+
+enum Output {
+    Stderr,
+    File(Arc<LogFile>),
+}
+
+/// A logger for the `log` facade, that writes the messages of the server
+/// and its handlers at or above a level, with their time, level and module,
+/// to stderr or a file. Any other implementation of `log` can be used
+/// instead.
+pub struct Logger {
+    level: LevelFilter,
+    output: Output,
+}
+
+impl Logger {
+    pub fn stderr(level: LevelFilter) -> Logger {
+        return Logger {
+            level: level,
+            output: Output::Stderr,
+        };
+    }
+
+    pub fn file(path: &str, level: LevelFilter) -> io::Result<Logger> {
+        return Ok(Logger {
+            level: level,
+            output: Output::File(LogFile::open(path)?),
+        });
+    }
+
+    // Makes it the logger of the process. Fails if there is one already.
+    pub fn init(self) -> Result<(), log::SetLoggerError> {
+        log::set_max_level(self.level);
+        log::set_boxed_logger(Box::new(self))
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = format!("{} {:5} {}: {}",
+                           rfc3339(SystemTime::now()),
+                           record.level(),
+                           record.target(),
+                           record.args());
+        // There is nowhere to report a failure to write the log.
+        let _ = match self.output {
+            Output::Stderr => writeln!(io::stderr(), "{}", line),
+            Output::File(ref file) => file.write_line(&line),
+        };
+    }
+
+    fn flush(&self) {}
+}
+
+/// The level of messages the `WEBSERVER_LOG` variable asks for, like
+/// "debug", or `default`.
+pub fn level_from_env(default: LevelFilter) -> LevelFilter {
+    match ::std::env::var("WEBSERVER_LOG") {
+        Ok(level) => level.parse().unwrap_or(default),
+        Err(_) => default,
+    }
+}
@@ -1,11 +1,253 @@
 extern crate webserver;
+#[macro_use]
+extern crate log;
 
-use webserver::handlers::*;
-use webserver::*;
+use std::env;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::process;
+use log::LevelFilter;
+use webserver::access_log::LogFormat;
+use webserver::config::*;
+use webserver::logging::{self, Logger};
+
+const USAGE: &str = "\
+Usage: webserver [OPTIONS] [CONFIG]
+       webserver serve [DIR] [OPTIONS]
+
+Runs the server of the configuration file CONFIG, or serves ./http on
+127.0.0.1:8080 without one. `serve` serves the files of DIR, the working
+directory by default, on [::]:8000 with directory listings.
+
+Options:
+  -c, --config FILE      Read the configuration from FILE
+  -d, --directory DIR    Serve the files of DIR, like `serve DIR`
+  -b, --bind ADDR        Listen on the IP address ADDR
+  -p, --port PORT        Listen on PORT
+  -w, --workers N        Run N worker threads
+  -l, --log-level LEVEL  Log at LEVEL: off, error, warn, info, debug or
+                         trace, $WEBSERVER_LOG or info by default
+      --check            Check the configuration and exit
+      --print-config     Print the effective configuration and exit
+  -h, --help             Print this help and exit
+  -V, --version          Print the version and exit
+
+--bind and --port change the first listener of the configuration.";
+
+// The command line, once parsed.
+struct Options {
+    serve: bool,
+    directory: Option<String>,
+    config: Option<String>,
+    bind: Option<IpAddr>,
+    port: Option<u16>,
+    workers: Option<usize>,
+    log_level: Option<LevelFilter>,
+    check: bool,
+    print_config: bool,
+}
 
 fn main() {
-    let mut server = WebServer::new("127.0.0.1:8080", 4);
-    server.add_handler("/", FileHandler::new("http/index.html"));
-    server.add_handler("/.*", FileSystemHandler::new("http"));
-    server.run();
+    let options = match parse_args(env::args().skip(1).collect()) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("webserver: {}", message);
+            eprintln!("Try `webserver --help` for more information.");
+            process::exit(2);
+        }
+    };
+    let level = options.log_level.unwrap_or_else(|| logging::level_from_env(LevelFilter::Info));
+    Logger::stderr(level).init().unwrap();
+    let config = match effective_config(&options) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("{}", e);
+            process::exit(1);
+        }
+    };
+    if options.print_config {
+        print!("{}", config);
+    }
+    // Building also loads the certificates and opens the logs, which the
+    // check is about.
+    let mut server = match config.build() {
+        Ok(server) => server,
+        Err(e) => {
+            error!("{}", e);
+            process::exit(1);
+        }
+    };
+    if options.check {
+        println!("The configuration is valid");
+    }
+    if options.check || options.print_config {
+        return;
+    }
+    // Bound before saying where the files are served.
+    if let Err(e) = server.bind() {
+        error!("{}", e);
+        process::exit(1);
+    }
+    if options.serve {
+        info!("Serving {} on http://{}/",
+              options.directory.as_deref().unwrap_or("."),
+              config.listeners[0].address);
+    }
+    server.shutdown_on_signals();
+    server.reopen_logs_on_sighup();
+    if let Err(e) = server.run() {
+        error!("{}", e);
+        process::exit(1);
+    }
+}
+
+fn parse_args(args: Vec<String>) -> Result<Options, String> {
+    let mut options = Options {
+        serve: false,
+        directory: None,
+        config: None,
+        bind: None,
+        port: None,
+        workers: None,
+        log_level: None,
+        check: false,
+        print_config: false,
+    };
+    let mut args = args.into_iter();
+    let mut first = true;
+    while let Some(arg) = args.next() {
+        // "--port=9000" is "--port 9000".
+        let (name, mut value) = match arg.split_once('=') {
+            Some((name, value)) if name.starts_with("--") => {
+                (name.to_string(), Some(value.to_string()))
+            }
+            _ => (arg.clone(), None),
+        };
+        let mut value_of = |name: &str| {
+            value.take()
+                 .or_else(|| args.next())
+                 .ok_or_else(|| format!("{} needs a value", name))
+        };
+        match name.as_str() {
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                process::exit(0);
+            }
+            "-V" | "--version" => {
+                println!("webserver {}", env!("CARGO_PKG_VERSION"));
+                process::exit(0);
+            }
+            "-c" | "--config" => options.config = Some(value_of(&name)?),
+            "-d" | "--directory" => {
+                options.directory = Some(value_of(&name)?);
+                options.serve = true;
+            }
+            "-b" | "--bind" => {
+                let bind = value_of(&name)?;
+                // "[::1]" is the IPv6 address in brackets, as in URLs.
+                let ip = bind.strip_prefix('[').and_then(|b| b.strip_suffix(']')).unwrap_or(&bind);
+                let ip = ip.parse().map_err(|_| format!("`{}` is not an IP address", bind))?;
+                options.bind = Some(ip);
+            }
+            "-p" | "--port" => {
+                let port = value_of(&name)?;
+                let port = port.parse().map_err(|_| format!("`{}` is not a port", port))?;
+                options.port = Some(port);
+            }
+            "-w" | "--workers" => {
+                let workers = value_of(&name)?;
+                match workers.parse() {
+                    Ok(n) if n > 0 => options.workers = Some(n),
+                    _ => return Err(format!("`{}` is not a number of workers", workers)),
+                }
+            }
+            "-l" | "--log-level" => {
+                let level = value_of(&name)?;
+                let level = level.parse().map_err(|_| format!("`{}` is not a log level", level))?;
+                options.log_level = Some(level);
+            }
+            "--check" => options.check = true,
+            "--print-config" => options.print_config = true,
+            "serve" if first => options.serve = true,
+            _ if name.starts_with('-') => {
+                return Err(format!("unknown option `{}`", name));
+            }
+            // The directory of `serve`, or the configuration file as in
+            // `webserver webserver.toml`.
+            _ if options.serve && options.directory.is_none() => options.directory = Some(arg),
+            _ if !options.serve && options.config.is_none() => options.config = Some(arg),
+            _ => return Err(format!("unexpected argument `{}`", arg)),
+        }
+        if value.is_some() {
+            return Err(format!("{} takes no value", name));
+        }
+        first = false;
+    }
+    if options.serve && options.config.is_some() {
+        return Err("`serve` can't be given a configuration file".to_string());
+    }
+    Ok(options)
+}
+
+// The configuration of the file, of `serve` or the default one, with the
+// options over it.
+fn effective_config(options: &Options) -> Result<Config, String> {
+    let mut config = match options.config {
+        Some(ref path) => Config::load(path).map_err(|e| e.to_string())?,
+        None if options.serve => serve_config(options.directory.as_deref().unwrap_or("."))?,
+        None => default_config(),
+    };
+    if let Some(workers) = options.workers {
+        config.workers = workers;
+    }
+    if options.bind.is_some() || options.port.is_some() {
+        let listener = &mut config.listeners[0];
+        let mut address = listener.address
+                                  .parse::<SocketAddr>()
+                                  .map_err(|_| format!("`{}` is not an address",
+                                                       listener.address))?;
+        if let Some(ip) = options.bind {
+            address.set_ip(ip);
+        }
+        if let Some(port) = options.port {
+            address.set_port(port);
+        }
+        listener.address = address.to_string();
+    }
+    Ok(config)
+}
+
+// Serves the files of `directory` and lists its directories, like
+// `python -m http.server`.
+fn serve_config(directory: &str) -> Result<Config, String> {
+    if !Path::new(directory).is_dir() {
+        return Err(format!("no directory at {}", directory));
+    }
+    let mut config = Config::new();
+    config.listeners.push(ListenerConfig::new("[::]:8000"));
+    let mut files = StaticFiles::new(directory);
+    files.listing = true;
+    config.routes.push(RouteConfig {
+        path: "/.*".to_string(),
+        action: RouteAction::Directory(files),
+    });
+    config.access_log = Some(AccessLogConfig::new(None, LogFormat::Common));
+    Ok(config)
+}
+
+// Serves ./http on 127.0.0.1:8080, as `webserver.toml` does.
+fn default_config() -> Config {
+    let mut config = Config::new();
+    config.workers = 4;
+    config.listeners.push(ListenerConfig::new("127.0.0.1:8080"));
+    config.routes.push(RouteConfig {
+        path: "/".to_string(),
+        action: RouteAction::File(StaticFiles::new("http/index.html")),
+    });
+    config.routes.push(RouteConfig {
+        path: "/.*".to_string(),
+        action: RouteAction::Directory(StaticFiles::new("http")),
+    });
+    config.access_log = Some(AccessLogConfig::new(None, LogFormat::Combined));
+    config
 }
@@ -0,0 +1,645 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use handler_lib::Handler;
+use http::{Request, Response};
+use workers::WorkerLoads;
+
+/// The buckets of the request latencies, in seconds.
+pub const DEFAULT_BUCKETS: &[f64] = &[0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5,
+                                      5.0, 10.0];
+
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// A value that only goes up, like a number of requests.
+#[derive(Debug, Clone, Default)]
+pub struct Counter {
+    value: Arc<AtomicU64>,
+}
+
+impl Counter {
+    // A counter in no registry. Those of a registry come from it.
+    pub fn new() -> Counter {
+        return Counter::default();
+    }
+
+    pub fn inc(&self) {
+        self.inc_by(1);
+    }
+
+    pub fn inc_by(&self, n: u64) {
+        self.value.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.value.load(Ordering::Relaxed)
+    }
+}
+
+// An f64 updated atomically, stored as its bits.
+#[derive(Debug, Default)]
+struct AtomicF64(AtomicU64);
+
+impl AtomicF64 {
+    fn get(&self) -> f64 {
+        f64::from_bits(self.0.load(Ordering::Relaxed))
+    }
+
+    fn set(&self, value: f64) {
+        self.0.store(value.to_bits(), Ordering::Relaxed);
+    }
+
+    fn add(&self, delta: f64) {
+        let mut current = self.0.load(Ordering::Relaxed);
+        loop {
+            let new = (f64::from_bits(current) + delta).to_bits();
+            match self.0.compare_exchange_weak(current, new, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => return,
+                Err(actual) => current = actual,
+            }
+        }
+    }
+}
+
+/// A value that goes up and down, like a number of open connections.
+#[derive(Debug, Clone, Default)]
+pub struct Gauge {
+    value: Arc<AtomicF64>,
+}
+
+impl Gauge {
+    pub fn new() -> Gauge {
+        return Gauge::default();
+    }
+
+    pub fn set(&self, value: f64) {
+        self.value.set(value);
+    }
+
+    pub fn add(&self, delta: f64) {
+        self.value.add(delta);
+    }
+
+    pub fn inc(&self) {
+        self.add(1.0);
+    }
+
+    pub fn dec(&self) {
+        self.add(-1.0);
+    }
+
+    pub fn get(&self) -> f64 {
+        self.value.get()
+    }
+}
+
+#[derive(Debug)]
+struct Buckets {
+    // The upper bounds, in increasing order. The last bucket, +Inf, has
+    // none.
+    bounds: Vec<f64>,
+    // Not cumulative: each observation is only counted in its bucket.
+    counts: Vec<AtomicU64>,
+    sum: AtomicF64,
+}
+
+/// Counts observed values, like latencies, in buckets by their upper
+/// bound.
+#[derive(Debug, Clone)]
+pub struct Histogram {
+    buckets: Arc<Buckets>,
+}
+
+impl Histogram {
+    pub fn new(bounds: &[f64]) -> Histogram {
+        let mut bounds: Vec<f64> = bounds.iter().cloned().filter(|b| b.is_finite()).collect();
+        bounds.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        bounds.dedup();
+        let counts = (0..bounds.len() + 1).map(|_| AtomicU64::new(0)).collect();
+        return Histogram {
+            buckets: Arc::new(Buckets {
+                bounds: bounds,
+                counts: counts,
+                sum: AtomicF64::default(),
+            }),
+        };
+    }
+
+    pub fn observe(&self, value: f64) {
+        let b = &self.buckets;
+        let idx = b.bounds.iter().position(|&bound| value <= bound).unwrap_or(b.bounds.len());
+        b.counts[idx].fetch_add(1, Ordering::Relaxed);
+        b.sum.add(value);
+    }
+
+    // Observes `duration` in seconds.
+    pub fn observe_duration(&self, duration: Duration) {
+        self.observe(duration.as_secs_f64());
+    }
+
+    // The number of values observed and their sum.
+    pub fn count(&self) -> u64 {
+        self.buckets.counts.iter().map(|c| c.load(Ordering::Relaxed)).sum()
+    }
+
+    pub fn sum(&self) -> f64 {
+        self.buckets.sum.get()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Kind {
+    Counter,
+    Gauge,
+    Histogram(Vec<f64>),
+}
+
+#[derive(Debug)]
+enum Metric {
+    Counter(Counter),
+    Gauge(Gauge),
+    Histogram(Histogram),
+}
+
+// The metrics of a name, one by value of their labels.
+#[derive(Debug)]
+struct Family {
+    name: String,
+    help: String,
+    kind: Kind,
+    labels: Vec<String>,
+    metrics: Mutex<BTreeMap<Vec<String>, Metric>>,
+}
+
+impl Family {
+    fn get(&self, values: &[&str]) -> Metric {
+        assert!(values.len() == self.labels.len(),
+                "{} has labels {:?}, got values {:?}",
+                self.name,
+                self.labels,
+                values);
+        let key: Vec<String> = values.iter().map(|v| v.to_string()).collect();
+        let mut metrics = self.metrics.lock().unwrap();
+        let metric = metrics.entry(key).or_insert_with(|| {
+            match self.kind {
+                Kind::Counter => Metric::Counter(Counter::new()),
+                Kind::Gauge => Metric::Gauge(Gauge::new()),
+                Kind::Histogram(ref bounds) => Metric::Histogram(Histogram::new(bounds)),
+            }
+        });
+        match *metric {
+            Metric::Counter(ref c) => Metric::Counter(c.clone()),
+            Metric::Gauge(ref g) => Metric::Gauge(g.clone()),
+            Metric::Histogram(ref h) => Metric::Histogram(h.clone()),
+        }
+    }
+
+    fn render(&self, out: &mut String) {
+        let kind = match self.kind {
+            Kind::Counter => "counter",
+            Kind::Gauge => "gauge",
+            Kind::Histogram(_) => "histogram",
+        };
+        let _ = writeln!(out, "# HELP {} {}", self.name, escape_help(&self.help));
+        let _ = writeln!(out, "# TYPE {} {}", self.name, kind);
+        for (values, metric) in self.metrics.lock().unwrap().iter() {
+            let labels = self.labels(values, None);
+            match *metric {
+                Metric::Counter(ref c) => {
+                    let _ = writeln!(out, "{}{} {}", self.name, labels, c.get());
+                }
+                Metric::Gauge(ref g) => {
+                    let _ = writeln!(out, "{}{} {}", self.name, labels, format_value(g.get()));
+                }
+                Metric::Histogram(ref h) => self.render_histogram(values, h, out),
+            }
+        }
+    }
+
+    fn render_histogram(&self, values: &[String], h: &Histogram, out: &mut String) {
+        let mut cumulative = 0;
+        for (idx, count) in h.buckets.counts.iter().enumerate() {
+            cumulative += count.load(Ordering::Relaxed);
+            let le = match h.buckets.bounds.get(idx) {
+                Some(&bound) => format_value(bound),
+                None => "+Inf".to_string(),
+            };
+            let _ = writeln!(out,
+                             "{}_bucket{} {}",
+                             self.name,
+                             self.labels(values, Some(&le)),
+                             cumulative);
+        }
+        let labels = self.labels(values, None);
+        let _ = writeln!(out, "{}_sum{} {}", self.name, labels, format_value(h.sum()));
+        let _ = writeln!(out, "{}_count{} {}", self.name, labels, cumulative);
+    }
+
+    // The labels of a sample, like `{route="/",status="200"}`, with the
+    // bucket of a histogram.
+    fn labels(&self, values: &[String], le: Option<&str>) -> String {
+        let mut pairs: Vec<String> = self.labels
+                                         .iter()
+                                         .zip(values)
+                                         .map(|(l, v)| format!("{}=\"{}\"", l, escape_value(v)))
+                                         .collect();
+        if let Some(le) = le {
+            pairs.push(format!("le=\"{}\"", le));
+        }
+        if pairs.is_empty() {
+            String::new()
+        } else {
+            format!("{{{}}}", pairs.join(","))
+        }
+    }
+}
+
+/// Counters with labels, one counter by value of the labels.
+#[derive(Debug, Clone)]
+pub struct CounterVec {
+    family: Arc<Family>,
+}
+
+impl CounterVec {
+    // The counter of `values`, one per label, created at 0 the first time.
+    pub fn with(&self, values: &[&str]) -> Counter {
+        match self.family.get(values) {
+            Metric::Counter(c) => c,
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// Gauges with labels.
+#[derive(Debug, Clone)]
+pub struct GaugeVec {
+    family: Arc<Family>,
+}
+
+impl GaugeVec {
+    pub fn with(&self, values: &[&str]) -> Gauge {
+        match self.family.get(values) {
+            Metric::Gauge(g) => g,
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// Histograms with labels, all with the same buckets.
+#[derive(Debug, Clone)]
+pub struct HistogramVec {
+    family: Arc<Family>,
+}
+
+impl HistogramVec {
+    pub fn with(&self, values: &[&str]) -> Histogram {
+        match self.family.get(values) {
+            Metric::Histogram(h) => h,
+            _ => unreachable!(),
+        }
+    }
+}
+
+type Collector = Arc<Fn() + Send + Sync>;
+
+struct Metrics {
+    families: Vec<Arc<Family>>,
+    collectors: Vec<Collector>,
+}
+
+/// The metrics of a server, rendered in the Prometheus text format. It is
+/// shared by its clones, so handlers can keep one and add their own
+/// metrics to it. Registering a name again with the same type and labels
+/// gives back the same metric, so that duplicated handlers share it.
+#[derive(Clone)]
+pub struct Registry {
+    metrics: Arc<Mutex<Metrics>>,
+}
+
+impl Registry {
+    pub fn new() -> Registry {
+        return Registry {
+            metrics: Arc::new(Mutex::new(Metrics {
+                families: Vec::new(),
+                collectors: Vec::new(),
+            })),
+        };
+    }
+
+    pub fn counter(&self, name: &str, help: &str) -> Counter {
+        self.counter_vec(name, help, &[]).with(&[])
+    }
+
+    pub fn counter_vec(&self, name: &str, help: &str, labels: &[&str]) -> CounterVec {
+        CounterVec { family: self.family(name, help, Kind::Counter, labels) }
+    }
+
+    pub fn gauge(&self, name: &str, help: &str) -> Gauge {
+        self.gauge_vec(name, help, &[]).with(&[])
+    }
+
+    pub fn gauge_vec(&self, name: &str, help: &str, labels: &[&str]) -> GaugeVec {
+        GaugeVec { family: self.family(name, help, Kind::Gauge, labels) }
+    }
+
+    // A histogram with buckets of upper bounds `buckets`, like
+    // `DEFAULT_BUCKETS`. A last one for any value is added.
+    pub fn histogram(&self, name: &str, help: &str, buckets: &[f64]) -> Histogram {
+        self.histogram_vec(name, help, &[], buckets).with(&[])
+    }
+
+    pub fn histogram_vec(&self,
+                         name: &str,
+                         help: &str,
+                         labels: &[&str],
+                         buckets: &[f64])
+                         -> HistogramVec {
+        let bounds = Histogram::new(buckets).buckets.bounds.clone();
+        HistogramVec { family: self.family(name, help, Kind::Histogram(bounds), labels) }
+    }
+
+    // Calls `collector` before the metrics are rendered, to update those
+    // that are sampled rather than counted, like a queue length.
+    pub fn add_collector<F>(&self, collector: F)
+        where F: Fn() + Send + Sync + 'static
+    {
+        self.metrics.lock().unwrap().collectors.push(Arc::new(collector));
+    }
+
+    fn family(&self, name: &str, help: &str, kind: Kind, labels: &[&str]) -> Arc<Family> {
+        assert!(is_valid_name(name, true), "Invalid metric name {:?}", name);
+        for label in labels {
+            assert!(is_valid_name(label, false) && *label != "le",
+                    "Invalid label name {:?}",
+                    label);
+        }
+        let mut metrics = self.metrics.lock().unwrap();
+        if let Some(family) = metrics.families.iter().find(|f| f.name == name) {
+            assert!(family.kind == kind && family.labels == labels,
+                    "Metric {} registered again with another type or labels",
+                    name);
+            return family.clone();
+        }
+        let family = Arc::new(Family {
+            name: name.to_string(),
+            help: help.to_string(),
+            kind: kind,
+            labels: labels.iter().map(|l| l.to_string()).collect(),
+            metrics: Mutex::new(BTreeMap::new()),
+        });
+        metrics.families.push(family.clone());
+        family
+    }
+
+    // All the metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        // Called unlocked, they may use the registry.
+        let collectors = self.metrics.lock().unwrap().collectors.clone();
+        for collector in collectors {
+            collector();
+        }
+        let families = self.metrics.lock().unwrap().families.clone();
+        let mut out = String::new();
+        for family in families {
+            family.render(&mut out);
+        }
+        out
+    }
+}
+
+// Metric names may have colons, label names may not.
+fn is_valid_name(name: &str, metric: bool) -> bool {
+    let valid = |c: char, first: bool| {
+        c.is_ascii_alphabetic() || c == '_' || (metric && c == ':') ||
+        (!first && c.is_ascii_digit())
+    };
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if valid(c, true) => chars.all(|c| valid(c, false)),
+        _ => false,
+    }
+}
+
+fn format_value(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".to_string()
+    } else if value.is_infinite() {
+        if value > 0.0 { "+Inf" } else { "-Inf" }.to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+fn escape_help(help: &str) -> String {
+    help.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+fn escape_value(value: &str) -> String {
+    escape_help(value).replace('"', "\\\"")
+}
+
+/// Answers with the metrics of a registry, for Prometheus to scrape.
+pub struct MetricsHandler {
+    registry: Registry,
+}
+
+impl MetricsHandler {
+    pub fn new(registry: Registry) -> MetricsHandler {
+        return MetricsHandler { registry: registry };
+    }
+}
+
+impl Handler for MetricsHandler {
+    fn process(&mut self, request: Request, response: &mut Response) {
+        if request.method() != "GET" && request.method() != "HEAD" {
+            response.set_method_not_allowed("GET, HEAD").send();
+            return;
+        }
+        response.set_header("Content-Type", CONTENT_TYPE)
+                .set_header("Cache-Control", "no-store")
+                .set_body(self.registry.render().as_bytes())
+                .send();
+    }
+
+    fn duplicate(&self) -> Box<Handler> {
+        Box::new(MetricsHandler::new(self.registry.clone()))
+    }
+}
+
+// The metrics of the requests.
+pub(crate) struct HttpMetrics {
+    requests: CounterVec,
+    duration: HistogramVec,
+}
+
+impl HttpMetrics {
+    // Counts the request of `method` answered with `status` by the handler of
+    // `route`, after `elapsed`.
+    pub(crate) fn observe(&self, route: &str, method: &str, status: u32, elapsed: Duration) {
+        self.requests.with(&[route, method, &status.to_string()]).inc();
+        self.duration.with(&[route, method]).observe_duration(elapsed);
+    }
+}
+
+// The bytes that went through the sockets of the connections, TLS
+// included.
+#[derive(Clone)]
+pub(crate) struct ConnMetrics {
+    pub(crate) received: Counter,
+    pub(crate) sent: Counter,
+}
+
+// The metrics the server collects itself.
+pub(crate) struct ServerMetrics {
+    pub(crate) http: Arc<HttpMetrics>,
+    pub(crate) conns: ConnMetrics,
+    pub(crate) accept_errors: Counter,
+}
+
+impl ServerMetrics {
+    pub(crate) fn register(registry: &Registry, loads: WorkerLoads) -> ServerMetrics {
+        let http = HttpMetrics {
+            requests: registry.counter_vec("webserver_http_requests_total",
+                                           "Requests answered, by route, method and status.",
+                                           &["route", "method", "status"]),
+            duration: registry.histogram_vec("webserver_http_request_duration_seconds",
+                                             "Time to answer requests, by route and method.",
+                                             &["route", "method"],
+                                             DEFAULT_BUCKETS),
+        };
+        let conns = ConnMetrics {
+            received: registry.counter("webserver_received_bytes_total",
+                                       "Bytes received from the clients."),
+            sent: registry.counter("webserver_sent_bytes_total", "Bytes sent to the clients."),
+        };
+        let accept_errors = registry.counter("webserver_accept_errors_total",
+                                             "Errors while accepting connections.");
+        let open = registry.gauge("webserver_connections", "Open connections.");
+        let worker_conns = registry.gauge_vec("webserver_worker_connections",
+                                              "Open connections, by worker thread.",
+                                              &["worker"]);
+        let queue_depth = registry.gauge_vec("webserver_worker_queue_depth",
+                                             "Events queued for a worker thread.",
+                                             &["worker"]);
+        registry.add_collector(move || {
+            let stats = loads.stats();
+            open.set(stats.iter().map(|s| s.connections).sum::<usize>() as f64);
+            for (worker, s) in stats.iter().enumerate() {
+                let worker = worker.to_string();
+                worker_conns.with(&[&worker]).set(s.connections as f64);
+                queue_depth.with(&[&worker]).set(s.queue_depth as f64);
+            }
+        });
+        return ServerMetrics {
+            http: Arc::new(http),
+            conns: conns,
+            accept_errors: accept_errors,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The lines of the rendered metrics that are samples of `name`.
+    fn samples(registry: &Registry, name: &str) -> Vec<String> {
+        registry.render()
+                .lines()
+                .filter(|l| l.starts_with(name))
+                .map(|l| l.to_string())
+                .collect()
+    }
+
+    #[test]
+    fn histogram_buckets_are_cumulative() {
+        let registry = Registry::new();
+        let h = registry.histogram_vec("latency_seconds", "Latency.", &["route"], &[1.0, 0.5]);
+        for &value in &[0.25, 0.5, 2.0, 0.75] {
+            h.with(&["/"]).observe(value);
+        }
+        assert_eq!(samples(&registry, "latency_seconds"),
+                   vec!["latency_seconds_bucket{route=\"/\",le=\"0.5\"} 2",
+                        "latency_seconds_bucket{route=\"/\",le=\"1\"} 3",
+                        "latency_seconds_bucket{route=\"/\",le=\"+Inf\"} 4",
+                        "latency_seconds_sum{route=\"/\"} 3.5",
+                        "latency_seconds_count{route=\"/\"} 4"]);
+        assert_eq!(h.with(&["/"]).count(), 4);
+        assert_eq!(h.with(&["/"]).sum(), 3.5);
+
+        let h = registry.histogram("empty_seconds", "Nothing.", &[0.1]);
+        assert_eq!(h.count(), 0);
+        assert_eq!(samples(&registry, "empty_seconds"),
+                   vec!["empty_seconds_bucket{le=\"0.1\"} 0",
+                        "empty_seconds_bucket{le=\"+Inf\"} 0",
+                        "empty_seconds_sum 0",
+                        "empty_seconds_count 0"]);
+    }
+
+    #[test]
+    fn renders_counters_and_gauges() {
+        let registry = Registry::new();
+        let counter = registry.counter("requests_total", "Requests,\nall of them \\o/.");
+        counter.inc_by(3);
+        let gauge = registry.gauge_vec("queue", "Queued.", &["pool"]);
+        gauge.with(&["b"]).set(1.5);
+        gauge.with(&["a"]).dec();
+        // Registered again, the same metric.
+        registry.counter("requests_total", "Requests.").inc();
+        let text = registry.render();
+        assert!(text.starts_with("# HELP requests_total Requests,\\nall of them \\\\o/.\n\
+                                  # TYPE requests_total counter\n\
+                                  requests_total 4\n"),
+                "{}",
+                text);
+        assert!(text.ends_with("# TYPE queue gauge\nqueue{pool=\"a\"} -1\nqueue{pool=\"b\"} 1.5\n"),
+                "{}",
+                text);
+    }
+
+    #[test]
+    fn label_values_are_escaped() {
+        let registry = Registry::new();
+        let counter = registry.counter_vec("paths_total", "Paths.", &["path"]);
+        counter.with(&["C:\\dir \"quoted\"\nnext"]).inc();
+        assert_eq!(samples(&registry, "paths_total"),
+                   vec!["paths_total{path=\"C:\\\\dir \\\"quoted\\\"\\nnext\"} 1"]);
+    }
+
+    #[test]
+    fn valid_names() {
+        for name in &["a", "_a", "http_requests_total", "a1", "ns:sub:metric"] {
+            assert!(is_valid_name(name, true), "{}", name);
+        }
+        assert!(is_valid_name("route_2", false));
+        for name in &["", "1a", "a-b", "a b", "é", "a.b"] {
+            assert!(!is_valid_name(name, true), "{}", name);
+        }
+        assert!(!is_valid_name("ns:label", false));
+        assert!(!is_valid_name(":label", false));
+    }
+
+    #[test]
+    #[should_panic(expected = "Metric requests registered again with another type or labels")]
+    fn names_keep_their_type() {
+        let registry = Registry::new();
+        registry.counter("requests", "Requests.");
+        registry.gauge("requests", "Requests.");
+    }
+
+    #[test]
+    #[should_panic(expected = "Metric requests registered again with another type or labels")]
+    fn names_keep_their_labels() {
+        let registry = Registry::new();
+        registry.counter_vec("requests", "Requests.", &["route"]);
+        registry.counter_vec("requests", "Requests.", &["method"]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid label name \"le\"")]
+    fn le_is_reserved() {
+        Registry::new().histogram_vec("latency", "Latency.", &["le"], DEFAULT_BUCKETS);
+    }
+}
@@ -0,0 +1,339 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::Path;
+
+const DEFAULT_TYPE: &str = "application/octet-stream";
+
+// The types of the extensions found on the web, as /etc/mime.types has them
+// or their newer registrations.
+const TYPES: [(&str, &str); 80] = [("html", "text/html"),
+                                   ("htm", "text/html"),
+                                   ("shtml", "text/html"),
+                                   ("css", "text/css"),
+                                   ("js", "text/javascript"),
+                                   ("mjs", "text/javascript"),
+                                   ("txt", "text/plain"),
+                                   ("text", "text/plain"),
+                                   ("log", "text/plain"),
+                                   ("csv", "text/csv"),
+                                   ("tsv", "text/tab-separated-values"),
+                                   ("md", "text/markdown"),
+                                   ("markdown", "text/markdown"),
+                                   ("xml", "text/xml"),
+                                   ("ics", "text/calendar"),
+                                   ("vtt", "text/vtt"),
+                                   ("json", "application/json"),
+                                   ("map", "application/json"),
+                                   ("jsonld", "application/ld+json"),
+                                   ("webmanifest", "application/manifest+json"),
+                                   ("xhtml", "application/xhtml+xml"),
+                                   ("rss", "application/rss+xml"),
+                                   ("atom", "application/atom+xml"),
+                                   ("pdf", "application/pdf"),
+                                   ("wasm", "application/wasm"),
+                                   ("zip", "application/zip"),
+                                   ("gz", "application/gzip"),
+                                   ("tgz", "application/gzip"),
+                                   ("bz2", "application/x-bzip2"),
+                                   ("xz", "application/x-xz"),
+                                   ("7z", "application/x-7z-compressed"),
+                                   ("tar", "application/x-tar"),
+                                   ("rar", "application/vnd.rar"),
+                                   ("jar", "application/java-archive"),
+                                   ("doc", "application/msword"),
+                                   ("xls", "application/vnd.ms-excel"),
+                                   ("ppt", "application/vnd.ms-powerpoint"),
+                                   ("docx",
+                                    "application/vnd.openxmlformats-officedocument.\
+                                     wordprocessingml.document"),
+                                   ("xlsx",
+                                    "application/vnd.openxmlformats-officedocument.\
+                                     spreadsheetml.sheet"),
+                                   ("pptx",
+                                    "application/vnd.openxmlformats-officedocument.\
+                                     presentationml.presentation"),
+                                   ("odt", "application/vnd.oasis.opendocument.text"),
+                                   ("ods", "application/vnd.oasis.opendocument.spreadsheet"),
+                                   ("rtf", "application/rtf"),
+                                   ("epub", "application/epub+zip"),
+                                   ("bin", "application/octet-stream"),
+                                   ("exe", "application/octet-stream"),
+                                   ("dll", "application/octet-stream"),
+                                   ("iso", "application/octet-stream"),
+                                   ("dmg", "application/octet-stream"),
+                                   ("png", "image/png"),
+                                   ("apng", "image/apng"),
+                                   ("jpg", "image/jpeg"),
+                                   ("jpeg", "image/jpeg"),
+                                   ("gif", "image/gif"),
+                                   ("webp", "image/webp"),
+                                   ("avif", "image/avif"),
+                                   ("svg", "image/svg+xml"),
+                                   ("svgz", "image/svg+xml"),
+                                   ("ico", "image/x-icon"),
+                                   ("bmp", "image/bmp"),
+                                   ("tif", "image/tiff"),
+                                   ("tiff", "image/tiff"),
+                                   ("woff", "font/woff"),
+                                   ("woff2", "font/woff2"),
+                                   ("ttf", "font/ttf"),
+                                   ("otf", "font/otf"),
+                                   ("eot", "application/vnd.ms-fontobject"),
+                                   ("mp4", "video/mp4"),
+                                   ("m4v", "video/mp4"),
+                                   ("webm", "video/webm"),
+                                   ("ogv", "video/ogg"),
+                                   ("mov", "video/quicktime"),
+                                   ("avi", "video/x-msvideo"),
+                                   ("mpeg", "video/mpeg"),
+                                   ("mp3", "audio/mpeg"),
+                                   ("m4a", "audio/mp4"),
+                                   ("ogg", "audio/ogg"),
+                                   ("oga", "audio/ogg"),
+                                   ("wav", "audio/wav"),
+                                   ("flac", "audio/flac")];
+
+// Types that are text without being "text/*".
+const TEXT_TYPES: [&str; 3] = ["application/javascript", "application/json", "application/xml"];
+
+// How much of a file is looked at to tell its type.
+const SNIFF_LENGTH: usize = 512;
+
+/// The Content-Type of files, found from their extension, or their content
+/// for those without one when sniffing is on. It starts with a built-in
+/// table of the common types, that files in the /etc/mime.types format and
+/// single types added can extend or override.
+///
+/// Text types get a charset parameter, "utf-8" unless set otherwise.
+#[derive(Debug, Clone)]
+pub struct MimeTypes {
+    types: HashMap<String, String>,
+    charset: Option<String>,
+    sniff: bool,
+}
+
+impl MimeTypes {
+    pub fn new() -> MimeTypes {
+        return MimeTypes {
+            types: TYPES.iter().map(|&(ext, mime)| (ext.to_string(), mime.to_string())).collect(),
+            charset: Some("utf-8".to_string()),
+            sniff: false,
+        };
+    }
+
+    // Adds the types of a file in the /etc/mime.types format, a type
+    // followed by its extensions on each line, over those there already.
+    pub fn load(&mut self, path: &str) -> io::Result<()> {
+        let file = BufReader::new(File::open(path)?);
+        for line in file.lines() {
+            let line = line?;
+            let line = line.split('#').next().unwrap_or("");
+            let mut words = line.split_whitespace();
+            let mime = match words.next() {
+                Some(mime) if mime.contains('/') => mime,
+                _ => continue,
+            };
+            for ext in words {
+                self.add(ext, mime);
+            }
+        }
+        Ok(())
+    }
+
+    // The type of the files with extension `ext`, like "json".
+    pub fn add(&mut self, ext: &str, mime: &str) {
+        let ext = ext.trim_start_matches('.').to_lowercase();
+        self.types.insert(ext, mime.to_string());
+    }
+
+    // The charset given to the text types, None to leave it out.
+    pub fn set_charset(&mut self, charset: Option<&str>) {
+        self.charset = charset.map(|c| c.to_string());
+    }
+
+    // Whether the type of files without an extension is told from their
+    // first bytes. They are "application/octet-stream" otherwise.
+    pub fn set_sniff(&mut self, sniff: bool) {
+        self.sniff = sniff;
+    }
+
+    // The type of the file at `path` by its extension, without parameters.
+    pub fn lookup(&self, path: &str) -> Option<&str> {
+        let ext = Path::new(path).extension()?.to_str()?.to_lowercase();
+        self.types.get(&ext).map(|mime| mime.as_str())
+    }
+
+    // The Content-Type header of the file at `path`.
+    pub fn content_type(&self, path: &str) -> String {
+        let has_ext = Path::new(path).extension().is_some();
+        let mime = match self.lookup(path) {
+            Some(mime) => mime,
+            None if self.sniff && !has_ext => sniff(path).unwrap_or(DEFAULT_TYPE),
+            None => DEFAULT_TYPE,
+        };
+        match self.charset {
+            Some(ref charset) if is_text(mime) => format!("{}; charset={}", mime, charset),
+            _ => mime.to_string(),
+        }
+    }
+}
+
+fn is_text(mime: &str) -> bool {
+    mime.starts_with("text/") || mime.ends_with("+xml") || mime.ends_with("+json") ||
+    TEXT_TYPES.contains(&mime)
+}
+
+// The type of a file by its first bytes, from the signatures of the common
+// formats, or as text if it looks like it.
+fn sniff(path: &str) -> Option<&'static str> {
+    let mut head = Vec::with_capacity(SNIFF_LENGTH);
+    File::open(path).ok()?.take(SNIFF_LENGTH as u64).read_to_end(&mut head).ok()?;
+    let signatures: [(&[u8], &str); 11] = [(b"\x89PNG\r\n\x1a\n", "image/png"),
+                                           (b"\xff\xd8\xff", "image/jpeg"),
+                                           (b"GIF87a", "image/gif"),
+                                           (b"GIF89a", "image/gif"),
+                                           (b"%PDF-", "application/pdf"),
+                                           (b"PK\x03\x04", "application/zip"),
+                                           (b"\x1f\x8b", "application/gzip"),
+                                           (b"\0asm", "application/wasm"),
+                                           (b"wOFF", "font/woff"),
+                                           (b"wOF2", "font/woff2"),
+                                           (b"\x1aE\xdf\xa3", "video/webm")];
+    for &(signature, mime) in &signatures {
+        if head.starts_with(signature) {
+            return Some(mime);
+        }
+    }
+    if head.len() >= 12 && head.starts_with(b"RIFF") && &head[8..12] == b"WEBP" {
+        return Some("image/webp");
+    }
+    if head.len() >= 8 && &head[4..8] == b"ftyp" {
+        return Some("video/mp4");
+    }
+    // Text is valid UTF-8 without control characters, but for a character
+    // cut at the end.
+    let text = match String::from_utf8(head) {
+        Ok(text) => text,
+        Err(e) if e.utf8_error().error_len().is_none() => {
+            let valid = e.utf8_error().valid_up_to();
+            String::from_utf8_lossy(&e.into_bytes()[..valid]).into_owned()
+        }
+        Err(_) => return None,
+    };
+    if text.chars().any(|c| c.is_control() && !c.is_whitespace()) {
+        return None;
+    }
+    let start = text.trim_start().to_lowercase();
+    if start.starts_with("<!doctype html") || start.starts_with("<html") {
+        Some("text/html")
+    } else if start.starts_with("<?xml") {
+        Some("text/xml")
+    } else {
+        Some("text/plain")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
+    use super::*;
+
+    // A file of the test in the temporary directory, named after it.
+    fn temp_file(name: &str, content: &[u8]) -> PathBuf {
+        let dir = env::temp_dir().join(format!("webserver-mime-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[test]
+    fn built_in_types() {
+        let types = MimeTypes::new();
+        assert_eq!(types.lookup("fonts/a.woff2"), Some("font/woff2"));
+        assert_eq!(types.lookup("app.wasm"), Some("application/wasm"));
+        assert_eq!(types.lookup("/js/module.mjs"), Some("text/javascript"));
+        assert_eq!(types.lookup("IMAGE.PNG"), Some("image/png"));
+        assert_eq!(types.lookup("archive.tar.gz"), Some("application/gzip"));
+        assert_eq!(types.lookup("README"), None);
+        assert_eq!(types.lookup("file.unknown"), None);
+        assert_eq!(types.content_type("file.unknown"), "application/octet-stream");
+    }
+
+    #[test]
+    fn charset_of_text_types() {
+        let mut types = MimeTypes::new();
+        assert_eq!(types.content_type("a.html"), "text/html; charset=utf-8");
+        assert_eq!(types.content_type("a.svg"), "image/svg+xml; charset=utf-8");
+        assert_eq!(types.content_type("a.json"), "application/json; charset=utf-8");
+        assert_eq!(types.content_type("a.webmanifest"),
+                   "application/manifest+json; charset=utf-8");
+        assert_eq!(types.content_type("a.png"), "image/png");
+        assert_eq!(types.content_type("a.wasm"), "application/wasm");
+        types.set_charset(Some("iso-8859-1"));
+        assert_eq!(types.content_type("a.css"), "text/css; charset=iso-8859-1");
+        types.set_charset(None);
+        assert_eq!(types.content_type("a.css"), "text/css");
+    }
+
+    #[test]
+    fn loads_mime_types_files() {
+        let path = temp_file("mime.types",
+                             b"# A comment line\n\
+                               \n\
+                               application/x-custom\tcst cst2  CST3 # and a comment\n\
+                               text/x-lonely\n\
+                               no-slash ignored\n\
+                               image/x-png png\n");
+        let mut types = MimeTypes::new();
+        types.load(path.to_str().unwrap()).unwrap();
+        assert_eq!(types.lookup("a.cst"), Some("application/x-custom"));
+        assert_eq!(types.lookup("a.cst2"), Some("application/x-custom"));
+        assert_eq!(types.lookup("a.cst3"), Some("application/x-custom"));
+        assert_eq!(types.lookup("a.ignored"), None);
+        assert_eq!(types.lookup("a.comment"), None);
+        // The file overrides the built-in types, and is overridden by those
+        // added after it.
+        assert_eq!(types.lookup("a.png"), Some("image/x-png"));
+        types.add(".PNG", "image/png");
+        types.add("cst", "text/x-custom");
+        assert_eq!(types.lookup("a.png"), Some("image/png"));
+        assert_eq!(types.content_type("a.cst"), "text/x-custom; charset=utf-8");
+        assert!(types.load("/nonexistent/mime.types").is_err());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn sniffs_files_without_an_extension() {
+        let png = temp_file("image", b"\x89PNG\r\n\x1a\nrest");
+        let webp = temp_file("photo", b"RIFF\0\0\0\0WEBPVP8 ");
+        let html = temp_file("page", b"\n  <!DOCTYPE html><html></html>");
+        // A character cut at the end of what is looked at is still text.
+        let mut cut = vec![b'a'; SNIFF_LENGTH - 1];
+        cut.extend_from_slice("\u{e9}".as_bytes());
+        let text = temp_file("notes", &cut);
+        let binary = temp_file("data", b"\0\x01\x02\x03");
+        let png_named = temp_file("image.bin", b"\x89PNG\r\n\x1a\n");
+
+        let mut types = MimeTypes::new();
+        let content_type = |types: &MimeTypes, path: &PathBuf| {
+            types.content_type(path.to_str().unwrap())
+        };
+        assert_eq!(content_type(&types, &png), "application/octet-stream");
+        types.set_sniff(true);
+        assert_eq!(content_type(&types, &png), "image/png");
+        assert_eq!(content_type(&types, &webp), "image/webp");
+        assert_eq!(content_type(&types, &html), "text/html; charset=utf-8");
+        assert_eq!(content_type(&types, &text), "text/plain; charset=utf-8");
+        assert_eq!(content_type(&types, &binary), "application/octet-stream");
+        // Only files without an extension are sniffed.
+        assert_eq!(content_type(&types, &png_named), "application/octet-stream");
+        for path in &[png, webp, html, text, binary, png_named] {
+            fs::remove_file(path).unwrap();
+        }
+    }
+}
@@ -0,0 +1,919 @@
+use std::fmt;
+use std::io::prelude::*;
+use std::io::{self, BufReader, ErrorKind};
+use std::net::{IpAddr, TcpStream, ToSocketAddrs};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use handler_lib::Handler;
+use http::{BodyReader, Request, Response, Status};
+use upstream::{Balancing, Group, Upstreams, UpstreamGroup};
+
+// Headers that only apply to one connection, they are not forwarded either
+// way. So are those the Connection header lists.
+pub(crate) const HOP_BY_HOP: [&str; 9] = ["connection",
+                               "keep-alive",
+                               "proxy-authenticate",
+                               "proxy-authorization",
+                               "proxy-connection",
+                               "te",
+                               "trailer",
+                               "transfer-encoding",
+                               "upgrade"];
+// Request headers the proxy sets itself. The body is sent right after the
+// head, so there is nothing to expect either.
+const REWRITTEN: [&str; 7] = ["host",
+                              "content-length",
+                              "expect",
+                              "x-forwarded-for",
+                              "x-forwarded-host",
+                              "x-forwarded-proto",
+                              "forwarded"];
+// Idle upstream connections older than this are not reused.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+// How much of a body is read from the upstream at once.
+const CHUNK_SIZE: usize = 16 * 1024;
+
+#[derive(Debug, Clone)]
+enum Address {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+pub(crate) enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Stream {
+    fn set_timeout(&self, timeout: Duration) -> io::Result<()> {
+        match *self {
+            Stream::Tcp(ref s) => {
+                s.set_read_timeout(Some(timeout))?;
+                s.set_write_timeout(Some(timeout))
+            }
+            Stream::Unix(ref s) => {
+                s.set_read_timeout(Some(timeout))?;
+                s.set_write_timeout(Some(timeout))
+            }
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Tcp(ref mut s) => s.read(buf),
+            Stream::Unix(ref mut s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Tcp(ref mut s) => s.write(buf),
+            Stream::Unix(ref mut s) => s.write(buf),
+        }
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Stream::Tcp(ref mut s) => s.flush(),
+            Stream::Unix(ref mut s) => s.flush(),
+        }
+    }
+}
+
+// Why the upstream didn't give a response.
+pub(crate) enum Error {
+    // Couldn't connect, the request can be sent to another upstream.
+    Connect(io::Error),
+    // The connection broke.
+    Unavailable(io::Error),
+    Timeout,
+    Invalid(String),
+    // A reused connection was closed by the upstream before it answered,
+    // the request can be sent again on a new one.
+    Stale,
+    // The client didn't send the whole body.
+    Aborted,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Connect(ref e) | Error::Unavailable(ref e) => e.fmt(f),
+            Error::Timeout => f.write_str("timed out"),
+            Error::Invalid(ref e) => write!(f, "invalid response: {}", e),
+            Error::Stale => f.write_str("connection closed before the response"),
+            Error::Aborted => f.write_str("request body cut short"),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        match e.kind() {
+            ErrorKind::TimedOut | ErrorKind::WouldBlock => Error::Timeout,
+            _ => Error::Unavailable(e),
+        }
+    }
+}
+
+// A request body read as it arrives. Once some of it was sent, the request
+// can't be sent again.
+struct StreamedBody {
+    reader: BodyReader,
+    started: bool,
+}
+
+// How the body of an upstream response is delimited.
+enum Framing {
+    None,
+    Length(u64),
+    Chunked,
+    // Until the upstream closes the connection.
+    Close,
+}
+
+struct ResponseHead {
+    code: u32,
+    reason: String,
+    headers: Vec<(String, String)>,
+    keep_alive: bool,
+}
+
+impl ResponseHead {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|&&(ref n, _)| n.eq_ignore_ascii_case(name))
+            .map(|&(_, ref v)| v.as_str())
+    }
+}
+
+// A server requests are forwarded to, with its idle connections.
+pub(crate) struct Upstream {
+    address: Address,
+    // The Host header it gets unless the handler sets one.
+    host: String,
+    idle: Mutex<Vec<(Stream, Instant)>>,
+}
+
+impl Upstream {
+    // `address` is either "host:port" or "unix:/path/to/socket".
+    pub(crate) fn new(address: &str) -> Upstream {
+        let (address, host) = match address.strip_prefix("unix:") {
+            Some(path) => (Address::Unix(PathBuf::from(path)), "localhost".to_string()),
+            None => {
+                let addr = address.trim_start_matches("http://").trim_end_matches('/');
+                (Address::Tcp(addr.to_string()), addr.to_string())
+            }
+        };
+        return Upstream {
+            address: address,
+            host: host,
+            idle: Mutex::new(Vec::new()),
+        };
+    }
+
+    pub(crate) fn address(&self) -> String {
+        match self.address {
+            Address::Tcp(ref addr) => addr.clone(),
+            Address::Unix(ref path) => format!("unix:{}", path.display()),
+        }
+    }
+
+    // Whether `path` answers a GET with a 2xx or 3xx status within
+    // `timeout`.
+    pub(crate) fn check(&self, path: &str, timeout: Duration) -> bool {
+        let check = || -> Result<bool, Error> {
+            let mut stream = self.open(timeout, timeout)?;
+            let head = format!("GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+                               path,
+                               self.host);
+            stream.write_all(head.as_bytes())?;
+            let response = read_head(&mut BufReader::new(stream))?;
+            Ok(response.code < 400)
+        };
+        check().unwrap_or(false)
+    }
+
+    // Returns a kept alive connection if there is one, and whether it was.
+    pub(crate) fn connect(&self,
+                          connect_timeout: Duration,
+                          timeout: Duration)
+                          -> Result<(Stream, bool), Error> {
+        {
+            let mut idle = self.idle.lock().unwrap();
+            while let Some((stream, since)) = idle.pop() {
+                if since.elapsed() < IDLE_TIMEOUT {
+                    return Ok((stream, true));
+                }
+            }
+        }
+        Ok((self.open(connect_timeout, timeout)?, false))
+    }
+
+    fn open(&self, connect_timeout: Duration, timeout: Duration) -> Result<Stream, Error> {
+        let connect = || -> io::Result<Stream> {
+            let stream = match self.address {
+                Address::Tcp(ref addr) => {
+                    let mut last_error = io::Error::new(ErrorKind::NotFound,
+                                                        format!("{} has no address", addr));
+                    let mut connected = None;
+                    for addr in addr.to_socket_addrs()? {
+                        match TcpStream::connect_timeout(&addr, connect_timeout) {
+                            Ok(stream) => {
+                                connected = Some(stream);
+                                break;
+                            }
+                            Err(e) => last_error = e,
+                        }
+                    }
+                    let stream = connected.ok_or(last_error)?;
+                    stream.set_nodelay(true)?;
+                    Stream::Tcp(stream)
+                }
+                Address::Unix(ref path) => Stream::Unix(UnixStream::connect(path)?),
+            };
+            stream.set_timeout(timeout)?;
+            Ok(stream)
+        };
+        connect().map_err(Error::Connect)
+    }
+
+    pub(crate) fn release(&self, stream: Stream, max_idle: usize) {
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < max_idle {
+            idle.push((stream, Instant::now()));
+        }
+    }
+}
+
+/// Forwards requests to upstream HTTP/1.1 servers, over TCP or Unix
+/// sockets, and sends back their responses. Upstream connections are kept
+/// alive and shared by the copies of the handler.
+///
+/// It blocks while waiting for the upstream, so it should run on the
+/// blocking pool. Only there do bodies go either way as they come,
+/// elsewhere they are held until they were read whole.
+pub struct ProxyHandler {
+    group: Arc<Group>,
+    host: Option<String>,
+    connect_timeout: Duration,
+    read_timeout: Duration,
+    max_idle: usize,
+}
+
+impl ProxyHandler {
+    // `upstream` is either "host:port" or "unix:/path/to/socket".
+    pub fn new(upstream: &str) -> ProxyHandler {
+        let mut group = UpstreamGroup::new(Balancing::RoundRobin);
+        group.add_server(upstream, 1);
+        // A single upstream is tried whatever happened to it before.
+        group.set_max_fails(0, Duration::from_secs(0));
+        return ProxyHandler::with_group(group);
+    }
+
+    // Spreads the requests over the servers of `group`.
+    pub fn with_group(group: UpstreamGroup) -> ProxyHandler {
+        return ProxyHandler {
+            group: group.start(),
+            host: None,
+            connect_timeout: Duration::from_secs(5),
+            read_timeout: Duration::from_secs(60),
+            max_idle: 16,
+        };
+    }
+
+    // Returns a handle on the state of the upstream servers.
+    pub fn upstreams(&self) -> Upstreams {
+        Upstreams::new(self.group.clone())
+    }
+
+    // The Host header sent to the upstreams, their address by default. The
+    // client's is passed in X-Forwarded-Host.
+    pub fn set_host(&mut self, host: &str) {
+        self.host = Some(host.to_string());
+    }
+
+    // How long to wait for a connection to an upstream, and for each read
+    // or write on it. The client gets a 504 when they run out.
+    pub fn set_timeouts(&mut self, connect: Duration, read: Duration) {
+        self.connect_timeout = connect;
+        self.read_timeout = read;
+    }
+
+    // How many idle connections are kept for reuse, by upstream server.
+    pub fn set_max_idle(&mut self, max_idle: usize) {
+        self.max_idle = max_idle;
+    }
+
+    // Forwards the request to `upstream`. Returns whether it answered
+    // without a gateway error of its own.
+    fn send(&self,
+            upstream: &Upstream,
+            req: &Request,
+            body: &mut Option<StreamedBody>,
+            resp: &mut Response)
+            -> Result<bool, Error> {
+        let head = self.request_head(req, body.is_some(), upstream);
+        loop {
+            // A body read as it arrives can't be sent again if a kept alive
+            // connection turns out to be closed.
+            let (mut stream, reused) = if body.is_some() {
+                (upstream.open(self.connect_timeout, self.read_timeout)?, false)
+            } else {
+                upstream.connect(self.connect_timeout, self.read_timeout)?
+            };
+            match self.forward(&mut stream, reused, &head, req, body, resp) {
+                Ok((keep_alive, ok)) => {
+                    if keep_alive {
+                        upstream.release(stream, self.max_idle);
+                    }
+                    return Ok(ok);
+                }
+                Err(Error::Stale) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn request_head(&self, req: &Request, streamed: bool, upstream: &Upstream) -> Vec<u8> {
+        let mut head = format!("{} {}", req.method(), req.uri);
+        if let Some(query) = req.query() {
+            head.push('?');
+            head.push_str(query);
+        }
+        head.push_str(" HTTP/1.1\r\n");
+        head.push_str(&format!("Host: {}\r\n", self.host.as_ref().unwrap_or(&upstream.host)));
+        let listed = req.header("Connection").map(connection_tokens).unwrap_or_default();
+        for (name, value) in req.headers() {
+            let lower = name.to_lowercase();
+            if lower.starts_with(':') || HOP_BY_HOP.contains(&lower.as_str()) ||
+               REWRITTEN.contains(&lower.as_str()) || listed.contains(&lower) {
+                continue;
+            }
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        let proto = if req.tls().is_some() {
+            "https"
+        } else {
+            "http"
+        };
+        let mut forwarded = vec![format!("proto={}", proto)];
+        if let Some(addr) = req.peer_addr() {
+            let ip = addr.ip();
+            head.push_str(&format!("X-Forwarded-For: {}\r\n",
+                                   append(req.header("X-Forwarded-For"), &ip.to_string())));
+            forwarded.insert(0,
+                             match ip {
+                                 IpAddr::V4(ip) => format!("for={}", ip),
+                                 IpAddr::V6(ip) => {
+                                     format!("for={}", quoted_string(&format!("[{}]", ip)))
+                                 }
+                             });
+        }
+        if let Some(host) = req.header("Host") {
+            head.push_str(&format!("X-Forwarded-Host: {}\r\n", host));
+            forwarded.push(format!("host={}", quoted_string(host)));
+        }
+        head.push_str(&format!("X-Forwarded-Proto: {}\r\n", proto));
+        head.push_str(&format!("Forwarded: {}\r\n",
+                               append(req.header("Forwarded"), &forwarded.join(";"))));
+        let with_body = ["POST", "PUT", "PATCH"].contains(&req.method());
+        if streamed {
+            match body_length(req) {
+                Some(length) => head.push_str(&format!("Content-Length: {}\r\n", length)),
+                None => head.push_str("Transfer-Encoding: chunked\r\n"),
+            }
+        } else if with_body || !req.body().is_empty() {
+            head.push_str(&format!("Content-Length: {}\r\n", req.body().len()));
+        }
+        head.push_str("\r\n");
+        head.into_bytes()
+    }
+
+    // Sends the request on `stream` and the upstream's response to the
+    // client. Returns whether the connection can be reused, and whether the
+    // response came whole and without a gateway error. Errors are only
+    // returned while nothing was sent to the client yet.
+    fn forward(&self,
+               stream: &mut Stream,
+               reused: bool,
+               head: &[u8],
+               req: &Request,
+               body: &mut Option<StreamedBody>,
+               resp: &mut Response)
+               -> Result<(bool, bool), Error> {
+        let written = stream.write_all(head).and_then(|_| stream.write_all(req.body()));
+        match written {
+            Err(_) if reused => return Err(Error::Stale),
+            Err(e) => return Err(Error::from(e)),
+            Ok(()) => {}
+        }
+        if let Some(ref mut body) = *body {
+            body.started = true;
+            send_body(&mut body.reader, body_length(req), stream)?;
+        }
+        let repeatable = is_idempotent(req) && body.is_none();
+        let mut reader = BufReader::new(stream);
+        let response = match read_head(&mut reader) {
+            // Requests that are safe to repeat are sent again when a reused
+            // connection breaks before the response.
+            Err(Error::Unavailable(_)) if reused && repeatable => return Err(Error::Stale),
+            result => result?,
+        };
+        let framing = if req.method() == "HEAD" || response.code == 204 || response.code == 304 {
+            Framing::None
+        } else if response.header("Transfer-Encoding").is_some_and(|t| {
+            connection_tokens(t).iter().any(|t| t == "chunked")
+        }) {
+            Framing::Chunked
+        } else if let Some(length) = response.header("Content-Length") {
+            match length.trim().parse() {
+                Ok(length) => Framing::Length(length),
+                Err(_) => return Err(Error::Invalid(format!("Content-Length {}", length))),
+            }
+        } else {
+            Framing::Close
+        };
+        resp.set_status(Status::new(response.code, &response.reason));
+        let listed = response.header("Connection").map(connection_tokens).unwrap_or_default();
+        for &(ref name, ref value) in &response.headers {
+            let lower = name.to_lowercase();
+            if HOP_BY_HOP.contains(&lower.as_str()) || listed.contains(&lower) ||
+               lower == "content-length" {
+                continue;
+            }
+            resp.add_header(name, value);
+        }
+        // The body goes to the client as it comes.
+        let copied = match framing {
+            Framing::None => {
+                // A response to HEAD keeps the length of the body it doesn't
+                // have.
+                if let Some(length) = response.header("Content-Length") {
+                    resp.set_header("Content-Length", length);
+                }
+                resp.send();
+                Ok(true)
+            }
+            Framing::Length(length) => {
+                resp.set_length(length).send();
+                copy_body(&mut reader, Some(length), resp).map_err(Error::from)
+            }
+            Framing::Chunked => {
+                resp.send_chunked(req);
+                copy_chunked(&mut reader, resp)
+            }
+            Framing::Close => {
+                resp.send_chunked(req);
+                copy_body(&mut reader, None, resp).map_err(Error::from)
+            }
+        };
+        let ok = !(502..=504).contains(&response.code);
+        match copied {
+            Ok(true) => resp.end(),
+            // The client went away, the rest of the body is not read.
+            Ok(false) => return Ok((false, ok)),
+            Err(e) => {
+                // Too late for an error status.
+                error!("Error while reading the upstream response: {}", e);
+                resp.abort();
+                return Ok((false, false));
+            }
+        }
+        let keep_alive = response.keep_alive && !matches!(framing, Framing::Close);
+        Ok((keep_alive && reader.buffer().is_empty(), ok))
+    }
+}
+
+impl Handler for ProxyHandler {
+    fn process(&mut self, mut req: Request, resp: &mut Response) {
+        let mut body = req.take_body_reader().map(|reader| {
+            StreamedBody {
+                reader: reader,
+                started: false,
+            }
+        });
+        let mut tried = Vec::new();
+        let mut error = None;
+        while let Some(idx) = self.group.pick(&req, &tried) {
+            tried.push(idx);
+            let upstream = self.group.upstream(idx);
+            let e = match self.send(upstream, &req, &mut body, resp) {
+                Ok(ok) => {
+                    self.group.done(idx, ok);
+                    return;
+                }
+                Err(Error::Aborted) => {
+                    // Not the upstream's fault, and no one to answer.
+                    debug!("Request to upstream {} cut short", upstream.address());
+                    self.group.done(idx, true);
+                    return;
+                }
+                Err(e) => e,
+            };
+            self.group.done(idx, false);
+            // The next server gets the request if this one surely didn't
+            // process it, or if it is safe to repeat and its body can be
+            // sent again.
+            let retry = match e {
+                Error::Connect(_) => true,
+                _ => is_idempotent(&req) && !body.as_ref().is_some_and(|b| b.started),
+            };
+            match e {
+                Error::Connect(ref e) | Error::Unavailable(ref e) => {
+                    error!("Upstream {} unavailable: {}", upstream.address(), e);
+                }
+                Error::Timeout => error!("Upstream {} timed out", upstream.address()),
+                Error::Invalid(ref e) => {
+                    error!("Invalid response from upstream {}: {}", upstream.address(), e);
+                }
+                Error::Stale | Error::Aborted => unreachable!(),
+            }
+            error = Some(e);
+            if !retry {
+                break;
+            }
+        }
+        match error {
+            Some(Error::Timeout) => resp.set_gateway_timeout().send(),
+            Some(Error::Connect(ref e)) if e.kind() == ErrorKind::TimedOut => {
+                resp.set_gateway_timeout().send()
+            }
+            Some(_) => resp.set_bad_gateway().send(),
+            None => {
+                error!("No upstream available");
+                resp.set_bad_gateway().send();
+            }
+        }
+    }
+    fn streams_body(&self) -> bool {
+        true
+    }
+    fn duplicate(&self) -> Box<Handler> {
+        return Box::new(ProxyHandler {
+            group: self.group.clone(),
+            host: self.host.clone(),
+            connect_timeout: self.connect_timeout,
+            read_timeout: self.read_timeout,
+            max_idle: self.max_idle,
+        });
+    }
+}
+
+pub(crate) fn is_idempotent(req: &Request) -> bool {
+    !["POST", "PATCH"].contains(&req.method())
+}
+
+// The lowercase names in a comma-separated header.
+fn connection_tokens(value: &str) -> Vec<String> {
+    value.split(',').map(|t| t.trim().to_lowercase()).filter(|t| !t.is_empty()).collect()
+}
+
+// A quoted-string of the Forwarded header (RFC 7239), for values that are
+// not a token.
+fn quoted_string(value: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in value.chars() {
+        if c == '"' || c == '\\' {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn append(list: Option<&str>, value: &str) -> String {
+    match list {
+        Some(list) => format!("{}, {}", list, value),
+        None => value.to_string(),
+    }
+}
+
+pub(crate) fn read_line<R: BufRead>(reader: &mut R, limit: &mut usize) -> Result<String, Error> {
+    let mut line = Vec::new();
+    reader.by_ref().take(*limit as u64).read_until(b'\n', &mut line)?;
+    if line.last() != Some(&b'\n') {
+        if line.is_empty() {
+            return Err(Error::Unavailable(io::Error::new(ErrorKind::UnexpectedEof,
+                                                         "connection closed")));
+        }
+        return Err(Error::Invalid("truncated or too large head".to_string()));
+    }
+    *limit -= line.len();
+    match String::from_utf8(line) {
+        Ok(line) => Ok(line.trim_end_matches(['\r', '\n']).to_string()),
+        Err(_) => Err(Error::Invalid("head is not UTF-8".to_string())),
+    }
+}
+
+// Reads the status line and headers, skipping interim 1xx responses.
+fn read_head<R: BufRead>(reader: &mut R) -> Result<ResponseHead, Error> {
+    let mut limit = MAX_HEAD_SIZE;
+    loop {
+        let line = read_line(reader, &mut limit)?;
+        let mut parts = line.splitn(3, ' ');
+        let version = parts.next().unwrap_or("");
+        let code = parts.next().and_then(|c| c.parse::<u32>().ok());
+        let reason = parts.next().unwrap_or("").to_string();
+        let code = match code {
+            Some(code) if version.starts_with("HTTP/1.") && (100..600).contains(&code) => code,
+            _ => return Err(Error::Invalid(format!("status line {:?}", line))),
+        };
+        let mut headers = Vec::new();
+        loop {
+            let line = read_line(reader, &mut limit)?;
+            if line.is_empty() {
+                break;
+            }
+            match line.find(':') {
+                Some(idx) => {
+                    headers.push((line[..idx].trim().to_string(),
+                                  line[idx + 1..].trim().to_string()))
+                }
+                None => return Err(Error::Invalid(format!("header line {:?}", line))),
+            }
+        }
+        if code == 101 {
+            return Err(Error::Invalid("upgrades are not supported".to_string()));
+        }
+        if code < 200 {
+            continue;
+        }
+        let mut head = ResponseHead {
+            code: code,
+            reason: reason,
+            headers: headers,
+            keep_alive: false,
+        };
+        let tokens = head.header("Connection").map(connection_tokens).unwrap_or_default();
+        head.keep_alive = if version == "HTTP/1.0" {
+            tokens.iter().any(|t| t == "keep-alive")
+        } else {
+            !tokens.iter().any(|t| t == "close")
+        };
+        return Ok(head);
+    }
+}
+
+// The length a request declares for its body, which is otherwise sent
+// chunked.
+fn body_length(req: &Request) -> Option<u64> {
+    req.header("Content-Length").and_then(|length| length.trim().parse().ok())
+}
+
+// Sends `length` bytes of a request body to the upstream as they arrive, or
+// the whole body in chunks if None.
+fn send_body<W: Write>(body: &mut BodyReader,
+                       length: Option<u64>,
+                       stream: &mut W)
+                       -> Result<(), Error> {
+    let mut remaining = length.unwrap_or(u64::MAX);
+    let mut buf = vec![0; CHUNK_SIZE];
+    let mut chunk = Vec::new();
+    while remaining > 0 {
+        let max = remaining.min(CHUNK_SIZE as u64) as usize;
+        let read = match body.read(&mut buf[..max]) {
+            Ok(0) if length.is_none() => break,
+            Ok(0) | Err(_) => return Err(Error::Aborted),
+            Ok(read) => read,
+        };
+        if length.is_some() {
+            stream.write_all(&buf[..read])?;
+        } else {
+            chunk.clear();
+            chunk.extend_from_slice(format!("{:x}\r\n", read).as_bytes());
+            chunk.extend_from_slice(&buf[..read]);
+            chunk.extend_from_slice(b"\r\n");
+            stream.write_all(&chunk)?;
+        }
+        remaining -= read as u64;
+    }
+    if length.is_none() {
+        stream.write_all(b"0\r\n\r\n")?;
+    }
+    Ok(())
+}
+
+// Sends a chunked body as its chunks are read. Returns false if the client
+// went away.
+fn copy_chunked<R: BufRead>(reader: &mut R, resp: &mut Response) -> Result<bool, Error> {
+    loop {
+        let mut limit = MAX_HEAD_SIZE;
+        let line = read_line(reader, &mut limit)?;
+        let size = line.split(';').next().unwrap_or("").trim();
+        let size = match u64::from_str_radix(size, 16) {
+            Ok(size) => size,
+            Err(_) => return Err(Error::Invalid(format!("chunk size {:?}", line))),
+        };
+        if size == 0 {
+            // Skip the trailer.
+            while !read_line(reader, &mut limit)?.is_empty() {}
+            return Ok(true);
+        }
+        if !copy_body(reader, Some(size), resp)? {
+            return Ok(false);
+        }
+        if !read_line(reader, &mut limit)?.is_empty() {
+            return Err(Error::Invalid("chunk longer than its size".to_string()));
+        }
+    }
+}
+
+// Sends `length` bytes of body read from `reader`, or all there is to read
+// if None, handing them to the client as they come. Returns false if the
+// client went away.
+pub(crate) fn copy_body<R: Read>(reader: &mut R,
+                                 length: Option<u64>,
+                                 resp: &mut Response)
+                                 -> io::Result<bool> {
+    let mut remaining = length.unwrap_or(u64::MAX);
+    let mut buf = vec![0; CHUNK_SIZE];
+    while remaining > 0 {
+        let max = remaining.min(CHUNK_SIZE as u64) as usize;
+        let read = reader.read(&mut buf[..max])?;
+        if read == 0 {
+            if length.is_none() {
+                break;
+            }
+            return Err(io::Error::new(ErrorKind::UnexpectedEof, "upstream closed early"));
+        }
+        resp.send_data(&buf[..read]);
+        if !resp.flush() {
+            return Ok(false);
+        }
+        remaining -= read as u64;
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use connection::Output;
+    use http::BodySender;
+    use super::*;
+
+    fn request(method: &str, headers: &[(&str, &str)], body: &[u8]) -> Request {
+        let headers = headers.iter().map(|&(n, v)| (n.to_string(), v.to_string())).collect();
+        Request::from_parts(method, "/app/x?y=1", "HTTP/1.1", headers, body.to_vec()).unwrap()
+    }
+
+    // The request line, then the header lines sorted: the request headers
+    // come in no particular order.
+    fn head(handler: &ProxyHandler, req: &Request, streamed: bool) -> Vec<String> {
+        let head = handler.request_head(req, streamed, &Upstream::new("127.0.0.1:9000"));
+        let head = String::from_utf8(head).unwrap();
+        assert!(head.ends_with("\r\n\r\n"));
+        let mut lines: Vec<String> = head.trim_end().split("\r\n").map(String::from).collect();
+        lines[1..].sort();
+        lines
+    }
+
+    #[test]
+    fn request_heads() {
+        let handler = ProxyHandler::new("127.0.0.1:9000");
+        let mut req = request("POST",
+                              &[("Host", "example.com"),
+                                ("Connection", "keep-alive, X-Private"),
+                                ("X-Private", "1"),
+                                ("Keep-Alive", "timeout=5"),
+                                ("TE", "trailers"),
+                                ("Upgrade", "h2c"),
+                                ("Proxy-Authorization", "Basic eDp5"),
+                                ("Expect", "100-continue"),
+                                ("content-length", "5"),
+                                ("X-Forwarded-For", "10.0.0.1"),
+                                ("Accept", "*/*")],
+                              b"hello");
+        req.set_peer_addr(Some("[::1]:4000".parse().unwrap()));
+        assert_eq!(head(&handler, &req, false),
+                   vec!["POST /app/x?y=1 HTTP/1.1",
+                        "Accept: */*",
+                        "Content-Length: 5",
+                        "Forwarded: for=\"[::1]\";proto=http;host=\"example.com\"",
+                        "Host: 127.0.0.1:9000",
+                        "X-Forwarded-For: 10.0.0.1, ::1",
+                        "X-Forwarded-Host: example.com",
+                        "X-Forwarded-Proto: http"]);
+    }
+
+    #[test]
+    fn streamed_request_heads() {
+        let mut handler = ProxyHandler::new("127.0.0.1:9000");
+        handler.set_host("app.internal");
+        let req = request("PUT", &[("content-LENGTH", " 12 ")], b"");
+        let lines = head(&handler, &req, true);
+        assert!(lines.contains(&"Content-Length: 12".to_string()));
+        assert!(lines.contains(&"Host: app.internal".to_string()));
+        let req = request("POST", &[("Transfer-Encoding", "chunked")], b"");
+        let lines = head(&handler, &req, true);
+        assert!(lines.contains(&"Transfer-Encoding: chunked".to_string()));
+        assert!(!lines.iter().any(|l| l.starts_with("Content-Length")));
+        // Bodyless requests don't get a Content-Length.
+        let lines = head(&handler, &request("GET", &[], b""), false);
+        assert!(!lines.iter().any(|l| l.starts_with("Content-Length")));
+    }
+
+    #[test]
+    fn header_values() {
+        assert_eq!(connection_tokens(" Keep-Alive,,X-Foo , "), vec!["keep-alive", "x-foo"]);
+        assert_eq!(quoted_string("a\"b\\c"), "\"a\\\"b\\\\c\"");
+        assert_eq!(append(None, "b"), "b");
+        assert_eq!(append(Some("a"), "b"), "a, b");
+    }
+
+    #[test]
+    fn response_heads() {
+        let mut reader = Cursor::new(&b"HTTP/1.1 100 Continue\r\n\r\n\
+                                        HTTP/1.1 404 Not Found\r\n\
+                                        content-length: 3\r\nX-A:b\r\n\r\nabc"[..]);
+        let head = read_head(&mut reader).ok().unwrap();
+        assert_eq!((head.code, head.reason.as_str()), (404, "Not Found"));
+        assert_eq!(head.header("Content-Length"), Some("3"));
+        assert_eq!(head.header("x-a"), Some("b"));
+        assert!(head.keep_alive);
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, b"abc");
+
+        let mut reader = Cursor::new(&b"HTTP/1.1 200 OK\r\nConnection: Close\r\n\r\n"[..]);
+        assert!(!read_head(&mut reader).ok().unwrap().keep_alive);
+        let mut reader = Cursor::new(&b"HTTP/1.0 200 OK\r\n\r\n"[..]);
+        assert!(!read_head(&mut reader).ok().unwrap().keep_alive);
+        let mut reader = Cursor::new(&b"HTTP/1.0 200 OK\r\nConnection: keep-alive\r\n\r\n"[..]);
+        assert!(read_head(&mut reader).ok().unwrap().keep_alive);
+    }
+
+    #[test]
+    fn invalid_response_heads() {
+        for &head in &[&b"HTTP/1.1 2000 OK\r\n\r\n"[..],
+                       b"ICY 200 OK\r\n\r\n",
+                       b"HTTP/1.1 200 OK\r\nNo colon\r\n\r\n",
+                       b"HTTP/1.1 200 OK\r\nX: truncated",
+                       b"HTTP/1.1 101 Switching Protocols\r\n\r\n"] {
+            match read_head(&mut Cursor::new(head)) {
+                Err(Error::Invalid(_)) => {}
+                _ => panic!("{:?}", String::from_utf8_lossy(head)),
+            }
+        }
+        match read_head(&mut Cursor::new(&b""[..])) {
+            Err(Error::Unavailable(_)) => {}
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn chunked_responses() {
+        let mut reader = Cursor::new(&b"5;ext=1\r\nhello\r\n6\r\n world\r\n\
+                                        0\r\nX-T: 1\r\n\r\nnext"[..]);
+        let mut out = Output::new();
+        assert!(copy_chunked(&mut reader, &mut Response::with_output(&mut out)).ok().unwrap());
+        assert_eq!(out.queued_data(), b"hello world");
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, b"next");
+
+        let mut out = Output::new();
+        let mut resp = Response::with_output(&mut out);
+        let mut reader = Cursor::new(&b"3\r\nhello\r\n0\r\n\r\n"[..]);
+        assert!(matches!(copy_chunked(&mut reader, &mut resp), Err(Error::Invalid(_))));
+    }
+
+    #[test]
+    fn request_bodies() {
+        // Without a length, the body is sent in chunks until its end.
+        let (sender, mut reader) = BodySender::new();
+        sender.send(b"hello");
+        sender.send(b" world");
+        sender.finish();
+        let mut sent = Vec::new();
+        assert!(send_body(&mut reader, None, &mut sent).is_ok());
+        assert_eq!(sent, b"b\r\nhello world\r\n0\r\n\r\n");
+        // With one, as it is.
+        let (sender, mut reader) = BodySender::new();
+        sender.send(b"hello");
+        sender.finish();
+        let mut sent = Vec::new();
+        assert!(send_body(&mut reader, Some(5), &mut sent).is_ok());
+        assert_eq!(sent, b"hello");
+        // A body that ends before its length, or is cut short by the client.
+        let (sender, mut reader) = BodySender::new();
+        sender.send(b"hel");
+        sender.finish();
+        assert!(matches!(send_body(&mut reader, Some(5), &mut Vec::new()), Err(Error::Aborted)));
+        let (sender, mut reader) = BodySender::new();
+        sender.send(b"hel");
+        drop(sender);
+        let mut sent = Vec::new();
+        assert!(matches!(send_body(&mut reader, None, &mut sent), Err(Error::Aborted)));
+        assert_eq!(sent, b"3\r\nhel\r\n");
+    }
+}
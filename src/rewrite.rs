@@ -0,0 +1,232 @@
+use handler_lib::{Handler, Regex};
+use http::{Request, Response, Status};
+use regex::Captures;
+
+/// What a rewrite rule does with the requests it matches.
+///
+/// Targets can use the groups captured by the pattern, as `$1` or `${1}`,
+/// and `${name}` for named groups. The query string of the request is added
+/// to them, after theirs if they have one, unless they end with '?'.
+#[derive(Debug, Clone)]
+pub enum Action {
+    /// Routes the request with this URI, the next rules are checked with it.
+    Rewrite(String),
+    /// Routes the request with this URI, the next rules are skipped.
+    RewriteLast(String),
+    /// Redirects the client with a 301, 302, 303, 307 or 308 status.
+    Redirect(u32, String),
+    /// Answers with this status, like a 403 or a 410.
+    Return(u32),
+}
+
+/// A condition for a rewrite rule to apply, besides its pattern. Patterns
+/// are regular expressions, they match anywhere in the value.
+#[derive(Debug, Clone)]
+pub enum Condition {
+    /// The header is there and matches the pattern.
+    Header(String, String),
+    /// The header is missing or doesn't match the pattern.
+    NotHeader(String, String),
+    /// The request has one of these methods.
+    Method(Vec<String>),
+    /// The query string matches the pattern, an empty one if there is none.
+    Query(String),
+}
+
+#[derive(Debug, Clone)]
+enum Test {
+    Header(String, Regex, bool),
+    Method(Vec<String>),
+    Query(Regex),
+}
+
+impl Test {
+    fn new(condition: Condition) -> Test {
+        match condition {
+            Condition::Header(name, pattern) => {
+                Test::Header(name, Regex::new(&pattern).unwrap(), true)
+            }
+            Condition::NotHeader(name, pattern) => {
+                Test::Header(name, Regex::new(&pattern).unwrap(), false)
+            }
+            Condition::Method(methods) => {
+                Test::Method(methods.iter().map(|m| m.to_uppercase()).collect())
+            }
+            Condition::Query(pattern) => Test::Query(Regex::new(&pattern).unwrap()),
+        }
+    }
+
+    fn check(&self, r: &Request) -> bool {
+        match *self {
+            Test::Header(ref name, ref regex, matches) => {
+                r.header(name).is_some_and(|value| regex.is_match(value)) == matches
+            }
+            Test::Method(ref methods) => methods.iter().any(|m| m == r.method()),
+            Test::Query(ref regex) => regex.is_match(r.query().unwrap_or("")),
+        }
+    }
+}
+
+/// Rewrites or answers the requests whose URI matches a pattern, before
+/// they are routed. The pattern is matched against the whole path, like
+/// those of the routes.
+#[derive(Debug, Clone)]
+pub struct RewriteRule {
+    pattern: Regex,
+    action: Action,
+    tests: Vec<Test>,
+}
+
+impl RewriteRule {
+    pub fn new(pattern: &str, action: Action) -> RewriteRule {
+        if let Action::Redirect(code, _) = action {
+            assert!(is_redirect(code), "{} is not a redirect status", code);
+        }
+        return RewriteRule {
+            pattern: Regex::new(&format!("^{}$", pattern)).unwrap(),
+            action: action,
+            tests: Vec::new(),
+        };
+    }
+
+    // The rule only applies to requests that also meet `condition`.
+    pub fn add_condition(&mut self, condition: Condition) {
+        self.tests.push(Test::new(condition));
+    }
+}
+
+// What is left to do with a request once the rewrite rules were applied.
+pub(crate) enum Outcome {
+    Route,
+    Redirect(Status, String),
+    Return(Status),
+}
+
+impl Outcome {
+    pub(crate) fn respond(self, resp: &mut Response) {
+        match self {
+            Outcome::Route => {}
+            Outcome::Redirect(status, location) => resp.set_redirect(status, &location).send(),
+            Outcome::Return(status) => {
+                let body = format!("<html><h1>{} {}</h1></html>", status.code(), status.desc());
+                resp.set_status(status)
+                    .set_header("Content-Type", "text/html")
+                    .set_body_str(&body)
+                    .send()
+            }
+        }
+    }
+}
+
+// Applies `rules` in order to `r`, rewriting its URI.
+pub(crate) fn apply(rules: &[RewriteRule], r: &mut Request) -> Outcome {
+    for rule in rules {
+        let captures = match rule.pattern.captures(&r.uri) {
+            Some(captures) if rule.tests.iter().all(|t| t.check(r)) => captures,
+            _ => continue,
+        };
+        let target = match rule.action {
+            Action::Rewrite(ref target) |
+            Action::RewriteLast(ref target) |
+            Action::Redirect(_, ref target) => target_uri(target, Some(&captures), r.query()),
+            Action::Return(code) => return Outcome::Return(Status::from_code(code)),
+        };
+        match rule.action {
+            Action::Redirect(code, _) => return Outcome::Redirect(Status::from_code(code), target),
+            // An internal rewrite to another server can only be a redirect.
+            _ if !target.starts_with('/') => return Outcome::Redirect(Status::found(), target),
+            Action::RewriteLast(_) => {
+                r.rewrite_uri(&target);
+                break;
+            }
+            _ => r.rewrite_uri(&target),
+        }
+    }
+    Outcome::Route
+}
+
+/// Redirects every request, to a target that can use the groups captured
+/// by the pattern, as rewrite rules do. The pattern is usually that of the
+/// route; when a request doesn't match it, it goes to the target as is.
+pub struct RedirectHandler {
+    pattern: Regex,
+    target: String,
+    code: u32,
+}
+
+impl RedirectHandler {
+    // `code` is 301, 302, 303, 307 or 308.
+    pub fn new(pattern: &str, target: &str, code: u32) -> RedirectHandler {
+        assert!(is_redirect(code), "{} is not a redirect status", code);
+        return RedirectHandler {
+            pattern: Regex::new(&format!("^{}$", pattern)).unwrap(),
+            target: target.to_string(),
+            code: code,
+        };
+    }
+}
+
+impl Handler for RedirectHandler {
+    fn process(&mut self, req: Request, resp: &mut Response) {
+        let captures = self.pattern.captures(&req.uri);
+        let location = target_uri(&self.target, captures.as_ref(), req.query());
+        resp.set_redirect(Status::from_code(self.code), &location).send();
+    }
+    fn duplicate(&self) -> Box<Handler> {
+        return Box::new(RedirectHandler {
+            pattern: self.pattern.clone(),
+            target: self.target.clone(),
+            code: self.code,
+        });
+    }
+}
+
+fn is_redirect(code: u32) -> bool {
+    [301, 302, 303, 307, 308].contains(&code)
+}
+
+// Expands the captures in `target`, and adds `query` to it.
+fn target_uri(target: &str, captures: Option<&Captures>, query: Option<&str>) -> String {
+    let mut uri = String::new();
+    let mut rest = target;
+    while let Some(idx) = rest.find('$') {
+        uri.push_str(&rest[..idx]);
+        rest = &rest[idx + 1..];
+        if let Some(after) = rest.strip_prefix('$') {
+            uri.push('$');
+            rest = after;
+            continue;
+        }
+        let (name, after) = match rest.strip_prefix('{') {
+            Some(braced) => match braced.find('}') {
+                Some(end) => (&braced[..end], &braced[end + 1..]),
+                None => ("", rest),
+            },
+            None => {
+                let end = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+                (&rest[..end], &rest[end..])
+            }
+        };
+        if name.is_empty() {
+            uri.push('$');
+            continue;
+        }
+        let value = captures.and_then(|captures| match name.parse::<usize>() {
+            Ok(idx) => captures.at(idx),
+            Err(_) => captures.name(name),
+        });
+        uri.push_str(value.unwrap_or(""));
+        rest = after;
+    }
+    uri.push_str(rest);
+    if let Some(uri) = uri.strip_suffix('?') {
+        return uri.to_string();
+    }
+    match query {
+        Some(query) if !query.is_empty() => {
+            let separator = if uri.contains('?') { '&' } else { '?' };
+            format!("{}{}{}", uri, separator, query)
+        }
+        _ => uri,
+    }
+}
@@ -0,0 +1,89 @@
+use std::thread;
+use std::process;
+use std::io::{self, ErrorKind};
+use std::sync::{Mutex, Once};
+use std::sync::atomic::{AtomicIsize, Ordering};
+use libc;
+use event_loop::ShutdownHandle;
+use logging;
+
+// Write end of the pipe used to get signals out of the signal handler.
+static SIGNAL_PIPE: AtomicIsize = AtomicIsize::new(-1);
+static START: Once = Once::new();
+// The server SIGTERM and SIGINT shut down, if asked.
+static SHUTDOWN: Mutex<Option<ShutdownHandle>> = Mutex::new(None);
+
+extern "C" fn on_signal(signum: libc::c_int) {
+    let fd = SIGNAL_PIPE.load(Ordering::SeqCst) as libc::c_int;
+    let byte = signum as u8;
+    // Only async-signal-safe calls in here.
+    unsafe {
+        libc::write(fd, &byte as *const u8 as *const libc::c_void, 1);
+    }
+}
+
+// Shuts the server down gracefully on SIGTERM or SIGINT. A second signal
+// exits right away.
+pub fn shutdown_on_signals(handle: ShutdownHandle) {
+    *SHUTDOWN.lock().unwrap() = Some(handle);
+    install(&[libc::SIGTERM, libc::SIGINT]);
+}
+
+// Reopens the log files on SIGHUP, for log rotation.
+pub fn reopen_logs_on_sighup() {
+    install(&[libc::SIGHUP]);
+}
+
+fn install(signums: &[libc::c_int]) {
+    START.call_once(start);
+    let handler: extern "C" fn(libc::c_int) = on_signal;
+    for &signum in signums {
+        unsafe {
+            libc::signal(signum, handler as libc::sighandler_t);
+        }
+    }
+}
+
+// Creates the pipe and the thread handling the signals written to it.
+fn start() {
+    let mut fds = [0 as libc::c_int; 2];
+    unsafe {
+        if libc::pipe(fds.as_mut_ptr()) != 0 {
+            panic!("Can't create signal pipe");
+        }
+        // Not for the processes started by the handlers, like CGI scripts.
+        // The server starts none before, and pipe2() is not everywhere.
+        for &fd in &fds {
+            libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+        }
+    }
+    let (read_fd, write_fd) = (fds[0], fds[1]);
+    SIGNAL_PIPE.store(write_fd as isize, Ordering::SeqCst);
+    thread::spawn(move || {
+        let mut signals = 0;
+        loop {
+            let mut byte = 0u8;
+            let n = unsafe { libc::read(read_fd, &mut byte as *mut u8 as *mut libc::c_void, 1) };
+            if n != 1 {
+                if io::Error::last_os_error().kind() == ErrorKind::Interrupted {
+                    continue;
+                }
+                error!("Can't read signal pipe, signals are ignored from now on");
+                return;
+            }
+            if byte as libc::c_int == libc::SIGHUP {
+                logging::reopen_files();
+                continue;
+            }
+            signals += 1;
+            if signals > 1 {
+                info!("Got signal {} again, exiting now", byte);
+                process::exit(1);
+            }
+            info!("Got signal {}, shutting down", byte);
+            if let Some(ref handle) = *SHUTDOWN.lock().unwrap() {
+                handle.shutdown();
+            }
+        }
+    });
+}
@@ -0,0 +1,192 @@
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use event_loop::Waker;
+use http::Request;
+
+// Sent when no event went out for a heartbeat interval.
+const HEARTBEAT: &[u8] = b":\n\n";
+
+/// An event to send on an event stream.
+#[derive(Debug, Clone)]
+pub struct Event {
+    id: Option<String>,
+    event: Option<String>,
+    data: String,
+    retry: Option<Duration>,
+}
+
+impl Event {
+    pub fn new(data: &str) -> Event {
+        return Event {
+            id: None,
+            event: None,
+            data: data.to_string(),
+            retry: None,
+        };
+    }
+
+    // The id the client sends back in `Last-Event-ID` when it reconnects.
+    pub fn set_id(&mut self, id: &str) -> &mut Event {
+        self.id = Some(id.to_string());
+        self
+    }
+
+    // The type of the event, "message" if not set.
+    pub fn set_event(&mut self, event: &str) -> &mut Event {
+        self.event = Some(event.to_string());
+        self
+    }
+
+    // How long the client waits before reconnecting.
+    pub fn set_retry(&mut self, retry: Duration) -> &mut Event {
+        self.retry = Some(retry);
+        self
+    }
+
+    fn as_bytes(&self) -> Vec<u8> {
+        let mut b = String::new();
+        if let Some(ref id) = self.id {
+            b.push_str(&format!("id: {}\n", single_line(id)));
+        }
+        if let Some(ref event) = self.event {
+            b.push_str(&format!("event: {}\n", single_line(event)));
+        }
+        if let Some(retry) = self.retry {
+            b.push_str(&format!("retry: {}\n", retry.as_millis()));
+        }
+        // Each line goes in a field of its own, the client joins them back.
+        for line in self.data.replace("\r\n", "\n").split(['\r', '\n']) {
+            b.push_str(&format!("data: {}\n", line));
+        }
+        b.push('\n');
+        b.into_bytes()
+    }
+}
+
+// A line break would end the field, and a NUL makes clients ignore the id.
+fn single_line(value: &str) -> String {
+    value.chars().filter(|&c| c != '\r' && c != '\n' && c != '\0').collect()
+}
+
+struct Shared {
+    queued: Vec<u8>,
+    closing: bool,
+    disconnected: bool,
+}
+
+/// Sends events on an event stream, from any thread.
+#[derive(Clone)]
+pub struct EventSender {
+    shared: Arc<Mutex<Shared>>,
+    waker: Waker,
+}
+
+impl EventSender {
+    // Queues `event`. Returns false, and drops the event, once the stream
+    // was closed or the client went away.
+    pub fn send(&self, event: &Event) -> bool {
+        {
+            let mut shared = self.shared.lock().unwrap();
+            if shared.closing || shared.disconnected {
+                return false;
+            }
+            shared.queued.extend_from_slice(&event.as_bytes());
+        }
+        self.waker.wake();
+        true
+    }
+
+    // Ends the stream once the events queued so far are sent.
+    pub fn close(&self) {
+        self.shared.lock().unwrap().closing = true;
+        self.waker.wake();
+    }
+
+    // Whether the stream was closed or the client went away, in which case
+    // the sender can be dropped.
+    pub fn is_closed(&self) -> bool {
+        let shared = self.shared.lock().unwrap();
+        shared.closing || shared.disconnected
+    }
+}
+
+/// Opens Server-Sent Events streams. The handler is given a sender for each
+/// request on its route, to keep and use from anywhere. It runs on the
+/// event loop, so it must not block.
+pub trait SseHandler : Send + 'static {
+    // Called for each request. `last_event_id` is the id of the last event
+    // the client got, when it reconnects after losing the stream.
+    fn open(&mut self, request: &Request, last_event_id: Option<&str>, events: EventSender);
+    // How long the stream can go without an event before a comment is sent,
+    // to keep proxies from closing it and to notice clients that are gone.
+    fn heartbeat(&self) -> Duration {
+        Duration::from_secs(15)
+    }
+    fn duplicate(&self) -> Box<SseHandler>;
+}
+
+// The connection side of an event stream.
+pub(crate) struct EventStream {
+    shared: Arc<Mutex<Shared>>,
+    heartbeat: Duration,
+    // Whether anything was sent since the last heartbeat.
+    active: bool,
+}
+
+impl EventStream {
+    pub(crate) fn open(handler: &mut Box<SseHandler>,
+                       request: &Request,
+                       waker: Waker)
+                       -> EventStream {
+        let shared = Arc::new(Mutex::new(Shared {
+            queued: Vec::new(),
+            closing: false,
+            disconnected: false,
+        }));
+        let sender = EventSender {
+            shared: shared.clone(),
+            waker: waker,
+        };
+        handler.open(request, request.header("Last-Event-ID"), sender);
+        return EventStream {
+            shared: shared,
+            heartbeat: handler.heartbeat(),
+            active: false,
+        };
+    }
+
+    // Takes the events queued since the last call, and whether the stream
+    // ends after them.
+    pub(crate) fn take(&mut self) -> (Vec<u8>, bool) {
+        let mut shared = self.shared.lock().unwrap();
+        let queued = mem::take(&mut shared.queued);
+        if !queued.is_empty() {
+            self.active = true;
+        }
+        (queued, shared.closing)
+    }
+
+    // Called when the heartbeat timer fires, returns what to send.
+    pub(crate) fn heartbeat(&mut self) -> &'static [u8] {
+        let active = self.active;
+        self.active = false;
+        if active {
+            &[]
+        } else {
+            HEARTBEAT
+        }
+    }
+
+    pub(crate) fn heartbeat_interval(&self) -> Duration {
+        self.heartbeat
+    }
+}
+
+impl Drop for EventStream {
+    fn drop(&mut self) {
+        if let Ok(mut shared) = self.shared.lock() {
+            shared.disconnected = true;
+        }
+    }
+}
@@ -0,0 +1,313 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime};
+use access_log::json_string;
+use date::rfc3339;
+use handler_lib::Handler;
+use http::{Request, Response};
+use util::escape_html;
+use workers::WorkerLoads;
+
+// The seconds of requests kept for the rates.
+const RATE_WINDOW: u64 = 300;
+
+/// Who can see the status page. It is a 404 for the others, as if there
+/// were none.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StatusAccess {
+    /// The clients on the loopback interface.
+    Localhost,
+    /// The connections to the listener on this address, like one added with
+    /// `WebServer::add_listener` for admins.
+    Listener(SocketAddr),
+    /// Anyone.
+    Anyone,
+}
+
+impl StatusAccess {
+    fn allows(&self, r: &Request) -> bool {
+        match *self {
+            // Also over IPv6 to an IPv4-mapped loopback address.
+            StatusAccess::Localhost => {
+                r.peer_addr().is_some_and(|a| a.ip().to_canonical().is_loopback())
+            }
+            StatusAccess::Listener(addr) => r.listener_addr() == Some(addr),
+            StatusAccess::Anyone => true,
+        }
+    }
+}
+
+// What the status page shows of a connection. The event handler fills in
+// the socket side, the app what goes on over it.
+#[derive(Debug, Clone)]
+pub(crate) struct ConnStatus {
+    pub(crate) peer: Option<SocketAddr>,
+    pub(crate) opened: Instant,
+    pub(crate) state: &'static str,
+    pub(crate) received: u64,
+    pub(crate) sent: u64,
+    pub(crate) protocol: &'static str,
+    // The last request received, like "GET /index.html".
+    pub(crate) request: Option<String>,
+    // The requests received so far.
+    pub(crate) requests: u64,
+}
+
+// The requests of the last seconds, by second since the server started.
+struct Rates {
+    seconds: Vec<(u64, u64)>,
+    total: u64,
+}
+
+impl Rates {
+    fn add(&mut self, second: u64, requests: u64) {
+        let slot = &mut self.seconds[(second % RATE_WINDOW) as usize];
+        if slot.0 != second {
+            *slot = (second, 0);
+        }
+        slot.1 += requests;
+        self.total += requests;
+    }
+
+    // The requests per second over the `window` seconds before `now`, the
+    // current one left out as it is not over.
+    fn rate(&self, now: u64, window: u64) -> f64 {
+        let window = window.min(now);
+        if window == 0 {
+            return 0.0;
+        }
+        let requests: u64 = self.seconds
+                                .iter()
+                                .filter(|&&(s, _)| s < now && s >= now - window)
+                                .map(|&(_, n)| n)
+                                .sum();
+        requests as f64 / window as f64
+    }
+}
+
+// The state of a running server, updated by its worker threads.
+pub(crate) struct ServerStatus {
+    started: Instant,
+    started_at: SystemTime,
+    loads: WorkerLoads,
+    // The open connections of each worker thread, by id.
+    conns: Vec<Mutex<HashMap<usize, ConnStatus>>>,
+    rates: Mutex<Rates>,
+}
+
+impl ServerStatus {
+    pub(crate) fn new(loads: WorkerLoads) -> ServerStatus {
+        let conns = (0..loads.len()).map(|_| Mutex::new(HashMap::new())).collect();
+        return ServerStatus {
+            started: Instant::now(),
+            started_at: SystemTime::now(),
+            loads: loads,
+            conns: conns,
+            rates: Mutex::new(Rates {
+                seconds: vec![(0, 0); RATE_WINDOW as usize],
+                total: 0,
+            }),
+        };
+    }
+
+    // Replaces what is known of connection `id`. The requests it got since
+    // the last update are counted in the rates.
+    pub(crate) fn update(&self, id: usize, status: ConnStatus) {
+        let new_requests = {
+            let mut conns = self.conns[id % self.conns.len()].lock().unwrap();
+            let before = conns.get(&id).map_or(0, |s| s.requests);
+            let requests = status.requests;
+            conns.insert(id, status);
+            requests.saturating_sub(before)
+        };
+        if new_requests > 0 {
+            let second = self.started.elapsed().as_secs();
+            self.rates.lock().unwrap().add(second, new_requests);
+        }
+    }
+
+    pub(crate) fn remove(&self, id: usize) {
+        self.conns[id % self.conns.len()].lock().unwrap().remove(&id);
+    }
+
+    // The open connections, by id, with their worker.
+    fn connections(&self) -> Vec<(usize, usize, ConnStatus)> {
+        let mut conns = Vec::new();
+        for (worker, table) in self.conns.iter().enumerate() {
+            for (id, status) in table.lock().unwrap().iter() {
+                conns.push((*id, worker, status.clone()));
+            }
+        }
+        conns.sort_by_key(|&(id, _, _)| id);
+        conns
+    }
+
+    // The requests since the start and the rates over 10 seconds, a minute
+    // and 5 minutes.
+    fn requests(&self) -> (u64, [f64; 3]) {
+        let now = self.started.elapsed().as_secs();
+        let rates = self.rates.lock().unwrap();
+        (rates.total, [rates.rate(now, 10), rates.rate(now, 60), rates.rate(now, 300)])
+    }
+
+    fn json(&self) -> String {
+        let (total, rates) = self.requests();
+        let mut out = String::new();
+        let _ = write!(out,
+                       "{{\"started\":{},\"uptime_seconds\":{},\"requests\":{{\"total\":{},\
+                        \"per_second_10s\":{:.3},\"per_second_1m\":{:.3},\"per_second_5m\":{:.3}}},\
+                        \"workers\":[",
+                       json_string(&rfc3339(self.started_at)),
+                       self.started.elapsed().as_secs(),
+                       total,
+                       rates[0],
+                       rates[1],
+                       rates[2]);
+        for (worker, stats) in self.loads.stats().iter().enumerate() {
+            let _ = write!(out,
+                           "{}{{\"worker\":{},\"connections\":{},\"queue_depth\":{},\
+                            \"events\":{}}}",
+                           if worker > 0 { "," } else { "" },
+                           worker,
+                           stats.connections,
+                           stats.queue_depth,
+                           stats.events);
+        }
+        out.push_str("],\"connections\":[");
+        for (idx, (id, worker, c)) in self.connections().into_iter().enumerate() {
+            let _ = write!(out,
+                           "{}{{\"id\":{},\"worker\":{},\"peer\":{},\"state\":{},\"protocol\":{},\
+                            \"request\":{},\"requests\":{},\"received\":{},\"sent\":{},\
+                            \"age_seconds\":{:.3}}}",
+                           if idx > 0 { "," } else { "" },
+                           id,
+                           worker,
+                           c.peer.map_or("null".to_string(), |p| json_string(&p.to_string())),
+                           json_string(c.state),
+                           json_string(c.protocol),
+                           c.request.as_ref().map_or("null".to_string(), |r| json_string(r)),
+                           c.requests,
+                           c.received,
+                           c.sent,
+                           c.opened.elapsed().as_secs_f64());
+        }
+        out.push_str("]}");
+        out
+    }
+
+    fn html(&self) -> String {
+        let (total, rates) = self.requests();
+        let mut out = String::new();
+        out.push_str("<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\">\
+                      <title>Server status</title>\n<style>\
+                      body{font-family:sans-serif}\
+                      table{border-collapse:collapse}\
+                      th,td{border:1px solid #ccc;padding:2px 8px;text-align:left}\
+                      </style></head><body>\n<h1>Server status</h1>\n");
+        let _ = writeln!(out,
+                         "<p>Started {}, up {}.</p>",
+                         rfc3339(self.started_at),
+                         format_seconds(self.started.elapsed().as_secs()));
+        let _ = writeln!(out,
+                         "<p>{} requests. Per second: {:.2} over 10 seconds, {:.2} over a \
+                          minute, {:.2} over 5 minutes.</p>",
+                         total,
+                         rates[0],
+                         rates[1],
+                         rates[2]);
+        out.push_str("<h2>Workers</h2>\n<table><tr><th>Worker</th><th>Connections</th>\
+                      <th>Queue depth</th><th>Events</th></tr>\n");
+        for (worker, stats) in self.loads.stats().iter().enumerate() {
+            let _ = writeln!(out,
+                             "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                             worker,
+                             stats.connections,
+                             stats.queue_depth,
+                             stats.events);
+        }
+        let conns = self.connections();
+        let _ = writeln!(out,
+                         "</table>\n<h2>Connections ({})</h2>\n<table><tr><th>Id</th>\
+                          <th>Worker</th><th>Client</th><th>State</th><th>Protocol</th>\
+                          <th>Request</th><th>Requests</th><th>Received</th><th>Sent</th>\
+                          <th>Age</th></tr>",
+                         conns.len());
+        for (id, worker, c) in conns {
+            let _ = writeln!(out,
+                             "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td>\
+                              <td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                             id,
+                             worker,
+                             c.peer.map_or("-".to_string(), |p| p.to_string()),
+                             c.state,
+                             c.protocol,
+                             escape_html(c.request.as_deref().unwrap_or("-")),
+                             c.requests,
+                             c.received,
+                             c.sent,
+                             format_seconds(c.opened.elapsed().as_secs()));
+        }
+        out.push_str("</table>\n</body></html>\n");
+        out
+    }
+}
+
+// Like "2d 3h 4m 5s", without the leading zeros.
+fn format_seconds(secs: u64) -> String {
+    let parts = [(secs / 86400, "d"), (secs / 3600 % 24, "h"), (secs / 60 % 60, "m")];
+    let mut out = String::new();
+    for &(n, unit) in &parts {
+        if n > 0 || !out.is_empty() {
+            let _ = write!(out, "{}{} ", n, unit);
+        }
+    }
+    let _ = write!(out, "{}s", secs % 60);
+    out
+}
+
+// Shows the status of the server, in HTML, or JSON to the clients that
+// accept it or ask for it with `?format=json`.
+pub(crate) struct StatusHandler {
+    status: Arc<ServerStatus>,
+    access: StatusAccess,
+}
+
+impl StatusHandler {
+    pub(crate) fn new(status: Arc<ServerStatus>, access: StatusAccess) -> StatusHandler {
+        return StatusHandler {
+            status: status,
+            access: access,
+        };
+    }
+}
+
+impl Handler for StatusHandler {
+    fn process(&mut self, request: Request, response: &mut Response) {
+        if !self.access.allows(&request) {
+            response.set_not_found().send();
+            return;
+        }
+        if request.method() != "GET" && request.method() != "HEAD" {
+            response.set_method_not_allowed("GET, HEAD").send();
+            return;
+        }
+        let json = request.query().is_some_and(|q| q.split('&').any(|p| p == "format=json")) ||
+                   request.header("Accept").is_some_and(|a| a.contains("application/json"));
+        let (content_type, body) = if json {
+            ("application/json", self.status.json())
+        } else {
+            ("text/html; charset=utf-8", self.status.html())
+        };
+        response.set_header("Content-Type", content_type)
+                .set_header("Cache-Control", "no-store")
+                .add_header("Vary", "Accept")
+                .set_body(body.as_bytes())
+                .send();
+    }
+
+    fn duplicate(&self) -> Box<Handler> {
+        Box::new(StatusHandler::new(self.status.clone(), self.access))
+    }
+}
@@ -0,0 +1,365 @@
+use std::time::{Duration, Instant};
+use std::mem;
+
+// Resolution of the wheel. Timers fire at most one tick late.
+const TICK_MS: u64 = 10;
+const SLOT_BITS: u64 = 6;
+const SLOTS: usize = 1 << SLOT_BITS;
+// 64^4 ticks of 10ms is about 46 hours. Longer timers are clamped to the
+// last slot, and put back there until they are due.
+const LEVELS: usize = 4;
+
+/// Identifies a scheduled timer so it can be cancelled.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Timer {
+    key: usize,
+    generation: u64,
+}
+
+struct Entry<T> {
+    tick: u64,
+    generation: u64,
+    value: T,
+}
+
+/// A hierarchical timer wheel.
+///
+/// Level 0 has one slot per tick, and each slot of the next level covers a
+/// whole rotation of the previous one. Timers are moved down a level when
+/// the wheel reaches their slot, so scheduling, cancelling and expiring are
+/// all O(1) whatever the number of timers.
+pub struct TimerWheel<T> {
+    start: Instant,
+    current: u64,
+    levels: Vec<Vec<Vec<(usize, u64)>>>,
+    entries: Vec<Option<Entry<T>>>,
+    free: Vec<usize>,
+    next_generation: u64,
+    len: usize,
+}
+
+impl<T> TimerWheel<T> {
+    pub fn new() -> TimerWheel<T> {
+        let mut levels = Vec::with_capacity(LEVELS);
+        for _ in 0..LEVELS {
+            let mut slots = Vec::with_capacity(SLOTS);
+            for _ in 0..SLOTS {
+                slots.push(Vec::new());
+            }
+            levels.push(slots);
+        }
+        return TimerWheel {
+            start: Instant::now(),
+            current: 0,
+            levels: levels,
+            entries: Vec::new(),
+            free: Vec::new(),
+            next_generation: 0,
+            len: 0,
+        };
+    }
+
+    // Schedules `value` to be returned by `expired()` once `delay` has passed.
+    pub fn schedule(&mut self, delay: Duration, value: T) -> Timer {
+        let deadline = Instant::now() + delay;
+        self.schedule_at(deadline, value)
+    }
+
+    pub fn schedule_at(&mut self, deadline: Instant, value: T) -> Timer {
+        let tick = self.tick_for(deadline);
+        let generation = self.next_generation;
+        self.next_generation += 1;
+        let entry = Entry {
+            tick: tick,
+            generation: generation,
+            value: value,
+        };
+        let key = match self.free.pop() {
+            Some(key) => {
+                self.entries[key] = Some(entry);
+                key
+            }
+            None => {
+                self.entries.push(Some(entry));
+                self.entries.len() - 1
+            }
+        };
+        self.len += 1;
+        self.insert(key, generation, tick);
+        return Timer {
+            key: key,
+            generation: generation,
+        };
+    }
+
+    // Cancels a timer. Returns its value if it had not fired yet.
+    pub fn cancel(&mut self, timer: Timer) -> Option<T> {
+        let matches = match self.entries.get(timer.key) {
+            Some(&Some(ref entry)) => entry.generation == timer.generation,
+            _ => false,
+        };
+        if !matches {
+            return None;
+        }
+        // The slot still references the key, it is skipped when the slot is
+        // processed since the generation won't match anymore.
+        self.release(timer.key)
+    }
+
+    // How long until the next timer may fire, None if there are no timers.
+    pub fn next_timeout(&self) -> Option<Duration> {
+        if self.len == 0 {
+            return None;
+        }
+        let mut next_tick = None;
+        for t in 1..SLOTS as u64 + 1 {
+            let tick = self.current + t;
+            if !self.levels[0][(tick % SLOTS as u64) as usize].is_empty() {
+                next_tick = Some(tick);
+                break;
+            }
+        }
+        if next_tick.is_none() {
+            // Wake up when the next level cascades its timers down.
+            for level in 1..LEVELS {
+                if self.levels[level].iter().any(|s| !s.is_empty()) {
+                    let span = 1 << (SLOT_BITS * level as u64);
+                    next_tick = Some((self.current / span + 1) * span);
+                    break;
+                }
+            }
+        }
+        match next_tick {
+            None => None,
+            Some(tick) => {
+                let at = self.start + Duration::from_millis(tick * TICK_MS);
+                let now = Instant::now();
+                if at > now {
+                    Some(at - now)
+                } else {
+                    Some(Duration::from_millis(0))
+                }
+            }
+        }
+    }
+
+    // Advances the wheel to now and returns the values of the timers that
+    // fired.
+    pub fn expired(&mut self) -> Vec<T> {
+        self.expired_at(Instant::now())
+    }
+
+    pub fn expired_at(&mut self, now: Instant) -> Vec<T> {
+        let mut fired = Vec::new();
+        let target = self.elapsed_ticks(now);
+        while self.current < target {
+            if self.len == 0 {
+                self.current = target;
+                break;
+            }
+            self.current += 1;
+            for level in 1..LEVELS {
+                let span = 1 << (SLOT_BITS * level as u64);
+                if self.current & (span - 1) != 0 {
+                    break;
+                }
+                self.cascade(level);
+            }
+            let slot = (self.current % SLOTS as u64) as usize;
+            let keys = mem::take(&mut self.levels[0][slot]);
+            for (key, generation) in keys {
+                if !self.is_live(key, generation) {
+                    continue;
+                }
+                let tick = self.entries[key].as_ref().unwrap().tick;
+                if tick <= self.current {
+                    if let Some(value) = self.release(key) {
+                        fired.push(value);
+                    }
+                } else {
+                    // Clamped timer that still has rotations to go.
+                    self.insert(key, generation, tick);
+                }
+            }
+        }
+        fired
+    }
+
+    fn cascade(&mut self, level: usize) {
+        let slot = ((self.current >> (SLOT_BITS * level as u64)) % SLOTS as u64) as usize;
+        let keys = mem::take(&mut self.levels[level][slot]);
+        for (key, generation) in keys {
+            if !self.is_live(key, generation) {
+                continue;
+            }
+            let tick = self.entries[key].as_ref().unwrap().tick;
+            if tick <= self.current {
+                // Due now: the slot of the current tick is processed after
+                // the cascades.
+                let slot = (self.current % SLOTS as u64) as usize;
+                self.levels[0][slot].push((key, generation));
+            } else {
+                self.insert(key, generation, tick);
+            }
+        }
+    }
+
+    fn insert(&mut self, key: usize, generation: u64, tick: u64) {
+        let tick = if tick <= self.current {
+            self.current + 1
+        } else {
+            tick
+        };
+        let delta = tick - self.current;
+        let mut level = 0;
+        while level < LEVELS - 1 && delta >= 1 << (SLOT_BITS * (level as u64 + 1)) {
+            level += 1;
+        }
+        let max = (1 << (SLOT_BITS * (level as u64 + 1))) - 1;
+        let slot_tick = if delta > max {
+            self.current + max
+        } else {
+            tick
+        };
+        let slot = ((slot_tick >> (SLOT_BITS * level as u64)) % SLOTS as u64) as usize;
+        self.levels[level][slot].push((key, generation));
+    }
+
+    fn is_live(&self, key: usize, generation: u64) -> bool {
+        match self.entries[key] {
+            Some(ref entry) => entry.generation == generation,
+            None => false,
+        }
+    }
+
+    fn release(&mut self, key: usize) -> Option<T> {
+        match self.entries[key].take() {
+            Some(entry) => {
+                self.free.push(key);
+                self.len -= 1;
+                Some(entry.value)
+            }
+            None => None,
+        }
+    }
+
+    fn elapsed_ticks(&self, now: Instant) -> u64 {
+        if now <= self.start {
+            return 0;
+        }
+        let elapsed = now - self.start;
+        let ms = elapsed.as_secs() * 1000 + elapsed.subsec_millis() as u64;
+        ms / TICK_MS
+    }
+
+    fn tick_for(&self, deadline: Instant) -> u64 {
+        if deadline <= self.start {
+            return 0;
+        }
+        let d = deadline - self.start;
+        // Round up so a timer never fires early.
+        let ms = d.as_secs() * 1000 + d.subsec_nanos().div_ceil(1_000_000) as u64;
+        ms.div_ceil(TICK_MS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at<T>(wheel: &TimerWheel<T>, tick: u64) -> Instant {
+        wheel.start + Duration::from_millis(tick * TICK_MS)
+    }
+
+    // The tick each timer fires at, advancing the wheel a tick at a time.
+    fn fired_ticks(wheel: &mut TimerWheel<u64>, from: u64, to: u64) -> Vec<(u64, u64)> {
+        let mut fired = Vec::new();
+        for tick in from..to + 1 {
+            let now = at(wheel, tick);
+            fired.extend(wheel.expired_at(now).into_iter().map(|value| (tick, value)));
+        }
+        fired
+    }
+
+    #[test]
+    fn timers_cascade_at_level_boundaries() {
+        let mut wheel = TimerWheel::new();
+        let ticks = [1, 63, 64, 65, 127, 128, 129, 4095, 4096, 4097, 4160, 8191, 8192];
+        for &tick in &ticks {
+            let deadline = at(&wheel, tick);
+            wheel.schedule_at(deadline, tick);
+        }
+        let fired = fired_ticks(&mut wheel, 1, 8200);
+        assert_eq!(fired, ticks.iter().map(|&t| (t, t)).collect::<Vec<_>>());
+        assert_eq!(wheel.next_timeout(), None);
+
+        // Scheduled once the wheel has turned, across the next boundaries.
+        for &delay in &[1, 60, 70, 4000, 4200] {
+            let deadline = at(&wheel, 8200 + delay);
+            wheel.schedule_at(deadline, 8200 + delay);
+        }
+        let fired = fired_ticks(&mut wheel, 8201, 12500);
+        let expected: Vec<_> = [8201, 8260, 8270, 12200, 12400].iter().map(|&t| (t, t)).collect();
+        assert_eq!(fired, expected);
+    }
+
+    #[test]
+    fn long_timers_are_clamped_to_the_wheel() {
+        let mut wheel = TimerWheel::new();
+        let span = 1 << (SLOT_BITS * LEVELS as u64);
+        let deadline = at(&wheel, span + 100);
+        wheel.schedule_at(deadline, 1);
+        // Kept in the last slot of the top level, then put back until due.
+        assert!(wheel.levels[LEVELS - 1][SLOTS - 1].len() == 1);
+        let now = at(&wheel, span + 99);
+        assert!(wheel.expired_at(now).is_empty());
+        let now = at(&wheel, span + 100);
+        assert_eq!(wheel.expired_at(now), vec![1]);
+    }
+
+    #[test]
+    fn stale_timers_cancel_nothing() {
+        let mut wheel = TimerWheel::new();
+        let first = wheel.schedule_at(at(&wheel, 5), "first");
+        assert_eq!(wheel.cancel(first), Some("first"));
+        assert_eq!(wheel.cancel(first), None);
+        // The slot of the first timer is reused.
+        let second = wheel.schedule_at(at(&wheel, 5), "second");
+        assert_eq!(wheel.cancel(first), None);
+        let now = at(&wheel, 5);
+        assert_eq!(wheel.expired_at(now), vec!["second"]);
+        assert_eq!(wheel.cancel(second), None);
+
+        let third = wheel.schedule_at(at(&wheel, 10), "third");
+        assert_eq!(wheel.cancel(second), None);
+        assert_eq!(wheel.cancel(third), Some("third"));
+        let now = at(&wheel, 10);
+        assert!(wheel.expired_at(now).is_empty());
+    }
+
+    #[test]
+    fn next_timeout_wakes_up_for_cascades() {
+        let mut wheel = TimerWheel::new();
+        assert_eq!(wheel.next_timeout(), None);
+        // On level 1, the wheel wakes up when tick 64 cascades it.
+        let deadline = at(&wheel, 100);
+        wheel.schedule_at(deadline, ());
+        let timeout = wheel.next_timeout().unwrap();
+        assert!(timeout <= Duration::from_millis(64 * TICK_MS), "{:?}", timeout);
+        assert!(timeout > Duration::from_millis(60 * TICK_MS), "{:?}", timeout);
+
+        // On level 2, at tick 4096.
+        let mut wheel = TimerWheel::new();
+        let deadline = at(&wheel, 5000);
+        wheel.schedule_at(deadline, ());
+        let timeout = wheel.next_timeout().unwrap();
+        assert!(timeout <= Duration::from_millis(4096 * TICK_MS), "{:?}", timeout);
+        assert!(timeout > Duration::from_millis(4000 * TICK_MS), "{:?}", timeout);
+        // Once cascaded to level 1, the next wake up is at tick 4160.
+        let now = at(&wheel, 4096);
+        assert!(wheel.expired_at(now).is_empty());
+        let remaining = at(&wheel, 4160).saturating_duration_since(Instant::now());
+        let timeout = wheel.next_timeout().unwrap();
+        assert!(timeout <= remaining && timeout + Duration::from_secs(1) > remaining);
+    }
+}
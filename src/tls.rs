@@ -0,0 +1,243 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufReader, ErrorKind};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+use rustls::{RootCertStore, ServerConfig};
+use rustls::crypto::ring::{self as provider, sign};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier};
+use rustls::sign::CertifiedKey;
+use rustls_pemfile;
+
+// How often the certificate files are checked for changes, at most.
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Whether clients must present a certificate signed by the configured CA.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClientAuth {
+    Required,
+    /// Clients without a certificate are accepted, `Request::tls()` tells
+    /// whether one was presented.
+    Optional,
+}
+
+/// The TLS side of a connection, as seen by the handlers.
+#[derive(Debug, Clone)]
+pub struct TlsInfo {
+    /// The host name the client asked for with SNI.
+    pub server_name: Option<String>,
+    /// The protocol negotiated with ALPN.
+    pub alpn_protocol: Option<String>,
+    /// The DER encoded certificate chain of the client, leaf first. Empty
+    /// unless client authentication is enabled and the client sent one.
+    pub client_certificates: Vec<Vec<u8>>,
+}
+
+/// Certificates and settings of a TLS listener.
+///
+/// Certificates and keys are read from PEM files, which are read again when
+/// they change on disk so that certificates can be renewed without a restart.
+pub struct TlsConfig {
+    default_cert: CertFiles,
+    sni_certs: Vec<(String, CertFiles)>,
+    alpn_protocols: Vec<String>,
+    client_auth: Option<(Vec<CertificateDer<'static>>, ClientAuth)>,
+}
+
+impl TlsConfig {
+    // `cert_path` holds the certificate chain, leaf first. The certificate
+    // is used for the clients that don't match any SNI certificate.
+    pub fn new(cert_path: &str, key_path: &str) -> io::Result<TlsConfig> {
+        return Ok(TlsConfig {
+            default_cert: CertFiles::load(cert_path, key_path)?,
+            sni_certs: Vec::new(),
+            alpn_protocols: vec!["h2".to_string(), "http/1.1".to_string()],
+            client_auth: None,
+        });
+    }
+
+    // Uses another certificate for the clients asking for `server_name`,
+    // which can be a wildcard like `*.example.com`.
+    pub fn add_certificate(&mut self,
+                           server_name: &str,
+                           cert_path: &str,
+                           key_path: &str)
+                           -> io::Result<()> {
+        let files = CertFiles::load(cert_path, key_path)?;
+        self.sni_certs.push((server_name.to_lowercase(), files));
+        Ok(())
+    }
+
+    // The protocols offered with ALPN, by order of preference.
+    pub fn set_alpn_protocols(&mut self, protocols: &[&str]) {
+        self.alpn_protocols = protocols.iter().map(|p| p.to_string()).collect();
+    }
+
+    // Asks clients for a certificate signed by one of the CAs of `ca_path`.
+    pub fn set_client_auth(&mut self, ca_path: &str, auth: ClientAuth) -> io::Result<()> {
+        let certs = read_certs(ca_path)?;
+        if certs.is_empty() {
+            return Err(invalid_data(format!("no certificate in {}", ca_path)));
+        }
+        self.client_auth = Some((certs, auth));
+        Ok(())
+    }
+
+    pub(crate) fn server_config(self) -> io::Result<Arc<ServerConfig>> {
+        let provider = Arc::new(provider::default_provider());
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+                          .with_safe_default_protocol_versions()
+                          .map_err(tls_error)?;
+        let builder = match self.client_auth {
+            None => builder.with_no_client_auth(),
+            Some((ca_certs, auth)) => {
+                let mut roots = RootCertStore::empty();
+                for cert in ca_certs {
+                    roots.add(cert).map_err(tls_error)?;
+                }
+                let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots),
+                                                                           provider);
+                let verifier = match auth {
+                    ClientAuth::Required => verifier,
+                    ClientAuth::Optional => verifier.allow_unauthenticated(),
+                };
+                builder.with_client_cert_verifier(verifier.build().map_err(tls_error)?)
+            }
+        };
+        let resolver = CertResolver {
+            default_cert: self.default_cert,
+            sni_certs: self.sni_certs.into_iter().collect(),
+            last_check: Mutex::new(Instant::now()),
+        };
+        let mut config = builder.with_cert_resolver(Arc::new(resolver));
+        config.alpn_protocols = self.alpn_protocols.into_iter().map(|p| p.into_bytes()).collect();
+        Ok(Arc::new(config))
+    }
+}
+
+// A certificate and its key, with what is needed to notice they changed.
+struct CertFiles {
+    cert_path: String,
+    key_path: String,
+    modified: Mutex<(Option<SystemTime>, Option<SystemTime>)>,
+    key: RwLock<Arc<CertifiedKey>>,
+}
+
+impl CertFiles {
+    fn load(cert_path: &str, key_path: &str) -> io::Result<CertFiles> {
+        let modified = (modified(cert_path), modified(key_path));
+        let key = load_certified_key(cert_path, key_path)?;
+        return Ok(CertFiles {
+            cert_path: cert_path.to_string(),
+            key_path: key_path.to_string(),
+            modified: Mutex::new(modified),
+            key: RwLock::new(Arc::new(key)),
+        });
+    }
+
+    fn current(&self) -> Arc<CertifiedKey> {
+        self.key.read().unwrap().clone()
+    }
+
+    // Loads the files again if they changed. A certificate that fails to
+    // load is reported and the previous one is kept.
+    fn reload_if_changed(&self) {
+        let modified = (modified(&self.cert_path), modified(&self.key_path));
+        let mut last_modified = self.modified.lock().unwrap();
+        if modified == *last_modified {
+            return;
+        }
+        match load_certified_key(&self.cert_path, &self.key_path) {
+            Ok(key) => {
+                info!("Reloaded certificate {}", self.cert_path);
+                *last_modified = modified;
+                *self.key.write().unwrap() = Arc::new(key);
+            }
+            Err(e) => {
+                error!("Error while reloading certificate {}: {}", self.cert_path, e);
+            }
+        }
+    }
+}
+
+// Picks the certificate from the SNI host name.
+struct CertResolver {
+    default_cert: CertFiles,
+    sni_certs: HashMap<String, CertFiles>,
+    last_check: Mutex<Instant>,
+}
+
+impl CertResolver {
+    fn find(&self, server_name: Option<&str>) -> &CertFiles {
+        if let Some(name) = server_name {
+            let name = name.to_lowercase();
+            if let Some(files) = self.sni_certs.get(&name) {
+                return files;
+            }
+            if let Some(idx) = name.find('.') {
+                if let Some(files) = self.sni_certs.get(&format!("*{}", &name[idx..])) {
+                    return files;
+                }
+            }
+        }
+        &self.default_cert
+    }
+
+    fn check_reload(&self) {
+        let mut last_check = self.last_check.lock().unwrap();
+        if last_check.elapsed() < RELOAD_CHECK_INTERVAL {
+            return;
+        }
+        *last_check = Instant::now();
+        self.default_cert.reload_if_changed();
+        for files in self.sni_certs.values() {
+            files.reload_if_changed();
+        }
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        self.check_reload();
+        Some(self.find(client_hello.server_name()).current())
+    }
+}
+
+impl fmt::Debug for CertResolver {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "CertResolver({} SNI certificates)", self.sni_certs.len())
+    }
+}
+
+fn modified(path: &str) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+fn read_certs(path: &str) -> io::Result<Vec<CertificateDer<'static>>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    rustls_pemfile::certs(&mut reader).collect()
+}
+
+fn load_certified_key(cert_path: &str, key_path: &str) -> io::Result<CertifiedKey> {
+    let certs = read_certs(cert_path)?;
+    if certs.is_empty() {
+        return Err(invalid_data(format!("no certificate in {}", cert_path)));
+    }
+    let mut reader = BufReader::new(File::open(key_path)?);
+    let key: PrivateKeyDer = match rustls_pemfile::private_key(&mut reader)? {
+        Some(key) => key,
+        None => return Err(invalid_data(format!("no private key in {}", key_path))),
+    };
+    let key = sign::any_supported_type(&key).map_err(tls_error)?;
+    Ok(CertifiedKey::new(certs, key))
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, msg)
+}
+
+fn tls_error<E: fmt::Display>(e: E) -> io::Error {
+    invalid_data(e.to_string())
+}
@@ -0,0 +1,808 @@
+// A parser for the TOML configuration files. It reads the whole syntax but
+// dates and times, and keeps the line of every value for the messages about
+// it.
+
+use std::char;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Value {
+    String(String),
+    Integer(i64),
+    Float(f64),
+    Boolean(bool),
+    Array(Vec<Item>),
+    Table(Table),
+    // An array of tables, from `[[name]]` headers.
+    Tables(Vec<Item>),
+}
+
+impl Value {
+    pub(crate) fn type_name(&self) -> &'static str {
+        match *self {
+            Value::String(_) => "a string",
+            Value::Integer(_) => "an integer",
+            Value::Float(_) => "a float",
+            Value::Boolean(_) => "a boolean",
+            Value::Array(_) | Value::Tables(_) => "an array",
+            Value::Table(_) => "a table",
+        }
+    }
+}
+
+// A value and the line it starts on.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Item {
+    pub(crate) value: Value,
+    pub(crate) line: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Table {
+    // In the order of the file.
+    pub(crate) entries: Vec<(String, Item)>,
+    // How the table was defined, which tells what can add keys to it later.
+    kind: TableKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TableKind {
+    // Only as the parent of other tables so far.
+    Implicit,
+    // By a `[name]` or `[[name]]` header.
+    Header,
+    // By the dotted keys of key/value pairs.
+    Dotted,
+    // Inline, it can't get more keys.
+    Inline,
+}
+
+impl Table {
+    fn new(kind: TableKind) -> Table {
+        return Table {
+            entries: Vec::new(),
+            kind: kind,
+        };
+    }
+
+    fn get_mut(&mut self, key: &str) -> Option<&mut Item> {
+        self.entries.iter_mut().find(|e| e.0 == key).map(|e| &mut e.1)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Error {
+    pub(crate) line: usize,
+    pub(crate) message: String,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+// Parses a whole document into its root table.
+pub(crate) fn parse(text: &str) -> Result<Table, Error> {
+    let mut parser = Parser {
+        chars: text.chars().collect(),
+        pos: 0,
+        line: 1,
+    };
+    parser.document()
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    line: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).cloned()
+    }
+
+    fn peek_at(&self, offset: usize) -> Option<char> {
+        self.chars.get(self.pos + offset).cloned()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek();
+        if let Some(c) = c {
+            self.pos += 1;
+            if c == '\n' {
+                self.line += 1;
+            }
+        }
+        c
+    }
+
+    fn starts_with(&self, s: &str) -> bool {
+        s.chars().enumerate().all(|(i, c)| self.peek_at(i) == Some(c))
+    }
+
+    fn error<T>(&self, message: String) -> Result<T, Error> {
+        Err(Error {
+            line: self.line,
+            message: message,
+        })
+    }
+
+    // Describes the next character for the messages about it.
+    fn found(&self) -> String {
+        match self.peek() {
+            None => "the end of the file".to_string(),
+            Some('\n') | Some('\r') => "the end of the line".to_string(),
+            Some(c) => format!("`{}`", c),
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), Error> {
+        if self.peek() != Some(c) {
+            return self.error(format!("expected `{}`, found {}", c, self.found()));
+        }
+        self.next();
+        Ok(())
+    }
+
+    fn skip_spaces(&mut self) {
+        while let Some(' ') | Some('\t') = self.peek() {
+            self.next();
+        }
+    }
+
+    fn skip_comment(&mut self) -> Result<(), Error> {
+        if self.peek() != Some('#') {
+            return Ok(());
+        }
+        while let Some(c) = self.peek() {
+            if c == '\n' || (c == '\r' && self.peek_at(1) == Some('\n')) {
+                break;
+            }
+            if is_control(c) {
+                return self.error("control character in a comment".to_string());
+            }
+            self.next();
+        }
+        Ok(())
+    }
+
+    // Skips a newline, CRLF or LF. Returns whether there was one.
+    fn skip_newline(&mut self) -> bool {
+        if self.starts_with("\r\n") {
+            self.next();
+        }
+        if self.peek() == Some('\n') {
+            self.next();
+            return true;
+        }
+        false
+    }
+
+    // Skips the spaces, comments and newlines between the values of an
+    // array.
+    fn skip_blank(&mut self) -> Result<(), Error> {
+        loop {
+            self.skip_spaces();
+            self.skip_comment()?;
+            if !self.skip_newline() {
+                return Ok(());
+            }
+        }
+    }
+
+    // What may follow a header or a key/value pair on its line.
+    fn end_of_line(&mut self) -> Result<(), Error> {
+        self.skip_spaces();
+        self.skip_comment()?;
+        if self.peek().is_some() && !self.skip_newline() {
+            return self.error(format!("expected the end of the line, found {}", self.found()));
+        }
+        Ok(())
+    }
+
+    fn document(&mut self) -> Result<Table, Error> {
+        let mut root = Table::new(TableKind::Header);
+        // The keys of the table the pairs go to.
+        let mut current = Vec::new();
+        loop {
+            self.skip_blank()?;
+            match self.peek() {
+                None => return Ok(root),
+                Some('[') => {
+                    let line = self.line;
+                    self.next();
+                    let array = self.peek() == Some('[');
+                    if array {
+                        self.next();
+                    }
+                    self.skip_spaces();
+                    let keys = self.key()?;
+                    self.skip_spaces();
+                    self.expect(']')?;
+                    if array {
+                        self.expect(']')?;
+                    }
+                    self.end_of_line()?;
+                    if array {
+                        add_table_array(&mut root, &keys, line)?;
+                    } else {
+                        add_table(&mut root, &keys, line)?;
+                    }
+                    current = keys;
+                }
+                Some(_) => {
+                    let table = table_at(&mut root, &current);
+                    self.key_value(table)?;
+                    self.end_of_line()?;
+                }
+            }
+        }
+    }
+
+    // Reads `key = value` into `table`.
+    fn key_value(&mut self, table: &mut Table) -> Result<(), Error> {
+        let line = self.line;
+        let keys = self.key()?;
+        self.skip_spaces();
+        self.expect('=')?;
+        self.skip_spaces();
+        let item = self.value()?;
+        insert_dotted(table, &keys, item, line)
+    }
+
+    // A dotted key, as its parts.
+    fn key(&mut self) -> Result<Vec<String>, Error> {
+        let mut keys = vec![self.simple_key()?];
+        loop {
+            self.skip_spaces();
+            if self.peek() != Some('.') {
+                return Ok(keys);
+            }
+            self.next();
+            self.skip_spaces();
+            keys.push(self.simple_key()?);
+        }
+    }
+
+    fn simple_key(&mut self) -> Result<String, Error> {
+        match self.peek() {
+            Some('"') if !self.starts_with("\"\"\"") => self.basic_string(),
+            Some('\'') if !self.starts_with("'''") => self.literal_string(),
+            _ => {
+                let mut key = String::new();
+                while let Some(c) = self.peek() {
+                    if !(c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+                        break;
+                    }
+                    key.push(c);
+                    self.next();
+                }
+                if key.is_empty() {
+                    return self.error(format!("expected a key, found {}", self.found()));
+                }
+                Ok(key)
+            }
+        }
+    }
+
+    fn value(&mut self) -> Result<Item, Error> {
+        let line = self.line;
+        let value = match self.peek() {
+            Some('"') if self.starts_with("\"\"\"") => Value::String(self.multiline_string()?),
+            Some('"') => Value::String(self.basic_string()?),
+            Some('\'') if self.starts_with("'''") => {
+                Value::String(self.multiline_literal_string()?)
+            }
+            Some('\'') => Value::String(self.literal_string()?),
+            Some('[') => Value::Array(self.array()?),
+            Some('{') => Value::Table(self.inline_table()?),
+            Some(_) => self.scalar()?,
+            None => return self.error("expected a value, found the end of the file".to_string()),
+        };
+        Ok(Item {
+            value: value,
+            line: line,
+        })
+    }
+
+    fn basic_string(&mut self) -> Result<String, Error> {
+        self.expect('"')?;
+        let mut s = String::new();
+        loop {
+            match self.peek() {
+                Some('"') => {
+                    self.next();
+                    return Ok(s);
+                }
+                Some('\\') => s.push(self.escape()?),
+                Some(c) if c == '\n' || c == '\r' || is_control(c) => {
+                    return self.error("unterminated string".to_string());
+                }
+                Some(c) => {
+                    s.push(c);
+                    self.next();
+                }
+                None => return self.error("unterminated string".to_string()),
+            }
+        }
+    }
+
+    fn multiline_string(&mut self) -> Result<String, Error> {
+        self.pos += 3;
+        // A newline right after the delimiter is not part of the string.
+        self.skip_newline();
+        let mut s = String::new();
+        loop {
+            if self.starts_with("\"\"\"") {
+                // Up to two quotes can end the string, before the delimiter.
+                let mut quotes = 3;
+                while quotes < 5 && self.peek_at(quotes) == Some('"') {
+                    quotes += 1;
+                }
+                self.pos += quotes;
+                s.push_str(&"\"\""[..quotes - 3]);
+                return Ok(s);
+            }
+            match self.peek() {
+                Some('\\') => {
+                    // A backslash at the end of a line trims the whitespace
+                    // after it, newlines included.
+                    let mut ahead = 1;
+                    while let Some(' ') | Some('\t') = self.peek_at(ahead) {
+                        ahead += 1;
+                    }
+                    let after = &self.chars[self.pos + ahead..];
+                    if after.starts_with(&['\n']) || after.starts_with(&['\r', '\n']) {
+                        self.pos += ahead;
+                        while let Some(' ') | Some('\t') | Some('\n') | Some('\r') = self.peek() {
+                            self.next();
+                        }
+                    } else {
+                        s.push(self.escape()?);
+                    }
+                }
+                Some(c) if c == '\n' || c == '\r' || !is_control(c) => {
+                    s.push(c);
+                    self.next();
+                }
+                Some(_) => return self.error("control character in a string".to_string()),
+                None => return self.error("unterminated string".to_string()),
+            }
+        }
+    }
+
+    fn literal_string(&mut self) -> Result<String, Error> {
+        self.expect('\'')?;
+        let mut s = String::new();
+        loop {
+            match self.next() {
+                Some('\'') => return Ok(s),
+                Some(c) if c == '\n' || c == '\r' || is_control(c) => {
+                    return self.error("unterminated string".to_string());
+                }
+                Some(c) => s.push(c),
+                None => return self.error("unterminated string".to_string()),
+            }
+        }
+    }
+
+    fn multiline_literal_string(&mut self) -> Result<String, Error> {
+        self.pos += 3;
+        self.skip_newline();
+        let mut s = String::new();
+        loop {
+            if self.starts_with("'''") {
+                let mut quotes = 3;
+                while quotes < 5 && self.peek_at(quotes) == Some('\'') {
+                    quotes += 1;
+                }
+                self.pos += quotes;
+                s.push_str(&"''"[..quotes - 3]);
+                return Ok(s);
+            }
+            match self.next() {
+                Some(c) if c == '\n' || c == '\r' || !is_control(c) => s.push(c),
+                Some(_) => return self.error("control character in a string".to_string()),
+                None => return self.error("unterminated string".to_string()),
+            }
+        }
+    }
+
+    fn escape(&mut self) -> Result<char, Error> {
+        self.next();
+        let c = match self.next() {
+            Some('b') => '\u{8}',
+            Some('t') => '\t',
+            Some('n') => '\n',
+            Some('f') => '\u{c}',
+            Some('r') => '\r',
+            Some('e') => '\u{1b}',
+            Some('"') => '"',
+            Some('\\') => '\\',
+            Some('u') => self.unicode_escape(4)?,
+            Some('U') => self.unicode_escape(8)?,
+            Some(c) => return self.error(format!("invalid escape `\\{}`", c)),
+            None => return self.error("unterminated string".to_string()),
+        };
+        Ok(c)
+    }
+
+    fn unicode_escape(&mut self, digits: usize) -> Result<char, Error> {
+        let mut hex = String::new();
+        for _ in 0..digits {
+            match self.peek() {
+                Some(c) if c.is_ascii_hexdigit() => {
+                    hex.push(c);
+                    self.next();
+                }
+                _ => return self.error(format!("expected {} hex digits", digits)),
+            }
+        }
+        let code = u32::from_str_radix(&hex, 16).unwrap();
+        match char::from_u32(code) {
+            Some(c) => Ok(c),
+            None => self.error(format!("invalid unicode escape `{}`", hex)),
+        }
+    }
+
+    fn array(&mut self) -> Result<Vec<Item>, Error> {
+        self.expect('[')?;
+        let mut items = Vec::new();
+        loop {
+            self.skip_blank()?;
+            if self.peek() == Some(']') {
+                self.next();
+                return Ok(items);
+            }
+            items.push(self.value()?);
+            self.skip_blank()?;
+            match self.peek() {
+                Some(',') => {
+                    self.next();
+                }
+                Some(']') => {}
+                _ => return self.error(format!("expected `,` or `]`, found {}", self.found())),
+            }
+        }
+    }
+
+    fn inline_table(&mut self) -> Result<Table, Error> {
+        self.expect('{')?;
+        let mut table = Table::new(TableKind::Header);
+        self.skip_spaces();
+        if self.peek() == Some('}') {
+            self.next();
+            table.kind = TableKind::Inline;
+            return Ok(table);
+        }
+        loop {
+            self.skip_spaces();
+            self.key_value(&mut table)?;
+            self.skip_spaces();
+            match self.peek() {
+                Some(',') => {
+                    self.next();
+                }
+                Some('}') => {
+                    self.next();
+                    freeze(&mut table);
+                    return Ok(table);
+                }
+                _ => return self.error(format!("expected `,` or `}}`, found {}", self.found())),
+            }
+        }
+    }
+
+    // A boolean or a number.
+    fn scalar(&mut self) -> Result<Value, Error> {
+        let mut word = String::new();
+        while let Some(c) = self.peek() {
+            if !(c.is_ascii_alphanumeric() || "+-._:".contains(c)) {
+                break;
+            }
+            word.push(c);
+            self.next();
+        }
+        match word.as_str() {
+            "" => return self.error(format!("expected a value, found {}", self.found())),
+            "true" => return Ok(Value::Boolean(true)),
+            "false" => return Ok(Value::Boolean(false)),
+            "inf" | "+inf" => return Ok(Value::Float(f64::INFINITY)),
+            "-inf" => return Ok(Value::Float(f64::NEG_INFINITY)),
+            "nan" | "+nan" | "-nan" => return Ok(Value::Float(f64::NAN)),
+            _ => {}
+        }
+        if word.contains(':') || word[1..].contains('-') && !word.contains(['e', 'E']) {
+            return self.error(format!("dates and times are not supported: `{}`", word));
+        }
+        let (sign, digits) = match word.strip_prefix('-') {
+            Some(rest) => ("-", rest),
+            None => ("", word.strip_prefix('+').unwrap_or(&word)),
+        };
+        let radix = match digits.get(..2) {
+            Some("0x") => 16,
+            Some("0o") => 8,
+            Some("0b") => 2,
+            _ => 10,
+        };
+        if radix != 10 {
+            let body = &digits[2..];
+            if sign.is_empty() && valid_underscores(body) {
+                if let Ok(n) = i64::from_str_radix(&body.replace('_', ""), radix) {
+                    return Ok(Value::Integer(n));
+                }
+            }
+            return self.error(format!("invalid number `{}`", word));
+        }
+        let leading_zero = digits.len() > 1 && digits.starts_with('0') &&
+                           digits.as_bytes()[1].is_ascii_digit();
+        if !leading_zero && valid_underscores(digits) {
+            let plain = format!("{}{}", sign, digits.replace('_', ""));
+            if plain.bytes().all(|b| b.is_ascii_digit() || b == b'-') {
+                if let Ok(n) = plain.parse() {
+                    return Ok(Value::Integer(n));
+                }
+            } else if valid_float(digits) {
+                if let Ok(f) = plain.parse() {
+                    return Ok(Value::Float(f));
+                }
+            }
+        }
+        self.error(format!("invalid value `{}`", word))
+    }
+}
+
+fn is_control(c: char) -> bool {
+    (c < ' ' && c != '\t') || c == '\u{7f}'
+}
+
+// Underscores must be between two digits.
+fn valid_underscores(digits: &str) -> bool {
+    let bytes = digits.as_bytes();
+    !digits.is_empty() &&
+    bytes.iter().enumerate().all(|(i, &b)| {
+        b != b'_' ||
+        (i > 0 && i + 1 < bytes.len() && bytes[i - 1].is_ascii_alphanumeric() &&
+         bytes[i + 1].is_ascii_alphanumeric())
+    })
+}
+
+// Digits are needed on both sides of the dot, and after the exponent.
+fn valid_float(digits: &str) -> bool {
+    let (mantissa, exponent) = match digits.find(['e', 'E']) {
+        Some(idx) => (&digits[..idx], Some(&digits[idx + 1..])),
+        None => (digits, None),
+    };
+    let int_digits = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit() || b == b'_');
+    let mantissa_ok = match mantissa.find('.') {
+        Some(idx) => int_digits(&mantissa[..idx]) && int_digits(&mantissa[idx + 1..]),
+        None => int_digits(mantissa),
+    };
+    let exponent_ok = match exponent {
+        Some(e) => int_digits(e.strip_prefix(['+', '-']).unwrap_or(e)),
+        None => true,
+    };
+    mantissa_ok && exponent_ok
+}
+
+// Makes an inline table and the tables in it immutable.
+fn freeze(table: &mut Table) {
+    table.kind = TableKind::Inline;
+    for &mut (_, ref mut item) in &mut table.entries {
+        if let Value::Table(ref mut t) = item.value {
+            freeze(t);
+        }
+    }
+}
+
+// The table a header put the pairs in, the last one of arrays of tables.
+fn table_at<'a>(root: &'a mut Table, keys: &[String]) -> &'a mut Table {
+    let mut table = root;
+    for key in keys {
+        let item = table.get_mut(key).expect("header table");
+        table = match item.value {
+            Value::Table(ref mut t) => t,
+            Value::Tables(ref mut ts) => {
+                match ts.last_mut().unwrap().value {
+                    Value::Table(ref mut t) => t,
+                    _ => unreachable!(),
+                }
+            }
+            _ => unreachable!(),
+        };
+    }
+    table
+}
+
+// Goes down to the parent of the table named by `keys`, creating the
+// missing tables on the way.
+fn parent_table<'a>(root: &'a mut Table,
+                    keys: &[String],
+                    line: usize)
+                    -> Result<&'a mut Table, Error> {
+    let mut table = root;
+    for (i, key) in keys[..keys.len() - 1].iter().enumerate() {
+        if table.get_mut(key).is_none() {
+            table.entries.push((key.clone(),
+                                Item {
+                                    value: Value::Table(Table::new(TableKind::Implicit)),
+                                    line: line,
+                                }));
+        }
+        let item = table.get_mut(key).unwrap();
+        table = match item.value {
+            Value::Table(ref mut t) if t.kind != TableKind::Inline => t,
+            Value::Tables(ref mut ts) => {
+                match ts.last_mut().unwrap().value {
+                    Value::Table(ref mut t) => t,
+                    _ => unreachable!(),
+                }
+            }
+            _ => {
+                return Err(Error {
+                    line: line,
+                    message: format!("`{}` is not a table", keys[..i + 1].join(".")),
+                })
+            }
+        };
+    }
+    Ok(table)
+}
+
+// Defines the table of a `[name]` header.
+fn add_table(root: &mut Table, keys: &[String], line: usize) -> Result<(), Error> {
+    let parent = parent_table(root, keys, line)?;
+    let key = keys.last().unwrap();
+    match parent.get_mut(key) {
+        None => {
+            parent.entries.push((key.clone(),
+                                 Item {
+                                     value: Value::Table(Table::new(TableKind::Header)),
+                                     line: line,
+                                 }));
+            Ok(())
+        }
+        Some(&mut Item { value: Value::Table(ref mut t), line: ref mut table_line })
+            if t.kind == TableKind::Implicit => {
+            t.kind = TableKind::Header;
+            *table_line = line;
+            Ok(())
+        }
+        Some(_) => {
+            Err(Error {
+                line: line,
+                message: format!("`{}` is already defined", keys.join(".")),
+            })
+        }
+    }
+}
+
+// Adds a table to the array of a `[[name]]` header.
+fn add_table_array(root: &mut Table, keys: &[String], line: usize) -> Result<(), Error> {
+    let parent = parent_table(root, keys, line)?;
+    let key = keys.last().unwrap();
+    let table = Item {
+        value: Value::Table(Table::new(TableKind::Header)),
+        line: line,
+    };
+    match parent.get_mut(key) {
+        None => {
+            parent.entries.push((key.clone(),
+                                 Item {
+                                     value: Value::Tables(vec![table]),
+                                     line: line,
+                                 }));
+            Ok(())
+        }
+        Some(&mut Item { value: Value::Tables(ref mut ts), .. }) => {
+            ts.push(table);
+            Ok(())
+        }
+        Some(_) => {
+            Err(Error {
+                line: line,
+                message: format!("`{}` is not an array of tables", keys.join(".")),
+            })
+        }
+    }
+}
+
+// Inserts the value of a pair with a dotted key, which defines the tables
+// on its way.
+fn insert_dotted(table: &mut Table, keys: &[String], item: Item, line: usize) -> Result<(), Error> {
+    let mut table = table;
+    for (i, key) in keys[..keys.len() - 1].iter().enumerate() {
+        if table.get_mut(key).is_none() {
+            table.entries.push((key.clone(),
+                                Item {
+                                    value: Value::Table(Table::new(TableKind::Dotted)),
+                                    line: line,
+                                }));
+        }
+        table = match table.get_mut(key).unwrap().value {
+            Value::Table(ref mut t) if t.kind == TableKind::Dotted ||
+                                       t.kind == TableKind::Implicit => {
+                t.kind = TableKind::Dotted;
+                t
+            }
+            _ => {
+                return Err(Error {
+                    line: line,
+                    message: format!("`{}` can't be extended", keys[..i + 1].join(".")),
+                })
+            }
+        };
+    }
+    let key = keys.last().unwrap();
+    if table.get_mut(key).is_some() {
+        return Err(Error {
+            line: line,
+            message: format!("duplicate key `{}`", keys.join(".")),
+        });
+    }
+    table.entries.push((key.clone(), item));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(text: &str) -> Error {
+        parse(text).unwrap_err()
+    }
+
+    #[test]
+    fn values_keep_their_line() {
+        let root = parse("# comment\na = 1\n\n[t]\nb = \"\"\"x\ny\"\"\"\nc = [\n  2,\n]\n")
+                       .unwrap();
+        assert_eq!(root.entries[0].0, "a");
+        assert_eq!(root.entries[0].1.line, 2);
+        let table = match root.entries[1].1.value {
+            Value::Table(ref t) => t,
+            ref v => panic!("{:?}", v),
+        };
+        assert_eq!(root.entries[1].1.line, 4);
+        assert_eq!(table.entries[0].1,
+                   Item {
+                       value: Value::String("x\ny".to_string()),
+                       line: 5,
+                   });
+        assert_eq!(table.entries[1].1.line, 7);
+    }
+
+    #[test]
+    fn errors_have_their_line() {
+        assert_eq!(error("a = 1\nb = \"open\n").line, 2);
+        assert_eq!(error("a = 1\n\n\na = 2\n").line, 4);
+        assert_eq!(error("[t]\nx = 1\n[t]\n").line, 3);
+        assert_eq!(error("a = [\n1,\n2\n").line, 4);
+        assert_eq!(error("s = \"\"\"\none\ntwo\n").line, 4);
+        assert_eq!(error("a = 1\nb = 1_\n").line, 2);
+        assert_eq!(error("a = { b = 1 }\n[a.c]\n").line, 2);
+    }
+
+    #[test]
+    fn duplicate_key_message() {
+        assert_eq!(error("[t]\nx.y = 1\nx.y = 2\n"),
+                   Error {
+                       line: 3,
+                       message: "duplicate key `x.y`".to_string(),
+                   });
+    }
+
+    #[test]
+    fn table_arrays() {
+        let root = parse("[[r]]\na = 1\n[[r]]\na = 2\n").unwrap();
+        match root.entries[0].1.value {
+            Value::Tables(ref ts) => {
+                assert_eq!(ts.len(), 2);
+                assert_eq!(ts[1].line, 3);
+            }
+            ref v => panic!("{:?}", v),
+        }
+        assert_eq!(error("r = 1\n[[r]]\n").line, 2);
+    }
+}
@@ -0,0 +1,617 @@
+use std::hash::Hasher;
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+use fnv::FnvHasher;
+use access_log::json_string;
+use handler_lib::Handler;
+use http::{Request, Response};
+use proxy::Upstream;
+
+// Points on the hash ring by unit of weight.
+const RING_POINTS: u32 = 160;
+
+/// The largest weight of a server, higher ones being lowered to it. It keeps
+/// the hash ring at most `160 * MAX_WEIGHT` points per server.
+pub const MAX_WEIGHT: u32 = 1000;
+
+/// How a request picks its upstream server.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Balancing {
+    RoundRobin,
+    /// Round robin, with each server getting its weight's share of requests.
+    Weighted,
+    /// The server with the fewest requests in flight, relative to its weight.
+    LeastConnections,
+    /// Requests with the same value of this header go to the same server,
+    /// unless it is down. Round robin for requests without it.
+    HashHeader(String),
+    /// Same as `HashHeader`, with the value of this cookie.
+    HashCookie(String),
+}
+
+/// An active health check: each upstream server gets a GET for `path` every
+/// `interval`, and must answer with a 2xx or 3xx status within `timeout`.
+#[derive(Debug, Clone)]
+pub struct HealthCheck {
+    pub path: String,
+    pub interval: Duration,
+    pub timeout: Duration,
+    /// Failed checks in a row for a server to be taken out.
+    pub fails: u32,
+    /// Passed checks in a row for it to be put back.
+    pub passes: u32,
+}
+
+impl HealthCheck {
+    pub fn new(path: &str) -> HealthCheck {
+        return HealthCheck {
+            path: path.to_string(),
+            interval: Duration::from_secs(5),
+            timeout: Duration::from_secs(2),
+            fails: 2,
+            passes: 1,
+        };
+    }
+}
+
+/// The upstream servers of a `ProxyHandler`, and how they are used.
+pub struct UpstreamGroup {
+    servers: Vec<(String, u32)>,
+    balancing: Balancing,
+    max_fails: u32,
+    fail_timeout: Duration,
+    max_active: usize,
+    health_check: Option<HealthCheck>,
+}
+
+impl UpstreamGroup {
+    pub fn new(balancing: Balancing) -> UpstreamGroup {
+        return UpstreamGroup {
+            servers: Vec::new(),
+            balancing: balancing,
+            max_fails: 1,
+            fail_timeout: Duration::from_secs(10),
+            max_active: 0,
+            health_check: None,
+        };
+    }
+
+    // `address` is either "host:port" or "unix:/path/to/socket". Weights
+    // are used by the weighted, least connections and hash balancing, and
+    // go from 1 to `MAX_WEIGHT`.
+    pub fn add_server(&mut self, address: &str, weight: u32) {
+        self.servers.push((address.to_string(), weight.clamp(1, MAX_WEIGHT)));
+    }
+
+    // A server that fails `max_fails` requests in a row, by not answering
+    // or with a gateway error, gets none for `fail_timeout`. Then a single
+    // request tries it again. 0 turns this off.
+    pub fn set_max_fails(&mut self, max_fails: u32, fail_timeout: Duration) {
+        self.max_fails = max_fails;
+        self.fail_timeout = fail_timeout;
+    }
+
+    // A server doesn't get more than `max_active` requests at once, 0 for no
+    // limit. Requests go to the other servers, or get a 502.
+    pub fn set_max_active(&mut self, max_active: usize) {
+        self.max_active = max_active;
+    }
+
+    // Checks the servers in the background, with a thread of its own.
+    pub fn set_health_check(&mut self, check: HealthCheck) {
+        self.health_check = Some(check);
+    }
+
+    // Starts the health checks. They stop with the last copy of the group.
+    pub(crate) fn start(self) -> Arc<Group> {
+        let mut ring = Vec::new();
+        for (idx, &(ref address, weight)) in self.servers.iter().enumerate() {
+            for point in 0..RING_POINTS * weight {
+                ring.push((hash(&format!("{}-{}", address, point)), idx));
+            }
+        }
+        ring.sort();
+        let group = Arc::new(Group {
+            servers: self.servers
+                         .iter()
+                         .map(|&(ref address, weight)| (Upstream::new(address), weight))
+                         .collect(),
+            balancing: self.balancing,
+            max_fails: self.max_fails,
+            fail_timeout: self.fail_timeout,
+            max_active: self.max_active,
+            ring: ring,
+            state: Mutex::new(State {
+                next: 0,
+                servers: self.servers.iter().map(|_| ServerState::new()).collect(),
+            }),
+        });
+        if let Some(check) = self.health_check {
+            let weak = Arc::downgrade(&group);
+            thread::spawn(move || run_health_checks(weak, check));
+        }
+        group
+    }
+}
+
+/// Whether a server gets requests, as far as failures go.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Circuit {
+    Closed,
+    /// Too many failures, the server gets no request until the timeout.
+    Open,
+    /// A request is trying the server again after the timeout.
+    HalfOpen,
+}
+
+/// The state of an upstream server.
+#[derive(Debug, Clone)]
+pub struct ServerStatus {
+    pub address: String,
+    pub weight: u32,
+    /// As found by the health checks, always true without them.
+    pub healthy: bool,
+    pub circuit: Circuit,
+    /// Requests in flight.
+    pub active: usize,
+    pub requests: u64,
+    pub failures: u64,
+}
+
+struct ServerState {
+    healthy: bool,
+    // Health checks in a row with the other result.
+    checks: u32,
+    fails: u32,
+    // When the circuit was opened, None while it is closed.
+    opened: Option<Instant>,
+    // A request is trying the server after the circuit was open.
+    trial: bool,
+    active: usize,
+    requests: u64,
+    failures: u64,
+    // For the weighted round robin.
+    current_weight: i64,
+}
+
+impl ServerState {
+    fn new() -> ServerState {
+        return ServerState {
+            healthy: true,
+            checks: 0,
+            fails: 0,
+            opened: None,
+            trial: false,
+            active: 0,
+            requests: 0,
+            failures: 0,
+            current_weight: 0,
+        };
+    }
+}
+
+struct State {
+    // For the round robin.
+    next: usize,
+    servers: Vec<ServerState>,
+}
+
+// The servers of a group, shared by the copies of a handler.
+pub(crate) struct Group {
+    servers: Vec<(Upstream, u32)>,
+    balancing: Balancing,
+    max_fails: u32,
+    fail_timeout: Duration,
+    max_active: usize,
+    // Hash points of the servers, sorted.
+    ring: Vec<(u64, usize)>,
+    state: Mutex<State>,
+}
+
+impl Group {
+    pub(crate) fn upstream(&self, idx: usize) -> &Upstream {
+        &self.servers[idx].0
+    }
+
+    // Picks a server for `req` out of those not `tried` yet, and counts the
+    // request as in flight on it. None if none can take it.
+    pub(crate) fn pick(&self, req: &Request, tried: &[usize]) -> Option<usize> {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let available: Vec<bool> = state.servers
+                                        .iter()
+                                        .enumerate()
+                                        .map(|(idx, s)| {
+                                            !tried.contains(&idx) && self.is_available(s, now)
+                                        })
+                                        .collect();
+        let key = match self.balancing {
+            Balancing::HashHeader(ref name) => req.header(name).map(|v| v.to_string()),
+            Balancing::HashCookie(ref name) => cookie(req, name),
+            _ => None,
+        };
+        let picked = match (&self.balancing, key) {
+            (_, Some(key)) => {
+                let start = self.ring.partition_point(|&(point, _)| point < hash(&key));
+                (0..self.ring.len())
+                    .map(|i| self.ring[(start + i) % self.ring.len()].1)
+                    .find(|&idx| available[idx])
+            }
+            (&Balancing::Weighted, None) => {
+                let total: i64 = (0..self.servers.len())
+                                     .filter(|&idx| available[idx])
+                                     .map(|idx| self.servers[idx].1 as i64)
+                                     .sum();
+                let mut best: Option<usize> = None;
+                for idx in (0..self.servers.len()).filter(|&idx| available[idx]) {
+                    state.servers[idx].current_weight += self.servers[idx].1 as i64;
+                    let current = state.servers[idx].current_weight;
+                    if best.is_none_or(|b| current > state.servers[b].current_weight) {
+                        best = Some(idx);
+                    }
+                }
+                if let Some(idx) = best {
+                    state.servers[idx].current_weight -= total;
+                }
+                best
+            }
+            (&Balancing::LeastConnections, None) => {
+                // Ties go round robin.
+                let start = state.next;
+                state.next = state.next.wrapping_add(1);
+                let len = self.servers.len();
+                (0..len)
+                    .map(|i| (start + i) % len.max(1))
+                    .filter(|&idx| available[idx])
+                    .min_by(|&a, &b| {
+                        let load_a = state.servers[a].active as u64 * self.servers[b].1 as u64;
+                        let load_b = state.servers[b].active as u64 * self.servers[a].1 as u64;
+                        load_a.cmp(&load_b)
+                    })
+            }
+            _ => {
+                let start = state.next;
+                state.next = state.next.wrapping_add(1);
+                let len = self.servers.len();
+                (0..len).map(|i| (start + i) % len.max(1)).find(|&idx| available[idx])
+            }
+        };
+        if let Some(idx) = picked {
+            let server = &mut state.servers[idx];
+            if server.opened.is_some() {
+                server.trial = true;
+            }
+            server.active += 1;
+            server.requests += 1;
+        }
+        picked
+    }
+
+    // Ends a request picked with `pick()`.
+    pub(crate) fn done(&self, idx: usize, ok: bool) {
+        let mut state = self.state.lock().unwrap();
+        let server = &mut state.servers[idx];
+        server.active -= 1;
+        server.trial = false;
+        if ok {
+            server.fails = 0;
+            server.opened = None;
+            return;
+        }
+        server.failures += 1;
+        server.fails += 1;
+        if self.max_fails > 0 && (server.fails >= self.max_fails || server.opened.is_some()) {
+            if server.opened.is_none() {
+                warn!("Upstream {} failed {} times, taking it out",
+                      self.servers[idx].0.address(),
+                      server.fails);
+            }
+            server.opened = Some(Instant::now());
+        }
+    }
+
+    fn is_available(&self, server: &ServerState, now: Instant) -> bool {
+        if !server.healthy || (self.max_active > 0 && server.active >= self.max_active) {
+            return false;
+        }
+        match server.opened {
+            None => true,
+            Some(opened) => now >= opened + self.fail_timeout && !server.trial,
+        }
+    }
+
+    fn status(&self) -> Vec<ServerStatus> {
+        let state = self.state.lock().unwrap();
+        let now = Instant::now();
+        self.servers
+            .iter()
+            .zip(state.servers.iter())
+            .map(|(&(ref upstream, weight), s)| {
+                let circuit = match s.opened {
+                    None => Circuit::Closed,
+                    Some(_) if s.trial => Circuit::HalfOpen,
+                    Some(opened) if now >= opened + self.fail_timeout => Circuit::HalfOpen,
+                    Some(_) => Circuit::Open,
+                };
+                ServerStatus {
+                    address: upstream.address(),
+                    weight: weight,
+                    healthy: s.healthy,
+                    circuit: circuit,
+                    active: s.active,
+                    requests: s.requests,
+                    failures: s.failures,
+                }
+            })
+            .collect()
+    }
+
+    fn check_result(&self, idx: usize, passed: bool, check: &HealthCheck) {
+        let mut state = self.state.lock().unwrap();
+        let server = &mut state.servers[idx];
+        if passed == server.healthy {
+            server.checks = 0;
+            return;
+        }
+        server.checks += 1;
+        let needed = if passed {
+            check.passes
+        } else {
+            check.fails
+        };
+        if server.checks >= needed.max(1) {
+            info!("Upstream {} is {}",
+                  self.servers[idx].0.address(),
+                  if passed {
+                      "back up"
+                  } else {
+                      "down"
+                  });
+            server.healthy = passed;
+            server.checks = 0;
+        }
+    }
+}
+
+// The checks of a group run on a thread of their own, which sleeps between
+// rounds. They block on the upstreams, which the timers of the event loops
+// must not, and there are only so many groups.
+fn run_health_checks(group: Weak<Group>, check: HealthCheck) {
+    loop {
+        thread::sleep(check.interval);
+        let group = match group.upgrade() {
+            Some(group) => group,
+            None => return,
+        };
+        for idx in 0..group.servers.len() {
+            let passed = group.upstream(idx).check(&check.path, check.timeout);
+            group.check_result(idx, passed, &check);
+        }
+    }
+}
+
+// FNV-1a, which unlike the hasher of the standard library is the same in
+// every build, so that all the servers map keys to the same upstreams. Its
+// high bits hardly change between short strings like "a:80-1" and "b:80-1",
+// so they are mixed with the finalizer of MurmurHash3 to spread the points
+// over the ring.
+fn hash(value: &str) -> u64 {
+    let mut hasher = FnvHasher::default();
+    hasher.write(value.as_bytes());
+    let mut h = hasher.finish();
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51afd7ed558ccd);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xc4ceb9fe1a85ec53);
+    h ^ (h >> 33)
+}
+
+fn cookie(req: &Request, name: &str) -> Option<String> {
+    req.header("Cookie")?
+       .split(';')
+       .filter_map(|c| c.trim().split_once('='))
+       .find(|&(n, _)| n == name)
+       .map(|(_, v)| v.to_string())
+}
+
+/// A handle on the upstream servers of a `ProxyHandler`.
+#[derive(Clone)]
+pub struct Upstreams {
+    group: Arc<Group>,
+}
+
+impl Upstreams {
+    pub(crate) fn new(group: Arc<Group>) -> Upstreams {
+        Upstreams { group: group }
+    }
+
+    pub fn status(&self) -> Vec<ServerStatus> {
+        self.group.status()
+    }
+}
+
+/// Shows the state of upstream servers as JSON.
+pub struct UpstreamStatusHandler {
+    upstreams: Upstreams,
+}
+
+impl UpstreamStatusHandler {
+    pub fn new(upstreams: Upstreams) -> UpstreamStatusHandler {
+        UpstreamStatusHandler { upstreams: upstreams }
+    }
+}
+
+impl Handler for UpstreamStatusHandler {
+    fn process(&mut self, _: Request, resp: &mut Response) {
+        let servers: Vec<String> = self.upstreams
+                                       .status()
+                                       .iter()
+                                       .map(|s| {
+                                           format!("{{\"address\":{},\"weight\":{},\
+                                                    \"healthy\":{},\"circuit\":\"{:?}\",\
+                                                    \"active\":{},\"requests\":{},\
+                                                    \"failures\":{}}}",
+                                                   json_string(&s.address),
+                                                   s.weight,
+                                                   s.healthy,
+                                                   s.circuit,
+                                                   s.active,
+                                                   s.requests,
+                                                   s.failures)
+                                       })
+                                       .collect();
+        resp.set_header("Content-Type", "application/json")
+            .set_body_str(&format!("[{}]\n", servers.join(",")))
+            .send();
+    }
+    fn duplicate(&self) -> Box<Handler> {
+        Box::new(UpstreamStatusHandler::new(self.upstreams.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn group(balancing: Balancing, servers: &[(&str, u32)]) -> Arc<Group> {
+        let mut group = UpstreamGroup::new(balancing);
+        for &(address, weight) in servers {
+            group.add_server(address, weight);
+        }
+        group.start()
+    }
+
+    fn request(user: Option<&str>) -> Request {
+        let headers = user.map(|u| vec![("X-User".to_string(), u.to_string())]);
+        Request::from_parts("GET", "/", "HTTP/1.1", headers.unwrap_or_default(), Vec::new())
+            .unwrap()
+    }
+
+    // Picks `n` servers, each request being done before the next.
+    fn picks(group: &Group, user: Option<&str>, n: usize) -> Vec<usize> {
+        (0..n).map(|_| {
+                  let idx = group.pick(&request(user), &[]).unwrap();
+                  group.done(idx, true);
+                  idx
+              })
+              .collect()
+    }
+
+    #[test]
+    fn round_robin_skips_tried_servers() {
+        let group = group(Balancing::RoundRobin, &[("a:1", 1), ("b:1", 1), ("c:1", 1)]);
+        assert_eq!(picks(&group, None, 4), vec![0, 1, 2, 0]);
+        assert_eq!(group.pick(&request(None), &[1, 2]), Some(0));
+        assert_eq!(group.pick(&request(None), &[0, 1, 2]), None);
+    }
+
+    #[test]
+    fn weighted_round_robin_is_smooth() {
+        let group = group(Balancing::Weighted, &[("a:1", 5), ("b:1", 1), ("c:1", 1)]);
+        let expected = vec![0, 0, 1, 0, 2, 0, 0];
+        assert_eq!(picks(&group, None, 7), expected);
+        assert_eq!(picks(&group, None, 7), expected);
+    }
+
+    #[test]
+    fn weights_are_clamped() {
+        let group = group(Balancing::Weighted, &[("a:1", 0), ("b:1", u32::MAX)]);
+        let weights: Vec<u32> = group.status().iter().map(|s| s.weight).collect();
+        assert_eq!(weights, vec![1, MAX_WEIGHT]);
+        assert_eq!(group.ring.len(), (RING_POINTS * (1 + MAX_WEIGHT)) as usize);
+    }
+
+    #[test]
+    fn hashing_keeps_keys_on_their_server() {
+        let balancing = Balancing::HashHeader("X-User".to_string());
+        let three = group(balancing.clone(), &[("a:1", 1), ("b:1", 1), ("c:1", 1)]);
+        let two = group(balancing, &[("a:1", 1), ("b:1", 1)]);
+        let users: Vec<String> = (0..300).map(|i| format!("user{}", i)).collect();
+        let mut counts = [0; 3];
+        for user in &users {
+            let idx = picks(&three, Some(user), 1)[0];
+            assert_eq!(picks(&three, Some(user), 3), vec![idx; 3]);
+            counts[idx] += 1;
+            // Only the keys of the removed server move.
+            let moved = picks(&two, Some(user), 1)[0];
+            if idx != 2 {
+                assert_eq!(moved, idx);
+            }
+        }
+        assert!(counts.iter().all(|&n| n > 50), "{:?}", counts);
+        // Requests without the header go round robin.
+        assert_eq!(picks(&three, None, 3), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn least_connections_is_relative_to_the_weight() {
+        let group = group(Balancing::LeastConnections, &[("a:1", 2), ("b:1", 1)]);
+        let a = group.pick(&request(None), &[]).unwrap();
+        let b = group.pick(&request(None), &[]).unwrap();
+        assert_eq!((a, b), (0, 1));
+        // a has half of its weight in flight, b all of it.
+        assert_eq!(group.pick(&request(None), &[]), Some(0));
+        group.done(1, true);
+        assert_eq!(group.pick(&request(None), &[]), Some(1));
+    }
+
+    #[test]
+    fn circuit_opens_then_tries_one_request() {
+        let mut upstreams = UpstreamGroup::new(Balancing::RoundRobin);
+        upstreams.add_server("a:1", 1);
+        upstreams.set_max_fails(2, Duration::from_millis(20));
+        let group = upstreams.start();
+        let circuit = || group.status()[0].circuit;
+        let pick = || group.pick(&request(None), &[]);
+
+        group.done(pick().unwrap(), false);
+        assert_eq!(circuit(), Circuit::Closed);
+        group.done(pick().unwrap(), false);
+        assert_eq!(circuit(), Circuit::Open);
+        assert_eq!(pick(), None);
+
+        thread::sleep(Duration::from_millis(30));
+        assert_eq!(circuit(), Circuit::HalfOpen);
+        assert_eq!(pick(), Some(0));
+        // A single request tries the server.
+        assert_eq!(pick(), None);
+        group.done(0, false);
+        assert_eq!(circuit(), Circuit::Open);
+        assert_eq!(pick(), None);
+
+        thread::sleep(Duration::from_millis(30));
+        assert_eq!(pick(), Some(0));
+        group.done(0, true);
+        assert_eq!(circuit(), Circuit::Closed);
+        assert_eq!(picks(&group, None, 2), vec![0, 0]);
+        assert_eq!(group.status()[0].failures, 3);
+    }
+
+    #[test]
+    fn health_checks_need_results_in_a_row() {
+        let group = group(Balancing::RoundRobin, &[("a:1", 1)]);
+        let mut check = HealthCheck::new("/health");
+        check.fails = 2;
+        check.passes = 3;
+        let healthy = || group.status()[0].healthy;
+
+        group.check_result(0, false, &check);
+        group.check_result(0, true, &check);
+        group.check_result(0, false, &check);
+        assert!(healthy());
+        group.check_result(0, false, &check);
+        assert!(!healthy());
+        assert_eq!(group.pick(&request(None), &[]), None);
+
+        group.check_result(0, true, &check);
+        group.check_result(0, true, &check);
+        group.check_result(0, false, &check);
+        group.check_result(0, true, &check);
+        group.check_result(0, true, &check);
+        assert!(!healthy());
+        group.check_result(0, true, &check);
+        assert!(healthy());
+        assert_eq!(group.pick(&request(None), &[]), Some(0));
+    }
+}
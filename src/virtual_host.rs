@@ -0,0 +1,113 @@
+use handler_lib::{Handler, HandlerRoute, SseRoute, WebSocketRoute};
+use rewrite::RewriteRule;
+use sse::SseHandler;
+use websocket::WebSocketHandler;
+
+// A name a site answers to.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum HostName {
+    Exact(String),
+    // "*.example.com", kept as ".example.com". Any subdomain matches, not
+    // the domain itself.
+    Wildcard(String),
+}
+
+impl HostName {
+    pub(crate) fn parse(name: &str) -> HostName {
+        let name = normalize(name);
+        match name.strip_prefix('*') {
+            Some(suffix) => HostName::Wildcard(suffix.to_string()),
+            None => HostName::Exact(name),
+        }
+    }
+}
+
+/// A site served for the requests to its host names, in one of the forms
+/// "example.com" or "*.example.com". Its routes are set like those of the
+/// `WebServer`, which then serves the requests to other names.
+pub struct VirtualHost {
+    pub(crate) names: Vec<HostName>,
+    pub(crate) handlers: Vec<HandlerRoute>,
+    pub(crate) ws_handlers: Vec<WebSocketRoute>,
+    pub(crate) sse_handlers: Vec<SseRoute>,
+    pub(crate) rewrite_rules: Vec<RewriteRule>,
+}
+
+impl VirtualHost {
+    pub fn new(name: &str) -> VirtualHost {
+        return VirtualHost {
+            names: vec![HostName::parse(name)],
+            handlers: Vec::new(),
+            ws_handlers: Vec::new(),
+            sse_handlers: Vec::new(),
+            rewrite_rules: Vec::new(),
+        };
+    }
+
+    // Another name the site answers to.
+    pub fn add_alias(&mut self, name: &str) {
+        self.names.push(HostName::parse(name));
+    }
+
+    pub fn add_handler<T>(&mut self, pattern: &str, handler: T)
+        where T: Handler
+    {
+        self.handlers.push(HandlerRoute(format!("^{}$", pattern), Box::new(handler)));
+    }
+
+    pub fn add_websocket_handler<T>(&mut self, pattern: &str, handler: T)
+        where T: WebSocketHandler
+    {
+        self.ws_handlers.push(WebSocketRoute(format!("^{}$", pattern), Box::new(handler)));
+    }
+
+    pub fn add_sse_handler<T>(&mut self, pattern: &str, handler: T)
+        where T: SseHandler
+    {
+        self.sse_handlers.push(SseRoute(format!("^{}$", pattern), Box::new(handler)));
+    }
+
+    // Rewrites the requests to the site before they are routed, after the
+    // rules added before.
+    pub fn add_rewrite_rule(&mut self, rule: RewriteRule) {
+        self.rewrite_rules.push(rule);
+    }
+}
+
+// Finds the site of `host` among `names`, by site: exact names first, then
+// the longest wildcard that matches. 0, the default site, if none does.
+pub(crate) fn find_site(names: &[(HostName, usize)], host: &str) -> usize {
+    let host = normalize(host);
+    let exact = names.iter().find(|&&(ref name, _)| *name == HostName::Exact(host.clone()));
+    if let Some(&(_, site)) = exact {
+        return site;
+    }
+    names.iter()
+         .filter_map(|&(ref name, site)| match *name {
+             HostName::Wildcard(ref suffix) if host.len() > suffix.len() &&
+                                               host.ends_with(suffix.as_str()) => {
+                 Some((suffix.len(), site))
+             }
+             _ => None,
+         })
+         .max_by_key(|&(len, _)| len)
+         .map_or(0, |(_, site)| site)
+}
+
+// The lowercase name of a Host header, without its port or the dot that may
+// end it.
+fn normalize(host: &str) -> String {
+    let name = if host.starts_with('[') {
+        // An IPv6 address.
+        match host.find(']') {
+            Some(idx) => &host[..idx + 1],
+            None => host,
+        }
+    } else {
+        match host.rfind(':') {
+            Some(idx) => &host[..idx],
+            None => host,
+        }
+    };
+    name.trim_end_matches('.').to_lowercase()
+}
@@ -0,0 +1,895 @@
+use std::collections::HashMap;
+use std::mem;
+use std::str;
+use std::time::Duration;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress};
+use ring::digest;
+use connection::Output;
+use event_loop::Waker;
+use http::{PendingRequest, Request, Response, Status};
+
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+// Larger messages close the connection with MESSAGE_TOO_BIG.
+const MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+// Control frames can't carry more.
+const MAX_CONTROL_PAYLOAD: usize = 125;
+// What a deflate sync flush ends with, left out of compressed messages.
+const DEFLATE_TAIL: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+// Opcodes.
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xa;
+
+const FIN: u8 = 0x80;
+// Marks the first frame of a compressed message.
+const RSV1: u8 = 0x40;
+const RSV2_3: u8 = 0x30;
+const MASK: u8 = 0x80;
+
+/// Close codes.
+pub const NORMAL_CLOSURE: u16 = 1000;
+pub const GOING_AWAY: u16 = 1001;
+pub const PROTOCOL_ERROR: u16 = 1002;
+pub const UNSUPPORTED_DATA: u16 = 1003;
+/// Reported to `close()` when the client's close frame had no code.
+pub const NO_STATUS: u16 = 1005;
+/// Reported to `close()` when the connection closed without a close frame.
+pub const ABNORMAL_CLOSURE: u16 = 1006;
+pub const INVALID_PAYLOAD: u16 = 1007;
+pub const POLICY_VIOLATION: u16 = 1008;
+pub const MESSAGE_TOO_BIG: u16 = 1009;
+pub const INTERNAL_ERROR: u16 = 1011;
+
+/// A message received from the client.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    /// Already answered with a pong.
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+}
+
+/// Handles a WebSocket connection once the handshake is done. Each
+/// connection gets its own copy of the handler, made with `duplicate()`.
+/// It runs on the event loop, so it must not block.
+///
+/// Open connections have no idle timeout, a handler that wants to notice
+/// dead clients can send pings from a timer.
+pub trait WebSocketHandler : Send + 'static {
+    // Called with the upgrade request once the connection is open.
+    fn open(&mut self, _request: &Request, _ws: &mut WebSocket) {}
+    fn message(&mut self, message: Message, ws: &mut WebSocket);
+    // Called once when the connection closes, with the client's close code,
+    // `NO_STATUS` or `ABNORMAL_CLOSURE`, or the code we closed with.
+    fn close(&mut self, _code: u16, _reason: &str) {}
+    // Called when a timer set with `WebSocket::set_timer()` fires.
+    fn timeout(&mut self, _token: usize, _ws: &mut WebSocket) {}
+    // Called after a waker from `WebSocket::waker()` was used.
+    fn wake(&mut self, _ws: &mut WebSocket) {}
+    fn duplicate(&self) -> Box<WebSocketHandler>;
+}
+
+/// The sending side of a WebSocket connection.
+pub struct WebSocket {
+    out: Output,
+    deflate: Option<Deflater>,
+    close_sent: bool,
+    timers: Vec<(Duration, usize)>,
+    waker: Waker,
+}
+
+impl WebSocket {
+    pub fn send_text(&mut self, text: &str) {
+        self.send_message(TEXT, text.as_bytes());
+    }
+
+    pub fn send_binary(&mut self, data: &[u8]) {
+        self.send_message(BINARY, data);
+    }
+
+    // `data` is cut to the 125 bytes a control frame can hold.
+    pub fn ping(&mut self, data: &[u8]) {
+        self.send_control(PING, data);
+    }
+
+    pub fn pong(&mut self, data: &[u8]) {
+        self.send_control(PONG, data);
+    }
+
+    // Starts the closing handshake. Nothing more is sent after it, and the
+    // connection closes once the client answers.
+    pub fn close(&mut self, code: u16, reason: &str) {
+        let mut payload = code.to_be_bytes().to_vec();
+        payload.extend_from_slice(reason.as_bytes());
+        self.send_control(CLOSE, &payload);
+        self.close_sent = true;
+    }
+
+    pub fn is_closing(&self) -> bool {
+        self.close_sent
+    }
+
+    // Calls the handler's `timeout()` with `token` after `delay`.
+    pub fn set_timer(&mut self, delay: Duration, token: usize) {
+        self.timers.push((delay, token));
+    }
+
+    // Returns a waker that makes the handler's `wake()` be called, from any
+    // thread, so that it can send what other connections produced.
+    pub fn waker(&self) -> Waker {
+        self.waker.clone()
+    }
+
+    fn send_message(&mut self, opcode: u8, data: &[u8]) {
+        if self.close_sent {
+            return;
+        }
+        match self.deflate {
+            Some(ref mut deflate) => {
+                let compressed = deflate.compress(data);
+                write_frame(&mut self.out, FIN | RSV1 | opcode, &compressed);
+            }
+            None => write_frame(&mut self.out, FIN | opcode, data),
+        }
+    }
+
+    fn send_control(&mut self, opcode: u8, data: &[u8]) {
+        if self.close_sent {
+            return;
+        }
+        let len = data.len().min(MAX_CONTROL_PAYLOAD);
+        write_frame(&mut self.out, FIN | opcode, &data[..len]);
+    }
+}
+
+fn write_frame(out: &mut Output, first: u8, payload: &[u8]) {
+    let mut head = vec![first];
+    let len = payload.len();
+    if len < 126 {
+        head.push(len as u8);
+    } else if len <= 0xffff {
+        head.push(126);
+        head.extend_from_slice(&(len as u16).to_be_bytes());
+    } else {
+        head.push(127);
+        head.extend_from_slice(&(len as u64).to_be_bytes());
+    }
+    out.write(&head);
+    out.write(payload);
+}
+
+// The permessage-deflate parameters agreed on with the client.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct DeflateParams {
+    server_no_context_takeover: bool,
+    client_no_context_takeover: bool,
+}
+
+struct Deflater {
+    compress: Compress,
+    no_context_takeover: bool,
+}
+
+impl Deflater {
+    fn compress(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(data.len() / 2 + 64);
+        let mut consumed = 0;
+        loop {
+            if out.capacity() - out.len() < 64 {
+                out.reserve(out.len() + 64);
+            }
+            let before = self.compress.total_in();
+            // Can't fail on a raw deflate stream with a sync flush.
+            let _ = self.compress.compress_vec(&data[consumed..], &mut out, FlushCompress::Sync);
+            consumed += (self.compress.total_in() - before) as usize;
+            // The flush is complete once it didn't fill the output.
+            if consumed == data.len() && out.len() < out.capacity() {
+                break;
+            }
+        }
+        if out.ends_with(&DEFLATE_TAIL) {
+            out.truncate(out.len() - DEFLATE_TAIL.len());
+        }
+        if self.no_context_takeover {
+            self.compress.reset();
+        }
+        out
+    }
+}
+
+struct Inflater {
+    decompress: Decompress,
+    no_context_takeover: bool,
+}
+
+impl Inflater {
+    fn decompress(&mut self, data: &[u8]) -> Result<Vec<u8>, u16> {
+        let mut input = data.to_vec();
+        input.extend_from_slice(&DEFLATE_TAIL);
+        let mut out = Vec::with_capacity(data.len() * 2 + 64);
+        let mut consumed = 0;
+        loop {
+            if out.len() == out.capacity() {
+                if out.len() >= MAX_MESSAGE_SIZE {
+                    return Err(MESSAGE_TOO_BIG);
+                }
+                out.reserve(out.len());
+            }
+            let (before_in, before_out) = (self.decompress.total_in(), out.len());
+            self.decompress
+                .decompress_vec(&input[consumed..], &mut out, FlushDecompress::Sync)
+                .map_err(|_| INVALID_PAYLOAD)?;
+            let read = (self.decompress.total_in() - before_in) as usize;
+            consumed += read;
+            if (consumed == input.len() && out.len() < out.capacity()) ||
+               (read == 0 && out.len() == before_out) {
+                break;
+            }
+        }
+        if out.len() > MAX_MESSAGE_SIZE {
+            return Err(MESSAGE_TOO_BIG);
+        }
+        if self.no_context_takeover {
+            self.decompress.reset(false);
+        }
+        Ok(out)
+    }
+}
+
+// Why an upgrade request was refused.
+pub(crate) enum HandshakeError {
+    // Not a WebSocket upgrade at all.
+    NotUpgrade,
+    UnsupportedVersion,
+    Invalid,
+}
+
+impl HandshakeError {
+    pub(crate) fn respond(&self, resp: &mut Response) {
+        match *self {
+            HandshakeError::NotUpgrade => {
+                resp.set_header("Upgrade", "websocket");
+            }
+            HandshakeError::UnsupportedVersion => {
+                resp.set_header("Sec-WebSocket-Version", "13");
+            }
+            HandshakeError::Invalid => {
+                resp.set_bad_request().send();
+                return;
+            }
+        }
+        resp.set_status(Status::upgrade_required())
+            .set_header("Content-Type", "text/html")
+            .set_body_str("<html><h1>426 Upgrade Required</h1></html>")
+            .send();
+    }
+}
+
+// An accepted upgrade request.
+pub(crate) struct Handshake {
+    accept: String,
+    deflate: Option<DeflateParams>,
+}
+
+impl Handshake {
+    // Checks an upgrade request as RFC 6455 asks.
+    pub(crate) fn check(r: &Request) -> Result<Handshake, HandshakeError> {
+        let upgrade = r.header("Upgrade").is_some_and(|u| has_token(u, "websocket"));
+        let connection = r.header("Connection").is_some_and(|c| has_token(c, "upgrade"));
+        if !upgrade || !connection {
+            return Err(HandshakeError::NotUpgrade);
+        }
+        if r.header("Sec-WebSocket-Version").map(|v| v.trim()) != Some("13") {
+            return Err(HandshakeError::UnsupportedVersion);
+        }
+        let key = match r.header("Sec-WebSocket-Key") {
+            Some(key) => key.trim(),
+            None => return Err(HandshakeError::Invalid),
+        };
+        if BASE64.decode(key).map(|k| k.len()) != Ok(16) {
+            return Err(HandshakeError::Invalid);
+        }
+        let hash = digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY,
+                                  format!("{}{}", key, ACCEPT_GUID).as_bytes());
+        let deflate = r.header("Sec-WebSocket-Extensions").and_then(negotiate_deflate);
+        return Ok(Handshake {
+            accept: BASE64.encode(hash.as_ref()),
+            deflate: deflate,
+        });
+    }
+
+    fn respond(&self, resp: &mut Response) {
+        resp.set_status(Status::switching_protocols())
+            .set_headers(&[("Upgrade", "websocket"),
+                           ("Connection", "Upgrade"),
+                           ("Sec-WebSocket-Accept", &self.accept)]);
+        if let Some(params) = self.deflate {
+            let mut extension = "permessage-deflate".to_string();
+            if params.server_no_context_takeover {
+                extension.push_str("; server_no_context_takeover");
+            }
+            if params.client_no_context_takeover {
+                extension.push_str("; client_no_context_takeover");
+            }
+            resp.set_header("Sec-WebSocket-Extensions", &extension);
+        }
+        resp.send();
+    }
+}
+
+fn has_token(value: &str, token: &str) -> bool {
+    value.split(',').any(|t| t.trim().eq_ignore_ascii_case(token))
+}
+
+// Picks the first permessage-deflate offer we support. We always compress
+// with a full window, so offers limiting ours are declined.
+fn negotiate_deflate(extensions: &str) -> Option<DeflateParams> {
+    'offers: for offer in extensions.split(',') {
+        let mut parts = offer.split(';').map(|p| p.trim());
+        if parts.next() != Some("permessage-deflate") {
+            continue;
+        }
+        let mut params = DeflateParams {
+            server_no_context_takeover: false,
+            client_no_context_takeover: false,
+        };
+        for param in parts {
+            let mut kv = param.splitn(2, '=');
+            let name = kv.next().unwrap_or("").trim();
+            let value = kv.next().map(|v| v.trim().trim_matches('"'));
+            match (name, value) {
+                ("server_no_context_takeover", None) => params.server_no_context_takeover = true,
+                ("client_no_context_takeover", None) => params.client_no_context_takeover = true,
+                ("server_max_window_bits", Some("15")) => {}
+                // The client's window doesn't matter to the decompressor.
+                ("client_max_window_bits", _) => {}
+                _ => continue 'offers,
+            }
+        }
+        return Some(params);
+    }
+    None
+}
+
+struct Frame {
+    fin: bool,
+    rsv1: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+// Parses the frame at the start of `data`. None if it is not all there,
+// an error with the close code if it is invalid.
+fn parse_frame(data: &[u8]) -> Option<Result<(Frame, usize), u16>> {
+    if data.len() < 2 {
+        return None;
+    }
+    let (first, second) = (data[0], data[1]);
+    let opcode = first & 0x0f;
+    if first & RSV2_3 != 0 || second & MASK == 0 {
+        return Some(Err(PROTOCOL_ERROR));
+    }
+    let (len, mut pos) = match second & 0x7f {
+        126 => {
+            if data.len() < 4 {
+                return None;
+            }
+            (u16::from_be_bytes([data[2], data[3]]) as u64, 4)
+        }
+        127 => {
+            if data.len() < 10 {
+                return None;
+            }
+            let mut len = [0; 8];
+            len.copy_from_slice(&data[2..10]);
+            (u64::from_be_bytes(len), 10)
+        }
+        len => (len as u64, 2),
+    };
+    if opcode & 0x8 != 0 && (len > MAX_CONTROL_PAYLOAD as u64 || first & FIN == 0) {
+        return Some(Err(PROTOCOL_ERROR));
+    }
+    if len > MAX_MESSAGE_SIZE as u64 {
+        return Some(Err(MESSAGE_TOO_BIG));
+    }
+    let len = len as usize;
+    if data.len() < pos + 4 + len {
+        return None;
+    }
+    let mask = [data[pos], data[pos + 1], data[pos + 2], data[pos + 3]];
+    pos += 4;
+    let payload = data[pos..pos + len]
+                      .iter()
+                      .enumerate()
+                      .map(|(i, b)| b ^ mask[i % 4])
+                      .collect();
+    let frame = Frame {
+        fin: first & FIN != 0,
+        rsv1: first & RSV1 != 0,
+        opcode: opcode,
+        payload: payload,
+    };
+    Some(Ok((frame, pos + len)))
+}
+
+fn valid_close_code(code: u16) -> bool {
+    matches!(code, 1000..=1003 | 1007..=1014 | 3000..=4999)
+}
+
+// A connection handed over to a WebSocket handler.
+pub(crate) struct WsConn {
+    handler: Box<WebSocketHandler>,
+    ws: WebSocket,
+    data: Vec<u8>,
+    inflate: Option<Inflater>,
+    // The message being received in fragments: its opcode, whether it is
+    // compressed and the payload so far.
+    fragments: Option<(u8, bool, Vec<u8>)>,
+    close_received: bool,
+    // The handler's `close()` was called.
+    closed: bool,
+    // Handler timer tokens, by connection timer token.
+    timers: HashMap<usize, usize>,
+}
+
+impl WsConn {
+    // Sends the 101 response and opens the connection.
+    pub(crate) fn open(handler: Box<WebSocketHandler>,
+                       handshake: Handshake,
+                       request: &Request,
+                       out: &mut Output,
+                       waker: Waker)
+                       -> WsConn {
+        handshake.respond(&mut Response::with_output(out));
+        let deflater = handshake.deflate.map(|params| {
+            Deflater {
+                compress: Compress::new(Compression::default(), false),
+                no_context_takeover: params.server_no_context_takeover,
+            }
+        });
+        let inflater = handshake.deflate.map(|params| {
+            Inflater {
+                decompress: Decompress::new(false),
+                no_context_takeover: params.client_no_context_takeover,
+            }
+        });
+        let mut conn = WsConn {
+            handler: handler,
+            ws: WebSocket {
+                out: Output::new(),
+                deflate: deflater,
+                close_sent: false,
+                timers: Vec::new(),
+                waker: waker,
+            },
+            data: Vec::new(),
+            inflate: inflater,
+            fragments: None,
+            close_received: false,
+            closed: false,
+            timers: HashMap::new(),
+        };
+        conn.handler.open(request, &mut conn.ws);
+        conn.flush(out);
+        conn
+    }
+
+    pub(crate) fn read(&mut self, data: &[u8], out: &mut Output) {
+        if self.close_received {
+            return;
+        }
+        self.data.extend_from_slice(data);
+        let mut pos = 0;
+        while !self.close_received {
+            let (frame, len) = match parse_frame(&self.data[pos..]) {
+                None => break,
+                Some(Ok(frame)) => frame,
+                Some(Err(code)) => {
+                    self.fail(code);
+                    break;
+                }
+            };
+            pos += len;
+            if let Err(code) = self.frame(frame) {
+                self.fail(code);
+                break;
+            }
+        }
+        self.data.drain(..pos);
+        self.flush(out);
+    }
+
+    // Calls the handler for a timer of `timers()`. Returns false if the
+    // token is not one of ours.
+    pub(crate) fn timeout(&mut self, token: usize, out: &mut Output) -> bool {
+        match self.timers.remove(&token) {
+            Some(handler_token) => {
+                if !self.closed {
+                    self.handler.timeout(handler_token, &mut self.ws);
+                    self.flush(out);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub(crate) fn wake(&mut self, out: &mut Output) {
+        if !self.closed {
+            self.handler.wake(&mut self.ws);
+            self.flush(out);
+        }
+    }
+
+    // Closes with GOING_AWAY, for a server shutdown.
+    pub(crate) fn shutdown(&mut self, out: &mut Output) {
+        if !self.ws.close_sent {
+            self.ws.close(GOING_AWAY, "");
+        }
+        self.flush(out);
+    }
+
+    // The timers the handler set, to be scheduled under connection tokens
+    // given with `add_timer()`.
+    pub(crate) fn take_timers(&mut self) -> Vec<(Duration, usize)> {
+        mem::take(&mut self.ws.timers)
+    }
+
+    pub(crate) fn add_timer(&mut self, id: usize, token: usize) {
+        self.timers.insert(id, token);
+    }
+
+    // Waiting for the client to answer our close frame is bounded by the
+    // body timeout. Open connections have no timeout.
+    pub(crate) fn pending(&self) -> PendingRequest {
+        match (self.ws.close_sent, self.close_received) {
+            (true, true) => PendingRequest::None,
+            (true, false) => PendingRequest::Body,
+            (false, _) => PendingRequest::Processing,
+        }
+    }
+
+    fn frame(&mut self, frame: Frame) -> Result<(), u16> {
+        if frame.rsv1 && (self.inflate.is_none() || frame.opcode == CONTINUATION ||
+                          frame.opcode & 0x8 != 0) {
+            return Err(PROTOCOL_ERROR);
+        }
+        match frame.opcode {
+            CONTINUATION => {
+                let (opcode, compressed, mut payload) = match self.fragments.take() {
+                    Some(fragments) => fragments,
+                    None => return Err(PROTOCOL_ERROR),
+                };
+                if payload.len() + frame.payload.len() > MAX_MESSAGE_SIZE {
+                    return Err(MESSAGE_TOO_BIG);
+                }
+                payload.extend_from_slice(&frame.payload);
+                if frame.fin {
+                    self.message(opcode, compressed, payload)
+                } else {
+                    self.fragments = Some((opcode, compressed, payload));
+                    Ok(())
+                }
+            }
+            TEXT | BINARY => {
+                if self.fragments.is_some() {
+                    return Err(PROTOCOL_ERROR);
+                }
+                if frame.fin {
+                    self.message(frame.opcode, frame.rsv1, frame.payload)
+                } else {
+                    self.fragments = Some((frame.opcode, frame.rsv1, frame.payload));
+                    Ok(())
+                }
+            }
+            CLOSE => {
+                let (code, reason) = match frame.payload.len() {
+                    0 => (NO_STATUS, String::new()),
+                    1 => return Err(PROTOCOL_ERROR),
+                    _ => {
+                        let code = u16::from_be_bytes([frame.payload[0], frame.payload[1]]);
+                        if !valid_close_code(code) {
+                            return Err(PROTOCOL_ERROR);
+                        }
+                        match str::from_utf8(&frame.payload[2..]) {
+                            Ok(reason) => (code, reason.to_string()),
+                            Err(_) => return Err(INVALID_PAYLOAD),
+                        }
+                    }
+                };
+                self.close_received = true;
+                if !self.ws.close_sent {
+                    // Echo the code, as the closing handshake asks.
+                    let payload = if code == NO_STATUS {
+                        Vec::new()
+                    } else {
+                        code.to_be_bytes().to_vec()
+                    };
+                    self.ws.send_control(CLOSE, &payload);
+                    self.ws.close_sent = true;
+                }
+                self.closed(code, &reason);
+                Ok(())
+            }
+            PING => {
+                self.ws.pong(&frame.payload);
+                self.deliver(Message::Ping(frame.payload));
+                Ok(())
+            }
+            PONG => {
+                self.deliver(Message::Pong(frame.payload));
+                Ok(())
+            }
+            _ => Err(PROTOCOL_ERROR),
+        }
+    }
+
+    fn message(&mut self, opcode: u8, compressed: bool, payload: Vec<u8>) -> Result<(), u16> {
+        let payload = if compressed {
+            self.inflate.as_mut().unwrap().decompress(&payload)?
+        } else {
+            payload
+        };
+        let message = if opcode == TEXT {
+            match String::from_utf8(payload) {
+                Ok(text) => Message::Text(text),
+                Err(_) => return Err(INVALID_PAYLOAD),
+            }
+        } else {
+            Message::Binary(payload)
+        };
+        self.deliver(message);
+        Ok(())
+    }
+
+    fn deliver(&mut self, message: Message) {
+        if !self.closed {
+            self.handler.message(message, &mut self.ws);
+        }
+    }
+
+    // Closes the connection because of an error from the client.
+    fn fail(&mut self, code: u16) {
+        debug!("Closing WebSocket connection with code {}", code);
+        if !self.ws.close_sent {
+            self.ws.close(code, "");
+        }
+        // Nothing more is read from a client that broke the protocol.
+        self.close_received = true;
+        self.closed(code, "");
+    }
+
+    fn closed(&mut self, code: u16, reason: &str) {
+        if !self.closed {
+            self.closed = true;
+            self.handler.close(code, reason);
+        }
+    }
+
+    // Queues what the handler sent on the connection, and closes it once
+    // both sides sent their close frame.
+    fn flush(&mut self, out: &mut Output) {
+        out.append(mem::replace(&mut self.ws.out, Output::new()));
+        if self.ws.close_sent && self.close_received {
+            out.close();
+        }
+    }
+}
+
+impl Drop for WsConn {
+    fn drop(&mut self) {
+        self.closed(ABNORMAL_CLOSURE, "");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use super::*;
+
+    // A client frame, masked as clients must.
+    fn client_frame(first: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x37, 0xfa, 0x21, 0x3d];
+        let mut out = Output::new();
+        write_frame(&mut out, first, payload);
+        let mut frame = out.queued_data();
+        let pos = frame.len() - payload.len();
+        frame[1] |= MASK;
+        frame.truncate(pos);
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    fn upgrade_request(extensions: Option<&str>) -> Request {
+        let mut headers = vec![("Upgrade".to_string(), "websocket".to_string()),
+                               ("Connection".to_string(), "keep-alive, Upgrade".to_string()),
+                               ("Sec-WebSocket-Version".to_string(), "13".to_string()),
+                               ("Sec-WebSocket-Key".to_string(),
+                                "dGhlIHNhbXBsZSBub25jZQ==".to_string())];
+        if let Some(extensions) = extensions {
+            headers.push(("Sec-WebSocket-Extensions".to_string(), extensions.to_string()));
+        }
+        Request::from_parts("GET", "/ws", "HTTP/1.1", headers, Vec::new()).unwrap()
+    }
+
+    // Echoes text messages and records what it was given.
+    struct Echo(Arc<Mutex<Vec<Message>>>, Arc<Mutex<Option<u16>>>);
+
+    impl WebSocketHandler for Echo {
+        fn message(&mut self, message: Message, ws: &mut WebSocket) {
+            if let Message::Text(ref text) = message {
+                ws.send_text(text);
+            }
+            self.0.lock().unwrap().push(message);
+        }
+        fn close(&mut self, code: u16, _reason: &str) {
+            *self.1.lock().unwrap() = Some(code);
+        }
+        fn duplicate(&self) -> Box<WebSocketHandler> {
+            Box::new(Echo(self.0.clone(), self.1.clone()))
+        }
+    }
+
+    fn open(extensions: Option<&str>) -> (WsConn, Echo, Output) {
+        let echo = Echo(Arc::new(Mutex::new(Vec::new())), Arc::new(Mutex::new(None)));
+        let r = upgrade_request(extensions);
+        let handshake = Handshake::check(&r).ok().unwrap();
+        let mut out = Output::new();
+        let conn = WsConn::open(echo.duplicate(), handshake, &r, &mut out, Waker::detached());
+        let response = String::from_utf8(out.take_queued().queued_data()).unwrap();
+        assert!(response.starts_with("HTTP/1.1 101 "));
+        (conn, echo, out)
+    }
+
+    fn close_frame(code: u16) -> Vec<u8> {
+        let mut out = Output::new();
+        write_frame(&mut out, FIN | CLOSE, &code.to_be_bytes());
+        out.queued_data()
+    }
+
+    #[test]
+    fn frame_lengths() {
+        for &(len, head) in &[(125, 2), (126, 4), (0xffff, 4), (0x10000, 10)] {
+            let mut out = Output::new();
+            write_frame(&mut out, FIN | BINARY, &vec![7; len]);
+            let data = out.queued_data();
+            assert_eq!(data.len(), head + len);
+            let frame = client_frame(FIN | BINARY, &vec![7; len]);
+            let (frame, used) = parse_frame(&frame).unwrap().ok().unwrap();
+            assert_eq!(used, head + 4 + len);
+            assert!(frame.fin && !frame.rsv1);
+            assert_eq!(frame.opcode, BINARY);
+            assert_eq!(frame.payload, vec![7; len]);
+        }
+    }
+
+    #[test]
+    fn partial_and_invalid_frames() {
+        let frame = client_frame(FIN | TEXT, b"Hello");
+        assert!(frame[2..6] != [0; 4]);
+        for len in 0..frame.len() {
+            assert!(parse_frame(&frame[..len]).is_none());
+        }
+        let (parsed, _) = parse_frame(&frame).unwrap().ok().unwrap();
+        assert_eq!(parsed.payload, b"Hello");
+        // Unmasked.
+        let mut out = Output::new();
+        write_frame(&mut out, FIN | TEXT, b"Hello");
+        assert_eq!(parse_frame(&out.queued_data()).unwrap().err(), Some(PROTOCOL_ERROR));
+        // Reserved bits, and control frames that are fragmented or too long.
+        let frame = client_frame(FIN | 0x20 | TEXT, b"x");
+        assert_eq!(parse_frame(&frame).unwrap().err(), Some(PROTOCOL_ERROR));
+        let frame = client_frame(PING, b"x");
+        assert_eq!(parse_frame(&frame).unwrap().err(), Some(PROTOCOL_ERROR));
+        let frame = client_frame(FIN | PING, &[0; 126]);
+        assert_eq!(parse_frame(&frame).unwrap().err(), Some(PROTOCOL_ERROR));
+        let mut frame = vec![FIN | BINARY, MASK | 127];
+        frame.extend_from_slice(&(MAX_MESSAGE_SIZE as u64 + 1).to_be_bytes());
+        assert_eq!(parse_frame(&frame).unwrap().err(), Some(MESSAGE_TOO_BIG));
+    }
+
+    #[test]
+    fn handshake() {
+        let r = upgrade_request(None);
+        let handshake = Handshake::check(&r).ok().unwrap();
+        assert_eq!(handshake.accept, "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+        assert!(handshake.deflate.is_none());
+        let r = Request::from_parts("GET", "/ws", "HTTP/1.1", Vec::new(), Vec::new()).unwrap();
+        assert!(matches!(Handshake::check(&r), Err(HandshakeError::NotUpgrade)));
+    }
+
+    #[test]
+    fn deflate_offers() {
+        let params = negotiate_deflate("permessage-deflate; client_max_window_bits").unwrap();
+        assert!(!params.server_no_context_takeover && !params.client_no_context_takeover);
+        let params = negotiate_deflate("permessage-deflate; server_max_window_bits=10, \
+                                        permessage-deflate; server_no_context_takeover")
+                         .unwrap();
+        assert!(params.server_no_context_takeover);
+        assert!(negotiate_deflate("x-webkit-deflate-frame").is_none());
+        assert!(negotiate_deflate("permessage-deflate; server_max_window_bits=9").is_none());
+    }
+
+    #[test]
+    fn messages_and_fragments() {
+        let (mut conn, echo, mut out) = open(None);
+        let mut data = client_frame(TEXT, b"Hel");
+        data.extend_from_slice(&client_frame(FIN | PING, b"p"));
+        data.extend_from_slice(&client_frame(FIN | CONTINUATION, b"lo"));
+        data.extend_from_slice(&client_frame(FIN | BINARY, &[1, 2]));
+        let (first, rest) = data.split_at(5);
+        conn.read(first, &mut out);
+        conn.read(rest, &mut out);
+        assert_eq!(*echo.0.lock().unwrap(),
+                   vec![Message::Ping(b"p".to_vec()),
+                        Message::Text("Hello".to_string()),
+                        Message::Binary(vec![1, 2])]);
+        let mut expected = vec![FIN | PONG, 1, b'p', FIN | TEXT, 5];
+        expected.extend_from_slice(b"Hello");
+        assert_eq!(out.take_queued().queued_data(), expected);
+        assert_eq!(conn.pending(), PendingRequest::Processing);
+    }
+
+    #[test]
+    fn closing_handshake() {
+        let (mut conn, echo, mut out) = open(None);
+        conn.read(&client_frame(FIN | CLOSE, &GOING_AWAY.to_be_bytes()), &mut out);
+        assert_eq!(*echo.1.lock().unwrap(), Some(GOING_AWAY));
+        assert_eq!(out.queued_data(), close_frame(GOING_AWAY));
+        assert!(out.is_closing());
+        assert_eq!(conn.pending(), PendingRequest::None);
+    }
+
+    #[test]
+    fn protocol_errors_close_the_connection() {
+        let (mut conn, echo, mut out) = open(None);
+        let mut out_unmasked = Output::new();
+        write_frame(&mut out_unmasked, FIN | TEXT, b"x");
+        conn.read(&out_unmasked.queued_data(), &mut out);
+        assert_eq!(*echo.1.lock().unwrap(), Some(PROTOCOL_ERROR));
+        assert_eq!(out.take_queued().queued_data(), close_frame(PROTOCOL_ERROR));
+        assert_eq!(conn.pending(), PendingRequest::None);
+
+        let (mut conn, echo, mut out) = open(None);
+        conn.read(&client_frame(FIN | TEXT, &[0xff, 0xfe]), &mut out);
+        assert_eq!(*echo.1.lock().unwrap(), Some(INVALID_PAYLOAD));
+        assert!(echo.0.lock().unwrap().is_empty());
+        // Compressed frames without the extension.
+        let (mut conn, echo, mut out) = open(None);
+        conn.read(&client_frame(FIN | RSV1 | TEXT, b"x"), &mut out);
+        assert_eq!(*echo.1.lock().unwrap(), Some(PROTOCOL_ERROR));
+    }
+
+    #[test]
+    fn compressed_messages() {
+        let (mut conn, echo, mut out) = open(Some("permessage-deflate"));
+        let mut deflater = Deflater {
+            compress: Compress::new(Compression::default(), false),
+            no_context_takeover: false,
+        };
+        // The second message refers to the first one.
+        for _ in 0..2 {
+            let compressed = deflater.compress(b"Hello Hello Hello");
+            conn.read(&client_frame(FIN | RSV1 | TEXT, &compressed), &mut out);
+        }
+        assert_eq!(*echo.0.lock().unwrap(),
+                   vec![Message::Text("Hello Hello Hello".to_string()); 2]);
+        let data = out.take_queued().queued_data();
+        let mut inflater = Inflater {
+            decompress: Decompress::new(false),
+            no_context_takeover: false,
+        };
+        assert_eq!(data[0], FIN | RSV1 | TEXT);
+        let len = data[1] as usize;
+        assert_eq!(inflater.decompress(&data[2..2 + len]), Ok(b"Hello Hello Hello".to_vec()));
+        assert!(echo.1.lock().unwrap().is_none());
+    }
+}
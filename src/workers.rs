@@ -0,0 +1,144 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Counters updated by the event loop and its worker threads.
+struct WorkerLoad {
+    connections: AtomicUsize,
+    queued: AtomicUsize,
+    events: AtomicUsize,
+}
+
+/// A snapshot of the load of a worker thread.
+#[derive(Debug, Clone, Copy)]
+pub struct WorkerStats {
+    /// Connections currently handled by the worker.
+    pub connections: usize,
+    /// Messages sent to the worker that it hasn't processed yet.
+    pub queue_depth: usize,
+    /// Messages processed since the server started.
+    pub events: usize,
+}
+
+/// Load counters of all the worker threads of a server, shared with the
+/// threads that update them.
+#[derive(Clone)]
+pub struct WorkerLoads {
+    workers: Arc<Vec<WorkerLoad>>,
+}
+
+impl WorkerLoads {
+    pub fn new(num_workers: usize) -> WorkerLoads {
+        let mut workers = Vec::new();
+        for _ in 0..num_workers {
+            workers.push(WorkerLoad {
+                connections: AtomicUsize::new(0),
+                queued: AtomicUsize::new(0),
+                events: AtomicUsize::new(0),
+            });
+        }
+        return WorkerLoads { workers: Arc::new(workers) };
+    }
+
+    pub fn len(&self) -> usize {
+        self.workers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.workers.is_empty()
+    }
+
+    pub fn stats(&self) -> Vec<WorkerStats> {
+        self.workers
+            .iter()
+            .map(|w| {
+                WorkerStats {
+                    connections: w.connections.load(Ordering::Relaxed),
+                    queue_depth: w.queued.load(Ordering::Relaxed),
+                    events: w.events.load(Ordering::Relaxed),
+                }
+            })
+            .collect()
+    }
+
+    // A new connection was handed to the worker.
+    pub(crate) fn assigned(&self, worker: usize) {
+        self.workers[worker].connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn queued(&self, worker: usize) {
+        self.workers[worker].queued.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn dequeued(&self, worker: usize) {
+        let w = &self.workers[worker];
+        w.queued.fetch_sub(1, Ordering::Relaxed);
+        w.events.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // An event was handled without going through the queue.
+    pub(crate) fn handled(&self, worker: usize) {
+        self.workers[worker].events.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn closed(&self, worker: usize, closed: usize) {
+        if closed > 0 {
+            self.workers[worker].connections.fetch_sub(closed, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Picks the worker thread that handles a new connection. A connection stays
+/// on that worker until it is closed.
+pub trait Assignment : Send + 'static {
+    fn assign(&mut self, workers: &[WorkerStats]) -> usize;
+}
+
+/// Hands connections to each worker in turn.
+pub struct RoundRobin {
+    next: usize,
+}
+
+impl RoundRobin {
+    pub fn new() -> RoundRobin {
+        RoundRobin { next: 0 }
+    }
+}
+
+impl Assignment for RoundRobin {
+    fn assign(&mut self, workers: &[WorkerStats]) -> usize {
+        let worker = self.next % workers.len();
+        self.next = worker + 1;
+        worker
+    }
+}
+
+/// Picks the worker with the fewest open connections.
+pub struct LeastConnections;
+
+impl Assignment for LeastConnections {
+    fn assign(&mut self, workers: &[WorkerStats]) -> usize {
+        least_by(workers, |w| (w.connections, w.queue_depth))
+    }
+}
+
+/// Picks the worker with the fewest messages waiting in its queue, which
+/// favors the workers that aren't stuck in long running handlers.
+pub struct LeastBusy;
+
+impl Assignment for LeastBusy {
+    fn assign(&mut self, workers: &[WorkerStats]) -> usize {
+        least_by(workers, |w| (w.queue_depth, w.connections))
+    }
+}
+
+fn least_by<F>(workers: &[WorkerStats], key: F) -> usize
+    where F: Fn(&WorkerStats) -> (usize, usize)
+{
+    let mut best = 0;
+    for i in 1..workers.len() {
+        if key(&workers[i]) < key(&workers[best]) {
+            best = i;
+        }
+    }
+    best
+}